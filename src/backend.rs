@@ -0,0 +1,139 @@
+use std::env;
+use std::io;
+use std::net;
+use std::path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use crate::exit::ExitConvention;
+use crate::firmware::Firmware;
+use crate::image::BootDrive;
+use crate::message::msg;
+use crate::target::Arch;
+
+/// 起動するVMの構成
+pub struct VmConfig<'a> {
+    pub firmware: &'a Firmware,
+    pub drive: &'a BootDrive,
+    /// VMMに渡す追加の引数
+    pub options: Vec<String>,
+}
+
+/// シリアルコンソールの繋ぎ方
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Serial {
+    /// 利用者の端末に繋ぐ。ゲストの出力を振り分けるため、標準出力はパイプにする
+    Terminal,
+    /// 標準入出力をパイプにして、呼び出し元がシリアルを読み書きする
+    Piped,
+}
+
+/// VMを実行する仮想化ソフトウェア。
+/// VMはどれも1つのプロセスとして扱い、シリアルはその標準入出力、VMM自身の出力は標準エラー出力に流す
+pub trait VmBackend {
+    /// VMMの実行ファイル。異常終了後の片付けで、残ったプロセスを確かめるためにも使う
+    fn program(&self) -> &path::Path;
+
+    /// VMを起動する。標準エラー出力は常にパイプにする
+    fn spawn(&self, vm: &VmConfig, serial: Serial) -> Result<Child, io::Error>;
+
+    /// `addr` で待ち受けるQMP互換の制御チャネルを有効にする引数
+    fn control_args(&self, addr: net::SocketAddr) -> Vec<String>;
+
+    /// VMMの標準エラー出力から、起動に失敗した理由と対処を示すメッセージを作る
+    fn explain_failure(&self, stderr: &[String], vm: &VmConfig) -> Option<String>;
+
+    /// VMMの終了状態をホストの終了コードに変換する
+    fn exit_code(&self, status: Option<ExitStatus>, convention: Option<&ExitConvention>) -> i32 {
+        crate::exit::host_exit_code(status, convention)
+    }
+}
+
+/// QEMUのシステムエミュレータ
+pub struct Qemu {
+    executable: path::PathBuf,
+}
+
+impl Qemu {
+    pub fn new(executable: path::PathBuf) -> Qemu {
+        Qemu { executable }
+    }
+
+    /// PATHから対象アーキテクチャのQEMUを探す
+    pub fn find(arch: Arch) -> Result<Qemu, io::Error> {
+        let qemu_name = arch.qemu_name();
+
+        let exec_path = env::var_os("PATH").and_then(|paths| {
+            env::split_paths(&paths).map(|path| path.join(qemu_name)).find(|path| path.is_file())
+        });
+
+        exec_path
+            .map(Qemu::new)
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, msg!(NotFound, qemu_name)))
+    }
+}
+
+impl VmBackend for Qemu {
+    fn program(&self) -> &path::Path {
+        self.executable.as_path()
+    }
+
+    fn spawn(&self, vm: &VmConfig, serial: Serial) -> Result<Child, io::Error> {
+        let (stdin, stdout, defaults) = match serial {
+            // ゲストが付けたタグに従って出力を振り分けるため、標準出力はパイプにする。
+            // JSON形式では、QEMUの出力を行ごとのイベントに変換して標準出力に流す
+            Serial::Terminal => (Stdio::inherit(), Stdio::piped(), crate::output::qemu_args(&vm.options)),
+            Serial::Piped => (Stdio::piped(), Stdio::piped(), Vec::new()),
+        };
+
+        let mut command = Command::new(self.executable.as_path());
+        command
+            .args(vm.firmware.pflash_args())
+            .arg("-drive")
+            .arg(vm.drive.drive_arg())
+            .args(defaults)
+            .args(vm.options.iter())
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(Stdio::piped());
+        crate::limits::apply(&mut command);
+
+        let process = command.spawn().map_err(crate::limits::explain_spawn_error)?;
+        crate::limits::attach(&process);
+        Ok(process)
+    }
+
+    fn control_args(&self, addr: net::SocketAddr) -> Vec<String> {
+        crate::qmp::qmp_args(addr)
+    }
+
+    fn explain_failure(&self, stderr: &[String], vm: &VmConfig) -> Option<String> {
+        crate::launch::translate(stderr, self.executable.as_path(), vm.firmware)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::backend::{Qemu, Serial, VmBackend, VmConfig};
+    use crate::firmware::Firmware;
+    use crate::image::BootDrive;
+
+    #[cfg(unix)]
+    #[test]
+    fn qemu_backend_spawns_with_firmware_and_drive() {
+        // 引数をそのまま標準出力に書くVMMとして使う
+        let qemu = Qemu::new(path::PathBuf::from("echo"));
+        let firmware = Firmware { code: "OVMF_CODE.fd".into(), vars: Some("OVMF_VARS.fd".into()), shell: None };
+        let drive = BootDrive::Directory(path::PathBuf::from("UEFI"));
+        let vm = VmConfig { firmware: &firmware, drive: &drive, options: vec!["-m".to_string(), "256M".to_string()] };
+
+        let output = qemu.spawn(&vm, Serial::Piped).unwrap().wait_with_output().unwrap();
+        let args = String::from_utf8(output.stdout).unwrap();
+        assert!(args.contains("file=OVMF_CODE.fd") && args.contains("-drive") && args.trim_end().ends_with("-m 256M"), "{}", args);
+        assert!(qemu.control_args("127.0.0.1:4444".parse().unwrap()).iter().any(|a| a.contains("127.0.0.1:4444")));
+
+        let stderr = ["qemu: Address already in use".to_string()];
+        assert!(qemu.explain_failure(&stderr, &vm).is_some());
+        // タイムアウトは失敗として扱う
+        assert_eq!(qemu.exit_code(None, None), 1);
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::BTreeMap;
+use std::path;
+use serde_json::{json, Value};
+use crate::pe::{PeFile, Symbol};
+use crate::size::format_size;
+
+/// コードを含むセクションの特性フラグ
+const SCN_CNT_CODE: u32 = 0x0000_0020;
+
+/// 読み取ったシンボルと、その読み取り元
+pub struct Symbols {
+    pub from: String,
+    pub symbols: Vec<Symbol>,
+}
+
+/// 関数のサイズと、それを定義したクレート
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub krate: String,
+    pub size: u64,
+}
+
+/// EFIファイルのサイズの内訳
+#[derive(Clone, Debug)]
+pub struct SizeReport {
+    pub file_size: u64,
+    pub headers: u64,
+    /// セクション名とファイル上の大きさ
+    pub sections: Vec<(String, u64)>,
+    /// シンボルを読み取った場所。シンボルが見つからなかった場合は `None`
+    pub symbols_from: Option<String>,
+    /// コードセクションのうち、シンボルに割り当てられたバイト数
+    pub code_size: u64,
+    pub functions: Vec<Function>,
+    pub crates: Vec<(String, u64)>,
+}
+
+/// シンボルの読み取り元を探す。PE自身のシンボルテーブル、`pdb` で指定したファイル、
+/// デバッグディレクトリに記録されたPDB、EFIファイルと同じ場所にある同名のPDBの順に調べる
+pub fn find_symbols(file: &path::Path, pe: &PeFile, pdb: Option<&path::Path>) -> Result<Option<Symbols>, Box<dyn std::error::Error>> {
+    let symbols = pe.symbols();
+    if !symbols.is_empty() && pdb.is_none() {
+        return Ok(Some(Symbols { from: "COFF symbol table".to_string(), symbols }));
+    }
+
+    let candidates: Vec<path::PathBuf> = match pdb {
+        Some(pdb) => vec![pdb.to_path_buf()],
+        None => pe.debug_entries().into_iter()
+            .filter_map(|d| d.pdb.map(path::PathBuf::from))
+            .chain(std::iter::once(file.with_extension("pdb")))
+            .collect(),
+    };
+    for candidate in candidates {
+        if pdb.is_none() && !candidate.is_file() {
+            continue;
+        }
+        let symbols = crate::pdb::public_symbols(&std::fs::read(candidate.as_path())?)?;
+        return Ok(Some(Symbols { from: candidate.display().to_string(), symbols }));
+    }
+
+    Ok(None)
+}
+
+/// セクションごと、関数ごと、クレートごとにサイズを集計する。
+///
+/// シンボル自体は大きさを持たないため、同じセクション内で次のシンボルまでの距離を関数の大きさとみなす。
+pub fn attribute(pe: &PeFile, symbols: Option<Symbols>) -> SizeReport {
+    let sections = pe.sections.iter().map(|s| (s.name.clone(), s.raw_size as u64)).collect();
+    let (symbols_from, symbols) = match symbols {
+        Some(s) => (Some(s.from), s.symbols),
+        None => (None, Vec::new()),
+    };
+
+    let mut functions = Vec::new();
+    for (idx, section) in pe.sections.iter().enumerate() {
+        if section.characteristics & SCN_CNT_CODE == 0 {
+            continue;
+        }
+
+        let mut in_section: Vec<&Symbol> = symbols.iter()
+            .filter(|s| s.section as usize == idx + 1 && s.offset < section.virtual_size)
+            .collect();
+        in_section.sort_by_key(|s| s.offset);
+        // 同じアドレスの別名は最初の1つだけを数える
+        in_section.dedup_by_key(|s| s.offset);
+
+        for (i, symbol) in in_section.iter().enumerate() {
+            let end = in_section.get(i + 1).map(|s| s.offset).unwrap_or(section.virtual_size);
+            let (name, krate) = demangle(symbol.name.as_str());
+            functions.push(Function { name, krate, size: (end - symbol.offset) as u64 });
+        }
+    }
+    functions.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+
+    let mut crates: BTreeMap<String, u64> = BTreeMap::new();
+    for function in functions.iter() {
+        *crates.entry(function.krate.clone()).or_default() += function.size;
+    }
+    let mut crates: Vec<(String, u64)> = crates.into_iter().collect();
+    crates.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    SizeReport {
+        file_size: pe.file_size(),
+        headers: pe.size_of_headers as u64,
+        sections,
+        symbols_from,
+        code_size: functions.iter().map(|f| f.size).sum(),
+        functions,
+        crates,
+    }
+}
+
+/// Rustのシンボル名を読みやすい形とクレート名にする。Rustのシンボルでなければクレート名は `[Unknown]`
+pub fn demangle(symbol: &str) -> (String, String) {
+    let unknown = || (symbol.to_string(), "[Unknown]".to_string());
+
+    if let Some(mangled) = symbol.strip_prefix("_R").or_else(|| symbol.strip_prefix("__R")) {
+        // v0形式は名前の復元までは行わず、クレート名だけを取り出す
+        return match v0_crate(mangled) {
+            Some(krate) => (symbol.to_string(), krate),
+            None => unknown(),
+        };
+    }
+
+    let mut rest = match symbol.strip_prefix("_ZN").or_else(|| symbol.strip_prefix("__ZN")) {
+        Some(rest) => rest,
+        None => return unknown(),
+    };
+    let mut components = Vec::new();
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+        let len: usize = match rest[..digits].parse() {
+            Ok(len) => len,
+            Err(_) => return unknown(),
+        };
+        match rest.get(digits..digits + len) {
+            Some(component) => components.push(component),
+            None => return unknown(),
+        }
+        rest = &rest[digits + len..];
+    }
+
+    // 末尾のハッシュは表示しない
+    if let Some(last) = components.last() {
+        if last.len() == 17 && last.starts_with('h') && last[1..].bytes().all(|b| b.is_ascii_hexdigit()) {
+            components.pop();
+        }
+    }
+    let name = components.iter().map(|c| unescape(c)).collect::<Vec<_>>().join("::");
+    let krate = match name.strip_prefix('<') {
+        // `<alloc::vec::Vec<T> as core::ops::drop::Drop>::drop` は実装している型のクレートに数える
+        Some(inner) => inner.trim_start_matches(['&', '*']).trim_start_matches("mut ").trim_start_matches("const ")
+            .split([':', '<', ' ', '>']).next().unwrap_or("").to_string(),
+        None => name.split("::").next().unwrap_or("").to_string(),
+    };
+    if krate.is_empty() {
+        return (name, "[Unknown]".to_string());
+    }
+
+    (name, krate)
+}
+
+fn unescape(component: &str) -> String {
+    const ESCAPES: &[(&str, &str)] = &[
+        ("$LT$", "<"), ("$GT$", ">"), ("$RF$", "&"), ("$BP$", "*"), ("$C$", ","), ("$SP$", "@"),
+        ("$LP$", "("), ("$RP$", ")"), ("$u20$", " "), ("$u22$", "\""), ("$u27$", "'"), ("$u2b$", "+"),
+        ("$u3b$", ";"), ("$u5b$", "["), ("$u5d$", "]"), ("$u7b$", "{"), ("$u7d$", "}"), ("$u7e$", "~"),
+    ];
+
+    let component = component.strip_prefix("_$").map(|c| format!("${}", c)).unwrap_or_else(|| component.to_string());
+    let mut out = String::new();
+    let mut rest = component.as_str();
+    while !rest.is_empty() {
+        if let Some((from, to)) = ESCAPES.iter().find(|(from, _)| rest.starts_with(from)) {
+            out.push_str(to);
+            rest = &rest[from.len()..];
+        } else if let Some(r) = rest.strip_prefix("..") {
+            out.push_str("::");
+            rest = r;
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    out
+}
+
+/// v0形式のシンボルのパスを先頭からたどり、クレートのルートの名前を返す
+fn v0_crate(mangled: &str) -> Option<String> {
+    let mut rest = mangled.trim_start_matches(|c: char| c.is_ascii_digit());
+    loop {
+        match rest.as_bytes().first()? {
+            // 入れ子のパスは `N<名前空間><親のパス><名前>` なので、親のパスへ進む
+            b'N' => rest = rest.get(2..)?,
+            b'I' => rest = &rest[1..],
+            b'M' | b'X' => {
+                rest = &rest[1..];
+                if let Some(r) = rest.strip_prefix('s') {
+                    rest = &r[r.find('_')? + 1..];
+                }
+            }
+            b'C' => {
+                rest = &rest[1..];
+                if let Some(r) = rest.strip_prefix('s') {
+                    rest = &r[r.find('_')? + 1..];
+                }
+                let digits = rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+                let len: usize = rest[..digits].parse().ok()?;
+                let rest = rest[digits..].strip_prefix('_').unwrap_or(&rest[digits..]);
+                return rest.get(..len).map(|s| s.to_string());
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn percent(part: u64, whole: u64) -> String {
+    if whole == 0 {
+        return "-".to_string();
+    }
+    format!("{:.1}%", part as f64 * 100.0 / whole as f64)
+}
+
+pub fn render(report: &SizeReport, top: usize) -> String {
+    let mut out = String::new();
+
+    out.push_str(&format!("File size: {}\n\n", format_size(report.file_size)));
+    out.push_str(&format!("  {:>7} {:>10}  {}\n", "File", "Size", "Section"));
+    out.push_str(&format!("  {:>7} {:>10}  {}\n", percent(report.headers, report.file_size), format_size(report.headers), "[headers]"));
+    for (name, size) in report.sections.iter() {
+        out.push_str(&format!("  {:>7} {:>10}  {}\n", percent(*size, report.file_size), format_size(*size), name));
+    }
+
+    let symbols_from = match &report.symbols_from {
+        Some(from) => from,
+        None => {
+            out.push_str("\nNo symbols found; build with debug info or pass --pdb to attribute code to crates and functions.\n");
+            return out;
+        }
+    };
+    out.push_str(&format!("\nSymbols: {} ({} functions, {} of code)\n", symbols_from, report.functions.len(), format_size(report.code_size)));
+
+    out.push_str(&format!("\n  {:>7} {:>10}  {}\n", "Code", "Size", "Crate"));
+    for (krate, size) in report.crates.iter().take(top) {
+        out.push_str(&format!("  {:>7} {:>10}  {}\n", percent(*size, report.code_size), format_size(*size), krate));
+    }
+    if report.crates.len() > top {
+        out.push_str(&format!("  ... and {} more crates\n", report.crates.len() - top));
+    }
+
+    out.push_str(&format!("\n  {:>7} {:>10}  {:<16} {}\n", "Code", "Size", "Crate", "Function"));
+    for function in report.functions.iter().take(top) {
+        out.push_str(&format!("  {:>7} {:>10}  {:<16} {}\n", percent(function.size, report.code_size), format_size(function.size), function.krate, function.name));
+    }
+    if report.functions.len() > top {
+        out.push_str(&format!("  ... and {} more functions\n", report.functions.len() - top));
+    }
+
+    out
+}
+
+pub fn to_json(file: &path::Path, report: &SizeReport, top: usize) -> Value {
+    let sections: Vec<Value> = report.sections.iter().map(|(name, size)| json!({ "name": name, "size": size })).collect();
+    let crates: Vec<Value> = report.crates.iter().map(|(name, size)| json!({ "name": name, "size": size })).collect();
+    let functions: Vec<Value> = report.functions.iter().take(top)
+        .map(|f| json!({ "name": f.name, "crate": f.krate, "size": f.size }))
+        .collect();
+
+    json!({
+        "file": file,
+        "file-size": report.file_size,
+        "headers": report.headers,
+        "sections": sections,
+        "symbols": report.symbols_from,
+        "code-size": report.code_size,
+        "crates": crates,
+        "functions": functions,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bloat::{attribute, demangle, render, Symbols};
+    use crate::pe::{PeFile, Symbol};
+
+    #[test]
+    fn demangle_rust_symbols() {
+        assert_eq!(demangle("_ZN4core3fmt5write17h0123456789abcdefE"), ("core::fmt::write".to_string(), "core".to_string()));
+        assert_eq!(
+            demangle("_ZN70_$LT$alloc..vec..Vec$LT$T$C$A$GT$$u20$as$u20$core..ops..drop..Drop$GT$4drop17h0123456789abcdefE"),
+            ("<alloc::vec::Vec<T,A> as core::ops::drop::Drop>::drop".to_string(), "alloc".to_string()),
+        );
+        assert_eq!(demangle("_RNvCs1234_4hoge8efi_main").1, "hoge");
+        assert_eq!(demangle("memcpy"), ("memcpy".to_string(), "[Unknown]".to_string()));
+    }
+
+    #[test]
+    fn attribute_code_to_functions_and_crates() {
+        let pe = PeFile::parse(crate::pe::test::sample_image()).unwrap();
+        let symbol = |name: &str, offset: u32| Symbol { name: name.to_string(), section: 1, offset, is_function: true };
+        let symbols = vec![
+            symbol("efi_main", 0x0),
+            symbol("_ZN4core3fmt5write17h0123456789abcdefE", 0x10),
+            symbol("_ZN4core3fmt9Formatter3pad17h0123456789abcdefE", 0xd0),
+            // 別名は数えない
+            symbol("_ZN4hoge4main17h0123456789abcdefE", 0x0),
+        ];
+        let report = attribute(&pe, Some(Symbols { from: "hoge.pdb".to_string(), symbols }));
+
+        assert_eq!(report.code_size, 0x100);
+        assert_eq!(report.functions[0].name, "core::fmt::write");
+        assert_eq!(report.functions[0].size, 0xc0);
+        assert_eq!(report.crates, [("core".to_string(), 0xf0), ("[Unknown]".to_string(), 0x10)]);
+
+        let text = render(&report, 1);
+        assert!(text.contains("  ... and 2 more functions\n"));
+        assert!(render(&attribute(&pe, None), 10).contains("No symbols found"));
+    }
+}
@@ -0,0 +1,158 @@
+use std::io;
+use std::net;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use serde_json::json;
+use crate::message::msg;
+use crate::qmp::Qmp;
+
+/// ゲストの出力に現れたらVMを一時停止する文字列
+static MARKERS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// 実行中のVMの出力を調べる状態。監視していない間は `None`
+static ACTIVE: Mutex<Option<Scanner>> = Mutex::new(None);
+
+/// 以降に起動するVMで、出力に現れたら一時停止する文字列を設定する
+pub fn set_markers(markers: Vec<String>) {
+    *MARKERS.lock().unwrap_or_else(|e| e.into_inner()) = markers.into_iter().filter(|m| !m.is_empty()).collect();
+}
+
+pub fn markers() -> Vec<String> {
+    MARKERS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// ゲストの出力から文字列を探す。出力の区切りをまたいで現れても見つけられるよう、末尾を残しておく
+struct Scanner {
+    markers: Vec<String>,
+    window: Vec<u8>,
+    hits: mpsc::Sender<String>,
+}
+
+impl Scanner {
+    /// 出力を加え、現れた文字列を順に返す。同じ文字列が繰り返し現れた場合はその回数だけ返す
+    fn feed(&mut self, text: &[u8]) -> Vec<String> {
+        self.window.extend_from_slice(text);
+        let mut found = Vec::new();
+        loop {
+            let first = self.markers.iter()
+                .filter_map(|m| self.window.windows(m.len()).position(|w| w == m.as_bytes()).map(|pos| (pos + m.len(), m)))
+                .min_by_key(|(end, _)| *end);
+            match first {
+                Some((end, marker)) => {
+                    found.push(marker.clone());
+                    self.window.drain(..end);
+                }
+                None => break,
+            }
+        }
+
+        let keep = self.markers.iter().map(|m| m.len()).max().unwrap_or(1) - 1;
+        if self.window.len() > keep {
+            self.window.drain(..self.window.len() - keep);
+        }
+        found
+    }
+}
+
+/// ゲストの出力を調べ、文字列が現れたら一時停止を求める
+pub fn observe(text: &[u8]) {
+    if let Some(scanner) = ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        for marker in scanner.feed(text) {
+            let _ = scanner.hits.send(marker);
+        }
+    }
+}
+
+/// 文字列が現れたらQMPの `stop` でVMを一時停止し、GDBで調べられるようにする。
+/// `attach_gdb` であればGDBを起動して接続し、GDBを終了したらVMの実行を再開する
+pub struct BreakMonitor {
+    handle: thread::JoinHandle<Result<usize, io::Error>>,
+}
+
+impl BreakMonitor {
+    pub fn start(markers: Vec<String>, attach_gdb: bool, qmp_addr: net::SocketAddr, gdb_addr: net::SocketAddr) -> BreakMonitor {
+        let (sender, receiver) = mpsc::channel();
+        *ACTIVE.lock().unwrap_or_else(|e| e.into_inner()) = Some(Scanner { markers, window: Vec::new(), hits: sender });
+
+        let handle = thread::spawn(move || {
+            let mut qmp: Option<Qmp> = None;
+            let mut hits = 0;
+            // QEMUが終了して監視を終えるまで続ける
+            while let Ok(marker) = receiver.recv() {
+                let qmp = match &mut qmp {
+                    Some(qmp) => qmp,
+                    None => qmp.insert(Qmp::connect(qmp_addr, Duration::from_secs(10))?),
+                };
+                qmp.execute("stop", None)?;
+                hits += 1;
+
+                let target = format!("target remote {}", gdb_addr);
+                crate::output::status(msg!(BreakpointHit, marker, target));
+                let symbolizer = crate::imagebase::symbolizer();
+                let (pc, location) = crate::freeze::stopped_at(qmp, symbolizer.as_ref());
+                crate::output::event("breakpoint-hit", json!({ "marker": marker, "gdb": gdb_addr.to_string(), "pc": pc, "location": location }));
+                if attach_gdb {
+                    let commands = symbolizer.map(|s| s.gdb_commands()).unwrap_or_default();
+                    crate::freeze::attach_gdb(target.as_str(), &commands);
+                    // 一時停止する前に転送された出力で見つかった分は、再開した直後にまた止めないよう捨てる
+                    receiver.try_iter().for_each(drop);
+                    let _ = qmp.execute("cont", None);
+                }
+            }
+
+            Ok(hits)
+        });
+
+        BreakMonitor { handle }
+    }
+
+    /// QEMUの終了後に呼び、VMを一時停止した回数を返す
+    pub fn finish(self) -> Result<usize, io::Error> {
+        *ACTIVE.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        self.handle.join().unwrap_or_else(|_| Err(io::Error::other("breakpoint monitor panicked")))
+    }
+}
+
+/// ゲストの出力は端末へ転送するときにしか調べないため、シリアルの出力先を `--serial-tcp` や `-serial` で
+/// 端末以外に変えていると文字列を見つけられない
+pub fn serial_redirected(serial_tcp: bool, options: &[String]) -> bool {
+    serial_tcp || options.windows(2).any(|o| o[0] == "-serial" && !["stdio", "mon:stdio", "chardev:serial-log"].contains(&o[1].as_str()))
+}
+
+/// QEMUに渡す引数。GDBの待ち受けをクラッシュの監視が追加しない場合は `gdb_addr` を渡す
+pub fn qemu_args(qmp_addr: net::SocketAddr, gdb_addr: Option<net::SocketAddr>) -> Vec<String> {
+    let mut args = crate::qmp::qmp_args(qmp_addr);
+    if let Some(gdb_addr) = gdb_addr {
+        args.extend(["-gdb".to_string(), format!("tcp:{}", gdb_addr)]);
+    }
+    args
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use crate::breakpoint::{serial_redirected, Scanner};
+
+    #[test]
+    fn find_markers_across_reads() {
+        let (sender, _receiver) = mpsc::channel();
+        let mut scanner = Scanner { markers: vec!["entering stage 2".to_string(), "ready".to_string()], window: Vec::new(), hits: sender };
+
+        assert!(scanner.feed(b"stage 1 done\r\nenter").is_empty());
+        assert_eq!(scanner.feed(b"ing stage 2\r\n"), ["entering stage 2"]);
+        // 一度見つけた出力は二度数えない
+        assert!(scanner.feed(b"\r\n").is_empty());
+        assert_eq!(scanner.feed(b"ready ready, entering stage 2"), ["ready", "ready", "entering stage 2"]);
+        assert!(scanner.window.len() < "entering stage 2".len());
+    }
+
+    #[test]
+    fn detect_serial_away_from_terminal() {
+        let options = |o: &[&str]| o.iter().map(|o| o.to_string()).collect::<Vec<_>>();
+        assert!(!serial_redirected(false, &options(&["-serial", "chardev:serial-log", "-serial", "stdio"])));
+        assert!(serial_redirected(true, &[]));
+        assert!(serial_redirected(false, &options(&["-serial", "file:serial.log"])));
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::path;
+use std::process::{Command, Stdio};
+use serde::Deserialize;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::target::Arch;
+
+/// `cargo build --message-format=json` が出力するメッセージのうち、必要な部分
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    target: Option<CargoTarget>,
+    executable: Option<path::PathBuf>,
+    success: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct CargoTarget {
+    name: String,
+    kind: Vec<String>,
+}
+
+/// cargoにそのまま渡すビルドの指定
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct BuildFlags {
+    /// releaseプロファイルでビルドする
+    #[arg(long, conflicts_with = "profile", global = true)]
+    pub release: bool,
+
+    /// 指定したプロファイルでビルドする
+    #[arg(long, value_name = "NAME", global = true)]
+    pub profile: Option<String>,
+
+    /// 有効にするフィーチャー（カンマ区切り、複数指定可）
+    #[arg(short = 'F', long, value_name = "FEATURES", global = true)]
+    pub features: Vec<String>,
+
+    #[arg(long, global = true)]
+    pub all_features: bool,
+
+    #[arg(long, global = true)]
+    pub no_default_features: bool,
+
+    /// ビルドするパッケージ
+    #[arg(short, long, value_name = "SPEC", global = true)]
+    pub package: Option<String>,
+
+    /// ビルドせず、既にビルドされたEFIファイルを使う
+    #[arg(long, global = true)]
+    pub no_build: bool,
+}
+
+impl BuildFlags {
+    fn cargo_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.release {
+            args.push("--release".to_string());
+        }
+        if let Some(profile) = &self.profile {
+            args.extend(["--profile".to_string(), profile.clone()]);
+        }
+        for features in self.features.iter() {
+            args.extend(["--features".to_string(), features.clone()]);
+        }
+        if self.all_features {
+            args.push("--all-features".to_string());
+        }
+        if self.no_default_features {
+            args.push("--no-default-features".to_string());
+        }
+        if let Some(package) = &self.package {
+            args.extend(["--package".to_string(), package.clone()]);
+        }
+
+        args
+    }
+
+    /// `target/<ターゲット>` 以下の、選んだプロファイルの成果物が置かれるディレクトリの名前
+    pub fn profile_dir(&self) -> &str {
+        match self.profile.as_deref() {
+            _ if self.release => "release",
+            None | Some("dev") | Some("test") => "debug",
+            Some("bench") => "release",
+            Some(profile) => profile,
+        }
+    }
+}
+
+/// ビルド結果
+pub struct BuildOutput {
+    /// バイナリ名と生成されたEFIファイルの対応
+    pub artifacts: HashMap<String, path::PathBuf>,
+    pub success: bool,
+}
+
+impl BuildOutput {
+    /// 生成物が得られなかったバイナリを返す
+    pub fn missing<'a>(&self, names: &'a [String]) -> Vec<&'a String> {
+        names.iter().filter(|n| !self.artifacts.contains_key(n.as_str())).collect()
+    }
+}
+
+fn cargo_command() -> Command {
+    // cargoのサブコマンドとして起動された場合は、同じcargoを使う
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    Command::new(cargo)
+}
+
+/// ワークスペース内の全バイナリを1回のcargo呼び出しでビルドする。
+/// 一部のメンバーのビルドが失敗しても、残りのメンバーのビルドは継続する。
+pub fn build_workspace(project_root: &path::Path, flags: &BuildFlags, arch: Arch, offline: bool) -> Result<BuildOutput, io::Error> {
+    let mut command = cargo_command();
+    if offline {
+        command.arg("--offline");
+    }
+    command.current_dir(project_root).arg("build");
+    // パッケージを指定した場合はそのパッケージのバイナリだけをビルドする
+    if flags.package.is_none() {
+        command.arg("--workspace");
+    }
+    command.arg("--bins").arg("--keep-going").args(flags.cargo_args());
+
+    run_build(command, arch)
+}
+
+/// ワークスペース内の指定したバイナリだけを1回のcargo呼び出しでビルドする。
+/// メンバーごとにアーキテクチャが異なる場合に、アーキテクチャごとに呼ぶ
+pub fn build_bins(project_root: &path::Path, names: &[String], flags: &BuildFlags, arch: Arch, offline: bool) -> Result<BuildOutput, io::Error> {
+    let mut command = cargo_command();
+    if offline {
+        command.arg("--offline");
+    }
+    command.current_dir(project_root).arg("build");
+    if flags.package.is_none() {
+        command.arg("--workspace");
+    }
+    for name in names {
+        command.arg("--bin").arg(name);
+    }
+    command.arg("--keep-going").args(flags.cargo_args());
+
+    run_build(command, arch)
+}
+
+/// バイナリを1つビルドし、生成されたEFIファイルのパスを返す
+pub fn build_bin(project_root: &path::Path, name: &str, flags: &BuildFlags, arch: Arch, offline: bool) -> Result<path::PathBuf, Box<dyn std::error::Error>> {
+    let mut command = cargo_command();
+    if offline {
+        command.arg("--offline");
+    }
+    command.current_dir(project_root).arg("build").arg("--bin").arg(name).args(flags.cargo_args());
+
+    let mut output = run_build(command, arch)?;
+    match output.artifacts.remove(name) {
+        Some(artifact) if output.success => Ok(artifact),
+        _ => Err(Box::new(Error::new(ErrorKind::BuildFailed, msg!(BuildFailed, name)))),
+    }
+}
+
+fn run_build(mut command: Command, arch: Arch) -> Result<BuildOutput, io::Error> {
+    let mut process = command
+        .arg("--target").arg(arch.rust_target())
+        .arg("--message-format=json-render-diagnostics")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(crate::output::child_stderr())
+        .spawn()?;
+
+    let stdout = process.stdout.take().expect("stdout of cargo is not piped");
+    let mut output = BuildOutput { artifacts: HashMap::new(), success: false };
+    for line in io::BufReader::new(stdout).lines() {
+        parse_message(line?.as_str(), &mut output);
+    }
+
+    let status = process.wait()?;
+    output.success &= status.success();
+
+    Ok(output)
+}
+
+fn parse_message(line: &str, output: &mut BuildOutput) {
+    let msg = match serde_json::from_str::<CargoMessage>(line) {
+        Ok(msg) => msg,
+        Err(_) => return,
+    };
+
+    match msg.reason.as_str() {
+        "compiler-artifact" => {
+            if let (Some(target), Some(exe)) = (msg.target, msg.executable) {
+                if target.kind.iter().any(|k| k == "bin") {
+                    output.artifacts.insert(target.name, exe);
+                }
+            }
+        }
+        "build-finished" => output.success = msg.success.unwrap_or(false),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::path;
+    use crate::build::{parse_message, BuildFlags, BuildOutput};
+
+    #[test]
+    fn collect_bin_artifacts() {
+        let lines = [
+            r#"{"reason":"compiler-artifact","target":{"name":"uefi","kind":["lib"]},"executable":null}"#,
+            r#"{"reason":"compiler-artifact","target":{"name":"hoge","kind":["bin"]},"executable":"/p/target/x86_64-unknown-uefi/debug/hoge.efi"}"#,
+            r#"{"reason":"build-finished","success":false}"#,
+        ];
+
+        let mut output = BuildOutput { artifacts: HashMap::new(), success: true };
+        for line in lines {
+            parse_message(line, &mut output);
+        }
+
+        assert!(!output.success);
+        assert_eq!(output.artifacts.len(), 1);
+        assert_eq!(output.artifacts["hoge"], path::Path::new("/p/target/x86_64-unknown-uefi/debug/hoge.efi"));
+
+        let names = vec!["hoge".to_string(), "fuga".to_string()];
+        assert_eq!(output.missing(&names), vec!["fuga"]);
+    }
+
+    #[test]
+    fn build_flags_are_passed_to_cargo() {
+        let flags = BuildFlags {
+            profile: Some("bench".to_string()),
+            features: vec!["log,net".to_string()],
+            no_default_features: true,
+            package: Some("hoge".to_string()),
+            ..BuildFlags::default()
+        };
+        assert_eq!(flags.cargo_args(), ["--profile", "bench", "--features", "log,net", "--no-default-features", "--package", "hoge"]);
+        assert_eq!(flags.profile_dir(), "release");
+        assert_eq!(BuildFlags::default().profile_dir(), "debug");
+        assert_eq!(BuildFlags { profile: Some("ci".to_string()), ..BuildFlags::default() }.profile_dir(), "ci");
+    }
+}
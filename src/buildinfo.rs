@@ -0,0 +1,153 @@
+use std::path;
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+use crate::pe::PeFile;
+
+/// ビルド情報を格納するセクションの名前
+pub const SECTION_NAME: &str = ".build";
+
+/// セクションの先頭に置く目印。`strings` で探しやすいよう、内容はテキストにしている
+const MAGIC: &str = "cargo-uefi build info\n";
+
+/// 配置したバイナリに埋め込むビルド情報
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BuildInfo {
+    /// gitのコミットのハッシュ。gitで管理されていなければ `None`
+    pub revision: Option<String>,
+    /// コミットされていない変更があったか
+    pub dirty: bool,
+    /// ビルドした時刻（UNIX時間の秒）
+    pub timestamp: u64,
+    pub profile: String,
+}
+
+impl BuildInfo {
+    /// プロジェクトのgitの状態と、ビルドされたバイナリからビルド情報を集める。
+    ///
+    /// 時刻は `SOURCE_DATE_EPOCH` が設定されていればその値を、なければバイナリの更新時刻を使うため、
+    /// 同じビルドを何度配置しても同じ内容になる。
+    pub fn collect(project_root: &path::Path, app_path: &path::Path) -> BuildInfo {
+        let git = |args: &[&str]| Command::new("git").arg("-C").arg(project_root).args(args).output().ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string());
+
+        let revision = git(&["rev-parse", "HEAD"]).filter(|r| !r.is_empty());
+        let dirty = revision.is_some() && git(&["status", "--porcelain", "--untracked-files=no"]).is_some_and(|s| !s.is_empty());
+        let timestamp = std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|s| s.parse().ok())
+            .or_else(|| {
+                let modified = std::fs::metadata(app_path).ok()?.modified().ok()?;
+                Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+            })
+            .unwrap_or(0);
+        // target/<ターゲット>/<プロファイル>/<名前>.efi
+        let profile = app_path.parent()
+            .and_then(|p| p.file_name())
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        BuildInfo { revision, dirty, timestamp, profile }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_string();
+        if let Some(revision) = &self.revision {
+            out.push_str(&format!("revision={}\n", revision));
+            out.push_str(&format!("dirty={}\n", self.dirty));
+        }
+        out.push_str(&format!("timestamp={}\n", self.timestamp));
+        out.push_str(&format!("profile={}\n", self.profile));
+        out.into_bytes()
+    }
+
+    fn decode(data: &[u8]) -> Option<BuildInfo> {
+        let text = std::str::from_utf8(data).ok()?.trim_end_matches('\0');
+        let text = text.strip_prefix(MAGIC)?;
+
+        let mut info = BuildInfo::default();
+        for (key, value) in text.lines().filter_map(|l| l.split_once('=')) {
+            match key {
+                "revision" => info.revision = Some(value.to_string()),
+                "dirty" => info.dirty = value == "true",
+                "timestamp" => info.timestamp = value.parse().ok()?,
+                "profile" => info.profile = value.to_string(),
+                // 後のバージョンで増えた項目は無視する
+                _ => {}
+            }
+        }
+
+        Some(info)
+    }
+
+    /// `2024-01-02 03:04:05 UTC` の形式の時刻
+    pub fn time(&self) -> String {
+        let days = (self.timestamp / 86400) as i64;
+        let secs = self.timestamp % 86400;
+
+        // 1970-01-01からの日数を年月日に変換する
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+    }
+
+    /// `abc123def456 (dirty), 2024-01-02 03:04:05 UTC, release` の形式の要約
+    pub fn summary(&self) -> String {
+        let revision = match &self.revision {
+            Some(revision) if self.dirty => format!("{} (dirty)", revision),
+            Some(revision) => revision.clone(),
+            None => "unknown revision".to_string(),
+        };
+        format!("{}, {}, {}", revision, self.time(), self.profile)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "revision": self.revision,
+            "dirty": self.dirty,
+            "timestamp": self.timestamp,
+            "time": self.time(),
+            "profile": self.profile,
+        })
+    }
+}
+
+/// バイナリの内容に、ビルド情報のセクションを追加する
+pub fn stamp(app: Vec<u8>, info: &BuildInfo) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let pe = PeFile::parse(app)?;
+    Ok(pe.with_section(SECTION_NAME, &info.encode())?)
+}
+
+/// バイナリに埋め込まれたビルド情報を読み取る
+pub fn read(pe: &PeFile) -> Option<BuildInfo> {
+    pe.section_data(SECTION_NAME).and_then(BuildInfo::decode)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::buildinfo::{read, BuildInfo, SECTION_NAME};
+    use crate::pe::PeFile;
+
+    #[test]
+    fn stamp_and_read_back() {
+        let info = BuildInfo { revision: Some("0123abcd".to_string()), dirty: true, timestamp: 1_700_000_000, profile: "release".to_string() };
+        let pe = PeFile::parse(crate::pe::test::sample_image()).unwrap();
+        assert_eq!(read(&pe), None);
+
+        let stamped = PeFile::parse(pe.with_section(SECTION_NAME, &info.encode()).unwrap()).unwrap();
+        assert_eq!(stamped.sections.len(), 3);
+        assert_eq!(stamped.sections[2].virtual_address, 0x3000);
+        assert_eq!(stamped.size_of_image, 0x4000);
+        assert_eq!(stamped.relocations().total(), 1);
+        assert_eq!(read(&stamped), Some(info.clone()));
+        assert_eq!(info.summary(), "0123abcd (dirty), 2023-11-14 22:13:20 UTC, release");
+
+        assert!(stamped.with_section(SECTION_NAME, b"").is_err());
+    }
+}
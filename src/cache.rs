@@ -0,0 +1,249 @@
+use std::path;
+use std::time::{Duration, SystemTime};
+use clap::{Args, ValueEnum};
+use serde_json::json;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::size::format_size;
+
+#[derive(Args)]
+pub struct CacheArgs {
+    /// `list` はキャッシュの項目と大きさを、`dir` はキャッシュのディレクトリを表示し、`prune` は古い項目を削除する
+    #[arg(value_enum)]
+    pub action: CacheAction,
+    /// `prune` で削除する項目の、最後に更新されてからの期間（例: `30d`、`12h`）
+    #[arg(long, value_name = "AGE", value_parser = parse_age, required_if_eq("action", "prune"))]
+    pub older_than: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum CacheAction {
+    List,
+    Dir,
+    Prune,
+}
+
+/// キャッシュの種類
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    /// 取得したファームウェアとツールのバンドル。全てのプロジェクトで共有する
+    Firmware,
+    /// ESPから作ったディスクイメージ
+    Images,
+    /// 保存したUEFI変数のプロファイル
+    Varstores,
+}
+
+impl Kind {
+    const ALL: [Kind; 3] = [Kind::Firmware, Kind::Images, Kind::Varstores];
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Firmware => "firmware",
+            Kind::Images => "images",
+            Kind::Varstores => "varstores",
+        }
+    }
+
+    fn dir(self, project_root: &path::Path) -> path::PathBuf {
+        match self {
+            Kind::Firmware => crate::fetch::cache_root(),
+            Kind::Images => project_root.join("target").join("uefi").join("images"),
+            Kind::Varstores => crate::varstore::varstore_dir(project_root),
+        }
+    }
+
+    /// キャッシュの項目。ファームウェアは取得元ごと（例: `ovmf-prebuilt/<タグ>`）のディレクトリ、それ以外はファイル
+    fn entries(self, dir: &path::Path) -> Vec<path::PathBuf> {
+        let children = |dir: &path::Path| -> Vec<path::PathBuf> {
+            let mut paths: Vec<_> = std::fs::read_dir(dir).into_iter().flatten().filter_map(|e| e.ok()).map(|e| e.path()).collect();
+            paths.sort();
+            paths
+        };
+        // ロックは他のプロセスが使っているかもしれないため、項目に含めない
+        let is_lock = |p: &path::Path| p.extension().is_some_and(|e| e == "lock");
+
+        match self {
+            Kind::Firmware => children(dir).into_iter()
+                .filter(|p| p.is_dir())
+                .flat_map(|source| children(source.as_path()))
+                .filter(|p| !is_lock(p))
+                .collect(),
+            Kind::Images => children(dir).into_iter().filter(|p| p.is_file() && !is_lock(p)).collect(),
+            Kind::Varstores => children(dir).into_iter().filter(|p| p.extension().is_some_and(|e| e == "fd")).collect(),
+        }
+    }
+
+    /// 項目を削除する間に取る、取得やイメージの作成と同じロック。プロファイルの保存はロックを取らない
+    fn lock_path(self, entry: &path::Path) -> Option<path::PathBuf> {
+        match self {
+            Kind::Firmware => Some(crate::lock::lock_path_for(entry)),
+            Kind::Images => entry.parent().map(crate::lock::lock_path_for),
+            Kind::Varstores => None,
+        }
+    }
+}
+
+/// キャッシュの1つの項目
+pub struct Entry {
+    pub kind: Kind,
+    pub path: path::PathBuf,
+    pub size: u64,
+    /// 項目の中で最も新しいファイルの更新時刻
+    pub modified: SystemTime,
+}
+
+impl Entry {
+    fn to_json(&self, now: SystemTime) -> serde_json::Value {
+        json!({
+            "kind": self.kind.name(),
+            "path": self.path,
+            "size": self.size,
+            "age-secs": now.duration_since(self.modified).map(|d| d.as_secs()).unwrap_or(0),
+        })
+    }
+}
+
+/// ディレクトリ以下の全てのファイルの大きさの合計と、最も新しい更新時刻
+fn measure(path: &path::Path) -> (u64, SystemTime) {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return (0, SystemTime::UNIX_EPOCH),
+    };
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    if !metadata.is_dir() {
+        return (metadata.len(), modified);
+    }
+
+    std::fs::read_dir(path).into_iter().flatten().filter_map(|e| e.ok()).fold((0, modified), |(size, newest), e| {
+        let (s, m) = measure(e.path().as_path());
+        (size + s, newest.max(m))
+    })
+}
+
+/// 全てのキャッシュの項目を集める
+pub fn collect(project_root: &path::Path) -> Vec<Entry> {
+    Kind::ALL.iter().flat_map(|kind| {
+        kind.entries(kind.dir(project_root).as_path()).into_iter().map(|path| {
+            let (size, modified) = measure(path.as_path());
+            Entry { kind: *kind, path, size, modified }
+        })
+    }).collect()
+}
+
+/// `30d` のような期間を読む。単位は `s`、`m`、`h`、`d`、`w`
+pub fn parse_age(s: &str) -> Result<Duration, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidArgument, msg!(CacheAgeInvalid, s));
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+
+    number.checked_mul(unit).map(Duration::from_secs).ok_or_else(invalid)
+}
+
+/// `older_than` より前から更新されていない項目
+fn stale(entries: Vec<Entry>, older_than: Duration, now: SystemTime) -> Vec<Entry> {
+    entries.into_iter().filter(|e| now.duration_since(e.modified).is_ok_and(|age| age >= older_than)).collect()
+}
+
+/// 経過時間を `3d` のような短い形にする
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        s if s >= 24 * 60 * 60 => format!("{}d", s / (24 * 60 * 60)),
+        s if s >= 60 * 60 => format!("{}h", s / (60 * 60)),
+        s => format!("{}m", s / 60),
+    }
+}
+
+pub fn run(args: &CacheArgs, project_root: &path::Path, wait_lock: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let now = SystemTime::now();
+    match args.action {
+        CacheAction::Dir => {
+            for kind in Kind::ALL {
+                crate::output::status(format!("{:<10} {}", kind.name(), kind.dir(project_root).display()));
+            }
+            let dirs: serde_json::Map<_, _> = Kind::ALL.iter().map(|k| (k.name().to_string(), json!(k.dir(project_root)))).collect();
+            crate::output::event("cache-dir", json!(dirs));
+        }
+        CacheAction::List => {
+            let entries = collect(project_root);
+            for kind in Kind::ALL {
+                let of_kind: Vec<_> = entries.iter().filter(|e| e.kind == kind).collect();
+                let total: u64 = of_kind.iter().map(|e| e.size).sum();
+                crate::output::status(msg!(CacheKindTotal, kind.name(), format_size(total), of_kind.len(), kind.dir(project_root).display()));
+                for entry in of_kind {
+                    let age = now.duration_since(entry.modified).unwrap_or_default();
+                    crate::output::status(format!("  {:>10}  {:>4}  {}", format_size(entry.size), format_age(age), entry.path.display()));
+                }
+            }
+            crate::output::event("cache-list", json!({
+                "total": entries.iter().map(|e| e.size).sum::<u64>(),
+                "entries": entries.iter().map(|e| e.to_json(now)).collect::<Vec<_>>(),
+            }));
+        }
+        CacheAction::Prune => {
+            let older_than = args.older_than.expect("clap requires --older-than for prune");
+            let mut removed = Vec::new();
+            for entry in stale(collect(project_root), older_than, now) {
+                // 使用中の項目は消さない
+                let lock = entry.kind.lock_path(entry.path.as_path()).map(|p| crate::lock::FileLock::acquire(p.as_path(), wait_lock)).transpose();
+                let result = lock.and_then(|_lock| match entry.path.is_dir() {
+                    true => std::fs::remove_dir_all(entry.path.as_path()),
+                    false => std::fs::remove_file(entry.path.as_path()),
+                });
+                match result {
+                    Ok(()) => removed.push(entry),
+                    Err(e) => crate::output::warning(msg!(CacheRemoveFailed, entry.path.display(), e)),
+                }
+            }
+            for entry in removed.iter() {
+                crate::output::status(format!("  {:>10}  {}", format_size(entry.size), entry.path.display()));
+            }
+            let freed: u64 = removed.iter().map(|e| e.size).sum();
+            crate::output::status(msg!(CachePruned, removed.len(), format_size(freed)));
+            crate::output::event("cache-pruned", json!({
+                "freed": freed,
+                "entries": removed.iter().map(|e| e.to_json(now)).collect::<Vec<_>>(),
+            }));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+    use crate::cache::{parse_age, stale, Entry, Kind};
+
+    #[test]
+    fn entries_and_age_filtering() {
+        assert_eq!(parse_age("30d").unwrap(), Duration::from_secs(30 * 24 * 60 * 60));
+        assert_eq!(parse_age("12h").unwrap(), Duration::from_secs(12 * 60 * 60));
+        assert!(parse_age("30").is_err() && parse_age("d").is_err() && parse_age("3y").is_err());
+
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(dir.as_path());
+        std::fs::create_dir_all(dir.join("ovmf-prebuilt").join("edk2-stable202502-r1")).unwrap();
+        std::fs::write(dir.join("ovmf-prebuilt").join("edk2-stable202502-r1").join("OVMF.fd"), b"fw").unwrap();
+        std::fs::write(dir.join("ovmf-prebuilt").join("edk2-stable202502-r1.lock"), b"").unwrap();
+        let entries = Kind::Firmware.entries(dir.as_path());
+        assert_eq!(entries, [dir.join("ovmf-prebuilt").join("edk2-stable202502-r1")]);
+
+        let now = SystemTime::now();
+        let entry = |days: u64| Entry { kind: Kind::Images, path: format!("{}.img", days).into(), size: 1, modified: now - Duration::from_secs(days * 24 * 60 * 60) };
+        let pruned: Vec<_> = stale(vec![entry(1), entry(31), entry(30)], parse_age("30d").unwrap(), now).into_iter().map(|e| e.path).collect();
+        assert_eq!(pruned, [std::path::PathBuf::from("31.img"), "30.img".into()]);
+
+        std::fs::remove_dir_all(dir.as_path()).unwrap();
+    }
+}
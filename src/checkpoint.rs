@@ -0,0 +1,226 @@
+use std::io;
+use std::path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use serde_json::json;
+use crate::message::msg;
+
+/// 起動中に宣言した順に通過すべきチェックポイント
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Checkpoint {
+    /// `marker` がなければ、ゲストがguest-controlの `CHECKPOINT <名前>` で通過を知らせる
+    pub name: String,
+    /// シリアルにこの文字列が出力された時点で通過したとみなす
+    pub marker: Option<String>,
+    /// 起動してから通過するまでの期限（秒）
+    pub within: Option<f64>,
+}
+
+/// 通過したチェックポイントの名前と時刻。guest-controlとシリアルの監視の両方から記録する
+static REACHED: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+
+pub fn record(name: &str) {
+    REACHED.lock().unwrap_or_else(|e| e.into_inner()).push((name.to_string(), Instant::now()));
+}
+
+/// これまでの記録を取り出し、`started` からの経過時間にする
+fn take(started: Instant) -> Vec<(String, Duration)> {
+    let reached = std::mem::take(&mut *REACHED.lock().unwrap_or_else(|e| e.into_inner()));
+    reached.into_iter().map(|(name, at)| (name, at.saturating_duration_since(started))).collect()
+}
+
+/// QEMUを実行し、チェックポイントを順番どおり期限内に通過したかを検査する。
+/// `serial_log` はマーカーを探すシリアルのログを書き出す場所で、マーカーを使う場合は必須
+pub fn run(
+    checkpoints: &[Checkpoint],
+    mut options: Vec<String>,
+    serial_log: Option<&path::Path>,
+    run: impl FnOnce(Vec<String>) -> Result<Option<std::process::ExitStatus>, io::Error>,
+) -> Result<(Option<std::process::ExitStatus>, bool), io::Error> {
+    let markers: Vec<_> = checkpoints.iter()
+        .filter_map(|c| c.marker.clone().map(|m| (c.name.clone(), m)))
+        .collect();
+    let serial_log = match (markers.is_empty(), serial_log) {
+        (true, _) => None,
+        (false, Some(log)) => Some(log),
+        (false, None) => return Err(io::Error::new(io::ErrorKind::InvalidInput, msg!(CheckpointNeedsSerialLog))),
+    };
+    if let Some(log) = serial_log {
+        crate::seriallog::prepare(log)?;
+        options.extend(crate::seriallog::qemu_args(log));
+    }
+
+    take(Instant::now());
+    let started = Instant::now();
+    let monitor = Monitor::start(checkpoints, markers, serial_log.map(|log| log.to_path_buf()), started, crate::supervise::request_stop);
+    let status = run(options);
+    monitor.finish()?;
+    let status = status?;
+
+    Ok((status, report(checkpoints, &take(started))))
+}
+
+/// 検査の結果を出力し、全て満たしていれば `true` を返す
+fn report(checkpoints: &[Checkpoint], reached: &[(String, Duration)]) -> bool {
+    let failures = evaluate(checkpoints, reached);
+    for failure in failures.iter() {
+        crate::output::status(msg!(CheckpointFailed, failure));
+    }
+    if failures.is_empty() {
+        crate::output::status(msg!(CheckpointsPassed, checkpoints.len()));
+    }
+
+    let reached: Vec<_> = reached.iter()
+        .map(|(name, at)| json!({ "name": name, "elapsed-ms": at.as_millis() as u64 }))
+        .collect();
+    crate::output::event("checkpoints", json!({ "passed": failures.is_empty(), "failures": failures, "reached": reached }));
+
+    failures.is_empty()
+}
+
+/// 宣言された順に、通過したか、前のチェックポイントより後か、期限内かを調べ、満たさなかった内容を返す
+fn evaluate(checkpoints: &[Checkpoint], reached: &[(String, Duration)]) -> Vec<String> {
+    let secs = |d: Duration| format!("{:.3}s", d.as_secs_f64());
+    let mut failures = Vec::new();
+    let mut previous: Option<(&str, Duration)> = None;
+    for checkpoint in checkpoints {
+        let name = checkpoint.name.as_str();
+        let at = match reached.iter().find(|(n, _)| n == name) {
+            Some((_, at)) => *at,
+            None => {
+                failures.push(match previous {
+                    Some((prev, prev_at)) => msg!(CheckpointMissedAfter, name, prev, secs(prev_at)),
+                    None => msg!(CheckpointMissed, name),
+                });
+                continue;
+            }
+        };
+
+        if let Some((prev, prev_at)) = previous.filter(|(_, prev_at)| at < *prev_at) {
+            failures.push(msg!(CheckpointOutOfOrder, name, secs(at), prev, secs(prev_at)));
+        }
+        if let Some(within) = checkpoint.within.filter(|w| at.as_secs_f64() > *w) {
+            failures.push(msg!(CheckpointLate, name, secs(at), within));
+        }
+        previous = Some((name, at));
+    }
+
+    failures
+}
+
+/// シリアルのログを追いかけてマーカーが現れた時点でチェックポイントの通過を記録し、
+/// 期限を過ぎても通過していないチェックポイントがあればVMを止める
+struct Monitor {
+    handle: thread::JoinHandle<Result<(), io::Error>>,
+    finished: Arc<AtomicBool>,
+}
+
+impl Monitor {
+    /// 期限を過ぎたときは、VMが終了するまで `stop` を呼び続ける
+    fn start(
+        checkpoints: &[Checkpoint],
+        mut markers: Vec<(String, String)>,
+        log: Option<path::PathBuf>,
+        started: Instant,
+        stop: impl Fn() + Send + 'static,
+    ) -> Monitor {
+        let deadlines: Vec<_> = checkpoints.iter()
+            .filter_map(|c| c.within.and_then(|w| Duration::try_from_secs_f64(w).ok()).map(|within| (c.name.clone(), within)))
+            .collect();
+        let finished = Arc::new(AtomicBool::new(false));
+        let flag = finished.clone();
+        let handle = thread::spawn(move || {
+            let longest = markers.iter().map(|(_, m)| m.len()).max().unwrap_or(0);
+            let mut tail = log.map(|log| crate::seriallog::Tail::new(log.as_path(), longest));
+            let mut stopping = false;
+            loop {
+                // QEMUの終了を知らされてから、書き出し終えたログを最後にもう一度読む
+                let last = flag.load(Ordering::SeqCst);
+                if let Some(tail) = tail.as_mut() {
+                    tail.read()?;
+                    markers.retain(|(name, marker)| {
+                        let found = tail.contains(marker.as_bytes());
+                        if found {
+                            record(name);
+                        }
+                        !found
+                    });
+                }
+                if last {
+                    break;
+                }
+
+                let reached = REACHED.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                let missed = deadlines.iter()
+                    .find(|(name, within)| started.elapsed() > *within && !reached.iter().any(|(n, _)| n == name));
+                if let Some((name, within)) = missed {
+                    if !stopping {
+                        crate::output::warning(msg!(CheckpointDeadlineStop, name, within.as_secs_f64()));
+                        crate::output::event("checkpoint-deadline", json!({ "name": name, "within": within.as_secs_f64() }));
+                        stopping = true;
+                    }
+                    // VMを起動する前に求めた場合でも止まるよう、終了するまで求め続ける
+                    stop();
+                }
+                crate::seriallog::pause();
+            }
+
+            Ok(())
+        });
+
+        Monitor { handle, finished }
+    }
+
+    fn finish(self) -> Result<(), io::Error> {
+        self.finished.store(true, Ordering::SeqCst);
+        self.handle.join().unwrap_or_else(|_| Err(io::Error::other("checkpoint monitor panicked")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+    use crate::checkpoint::{evaluate, Checkpoint, Monitor};
+
+    fn checkpoint(name: &str, within: Option<f64>) -> Checkpoint {
+        Checkpoint { name: name.to_string(), marker: None, within }
+    }
+
+    #[test]
+    fn checkpoints_must_be_reached_in_order_and_in_time() {
+        let checkpoints = [checkpoint("stage1", Some(2.0)), checkpoint("fs-mounted", None), checkpoint("kernel-handoff", Some(5.0))];
+        let at = |name: &str, ms: u64| (name.to_string(), Duration::from_millis(ms));
+
+        assert!(evaluate(&checkpoints, &[at("stage1", 1500), at("fs-mounted", 2500), at("kernel-handoff", 4000)]).is_empty());
+
+        let failures = evaluate(&checkpoints, &[at("stage1", 2500), at("kernel-handoff", 6000)]);
+        assert_eq!(failures.len(), 3);
+        assert!(failures[0].contains("stage1") && failures[0].contains("2.500s"));
+        assert!(failures[1].contains("fs-mounted") && failures[1].contains("stage1"));
+        assert!(failures[2].contains("kernel-handoff") && failures[2].contains("6.000s"));
+
+        let failures = evaluate(&checkpoints, &[at("fs-mounted", 500), at("stage1", 1000), at("kernel-handoff", 1200)]);
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].contains("fs-mounted") && failures[0].contains("0.500s"));
+    }
+
+    #[test]
+    fn missed_deadline_stops_vm() {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let flag = stopped.clone();
+        let checkpoints = [checkpoint("never-reached-deadline", Some(0.05))];
+        let monitor = Monitor::start(&checkpoints, Vec::new(), None, Instant::now(), move || flag.store(true, Ordering::SeqCst));
+        let started = Instant::now();
+        while !stopped.load(Ordering::SeqCst) && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        monitor.finish().unwrap();
+        assert!(stopped.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,221 @@
+use std::io;
+use std::path;
+use std::time::Duration;
+use clap::Args;
+
+#[derive(Args)]
+pub struct CompareArgs {
+    /// 比較するファームウェアイメージ（2つ指定する）
+    #[arg(long = "firmware", value_name = "FILE", num_args = 1, required = true)]
+    pub firmware: Vec<path::PathBuf>,
+
+    /// 各実行でQEMUを強制終了するまでの秒数
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+
+    #[arg(last = true)]
+    pub qemu_cmd: Vec<String>,
+}
+
+/// 1つのファームウェアでの実行結果
+pub struct RunResult {
+    pub firmware: path::PathBuf,
+    pub outcome: String,
+    pub transcript: Vec<String>,
+}
+
+/// 各ファームウェアでアプリケーションを実行し、シリアル出力と結果を比較する。
+/// 差分がなければ `true` を返す。
+pub fn compare(args: &CompareArgs, backend: &dyn crate::backend::VmBackend, drive: &crate::image::BootDrive, base_options: &[String], log_dir: &path::Path) -> Result<bool, Box<dyn std::error::Error>> {
+    if args.firmware.len() != 2 {
+        return Err(Box::new(crate::error::Error::new(
+            crate::error::ErrorKind::InvalidArgument,
+            crate::message::msg!(CompareFirmwareCount, args.firmware.len())
+        )));
+    }
+
+    std::fs::create_dir_all(log_dir)?;
+    let timeout = args.timeout.map(Duration::from_secs);
+
+    let mut results = Vec::new();
+    for (idx, firmware) in args.firmware.iter().enumerate() {
+        if !firmware.is_file() {
+            return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, crate::message::msg!(NotFound, firmware.display()))));
+        }
+
+        let log_path = log_dir.join(format!("{}.log", idx));
+        let mut options = base_options.to_vec();
+        options.extend([
+            "-serial".to_string(), format!("file:{}", log_path.display()),
+            "-display".to_string(), "none".to_string(),
+        ]);
+        options.extend(args.qemu_cmd.iter().cloned());
+
+        let stderr_log = log_dir.join(format!("{}.stderr.log", idx));
+        let code = crate::firmware::Firmware::from_code(firmware.clone());
+        let vm = crate::backend::VmConfig { firmware: &code, drive, options };
+        let status = crate::supervise::run_vm(backend, &vm, timeout, Some(stderr_log.as_path()))?;
+        let outcome = match status {
+            Some(status) => status.to_string(),
+            None => crate::message::msg!(RunTimedOut),
+        };
+
+        let raw = std::fs::read(log_path.as_path()).unwrap_or_default();
+        let transcript = normalize(String::from_utf8_lossy(&raw).as_ref());
+
+        results.push(RunResult { firmware: firmware.clone(), outcome, transcript });
+    }
+
+    let (a, b) = (&results[0], &results[1]);
+    let same = a.outcome == b.outcome && a.transcript == b.transcript;
+    if crate::output::json() {
+        let runs: Vec<_> = results.iter().map(|r| serde_json::json!({
+            "firmware": r.firmware,
+            "outcome": r.outcome,
+            "transcript": r.transcript,
+        })).collect();
+        crate::output::event("compare-finished", serde_json::json!({ "same": same, "runs": runs }));
+    } else {
+        print!("{}", render(a, b));
+    }
+
+    Ok(same)
+}
+
+/// ファームウェアごとに異なるエスケープシーケンスや改行コードを取り除き、行単位に分割する
+pub fn normalize(transcript: &str) -> Vec<String> {
+    let mut text = String::new();
+    let mut chars = transcript.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                // CSIシーケンス（ESC [ ... 終端文字）を読み飛ばす
+                if chars.peek() == Some(&'[') {
+                    chars.next();
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            break;
+                        }
+                    }
+                } else {
+                    chars.next();
+                }
+            }
+            '\r' => {}
+            c if c.is_control() && c != '\n' && c != '\t' => {}
+            c => text.push(c),
+        }
+    }
+
+    text.lines()
+        .map(|l| l.trim_end().to_string())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// 行単位の差分
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DiffLine<'a> {
+    Same(&'a str),
+    Changed(&'a str, &'a str),
+    Left(&'a str),
+    Right(&'a str),
+}
+
+pub fn diff<'a>(a: &'a [String], b: &'a [String]) -> Vec<DiffLine<'a>> {
+    // 最長共通部分列を求める
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push(DiffLine::Same(&a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(DiffLine::Left(&a[i]));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Right(&b[j]));
+            j += 1;
+        }
+    }
+
+    // 隣接する片側のみの行を変更行としてまとめる
+    let mut merged: Vec<DiffLine> = Vec::new();
+    for line in lines {
+        match (merged.last(), &line) {
+            (Some(DiffLine::Left(l)), DiffLine::Right(r)) => {
+                let l = *l;
+                merged.pop();
+                merged.push(DiffLine::Changed(l, r));
+            }
+            _ => merged.push(line),
+        }
+    }
+
+    merged
+}
+
+const COLUMN_WIDTH: usize = 60;
+
+fn render(a: &RunResult, b: &RunResult) -> String {
+    fn column(s: &str) -> String {
+        let truncated: String = s.chars().take(COLUMN_WIDTH).collect();
+        format!("{:<width$}", truncated, width = COLUMN_WIDTH)
+    }
+
+    let mut out = String::new();
+    out.push_str(&format!("{} {}\n", column(&a.firmware.display().to_string()), b.firmware.display()));
+    out.push_str(&format!("{} {}\n", column(&crate::message::msg!(CompareResult, a.outcome)), crate::message::msg!(CompareResult, b.outcome)));
+    out.push_str(&format!("{}\n", "-".repeat(COLUMN_WIDTH * 2 + 3)));
+
+    for line in diff(&a.transcript, &b.transcript) {
+        let (l, mark, r) = match line {
+            DiffLine::Same(s) => (s, ' ', s),
+            DiffLine::Changed(l, r) => (l, '|', r),
+            DiffLine::Left(l) => (l, '<', ""),
+            DiffLine::Right(r) => ("", '>', r),
+        };
+        out.push_str(format!("{} {} {}", column(l), mark, r).trim_end());
+        out.push('\n');
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compare::{diff, normalize, DiffLine};
+
+    #[test]
+    fn normalize_strips_escapes_and_carriage_returns() {
+        let raw = "\x1b[2J\x1b[01;01HBdsDxe: loading\r\n\r\n\x1b[0mHello  \r\n";
+        let lines = normalize(raw);
+        assert_eq!(lines, vec!["BdsDxe: loading", "Hello"]);
+    }
+
+    #[test]
+    fn diff_reports_changed_and_missing_lines() {
+        let a: Vec<String> = ["boot", "stage1", "done"].iter().map(|s| s.to_string()).collect();
+        let b: Vec<String> = ["boot", "stage1'", "done", "extra"].iter().map(|s| s.to_string()).collect();
+
+        let lines = diff(&a, &b);
+        assert_eq!(lines, vec![
+            DiffLine::Same("boot"),
+            DiffLine::Changed("stage1", "stage1'"),
+            DiffLine::Same("done"),
+            DiffLine::Right("extra"),
+        ]);
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::BTreeMap;
+use serde::Deserialize;
+use toml_edit::easy;
+use crate::checkpoint::Checkpoint;
+use crate::disk::{DiskConfig, DriveOptions};
+use crate::exit::ExitConvention;
+use crate::fetch::ProxyConfig;
+use crate::firmware::{FirmwarePaths, Mirrors, OvmfPrebuilt};
+use crate::fwcfg::FwCfgEntry;
+use crate::limits::LimitsConfig;
+use crate::netem::Impairment;
+use crate::probe::Probe;
+use crate::provision::Provision;
+use crate::shelltools::ShellToolsConfig;
+use crate::staging::ExtraFile;
+use crate::verify::SecureBootConfig;
+
+/// Cargo.toml の `[package.metadata.cargo-uefi]`（または `[workspace.metadata.cargo-uefi]`）に書かれた設定
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    /// 全てのディスクに適用するI/O設定
+    #[serde(default)]
+    pub drive: DriveOptions,
+    /// 追加で接続するデータディスク
+    #[serde(default)]
+    pub disks: Vec<DiskConfig>,
+    /// isa-debug-exitによる終了コードの受け渡し。指定するとデバイスを自動で追加する
+    pub exit: Option<ExitConvention>,
+    /// 起動に使うファームウェア。相対パスはプロジェクトルートからのパスとみなす
+    pub firmware: Option<FirmwarePaths>,
+    /// ファームウェアを用意する方法を試す順序。書かれていない方法は使わない
+    pub firmware_order: Option<Vec<String>>,
+    /// ファームウェアを用意するコマンドとその引数
+    #[serde(default)]
+    pub firmware_plugin: Vec<String>,
+    /// rust-osdev/ovmf-prebuilt から取得するファームウェア
+    pub ovmf_prebuilt: Option<OvmfPrebuilt>,
+    /// 常にQEMUに渡す引数。コマンドラインの `--` 以降の引数はこれより後に渡す
+    #[serde(default)]
+    pub qemu_args: Vec<String>,
+    /// ファームウェアのダウンロード元のミラー
+    #[serde(default)]
+    pub mirrors: Mirrors,
+    /// ファームウェアのダウンロードに使うプロキシ
+    pub proxy: Option<ProxyConfig>,
+    /// fw_cfgを通してゲストに渡すデータ
+    #[serde(default)]
+    pub fw_cfg: Vec<FwCfgEntry>,
+    /// アプリケーションと一緒にESPへ配置するファイル
+    #[serde(default)]
+    pub files: Vec<ExtraFile>,
+    /// 起動中に順番どおり期限内に通過すべきチェックポイント
+    #[serde(default)]
+    pub checkpoints: Vec<Checkpoint>,
+    /// ゲストの動作中に、フォワードしたポートに対してホストから行う検査
+    #[serde(default)]
+    pub probes: Vec<Probe>,
+    /// `verify-image` でブートファイルのSecure Boot署名を検証する設定
+    pub secure_boot: Option<SecureBootConfig>,
+    /// ユーザーモードネットワークに加える遅延、損失、帯域の制限
+    pub network_impairment: Option<Impairment>,
+    /// 常に追加するデバイスプリセット。コマンドラインの `--with` で選んだものはこれより後に渡す
+    #[serde(default)]
+    pub with: Vec<String>,
+    /// 利用者が定義するデバイスプリセット。名前とQEMUの引数の組
+    #[serde(default)]
+    pub device_presets: BTreeMap<String, Vec<String>>,
+    /// 実行の前に整えるUEFI変数、データディスク、時計
+    #[serde(default)]
+    pub provision: Provision,
+    /// `shell-tools` で配置するツールのバンドル
+    pub shell_tools: Option<ShellToolsConfig>,
+    /// ゲストの出力に現れたらVMを一時停止する文字列。コマンドラインの `--break-on` で指定したものはこれに加える
+    #[serde(default)]
+    pub break_on: Vec<String>,
+    /// QEMUのプロセスに課すCPU時間、メモリ、優先度の制限
+    #[serde(default)]
+    pub limits: LimitsConfig,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    package: Option<MetadataHolder>,
+    workspace: Option<MetadataHolder>,
+}
+
+#[derive(Deserialize)]
+struct MetadataHolder {
+    metadata: Option<Metadata>,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    #[serde(rename = "cargo-uefi")]
+    cargo_uefi: Option<Config>,
+}
+
+/// Cargo.toml の内容から設定を読み込む。
+/// パッケージとワークスペースの両方に設定がある場合は、パッケージの設定を使う。
+pub fn from_manifest(toml: &str) -> Result<Config, toml_edit::de::Error> {
+    fn config_of(holder: Option<MetadataHolder>) -> Option<Config> {
+        holder.and_then(|h| h.metadata).and_then(|m| m.cargo_uefi)
+    }
+
+    let manifest = easy::from_str::<Manifest>(toml)?;
+    let config = config_of(manifest.package)
+        .or(config_of(manifest.workspace))
+        .unwrap_or_default();
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::config::from_manifest;
+    use crate::disk::{AioMode, CacheMode};
+    use crate::signature::SignatureKind;
+
+    #[test]
+    fn parse_drive_and_disks() {
+        let toml = r#"
+        [package]
+        name = "hoge"
+
+        [package.metadata.cargo-uefi.drive]
+        cache = "none"
+        aio = "io_uring"
+
+        [[package.metadata.cargo-uefi.disks]]
+        file = "data.img"
+        discard = true
+
+        [[package.metadata.cargo-uefi.disks]]
+        file = "big.qcow2"
+        format = "qcow2"
+        cache = "writeback"
+
+        [[package.metadata.cargo-uefi.disks.errors]]
+        event = "read_aio"
+        sector = 2048
+        errno = "EIO"
+        once = true
+        "#;
+
+        let config = from_manifest(toml).unwrap();
+        assert_eq!(config.drive.cache, Some(CacheMode::None));
+        assert_eq!(config.drive.aio, Some(AioMode::IoUring));
+        assert_eq!(config.disks.len(), 2);
+        assert_eq!(config.disks[0].file, path::Path::new("data.img"));
+        assert_eq!(config.disks[0].options.discard, Some(true));
+        assert_eq!(config.disks[1].format.as_deref(), Some("qcow2"));
+        assert_eq!(config.disks[1].options.cache, Some(CacheMode::Writeback));
+        assert!(config.disks[0].errors.is_empty());
+        assert_eq!(config.disks[1].errors[0].sector, Some(2048));
+        assert!(config.disks[1].errors[0].once);
+    }
+
+    #[test]
+    fn parse_workspace_metadata() {
+        let toml = r#"
+        [workspace]
+        members = ["hoge"]
+
+        [workspace.metadata.cargo-uefi.drive]
+        discard = true
+        "#;
+
+        let config = from_manifest(toml).unwrap();
+        assert_eq!(config.drive.discard, Some(true));
+        assert!(config.disks.is_empty());
+    }
+
+    #[test]
+    fn parse_exit_convention() {
+        let toml = r#"
+        [package]
+        name = "hoge"
+
+        [package.metadata.cargo-uefi.exit]
+        iobase = 0x501
+        success = 3
+        "#;
+
+        let exit = from_manifest(toml).unwrap().exit.unwrap();
+        assert_eq!(exit.iobase, 0x501);
+        assert_eq!(exit.iosize, 4);
+        assert_eq!(exit.success, 3);
+    }
+
+    #[test]
+    fn parse_proxy() {
+        let toml = r#"
+        [package]
+        name = "hoge"
+
+        [package.metadata.cargo-uefi.proxy]
+        url = "http://proxy.corp:3128"
+        no-proxy = "localhost"
+        user = "ci"
+        password-env = "PROXY_PASSWORD"
+        "#;
+
+        let proxy = from_manifest(toml).unwrap().proxy.unwrap();
+        assert_eq!(proxy.url.as_deref(), Some("http://proxy.corp:3128"));
+        assert_eq!(proxy.no_proxy.as_deref(), Some("localhost"));
+        assert_eq!(proxy.user.as_deref(), Some("ci"));
+        assert_eq!(proxy.password_env.as_deref(), Some("PROXY_PASSWORD"));
+    }
+
+    #[test]
+    fn parse_mirrors() {
+        let toml = r#"
+        [workspace]
+        members = ["hoge"]
+
+        [workspace.metadata.cargo-uefi.mirrors]
+        ovmf-prebuilt = ["https://a.corp/ovmf", "https://b.corp/ovmf"]
+        upstream = false
+        "#;
+
+        let mirrors = from_manifest(toml).unwrap().mirrors;
+        assert_eq!(mirrors.ovmf_prebuilt.len(), 2);
+        assert!(!mirrors.upstream);
+        assert!(from_manifest("[package]\nname = \"hoge\"\n").unwrap().mirrors.upstream);
+    }
+
+    #[test]
+    fn parse_ovmf_prebuilt_signature() {
+        let toml = r#"
+        [package]
+        name = "hoge"
+
+        [package.metadata.cargo-uefi.ovmf-prebuilt]
+        tag = "edk2-stable202502-r1"
+        signature = { kind = "gpg", key = "keys/ovmf.asc" }
+        "#;
+
+        let signature = from_manifest(toml).unwrap().ovmf_prebuilt.unwrap().signature.unwrap();
+        assert_eq!(signature.kind, SignatureKind::Gpg);
+        assert_eq!(signature.key, path::Path::new("keys/ovmf.asc"));
+        assert_eq!(signature.extension(), "asc");
+    }
+
+    #[test]
+    fn parse_fw_cfg() {
+        let toml = r#"
+        [package]
+        name = "hoge"
+
+        [[package.metadata.cargo-uefi.fw-cfg]]
+        name = "opt/com.example.hoge/config"
+        file = "config.json"
+
+        [[package.metadata.cargo-uefi.fw-cfg]]
+        name = "opt/com.example.hoge/mode"
+        string = "test"
+        "#;
+
+        let fw_cfg = from_manifest(toml).unwrap().fw_cfg;
+        assert_eq!(fw_cfg[0].file.as_deref(), Some(path::Path::new("config.json")));
+        assert_eq!(fw_cfg[1].string.as_deref(), Some("test"));
+    }
+
+    #[test]
+    fn parse_firmware_files_and_checkpoints() {
+        let toml = r#"
+        [package]
+        name = "hoge"
+
+        [package.metadata.cargo-uefi]
+        qemu-args = ["-m", "256M"]
+        with = ["net-virtio", "tpm"]
+        firmware-order = ["path", "plugin"]
+        firmware-plugin = ["tools/firmware.sh", "--release"]
+        firmware = { code = "/usr/share/OVMF/OVMF_CODE_4M.fd", vars = "/usr/share/OVMF/OVMF_VARS_4M.fd" }
+
+        [package.metadata.cargo-uefi.device-presets]
+        tpm = ["-device", "tpm-tis,tpmdev=tpm0"]
+
+        [[package.metadata.cargo-uefi.files]]
+        source = "assets/initrd.img"
+        path = "/EFI/hoge/initrd.img"
+
+        [[package.metadata.cargo-uefi.checkpoints]]
+        name = "stage1"
+        marker = "stage1 ready"
+        within = 2
+
+        [[package.metadata.cargo-uefi.checkpoints]]
+        name = "fs-mounted"
+
+        [[package.metadata.cargo-uefi.probes]]
+        name = "web"
+        guest-port = 80
+        http = "/health"
+        after = 1.5
+
+        [package.metadata.cargo-uefi.provision]
+        rtc = "2024-01-01T09:00:00"
+
+        [[package.metadata.cargo-uefi.provision.variables]]
+        name = "PlatformLang"
+        guid = "global"
+        string = "ja-JP"
+
+        [[package.metadata.cargo-uefi.provision.disks]]
+        size = "32M"
+        files = [{ source = "fixtures/input.txt", path = "/input.txt" }]
+        "#;
+
+        let config = from_manifest(toml).unwrap();
+        assert_eq!(config.firmware.unwrap().vars.unwrap(), path::Path::new("/usr/share/OVMF/OVMF_VARS_4M.fd"));
+        assert_eq!(config.qemu_args, ["-m", "256M"]);
+        assert_eq!(config.with, ["net-virtio", "tpm"]);
+        assert_eq!(config.device_presets["tpm"], ["-device", "tpm-tis,tpmdev=tpm0"]);
+        assert_eq!(config.provision.variables[0].guid, "global");
+        assert_eq!(config.provision.disks[0].files[0].path, "/input.txt");
+        assert_eq!(config.provision.qemu_args(), ["-rtc", "base=2024-01-01T09:00:00"]);
+        assert_eq!(config.firmware_order.unwrap(), ["path", "plugin"]);
+        assert_eq!(config.firmware_plugin[0], "tools/firmware.sh");
+        assert_eq!(config.files[0].source, path::Path::new("assets/initrd.img"));
+        assert_eq!(config.checkpoints[0].marker.as_deref(), Some("stage1 ready"));
+        assert_eq!(config.checkpoints[0].within, Some(2.0));
+        assert_eq!(config.checkpoints[1].within, None);
+        assert_eq!(config.probes[0].guest_port, 80);
+        assert_eq!(config.probes[0].after, 1.5);
+        assert_eq!(config.probes[0].within, None);
+    }
+
+    #[test]
+    fn missing_metadata_is_default() {
+        let config = from_manifest("[package]\nname = \"hoge\"\n").unwrap();
+        assert_eq!(config.drive.cache, None);
+        assert!(config.disks.is_empty());
+    }
+}
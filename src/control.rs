@@ -0,0 +1,268 @@
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::net;
+use std::path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+use crate::message::msg;
+use crate::ports::PortAllocator;
+use crate::qmp::Qmp;
+
+/// ゲストからの要求を受け付けるシリアルポートのchardevのID
+const CHARDEV_ID: &str = "cargo-uefi-control";
+
+/// ゲストからの要求の処理結果
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// チェックポイントの名前と、起動からの経過時間
+    pub checkpoints: Vec<(String, Duration)>,
+    /// ゲストの要求で書き出したファイル
+    pub artifacts: Vec<path::PathBuf>,
+    /// ゲストが報告したアプリケーションの読み込み先
+    pub image: Option<crate::imagebase::LoadedImage>,
+}
+
+/// 2番目のシリアルポート(COM2, I/Oポート0x2f8)を通して、ゲストからホストへの要求を受け付ける。
+///
+/// 要求は1行に1つで、ホストは処理の結果として `OK` または `ERR <理由>` の1行を返す。
+///
+/// - `LOG <テキスト>`: メッセージを出力する
+/// - `EVENT <名前> [JSON]`: 構造化されたイベントを出力する
+/// - `CHECKPOINT <名前>`: 起動からの経過時間を記録する
+/// - `WRITE <名前> <バイト数>`: 続く指定したバイト数のデータをファイルとして保存する
+/// - `SCREENSHOT <名前>`: 画面をPPM形式で保存する
+/// - `IMAGE <アドレス> <大きさ>`: アプリケーションが読み込まれた先頭アドレスと大きさ（16進数）を知らせる
+pub struct ControlServer {
+    port: u16,
+    qmp_addr: net::SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<Result<Summary, io::Error>>,
+}
+
+impl ControlServer {
+    /// 待ち受けを開始する。ゲストが書き出すファイルは `artifacts_dir` に保存する
+    pub fn start(artifacts_dir: path::PathBuf, ports: &mut PortAllocator) -> Result<ControlServer, io::Error> {
+        // QEMUが接続してくる前に待ち受けを始めておく
+        let listener = ports.listen("control")?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        let qmp_addr = ports.allocate("control-qmp")?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_stop = stop.clone();
+        let handle = thread::spawn(move || {
+            let stream = loop {
+                match listener.accept() {
+                    Ok((stream, _)) => break stream,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        // QEMUが接続する前に終了した場合
+                        if thread_stop.load(Ordering::SeqCst) {
+                            return Ok(Summary::default());
+                        }
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            stream.set_nonblocking(false)?;
+
+            let mut session = Session::new(artifacts_dir.as_path(), Some(qmp_addr));
+            session.serve(io::BufReader::new(stream.try_clone()?), stream)?;
+            Ok(session.summary)
+        });
+
+        Ok(ControlServer { port, qmp_addr, stop, handle })
+    }
+
+    /// QEMUに要求を受け付けるシリアルポートと、スクリーンショットに使うQMPを追加する引数
+    pub fn qemu_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "-chardev".to_string(),
+            format!("socket,id={},host=127.0.0.1,port={}", CHARDEV_ID, self.port),
+            "-device".to_string(),
+            format!("isa-serial,chardev={},index=1", CHARDEV_ID),
+        ];
+        args.extend(crate::qmp::qmp_args(self.qmp_addr));
+        args
+    }
+
+    /// QEMUの終了後に呼び、処理結果を返す
+    pub fn finish(self) -> Result<Summary, io::Error> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.handle.join().unwrap_or_else(|_| Err(io::Error::other("guest control server panicked")))
+    }
+}
+
+struct Session<'a> {
+    artifacts_dir: &'a path::Path,
+    started: Instant,
+    qmp_addr: Option<net::SocketAddr>,
+    qmp: Option<Qmp>,
+    summary: Summary,
+}
+
+impl<'a> Session<'a> {
+    fn new(artifacts_dir: &'a path::Path, qmp_addr: Option<net::SocketAddr>) -> Session<'a> {
+        Session { artifacts_dir, started: Instant::now(), qmp_addr, qmp: None, summary: Summary::default() }
+    }
+
+    /// 接続が閉じられるまで要求を処理する
+    fn serve<R: BufRead, W: Write>(&mut self, mut reader: R, mut writer: W) -> Result<(), io::Error> {
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(());
+            }
+
+            let text = String::from_utf8_lossy(&line);
+            let request = text.trim_end_matches(['\r', '\n']);
+            if request.is_empty() {
+                continue;
+            }
+
+            let reply = match self.handle(request, &mut reader) {
+                Ok(()) => "OK".to_string(),
+                Err(e) => format!("ERR {}", e),
+            };
+            write!(writer, "{}\r\n", reply)?;
+            writer.flush()?;
+        }
+    }
+
+    fn handle<R: Read>(&mut self, request: &str, reader: &mut R) -> Result<(), String> {
+        let (command, rest) = request.split_once(' ').unwrap_or((request, ""));
+        match command {
+            "LOG" => {
+                crate::output::status(msg!(GuestLog, rest));
+                crate::output::event("guest-log", json!({ "message": rest }));
+            }
+            "EVENT" => {
+                let (name, data) = rest.split_once(' ').unwrap_or((rest, ""));
+                let name = validate_name(name)?;
+                let data = match data.trim() {
+                    "" => Value::Null,
+                    data => serde_json::from_str(data).map_err(|e| format!("invalid JSON: {}", e))?,
+                };
+                crate::output::status(msg!(GuestEvent, name, data));
+                crate::output::event("guest-event", json!({ "name": name, "data": data }));
+            }
+            "CHECKPOINT" => {
+                let name = validate_name(rest)?;
+                let elapsed = self.started.elapsed();
+                crate::output::status(msg!(GuestCheckpoint, name, format!("{:.3}", elapsed.as_secs_f64())));
+                crate::output::event("checkpoint", json!({ "name": name, "elapsed-ms": elapsed.as_millis() as u64 }));
+                self.summary.checkpoints.push((name.to_string(), elapsed));
+                crate::checkpoint::record(name);
+            }
+            "WRITE" => {
+                let (name, len) = rest.split_once(' ').ok_or("usage: WRITE <name> <length>")?;
+                let len: u64 = len.trim().parse().map_err(|_| format!("invalid length: {}", len))?;
+
+                // 名前が不正でもデータは読み捨て、次の要求と混ざらないようにする
+                let mut data = Vec::new();
+                reader.take(len).read_to_end(&mut data).map_err(|e| e.to_string())?;
+                if data.len() as u64 != len {
+                    return Err(format!("expected {} bytes, got {}", len, data.len()));
+                }
+
+                let name = validate_name(name)?;
+                let path = self.artifact_path(name)?;
+                std::fs::write(path.as_path(), data).map_err(|e| e.to_string())?;
+                self.record_artifact(path);
+            }
+            "SCREENSHOT" => {
+                let name = validate_name(rest)?;
+                let path = self.artifact_path(format!("{}.ppm", name).as_str())?;
+                let qmp = self.qmp()?;
+                qmp.execute("screendump", Some(json!({ "filename": path }))).map_err(|e| e.to_string())?;
+                self.record_artifact(path);
+            }
+            "IMAGE" => {
+                let image = crate::imagebase::LoadedImage::parse(rest).ok_or("usage: IMAGE <base> <size>")?;
+                crate::output::status(msg!(GuestImageLoaded, format!("{:#x}", image.base), format!("{:#x}", image.size)));
+                crate::output::event("image-loaded", json!({ "base": image.base, "size": image.size }));
+                crate::imagebase::record(image);
+                self.summary.image = Some(image);
+            }
+            _ => return Err(format!("unknown command: {}", command)),
+        }
+
+        Ok(())
+    }
+
+    fn artifact_path(&self, name: &str) -> Result<path::PathBuf, String> {
+        std::fs::create_dir_all(self.artifacts_dir).map_err(|e| e.to_string())?;
+        Ok(self.artifacts_dir.join(name))
+    }
+
+    fn record_artifact(&mut self, path: path::PathBuf) {
+        crate::output::status(msg!(GuestWrote, path.display()));
+        crate::output::event("artifact", json!({ "path": path }));
+        self.summary.artifacts.push(path);
+    }
+
+    fn qmp(&mut self) -> Result<&mut Qmp, String> {
+        if self.qmp.is_none() {
+            let addr = self.qmp_addr.ok_or("screenshots are not available")?;
+            self.qmp = Some(Qmp::connect(addr, Duration::from_secs(5)).map_err(|e| e.to_string())?);
+        }
+
+        Ok(self.qmp.as_mut().expect("QMP is connected"))
+    }
+}
+
+/// ゲストが指定する名前を、成果物のディレクトリから出られないものに制限する
+fn validate_name(name: &str) -> Result<&str, String> {
+    let valid = !name.is_empty()
+        && name.len() <= 128
+        && !name.starts_with('.')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+
+    if valid {
+        Ok(name)
+    } else {
+        Err(format!("invalid name: {:?}", name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::control::{validate_name, Session};
+
+    #[test]
+    fn handle_requests() {
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-test-control-{}", std::process::id()));
+        let input = b"CHECKPOINT booted\r\nWRITE result.bin 5\nhello\nWRITE ../escape 3\nabcEVENT done {\"passed\":3}\nBOGUS\nIMAGE 0x3e5a0000 0x4000\n";
+        let mut output = Vec::new();
+
+        let mut session = Session::new(dir.as_path(), None);
+        session.serve(&input[..], &mut output).unwrap();
+
+        let replies = String::from_utf8(output).unwrap();
+        let replies: Vec<_> = replies.lines().collect();
+        assert_eq!(replies[..2], ["OK", "OK"]);
+        assert!(replies[2].starts_with("ERR invalid name"));
+        assert_eq!(replies[3], "OK");
+        assert!(replies[4].starts_with("ERR unknown command"));
+        assert_eq!(replies[5], "OK");
+
+        assert_eq!(session.summary.checkpoints[0].0, "booted");
+        assert_eq!(session.summary.image.unwrap().base, 0x3e5a0000);
+        assert_eq!(session.summary.artifacts, vec![dir.join("result.bin")]);
+        assert_eq!(std::fs::read(dir.join("result.bin")).unwrap(), b"hello");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn names_stay_inside_artifacts_dir() {
+        assert!(validate_name("screen-1.ppm").is_ok());
+        assert!(validate_name("../x").is_err());
+        assert!(validate_name(".hidden").is_err());
+        assert!(validate_name("a/b").is_err());
+        assert!(validate_name("").is_err());
+    }
+}
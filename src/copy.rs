@@ -0,0 +1,139 @@
+use std::io;
+use std::io::{IsTerminal, Read, Seek, Write};
+use std::path;
+use sha2::{Digest, Sha256};
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// このサイズ以上のファイルをコピーする際に進捗を表示する
+const PROGRESS_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// 0だけのチャンクを書き込まずにシークで読み飛ばし、スパースなファイルとして書き出す
+struct SparseWriter {
+    file: std::fs::File,
+}
+
+impl Write for SparseWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.iter().all(|b| *b == 0) {
+            self.file.seek(io::SeekFrom::Current(buf.len() as i64))?;
+            Ok(buf.len())
+        } else {
+            self.file.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// ファイルをコピーし、コピー先のサイズとハッシュ値がコピー元と一致することを確認する。
+/// コピー元の0で埋められた領域は、コピー先ではファイルシステム上の穴になる。
+pub fn copy_file(src: &path::Path, dst: &path::Path) -> Result<u64, io::Error> {
+    let mut reader = std::fs::File::open(src)?;
+    let len = reader.metadata()?.len();
+    let mut writer = SparseWriter { file: std::fs::File::create(dst)? };
+
+    let label = src.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let (written, digest) = copy_stream(&mut reader, &mut writer, len, label.as_str())?;
+    // 末尾が穴の場合にファイルサイズが足りなくならないよう、長さを確定させる
+    writer.file.set_len(written)?;
+    writer.file.sync_all()?;
+
+    let mut copied = std::fs::File::open(dst)?;
+    let copied_len = copied.metadata()?.len();
+    if written != len || copied_len != len {
+        return Err(io::Error::other(
+            format!("size mismatch while copying {}: expected {} bytes, got {}", src.display(), len, copied_len)
+        ));
+    }
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut copied, &mut hasher)?;
+    if hasher.finalize().as_slice() != digest.as_slice() {
+        return Err(io::Error::other(format!("hash mismatch while copying {} to {}", src.display(), dst.display())));
+    }
+
+    Ok(written)
+}
+
+/// `len` バイトのデータを `reader` から `writer` に流し込み、書き込んだバイト数と SHA-256 を返す。
+/// 大きなデータの場合は、端末に進捗を表示する。
+pub fn copy_stream<R: Read, W: Write>(reader: &mut R, writer: &mut W, len: u64, label: &str) -> Result<(u64, Vec<u8>), io::Error> {
+    let show_progress = len >= PROGRESS_THRESHOLD && io::stderr().is_terminal() && !crate::output::quiet();
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut written = 0u64;
+    let mut last_percent = None;
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        writer.write_all(&buf[..n])?;
+        hasher.update(&buf[..n]);
+        written += n as u64;
+
+        if show_progress {
+            let percent = written * 100 / len.max(1);
+            if last_percent != Some(percent) {
+                eprint!("\r{}", progress_line(label, written, len));
+                last_percent = Some(percent);
+            }
+        }
+    }
+
+    if show_progress {
+        eprintln!();
+    }
+
+    Ok((written, hasher.finalize().to_vec()))
+}
+
+const BAR_WIDTH: u64 = 30;
+
+fn progress_line(label: &str, done: u64, total: u64) -> String {
+    const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+    let total = total.max(1);
+    let filled = (done.min(total) * BAR_WIDTH / total) as usize;
+    let bar = format!("{}{}", "=".repeat(filled), " ".repeat(BAR_WIDTH as usize - filled));
+    format!(
+        "{} [{}] {:>3}% {:.2}/{:.2} GiB",
+        label, bar, done.min(total) * 100 / total, done as f64 / GIB, total as f64 / GIB
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::copy::{copy_file, progress_line};
+
+    #[test]
+    fn progress_line_reports_ratio() {
+        let line = progress_line("kernel", 512, 1024);
+        assert!(line.starts_with("kernel [==============="));
+        assert!(line.contains(" 50% "));
+    }
+
+    #[test]
+    fn copy_file_verifies_contents() {
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-test-copy-{}", std::process::id()));
+        std::fs::create_dir_all(dir.as_path()).unwrap();
+        let src = dir.join("src.bin");
+        let dst = dir.join("dst.bin");
+        let mut content = vec![0xa5u8; 3 * 1024 * 1024 + 7];
+        content.extend(vec![0u8; 2 * 1024 * 1024]);
+        std::fs::write(src.as_path(), content).unwrap();
+
+        let written = copy_file(src.as_path(), dst.as_path()).unwrap();
+        assert_eq!(written, 5 * 1024 * 1024 + 7);
+        assert_eq!(std::fs::read(src).unwrap(), std::fs::read(dst).unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
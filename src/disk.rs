@@ -0,0 +1,332 @@
+use std::path;
+use clap::ValueEnum;
+use serde::Deserialize;
+use crate::error::{Error, ErrorKind};
+
+/// QEMUのドライブのキャッシュモード
+#[derive(Copy, Clone, Eq, PartialEq, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    Writeback,
+    Writethrough,
+    None,
+    Directsync,
+    Unsafe,
+}
+
+impl CacheMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CacheMode::Writeback => "writeback",
+            CacheMode::Writethrough => "writethrough",
+            CacheMode::None => "none",
+            CacheMode::Directsync => "directsync",
+            CacheMode::Unsafe => "unsafe",
+        }
+    }
+}
+
+/// QEMUのドライブの非同期I/Oバックエンド
+#[derive(Copy, Clone, Eq, PartialEq, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AioMode {
+    Threads,
+    Native,
+    #[value(name = "io_uring")]
+    #[serde(rename = "io_uring")]
+    IoUring,
+}
+
+impl AioMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AioMode::Threads => "threads",
+            AioMode::Native => "native",
+            AioMode::IoUring => "io_uring",
+        }
+    }
+}
+
+/// ドライブごとのI/O設定。未指定の項目はQEMUの既定値になる
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+pub struct DriveOptions {
+    pub cache: Option<CacheMode>,
+    pub aio: Option<AioMode>,
+    pub discard: Option<bool>,
+}
+
+impl DriveOptions {
+    /// `other` で指定された項目を優先して、2つの設定を合成する
+    pub fn merge(&self, other: &DriveOptions) -> DriveOptions {
+        DriveOptions {
+            cache: other.cache.or(self.cache),
+            aio: other.aio.or(self.aio),
+            discard: other.discard.or(self.discard),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        // aio=native はホストのページキャッシュを経由しない場合にしか使えない
+        let direct = matches!(self.cache, Some(CacheMode::None) | Some(CacheMode::Directsync));
+        if self.aio == Some(AioMode::Native) && !direct {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                crate::message::msg!(NativeAioCache)
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// `-drive` の値の末尾に付け加えるオプション文字列を返す
+    pub fn suffix(&self) -> String {
+        let mut suffix = String::new();
+        if let Some(cache) = self.cache {
+            suffix.push_str(&format!(",cache={}", cache.as_str()));
+        }
+        if let Some(aio) = self.aio {
+            suffix.push_str(&format!(",aio={}", aio.as_str()));
+        }
+        if let Some(discard) = self.discard {
+            suffix.push_str(if discard { ",discard=unmap" } else { ",discard=ignore" });
+        }
+
+        suffix
+    }
+}
+
+/// `errno` にはホストのエラー番号か、その名前を書ける
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Errno {
+    Number(i32),
+    Name(String),
+}
+
+impl Errno {
+    /// QEMUに渡すエラー番号。名前はLinuxの番号に変換する
+    fn number(&self) -> Option<i32> {
+        const NAMES: &[(&str, i32)] = &[
+            ("EPERM", 1), ("EIO", 5), ("ENOMEM", 12), ("EACCES", 13), ("EBUSY", 16), ("EINVAL", 22),
+            ("EFBIG", 27), ("ENOSPC", 28), ("EROFS", 30), ("ENOTSUP", 95), ("ETIMEDOUT", 110),
+        ];
+        match self {
+            Errno::Number(n) if *n > 0 => Some(*n),
+            Errno::Number(_) => None,
+            Errno::Name(name) => NAMES.iter().find(|(n, _)| n == name).map(|(_, v)| *v),
+        }
+    }
+}
+
+/// QEMUのblkdebugでデータディスクに起こすI/Oエラー
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct InjectError {
+    /// エラーを有効にするblkdebugのイベント（`read_aio`、`write_aio`、`flush_to_disk` など）
+    pub event: String,
+    /// 対象にするI/Oの種類（`read`、`write`、`flush` など）。省略時は全て
+    pub iotype: Option<String>,
+    /// 省略時は `EIO`
+    pub errno: Option<Errno>,
+    /// このセクタを含むI/Oだけを失敗させる。qcow2などではイメージファイル上のセクタになる
+    pub sector: Option<u64>,
+    /// 1回失敗させたら規則を取り除く
+    #[serde(default)]
+    pub once: bool,
+    /// 要求を処理せずに即座に失敗させる
+    #[serde(default)]
+    pub immediately: bool,
+}
+
+impl InjectError {
+    /// `-drive` に付け加える、`idx` 番目の規則のオプション
+    fn suffix(&self, idx: usize) -> Result<String, Error> {
+        let invalid = |value: &str| Error::new(ErrorKind::InvalidArgument, crate::message::msg!(BlkdebugInvalid, value));
+        // QEMUのオプションの区切りを含められないよう、名前に使える文字を限る
+        let name = |value: &str| match !value.is_empty() && value.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-') {
+            true => Ok(()),
+            false => Err(invalid(value)),
+        };
+
+        let prefix = format!(",file.inject-error.{}.", idx);
+        name(self.event.as_str())?;
+        let mut suffix = format!("{}event={}", prefix, self.event);
+        if let Some(iotype) = &self.iotype {
+            name(iotype.as_str())?;
+            suffix.push_str(&format!("{}iotype={}", prefix, iotype));
+        }
+        let errno = match &self.errno {
+            Some(errno) => errno.number().ok_or_else(|| match errno {
+                Errno::Number(n) => invalid(n.to_string().as_str()),
+                Errno::Name(name) => invalid(name.as_str()),
+            })?,
+            None => 5,
+        };
+        suffix.push_str(&format!("{}errno={}", prefix, errno));
+        if let Some(sector) = self.sector {
+            suffix.push_str(&format!("{}sector={}", prefix, sector));
+        }
+        suffix.push_str(&format!("{}once={}", prefix, if self.once { "on" } else { "off" }));
+        suffix.push_str(&format!("{}immediately={}", prefix, if self.immediately { "on" } else { "off" }));
+
+        Ok(suffix)
+    }
+}
+
+/// 起動ドライブとは別に接続するデータディスク
+#[derive(Clone, Debug, Deserialize)]
+pub struct DiskConfig {
+    pub file: path::PathBuf,
+    pub format: Option<String>,
+    #[serde(flatten)]
+    pub options: DriveOptions,
+    /// blkdebugで起こすI/Oエラー。指定するとイメージとフォーマットの間にblkdebugを挟む
+    #[serde(default)]
+    pub errors: Vec<InjectError>,
+}
+
+impl DiskConfig {
+    pub fn from_path(file: path::PathBuf) -> DiskConfig {
+        DiskConfig { file, format: None, options: DriveOptions::default(), errors: Vec::new() }
+    }
+
+    /// `-drive` の `file` に当たるオプション
+    fn file_option(&self, file: &path::Path) -> Result<String, Error> {
+        if self.errors.is_empty() {
+            return Ok(format!("file={}", file.display()));
+        }
+
+        let mut option = format!("file.driver=blkdebug,file.image.filename={}", crate::fwcfg::escape(file.display().to_string().as_str()));
+        for (idx, error) in self.errors.iter().enumerate() {
+            option.push_str(error.suffix(idx)?.as_str());
+        }
+
+        Ok(option)
+    }
+
+    fn format(&self) -> &str {
+        match &self.format {
+            Some(format) => format.as_str(),
+            None if self.file.extension().map(|e| e == "qcow2").unwrap_or(false) => "qcow2",
+            None => "raw",
+        }
+    }
+}
+
+/// データディスクを接続するためのQEMUの引数を返す。相対パスは `root` を基準にする
+pub fn disk_args(disks: &[DiskConfig], defaults: &DriveOptions, root: &path::Path) -> Result<Vec<String>, Error> {
+    let mut args = Vec::new();
+    for (idx, disk) in disks.iter().enumerate() {
+        let options = defaults.merge(&disk.options);
+        options.validate()?;
+
+        let file = root.join(disk.file.as_path());
+        if !file.is_file() {
+            return Err(Error::new(ErrorKind::InvalidArgument, crate::message::msg!(NotFound, file.display())));
+        }
+
+        args.push("-drive".to_string());
+        args.push(format!("id={},format={},{}{}", disk_id(idx), disk.format(), disk.file_option(file.as_path())?, options.suffix()));
+    }
+
+    Ok(args)
+}
+
+fn disk_id(idx: usize) -> String {
+    format!("disk{}", idx)
+}
+
+/// 実行中に取得したブロックデバイスの統計から、各データディスクへのdiscard要求の有無をまとめる
+pub fn discard_report(disks: &[DiskConfig], stats: &[crate::qmp::BlockStats]) -> String {
+    let mut report = String::from("discard report:\n");
+    for (idx, disk) in disks.iter().enumerate() {
+        let id = disk_id(idx);
+        let line = match stats.iter().find(|s| s.device == id) {
+            Some(s) if s.stats.unmap_operations > 0 => format!(
+                "{} unmap operations, {} bytes", s.stats.unmap_operations, s.stats.unmap_bytes
+            ),
+            Some(_) => "no discard requests".to_string(),
+            None => "no statistics available".to_string(),
+        };
+        report.push_str(&format!("  {} ({}): {}\n", id, disk.file.display(), line));
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::disk::{discard_report, disk_args, AioMode, CacheMode, DiskConfig, DriveOptions, Errno, InjectError};
+    use crate::qmp::{BlockDeviceStats, BlockStats};
+
+    #[test]
+    fn drive_options_suffix() {
+        let options = DriveOptions { cache: Some(CacheMode::None), aio: Some(AioMode::IoUring), discard: Some(true) };
+        assert_eq!(options.suffix(), ",cache=none,aio=io_uring,discard=unmap");
+        assert_eq!(DriveOptions::default().suffix(), "");
+    }
+
+    #[test]
+    fn per_disk_options_override_defaults() {
+        let defaults = DriveOptions { cache: Some(CacheMode::Writeback), aio: Some(AioMode::Threads), discard: None };
+        let disk = DriveOptions { cache: Some(CacheMode::None), aio: None, discard: Some(false) };
+        let merged = defaults.merge(&disk);
+        assert_eq!(merged, DriveOptions { cache: Some(CacheMode::None), aio: Some(AioMode::Threads), discard: Some(false) });
+    }
+
+    #[test]
+    fn native_aio_requires_direct_cache() {
+        let options = DriveOptions { cache: Some(CacheMode::Writeback), aio: Some(AioMode::Native), discard: None };
+        assert!(options.validate().is_err());
+
+        let options = DriveOptions { cache: Some(CacheMode::None), aio: Some(AioMode::Native), discard: None };
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn inject_errors_through_blkdebug() {
+        let root = std::env::temp_dir().join(format!("cargo-uefi-blkdebug-{}", std::process::id()));
+        std::fs::create_dir_all(root.as_path()).unwrap();
+        std::fs::write(root.join("data.img"), [0u8; 512]).unwrap();
+
+        let mut disk = DiskConfig::from_path(path::PathBuf::from("data.img"));
+        disk.errors = vec![
+            InjectError { event: "read_aio".to_string(), iotype: None, errno: None, sector: Some(2048), once: true, immediately: false },
+            InjectError { event: "write_aio".to_string(), iotype: Some("write".to_string()), errno: Some(Errno::Name("ENOSPC".to_string())), sector: None, once: false, immediately: true },
+        ];
+        let args = disk_args(std::slice::from_ref(&disk), &DriveOptions::default(), root.as_path()).unwrap();
+        assert_eq!(args[1], format!(
+            "id=disk0,format=raw,file.driver=blkdebug,file.image.filename={},{},{}",
+            root.join("data.img").display(),
+            "file.inject-error.0.event=read_aio,file.inject-error.0.errno=5,file.inject-error.0.sector=2048,file.inject-error.0.once=on,file.inject-error.0.immediately=off",
+            "file.inject-error.1.event=write_aio,file.inject-error.1.iotype=write,file.inject-error.1.errno=28,file.inject-error.1.once=off,file.inject-error.1.immediately=on",
+        ));
+
+        disk.errors[0].event = "read_aio,file=/etc/passwd".to_string();
+        assert!(disk_args(std::slice::from_ref(&disk), &DriveOptions::default(), root.as_path()).is_err());
+        disk.errors[0].event = "read_aio".to_string();
+        disk.errors[0].errno = Some(Errno::Name("EWHATEVER".to_string()));
+        assert!(disk_args(&[disk], &DriveOptions::default(), root.as_path()).is_err());
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn report_discard_per_disk() {
+        let disks = vec![
+            DiskConfig::from_path(path::PathBuf::from("a.img")),
+            DiskConfig::from_path(path::PathBuf::from("b.img")),
+            DiskConfig::from_path(path::PathBuf::from("c.img")),
+        ];
+        let stats = vec![
+            BlockStats { device: "disk0".to_string(), stats: BlockDeviceStats { unmap_operations: 2, unmap_bytes: 8192 } },
+            BlockStats { device: "disk1".to_string(), stats: BlockDeviceStats { unmap_operations: 0, unmap_bytes: 0 } },
+        ];
+
+        let report = discard_report(&disks, &stats);
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines[1], "  disk0 (a.img): 2 unmap operations, 8192 bytes");
+        assert_eq!(lines[2], "  disk1 (b.img): no discard requests");
+        assert_eq!(lines[3], "  disk2 (c.img): no statistics available");
+    }
+}
@@ -10,6 +10,14 @@ pub struct Error {
 pub enum ErrorKind {
     NotAbleDetermineBinary,
     BinaryNotFound,
+    InvalidArgument,
+    DownloadFailed,
+    ChecksumMismatch,
+    ExtractFailed,
+    Offline,
+    SignatureInvalid,
+    MalformedPe,
+    BuildFailed,
 }
 
 impl Error {
@@ -0,0 +1,148 @@
+use std::process::ExitStatus;
+use serde::Deserialize;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::target::Arch;
+
+/// isa-debug-exitデバイスを使ったゲストからの終了コードの受け渡し方法。
+/// ゲストがポートに `code` を書き込むと、QEMUは `(code << 1) | 1` で終了する。
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ExitConvention {
+    pub iobase: u16,
+    pub iosize: u8,
+    /// 成功とみなすQEMUの終了コード。qemu-exitクレートの `custom_exit_success` に相当する
+    pub success: i32,
+}
+
+impl Default for ExitConvention {
+    fn default() -> Self {
+        // bootimageやqemu-exitの例で広く使われている値
+        ExitConvention { iobase: 0xf4, iosize: 4, success: 33 }
+    }
+}
+
+impl ExitConvention {
+    pub fn validate(&self) -> Result<(), Error> {
+        // isa-debug-exit経由の終了コードは必ず奇数になる
+        if self.success % 2 == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                msg!(ExitSuccessOdd, self.success)
+            ));
+        }
+        if ![1, 2, 4].contains(&self.iosize) {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                msg!(ExitIosize, self.iosize)
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// isa-debug-exitはISAバスのデバイスのため、x86のマシンでしか使えない
+    pub fn device_args(&self, arch: Arch) -> Result<Vec<String>, Error> {
+        if arch == Arch::Aarch64 {
+            return Err(Error::new(ErrorKind::InvalidArgument, msg!(ExitDeviceUnsupported, arch.rust_target())));
+        }
+
+        Ok(vec![
+            "-device".to_string(),
+            format!("isa-debug-exit,iobase={:#x},iosize={:#x}", self.iobase, self.iosize),
+        ])
+    }
+
+    /// QEMUの終了コードを、ホストのプロセスの終了コードに変換する
+    pub fn host_code(&self, qemu_code: i32) -> i32 {
+        if qemu_code == self.success {
+            return 0;
+        }
+
+        // ゲストが書き込んだ値をそのまま返す。0を書き込んだ場合（qemu-exitのexit_failure）は1にする
+        match qemu_code {
+            code if code % 2 == 1 && code >> 1 != 0 => code >> 1,
+            _ => 1,
+        }
+    }
+}
+
+/// QEMUの終了状態をホストの終了コードに変換する。
+/// 終了コードの受け渡し方法が指定されていなければ、ゲストは終了コードを渡せないため、
+/// QEMUが正常に終了したかどうかだけを0か1で返す。
+pub fn host_exit_code(status: Option<ExitStatus>, convention: Option<&ExitConvention>) -> i32 {
+    let code = match status.and_then(|s| s.code()) {
+        Some(code) => code,
+        // タイムアウトやシグナルによる終了
+        None => return 1,
+    };
+
+    match convention {
+        Some(convention) => convention.host_code(code),
+        None if code == 0 => 0,
+        None => 1,
+    }
+}
+
+/// `0xf4` のような16進数、または10進数のI/Oポート番号を読み取る
+pub fn parse_iobase(s: &str) -> Result<u16, Error> {
+    let parsed = match s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+
+    parsed.map_err(|_| Error::new(ErrorKind::InvalidArgument, msg!(InvalidIoPort, s)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::exit::{parse_iobase, ExitConvention};
+    use crate::target::Arch;
+
+    #[test]
+    fn map_qemu_exit_codes() {
+        let convention = ExitConvention::default();
+        assert_eq!(convention.host_code(33), 0);
+        // qemu-exitのexit_failure
+        assert_eq!(convention.host_code(1), 1);
+        // bootimageの慣習での失敗 (0x11 << 1) | 1
+        assert_eq!(convention.host_code(35), 17);
+        // ゲストがポートに書き込まずにQEMUが異常終了した場合
+        assert_eq!(convention.host_code(2), 1);
+        assert_eq!(convention.host_code(0), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn qemu_status_without_convention() {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+        use crate::exit::host_exit_code;
+
+        let exited = |code: i32| Some(ExitStatus::from_raw(code << 8));
+        // 受け渡し方法がなければ、QEMUの終了コードをゲストの結果として扱わない
+        assert_eq!(host_exit_code(exited(0), None), 0);
+        assert_eq!(host_exit_code(exited(33), None), 1);
+        assert_eq!(host_exit_code(exited(33), Some(&ExitConvention::default())), 0);
+        assert_eq!(host_exit_code(None, None), 1);
+    }
+
+    #[test]
+    fn device_args_and_validation() {
+        let convention = ExitConvention { iobase: 0x501, iosize: 2, success: 3 };
+        assert!(convention.validate().is_ok());
+        assert_eq!(convention.device_args(Arch::X86_64).unwrap()[1], "isa-debug-exit,iobase=0x501,iosize=0x2");
+        assert!(convention.device_args(Arch::Aarch64).is_err());
+
+        assert!(ExitConvention { success: 32, ..Default::default() }.validate().is_err());
+        assert!(ExitConvention { iosize: 3, ..Default::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn parse_hex_and_decimal() {
+        assert_eq!(parse_iobase("0xf4").unwrap(), 0xf4);
+        assert_eq!(parse_iobase("1281").unwrap(), 0x501);
+        assert!(parse_iobase("0xzz").is_err());
+        assert!(parse_iobase("0x10000").is_err());
+    }
+}
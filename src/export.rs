@@ -0,0 +1,229 @@
+use std::io;
+use std::path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+use crate::image::BootDrive;
+use crate::message::msg;
+use crate::target::Arch;
+
+/// ファームウェアのデバッグ出力を記録するファイルの名前
+pub const FIRMWARE_LOG: &str = "firmware.log";
+
+/// ファイルシステムの更新時刻は粗いことがあるため、実行を始める少し前からのファイルを実行のものとみなす
+const MODIFIED_SLACK: Duration = Duration::from_secs(1);
+
+/// 実行IDを指定する環境変数。CIのジョブの番号などを渡せば、アップロードした成果物とジョブを対応させられる
+const RUN_ID_ENV: &str = "CARGO_UEFI_RUN_ID";
+
+/// `--artifacts-dir` に、実行ごとの成果物をCIの成果物のアップロードでそのまま扱える構成で書き出す。
+/// `<DIR>/<実行ID>/summary.json` に全体の結果を、`<DIR>/<実行ID>/<アプリケーション>/` に
+/// `result.json`、`logs/`、`screenshots/`、`images/` を置く。
+/// 途中でエラーになっても、それまでの結果を `summary.json` に書いてから破棄する
+pub struct Export {
+    run_id: String,
+    root: path::PathBuf,
+    apps: Vec<Value>,
+    finished: bool,
+}
+
+/// 書き出す1回の実行の成果物
+pub struct Run<'a> {
+    /// 実行中にログやゲストが保存したファイルを書き込んだディレクトリ
+    pub artifacts: &'a path::Path,
+    /// 実行を始めた時刻。これより前に書き込まれたファイルは前の実行のもの
+    pub since: SystemTime,
+    /// 生成したディスクイメージやVARSイメージと、書き出すときの名前
+    pub images: Vec<(String, path::PathBuf)>,
+    /// `run-finished` イベントと同じ内容の結果
+    pub result: Value,
+}
+
+impl Export {
+    pub fn new(dir: &path::Path) -> Result<Export, io::Error> {
+        let requested = std::env::var(RUN_ID_ENV).ok();
+        if let Some(id) = requested.as_deref().filter(|id| !valid_run_id(id)) {
+            crate::output::warning(msg!(RunIdInvalid, RUN_ID_ENV, id));
+        }
+        let run_id = requested.filter(|id| valid_run_id(id))
+            .unwrap_or_else(|| generated_run_id(SystemTime::now(), std::process::id()));
+        let root = dir.join(run_id.as_str());
+        std::fs::create_dir_all(root.as_path())?;
+
+        Ok(Export { run_id, root, apps: Vec::new(), finished: false })
+    }
+
+    /// 実行の成果物を `<実行ID>/<app>/` に複製し、結果を `result.json` に書く
+    pub fn collect(&mut self, app: &str, run: &Run) -> Result<(), io::Error> {
+        let dir = self.root.join(app);
+        std::fs::create_dir_all(dir.as_path())?;
+        let mut files = Vec::new();
+        let since = run.since - MODIFIED_SLACK;
+        let mut entries: Vec<_> = std::fs::read_dir(run.artifacts).into_iter().flatten().filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file() && p.metadata().and_then(|m| m.modified()).is_ok_and(|m| m >= since))
+            .collect();
+        entries.sort();
+        for entry in entries {
+            let name = entry.file_name().expect("read_dir returns named entries").to_string_lossy().into_owned();
+            let kind = match entry.extension().is_some_and(|e| e == "ppm") {
+                true => "screenshots",
+                false => "logs",
+            };
+            files.push(copy_into(entry.as_path(), dir.as_path(), kind, name.as_str())?);
+        }
+        for (name, image) in run.images.iter().filter(|(_, image)| image.is_file()) {
+            files.push(copy_into(image.as_path(), dir.as_path(), "images", name.as_str())?);
+        }
+
+        let mut result = run.result.clone();
+        result["run-id"] = json!(self.run_id);
+        result["files"] = json!(files);
+        std::fs::write(dir.join("result.json"), serde_json::to_string_pretty(&result)?)?;
+        self.apps.push(result);
+
+        Ok(())
+    }
+
+    /// ビルドに失敗して実行しなかったアプリケーションを記録する
+    pub fn build_failed(&mut self, app: &str) {
+        self.apps.push(json!({ "app": app, "run-id": self.run_id, "build-failed": true }));
+    }
+
+    /// 全体の結果を `summary.json` に書き、書き出した場所を報告する
+    pub fn finish(mut self) -> Result<(), io::Error> {
+        self.finished = true;
+        self.write_summary(true)
+    }
+
+    /// `complete` でなければ、エラーで全てのアプリケーションを実行し終えなかったものとして失敗にする
+    fn write_summary(&self, complete: bool) -> Result<(), io::Error> {
+        let passed = complete && self.apps.iter().all(|app| app["exit-code"] == json!(0));
+        let summary = json!({ "run-id": self.run_id, "passed": passed, "complete": complete, "apps": self.apps });
+        std::fs::write(self.root.join("summary.json"), serde_json::to_string_pretty(&summary)?)?;
+        crate::output::status(msg!(ArtifactsExported, self.root.display()));
+        crate::output::event("artifacts-exported", json!({ "run-id": self.run_id, "path": self.root }));
+
+        Ok(())
+    }
+}
+
+impl Drop for Export {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.write_summary(false) {
+                crate::output::warning(msg!(ArtifactsSummaryFailed, self.root.display(), e));
+            }
+        }
+    }
+}
+
+/// ディレクトリの名前に使えない実行IDは無視する
+fn valid_run_id(id: &str) -> bool {
+    !id.is_empty() && id != "." && id != ".." && !id.contains(['/', '\\'])
+}
+
+/// `dir/kind/name` に複製し、`dir` からの相対パスを返す
+fn copy_into(source: &path::Path, dir: &path::Path, kind: &str, name: &str) -> Result<String, io::Error> {
+    std::fs::create_dir_all(dir.join(kind))?;
+    crate::copy::copy_file(source, dir.join(kind).join(name).as_path())?;
+    Ok(format!("{}/{}", kind, name))
+}
+
+/// `20261014-093000-1234` のような、UTCの開始時刻とプロセスIDからなる実行ID。名前の順が実行の順になる
+fn generated_run_id(now: SystemTime, pid: u32) -> String {
+    let secs = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, time) = (secs / 86400, secs % 86400);
+    // 1970-01-01からの日数を年月日にする（Howard Hinnantのcivil_from_days）
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}-{}", year, month, day, time / 3600, time / 60 % 60, time % 60, pid)
+}
+
+/// x86のOVMFがI/Oポート0x402に書くデバッグ出力を `log` に記録するQEMUの引数。利用者が指定している場合は変更しない
+pub fn firmware_log_args(arch: Arch, log: &path::Path, options: &[String]) -> Vec<String> {
+    if arch == Arch::Aarch64 || options.iter().any(|o| o == "-debugcon") {
+        return Vec::new();
+    }
+    vec![
+        "-debugcon".to_string(),
+        format!("file:{}", log.display()),
+        "-global".to_string(),
+        "isa-debugcon.iobase=0x402".to_string(),
+    ]
+}
+
+/// 起動ドライブ、VARSイメージ、データディスクのうち、生成したもの
+pub fn generated_images(drive: &BootDrive, vars: Option<&path::Path>, disks: &[path::PathBuf]) -> Vec<(String, path::PathBuf)> {
+    let mut images = Vec::new();
+    if let BootDrive::Image(image, _) = drive {
+        images.push(("boot.img".to_string(), image.clone()));
+    }
+    if let Some(vars) = vars {
+        images.push(("vars.fd".to_string(), vars.to_path_buf()));
+    }
+    for disk in disks {
+        if let Some(name) = disk.file_name() {
+            images.push((name.to_string_lossy().into_owned(), disk.clone()));
+        }
+    }
+    images
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use serde_json::json;
+    use crate::export::{generated_run_id, valid_run_id, Export, Run};
+
+    #[test]
+    fn export_run_in_ci_layout() {
+        assert_eq!(generated_run_id(UNIX_EPOCH + Duration::from_secs(1_791_970_200), 42), "20261014-093000-42");
+        assert_eq!(generated_run_id(UNIX_EPOCH + Duration::from_secs(951_782_400), 7), "20000229-000000-7");
+        assert!(valid_run_id("ci-1.2"));
+        assert!(["", ".", "..", "a/b", "a\\b"].iter().all(|id| !valid_run_id(id)));
+
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-export-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(dir.as_path());
+        let artifacts = dir.join("artifacts");
+        std::fs::create_dir_all(artifacts.as_path()).unwrap();
+        let since = SystemTime::now();
+        let stale = std::fs::File::create(artifacts.join("stale.log")).unwrap();
+        stale.set_modified(since - Duration::from_secs(60)).unwrap();
+        std::fs::write(artifacts.join("serial.log"), b"Hello").unwrap();
+        std::fs::write(artifacts.join("screen-1.ppm"), b"P6").unwrap();
+        std::fs::write(dir.join("vars.fd"), b"VARS").unwrap();
+
+        let mut export = Export { run_id: "ci-1".to_string(), root: dir.join("out").join("ci-1"), apps: Vec::new(), finished: false };
+        let run = Run { artifacts: artifacts.as_path(), since, images: vec![("vars.fd".to_string(), dir.join("vars.fd"))], result: json!({ "app": "hoge", "exit-code": 0 }) };
+        export.collect("hoge", &run).unwrap();
+        export.build_failed("fuga");
+        let app = dir.join("out").join("ci-1").join("hoge");
+        let result: serde_json::Value = serde_json::from_slice(&std::fs::read(app.join("result.json")).unwrap()).unwrap();
+        assert_eq!(result["files"], json!(["screenshots/screen-1.ppm", "logs/serial.log", "images/vars.fd"]));
+        assert_eq!(std::fs::read(app.join("logs").join("serial.log")).unwrap(), b"Hello");
+        assert!(!app.join("logs").join("stale.log").exists());
+
+        export.finish().unwrap();
+        let summary: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("out").join("ci-1").join("summary.json")).unwrap()).unwrap();
+        assert_eq!((summary["passed"].as_bool(), summary["apps"].as_array().map(|a| a.len())), (Some(false), Some(2)));
+
+        // エラーで途中で終わっても、それまでの結果を書く
+        let root = dir.join("out").join("ci-2");
+        std::fs::create_dir_all(root.as_path()).unwrap();
+        let mut export = Export { run_id: "ci-2".to_string(), root: root.clone(), apps: Vec::new(), finished: false };
+        export.collect("hoge", &run).unwrap();
+        drop(export);
+        let summary: serde_json::Value = serde_json::from_slice(&std::fs::read(root.join("summary.json")).unwrap()).unwrap();
+        assert_eq!((summary["passed"].as_bool(), summary["complete"].as_bool()), (Some(false), Some(false)));
+
+        std::fs::remove_dir_all(dir.as_path()).unwrap();
+    }
+}
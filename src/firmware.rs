@@ -0,0 +1,564 @@
+use std::io;
+use std::path;
+use std::process::{Command, Stdio};
+use serde::Deserialize;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::target::Arch;
+
+/// QEMUに渡すUEFIファームウェア
+#[derive(Clone, Debug)]
+pub struct Firmware {
+    /// ファームウェア本体（CODEのみ、またはCODEとVARSを結合したイメージ）
+    pub code: path::PathBuf,
+    /// UEFI変数を保存するVARSイメージ
+    pub vars: Option<path::PathBuf>,
+    /// ファームウェアと同じビルドのUEFI Shell
+    pub shell: Option<path::PathBuf>,
+}
+
+impl Firmware {
+    pub fn from_code(code: path::PathBuf) -> Firmware {
+        Firmware { code, vars: None, shell: None }
+    }
+
+    /// VARSイメージを `dir` 以下に複製し、その複製を使うファームウェアを返す。
+    /// 元のVARSイメージはキャッシュなど共有のファイルであるため、直接書き込ませない。
+    pub fn with_vars_copy(&self, dir: &path::Path) -> Result<Firmware, io::Error> {
+        let vars = match &self.vars {
+            Some(vars) => vars,
+            None => return Ok(self.clone()),
+        };
+
+        std::fs::create_dir_all(dir)?;
+        let copy = dir.join("OVMF_VARS.fd");
+        crate::copy::copy_file(vars, copy.as_path())?;
+
+        Ok(Firmware { vars: Some(copy), ..self.clone() })
+    }
+
+    pub fn pflash_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "-drive".to_string(),
+            format!("if=pflash,format=raw,readonly=on,file={}", self.code.display()),
+        ];
+        if let Some(vars) = &self.vars {
+            args.push("-drive".to_string());
+            args.push(format!("if=pflash,format=raw,file={}", vars.display()));
+        }
+
+        args
+    }
+}
+
+/// パスで指定するファームウェア。VARSを指定すると、CODEとは別のpflashドライブとして接続する
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FirmwarePaths {
+    pub code: path::PathBuf,
+    pub vars: Option<path::PathBuf>,
+}
+
+impl FirmwarePaths {
+    /// 相対パスを `base` からのパスとみなす
+    pub fn resolve(self, base: &path::Path) -> FirmwarePaths {
+        FirmwarePaths { code: base.join(self.code), vars: self.vars.map(|v| base.join(v)) }
+    }
+
+    pub fn firmware(&self) -> Result<Firmware, io::Error> {
+        for file in std::iter::once(&self.code).chain(self.vars.as_ref()) {
+            if !file.is_file() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg!(NotFound, file.display())));
+            }
+        }
+
+        Ok(Firmware { code: self.code.clone(), vars: self.vars.clone(), shell: None })
+    }
+}
+
+/// `OVMF_PATH` 環境変数で指定されたファームウェア。
+/// ファイルならそれをCODE（またはCODEとVARSを結合したイメージ）とし、ディレクトリならその中からCODEとVARSの組を探す
+pub fn from_env() -> Option<Result<Firmware, io::Error>> {
+    let path = path::PathBuf::from(std::env::var_os("OVMF_PATH")?);
+    if path.is_file() {
+        return Some(Ok(Firmware::from_code(path)));
+    }
+
+    Some(find_in_dir(path.as_path()).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, msg!(FirmwareNotInDir, path.display()))))
+}
+
+/// ディレクトリ直下のファームウェアを探す。ディストリビューションやovmf-prebuiltで使われている名前を試す
+fn find_in_dir(dir: &path::Path) -> Option<Firmware> {
+    const DIR_FIRMWARE: &[Candidate] = &[
+        Candidate { code: "OVMF_CODE_4M.fd", vars: Some("OVMF_VARS_4M.fd") },
+        Candidate { code: "OVMF_CODE.fd", vars: Some("OVMF_VARS.fd") },
+        Candidate { code: "AAVMF_CODE.fd", vars: Some("AAVMF_VARS.fd") },
+        Candidate { code: "code.fd", vars: Some("vars.fd") },
+        Candidate { code: "OVMF.fd", vars: None },
+    ];
+
+    find_candidate(DIR_FIRMWARE, dir)
+}
+
+/// `--download-ovmf` で取得する ovmf-prebuilt のリリース
+pub const DEFAULT_OVMF_PREBUILT_TAG: &str = "edk2-stable202502-r1";
+
+/// `DEFAULT_OVMF_PREBUILT_TAG` のtarballの SHA-256。ミラーやキャッシュから取得した場合も、これと一致しなければ使わない
+pub const DEFAULT_OVMF_PREBUILT_SHA256: &str = "6d6122e88cdc09e1ffafb6a39fbdbfba668a6ded3f2a032b2cd6c0b7ff6d69df";
+
+/// プロジェクトルートに置くファームウェアの名前。aarch64ではpflashの大きさ（64MiB）に揃えたイメージを置く
+pub fn project_firmware_name(arch: Arch) -> &'static str {
+    match arch {
+        Arch::X86_64 => "OVMF.fd",
+        Arch::Aarch64 => "AAVMF.fd",
+        Arch::I686 => "OVMF32.fd",
+    }
+}
+
+/// システムにインストールされたファームウェアの候補
+struct Candidate {
+    code: &'static str,
+    vars: Option<&'static str>,
+}
+
+/// x86_64のファームウェアを探す場所。先に書かれたものを優先する。
+/// CODEとVARSはサイズが一致している必要があるため、対になるファイルを組で書く。
+const X64_FIRMWARE: &[Candidate] = &[
+    // Debian / Ubuntu
+    Candidate { code: "/usr/share/OVMF/OVMF_CODE_4M.fd", vars: Some("/usr/share/OVMF/OVMF_VARS_4M.fd") },
+    Candidate { code: "/usr/share/OVMF/OVMF_CODE.fd", vars: Some("/usr/share/OVMF/OVMF_VARS.fd") },
+    // Fedora / RHEL
+    Candidate { code: "/usr/share/edk2/ovmf/OVMF_CODE.fd", vars: Some("/usr/share/edk2/ovmf/OVMF_VARS.fd") },
+    // Arch Linux
+    Candidate { code: "/usr/share/edk2/x64/OVMF_CODE.4m.fd", vars: Some("/usr/share/edk2/x64/OVMF_VARS.4m.fd") },
+    Candidate { code: "/usr/share/edk2/x64/OVMF_CODE.fd", vars: Some("/usr/share/edk2/x64/OVMF_VARS.fd") },
+    Candidate { code: "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd", vars: Some("/usr/share/edk2-ovmf/x64/OVMF_VARS.fd") },
+    // openSUSE
+    Candidate { code: "/usr/share/qemu/ovmf-x86_64-code.bin", vars: Some("/usr/share/qemu/ovmf-x86_64-vars.bin") },
+    // Gentoo
+    Candidate { code: "/usr/share/edk2-ovmf/OVMF_CODE.fd", vars: Some("/usr/share/edk2-ovmf/OVMF_VARS.fd") },
+    // NixOS（libvirtdが有効な場合）
+    Candidate { code: "/run/libvirt/nix-ovmf/OVMF_CODE.fd", vars: Some("/run/libvirt/nix-ovmf/OVMF_VARS.fd") },
+    // QEMUに同梱されたファームウェア（Linux / FreeBSD / OpenBSD / NetBSD / Homebrew）
+    Candidate { code: "/usr/share/qemu/edk2-x86_64-code.fd", vars: Some("/usr/share/qemu/edk2-i386-vars.fd") },
+    Candidate { code: "/usr/local/share/qemu/edk2-x86_64-code.fd", vars: Some("/usr/local/share/qemu/edk2-i386-vars.fd") },
+    Candidate { code: "/usr/pkg/share/qemu/edk2-x86_64-code.fd", vars: Some("/usr/pkg/share/qemu/edk2-i386-vars.fd") },
+    Candidate { code: "/opt/homebrew/share/qemu/edk2-x86_64-code.fd", vars: Some("/opt/homebrew/share/qemu/edk2-i386-vars.fd") },
+    // FreeBSD (sysutils/edk2)
+    Candidate { code: "/usr/local/share/edk2-qemu/QEMU_UEFI_CODE-x86_64.fd", vars: Some("/usr/local/share/edk2-qemu/QEMU_UEFI_VARS-x86_64.fd") },
+    // OpenBSD (sysutils/ovmf)
+    Candidate { code: "/usr/local/share/ovmf/OVMF.fd", vars: None },
+    // CODEとVARSを結合したイメージ
+    Candidate { code: "/usr/share/OVMF/OVMF.fd", vars: None },
+    Candidate { code: "/usr/share/ovmf/OVMF.fd", vars: None },
+    Candidate { code: "/usr/share/ovmf/x64/OVMF.fd", vars: None },
+];
+
+/// aarch64のファームウェア（AAVMF）を探す場所
+const AA64_FIRMWARE: &[Candidate] = &[
+    // Debian / Ubuntu
+    Candidate { code: "/usr/share/AAVMF/AAVMF_CODE.fd", vars: Some("/usr/share/AAVMF/AAVMF_VARS.fd") },
+    // Fedora / RHEL
+    Candidate { code: "/usr/share/edk2/aarch64/QEMU_EFI-pflash.raw", vars: Some("/usr/share/edk2/aarch64/vars-template-pflash.raw") },
+    // Arch Linux
+    Candidate { code: "/usr/share/edk2/aarch64/QEMU_CODE.fd", vars: Some("/usr/share/edk2/aarch64/QEMU_VARS.fd") },
+    // QEMUに同梱されたファームウェア
+    Candidate { code: "/usr/share/qemu/edk2-aarch64-code.fd", vars: Some("/usr/share/qemu/edk2-arm-vars.fd") },
+    Candidate { code: "/usr/local/share/qemu/edk2-aarch64-code.fd", vars: Some("/usr/local/share/qemu/edk2-arm-vars.fd") },
+    Candidate { code: "/usr/pkg/share/qemu/edk2-aarch64-code.fd", vars: Some("/usr/pkg/share/qemu/edk2-arm-vars.fd") },
+    Candidate { code: "/opt/homebrew/share/qemu/edk2-aarch64-code.fd", vars: Some("/opt/homebrew/share/qemu/edk2-arm-vars.fd") },
+];
+
+/// i686のファームウェア（OVMF32）を探す場所
+const IA32_FIRMWARE: &[Candidate] = &[
+    // Debian / Ubuntu
+    Candidate { code: "/usr/share/OVMF/OVMF32_CODE_4M.fd", vars: Some("/usr/share/OVMF/OVMF32_VARS_4M.fd") },
+    // Fedora / RHEL
+    Candidate { code: "/usr/share/edk2/ovmf-ia32/OVMF_CODE.fd", vars: Some("/usr/share/edk2/ovmf-ia32/OVMF_VARS.fd") },
+    // Arch Linux
+    Candidate { code: "/usr/share/edk2/ia32/OVMF_CODE.4m.fd", vars: Some("/usr/share/edk2/ia32/OVMF_VARS.4m.fd") },
+    Candidate { code: "/usr/share/edk2/ia32/OVMF_CODE.fd", vars: Some("/usr/share/edk2/ia32/OVMF_VARS.fd") },
+    // QEMUに同梱されたファームウェア
+    Candidate { code: "/usr/share/qemu/edk2-i386-code.fd", vars: Some("/usr/share/qemu/edk2-i386-vars.fd") },
+    Candidate { code: "/usr/local/share/qemu/edk2-i386-code.fd", vars: Some("/usr/local/share/qemu/edk2-i386-vars.fd") },
+    Candidate { code: "/usr/pkg/share/qemu/edk2-i386-code.fd", vars: Some("/usr/pkg/share/qemu/edk2-i386-vars.fd") },
+    Candidate { code: "/opt/homebrew/share/qemu/edk2-i386-code.fd", vars: Some("/opt/homebrew/share/qemu/edk2-i386-vars.fd") },
+];
+
+/// `root` 以下から、システムにインストールされたファームウェアを探す
+pub fn find_system_firmware(arch: Arch, root: &path::Path) -> Option<Firmware> {
+    let candidates = match arch {
+        Arch::X86_64 => X64_FIRMWARE,
+        Arch::Aarch64 => AA64_FIRMWARE,
+        Arch::I686 => IA32_FIRMWARE,
+    };
+
+    find_candidate(candidates, root)
+}
+
+fn find_candidate(candidates: &[Candidate], root: &path::Path) -> Option<Firmware> {
+    let under_root = |p: &str| root.join(p.trim_start_matches('/'));
+
+    candidates.iter().find_map(|c| {
+        let code = under_root(c.code);
+        let vars = c.vars.map(under_root);
+        let found = code.is_file() && vars.as_ref().map(|v| v.is_file()).unwrap_or(true);
+
+        found.then_some(Firmware { code, vars, shell: None })
+    })
+}
+
+/// Nixでインストールされたファームウェアを探す。
+/// `NIX_OVMF` 環境変数で OVMF パッケージのパスが与えられていればそれを使い、
+/// なければ `nix eval` で nixpkgs の OVMF を問い合わせる（ストアに存在する場合のみ使う）。
+/// nixpkgs の OVMF はx86_64向けのものだけを探す。
+pub fn find_nix_firmware(arch: Arch, offline: bool) -> Option<Firmware> {
+    if arch != Arch::X86_64 {
+        return None;
+    }
+
+    let package = match std::env::var_os("NIX_OVMF") {
+        Some(package) => path::PathBuf::from(package),
+        None => nix_eval_ovmf(offline)?,
+    };
+
+    find_in_nix_package(package.as_path())
+}
+
+fn find_in_nix_package(package: &path::Path) -> Option<Firmware> {
+    const NIX_FIRMWARE: &[Candidate] = &[
+        Candidate { code: "FV/OVMF_CODE.fd", vars: Some("FV/OVMF_VARS.fd") },
+        Candidate { code: "FV/OVMF.fd", vars: None },
+    ];
+
+    find_candidate(NIX_FIRMWARE, package)
+}
+
+fn nix_eval_ovmf(offline: bool) -> Option<path::PathBuf> {
+    let mut command = Command::new("nix");
+    command.args(["--extra-experimental-features", "nix-command flakes"]);
+    // nixpkgsのflakeの取得にネットワークを使わないようにする
+    if offline {
+        command.arg("--offline");
+    }
+
+    let output = command
+        .args(["eval", "--raw", "nixpkgs#OVMF.fd"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let path = path::PathBuf::from(String::from_utf8(output.stdout).ok()?.trim());
+    path.is_dir().then_some(path)
+}
+
+/// rust-osdev/ovmf-prebuilt のリリースを指定する設定
+#[derive(Clone, Debug, Deserialize)]
+pub struct OvmfPrebuilt {
+    /// リリースのタグ（例: `edk2-stable202502-r1`）
+    pub tag: String,
+    /// リリースのtarballの SHA-256
+    pub sha256: Option<String>,
+    /// tarballの署名を検証する場合の設定
+    pub signature: Option<crate::signature::SignatureConfig>,
+}
+
+const OVMF_PREBUILT_URL: &str = "https://github.com/rust-osdev/ovmf-prebuilt/releases/download";
+
+/// ファームウェアのダウンロード元を置き換えるミラー
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Mirrors {
+    /// ovmf-prebuilt のリリースを配信するURL。`<URL>/<tag>/<tag>-bin.tar.xz` から取得する
+    pub ovmf_prebuilt: Vec<String>,
+    /// ミラーから取得できなかった場合に、本家からの取得を試すか
+    pub upstream: bool,
+}
+
+impl Default for Mirrors {
+    fn default() -> Self {
+        Mirrors { ovmf_prebuilt: Vec::new(), upstream: true }
+    }
+}
+
+/// ovmf-prebuilt のtarball内での、アーキテクチャごとのディレクトリ名
+pub fn prebuilt_arch_dir(arch: Arch) -> &'static str {
+    match arch {
+        Arch::X86_64 => "x64",
+        Arch::Aarch64 => "aarch64",
+        Arch::I686 => "ia32",
+    }
+}
+
+impl OvmfPrebuilt {
+    /// `DEFAULT_OVMF_PREBUILT_TAG` のリリース
+    pub fn default_release(signature: Option<crate::signature::SignatureConfig>) -> OvmfPrebuilt {
+        OvmfPrebuilt {
+            tag: DEFAULT_OVMF_PREBUILT_TAG.to_string(),
+            sha256: Some(DEFAULT_OVMF_PREBUILT_SHA256.to_string()),
+            signature,
+        }
+    }
+
+    /// 検証に使う SHA-256。指定がなくても、既定のリリースであれば固定した値で検証する
+    fn expected_sha256(&self) -> Option<&str> {
+        self.sha256.as_deref().or_else(|| (self.tag == DEFAULT_OVMF_PREBUILT_TAG).then_some(DEFAULT_OVMF_PREBUILT_SHA256))
+    }
+
+    fn tarball_name(&self) -> String {
+        format!("{}-bin.tar.xz", self.tag)
+    }
+
+    fn url_on(&self, base: &str) -> String {
+        format!("{}/{}/{}", base.trim_end_matches('/'), self.tag, self.tarball_name())
+    }
+
+    /// ダウンロードを試すURLを優先順に返す。ミラーを先に試し、許可されていれば最後に本家を使う
+    fn urls(&self, mirrors: &Mirrors) -> Vec<String> {
+        let upstream = mirrors.upstream.then_some(OVMF_PREBUILT_URL);
+        mirrors.ovmf_prebuilt.iter()
+            .map(String::as_str)
+            .chain(upstream)
+            .map(|base| self.url_on(base))
+            .collect()
+    }
+
+    /// 展開したtarball内の、対象アーキテクチャのファイルを指すファームウェア
+    fn firmware_in(&self, dir: &path::Path, arch: Arch) -> Firmware {
+        let arch_dir = dir.join(format!("{}-bin", self.tag)).join(prebuilt_arch_dir(arch));
+        Firmware {
+            code: arch_dir.join("code.fd"),
+            vars: Some(arch_dir.join("vars.fd")),
+            shell: Some(arch_dir.join("shell.efi")),
+        }
+    }
+}
+
+/// ovmf-prebuilt のリリースをキャッシュから取得する。キャッシュになければダウンロードして展開する
+/// 署名の検証に失敗した場合は、`allow_unverified` が指定されていない限りエラーにする
+pub fn ovmf_prebuilt(
+    source: &OvmfPrebuilt,
+    arch: Arch,
+    mirrors: &Mirrors,
+    wait_lock: bool,
+    allow_unverified: bool,
+    network: &crate::fetch::Network,
+) -> Result<Firmware, Box<dyn std::error::Error>> {
+    let dir = crate::fetch::cache_root().join("ovmf-prebuilt").join(source.tag.as_str());
+    let firmware = source.firmware_in(dir.as_path(), arch);
+    if firmware.code.is_file() {
+        return Ok(firmware);
+    }
+
+    let _lock = crate::lock::FileLock::acquire(crate::lock::lock_path_for(dir.as_path()).as_path(), wait_lock)?;
+    if firmware.code.is_file() {
+        return Ok(firmware);
+    }
+
+    let tarball = dir.join(source.tarball_name());
+    if !tarball.is_file() {
+        crate::fetch::download_any(&source.urls(mirrors), tarball.as_path(), network)?;
+    }
+
+    match source.expected_sha256() {
+        Some(expected) => {
+            if let Err(e) = crate::fetch::verify_sha256(tarball.as_path(), expected) {
+                let _ = std::fs::remove_file(tarball.as_path());
+                return Err(e);
+            }
+        }
+        None => crate::output::warning(
+            msg!(TarballUnverified, source.tarball_name(), crate::fetch::sha256_file(tarball.as_path())?)
+        ),
+    }
+
+    if let Some(signature) = &source.signature {
+        if let Err(e) = verify_signature(source, signature, mirrors, tarball.as_path(), network) {
+            if !allow_unverified {
+                let _ = std::fs::remove_file(tarball.as_path());
+                return Err(e);
+            }
+            crate::output::warning(msg!(UnverifiedContinuing, e));
+        }
+    }
+
+    extract_tar_xz(tarball.as_path(), dir.as_path())?;
+    if !firmware.code.is_file() {
+        return Err(Box::new(Error::new(
+            ErrorKind::ExtractFailed,
+            msg!(TarballMissingFile, source.tarball_name(), firmware.code.display())
+        )));
+    }
+
+    Ok(firmware)
+}
+
+/// tarballと同じ場所から署名を取得して検証する
+fn verify_signature(
+    source: &OvmfPrebuilt,
+    signature: &crate::signature::SignatureConfig,
+    mirrors: &Mirrors,
+    tarball: &path::Path,
+    network: &crate::fetch::Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut name = tarball.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".");
+    name.push(signature.extension());
+    let signature_file = tarball.with_file_name(name);
+
+    if !signature_file.is_file() {
+        let urls = source.urls(mirrors).iter().map(|url| signature.signature_url(url)).collect::<Vec<_>>();
+        crate::fetch::download_any(&urls, signature_file.as_path(), network)?;
+    }
+
+    let result = crate::signature::verify(signature, tarball, signature_file.as_path());
+    if result.is_err() {
+        let _ = std::fs::remove_file(signature_file.as_path());
+    }
+
+    Ok(result?)
+}
+
+pub fn extract_tar_xz(tarball: &path::Path, dest: &path::Path) -> Result<(), Error> {
+    let status = Command::new("tar")
+        .arg("-xJf").arg(tarball)
+        .arg("-C").arg(dest)
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|e| Error::new(ErrorKind::ExtractFailed, msg!(RunFailed, "tar", e)))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::new(ErrorKind::ExtractFailed, msg!(ExtractFailed, tarball.display(), status)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::firmware::{find_in_dir, find_in_nix_package, find_system_firmware, Firmware, Mirrors, OvmfPrebuilt, DEFAULT_OVMF_PREBUILT_SHA256};
+    use crate::target::Arch;
+
+    fn scratch_root(name: &str, files: &[&str]) -> path::PathBuf {
+        let root = std::env::temp_dir().join(format!("cargo-uefi-test-fw-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(root.as_path());
+        for file in files {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+
+        root
+    }
+
+    #[test]
+    fn probe_prefers_paired_code_and_vars() {
+        // VARSのないCODEは使わず、次の候補に進む
+        let root = scratch_root("probe", &[
+            "usr/share/OVMF/OVMF_CODE_4M.fd",
+            "usr/share/edk2/ovmf/OVMF_CODE.fd",
+            "usr/share/edk2/ovmf/OVMF_VARS.fd",
+        ]);
+
+        let firmware = find_system_firmware(Arch::X86_64, root.as_path()).unwrap();
+        assert_eq!(firmware.code, root.join("usr/share/edk2/ovmf/OVMF_CODE.fd"));
+        assert_eq!(firmware.vars.unwrap(), root.join("usr/share/edk2/ovmf/OVMF_VARS.fd"));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn probe_bsd_locations() {
+        let root = scratch_root("bsd", &["usr/local/share/ovmf/OVMF.fd"]);
+        let firmware = find_system_firmware(Arch::X86_64, root.as_path()).unwrap();
+        assert_eq!(firmware.code, root.join("usr/local/share/ovmf/OVMF.fd"));
+        assert!(firmware.vars.is_none());
+        std::fs::remove_dir_all(root).unwrap();
+
+        let root = scratch_root("freebsd", &[
+            "usr/local/share/qemu/edk2-x86_64-code.fd",
+            "usr/local/share/qemu/edk2-i386-vars.fd",
+        ]);
+        let firmware = find_system_firmware(Arch::X86_64, root.as_path()).unwrap();
+        assert_eq!(firmware.code, root.join("usr/local/share/qemu/edk2-x86_64-code.fd"));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn probe_per_architecture() {
+        let root = scratch_root("arch", &[
+            "usr/share/OVMF/OVMF_CODE_4M.fd",
+            "usr/share/OVMF/OVMF_VARS_4M.fd",
+            "usr/share/AAVMF/AAVMF_CODE.fd",
+            "usr/share/AAVMF/AAVMF_VARS.fd",
+        ]);
+        let firmware = find_system_firmware(Arch::Aarch64, root.as_path()).unwrap();
+        assert_eq!(firmware.code, root.join("usr/share/AAVMF/AAVMF_CODE.fd"));
+        assert!(find_system_firmware(Arch::I686, root.as_path()).is_none());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn probe_firmware_dir() {
+        let root = scratch_root("dir", &["OVMF_CODE.fd", "OVMF_VARS.fd", "OVMF.fd"]);
+        let firmware = find_in_dir(root.as_path()).unwrap();
+        assert_eq!(firmware.code, root.join("OVMF_CODE.fd"));
+        assert_eq!(firmware.vars.unwrap(), root.join("OVMF_VARS.fd"));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn probe_nix_package() {
+        let root = scratch_root("nix", &["FV/OVMF_CODE.fd", "FV/OVMF_VARS.fd"]);
+        let firmware = find_in_nix_package(root.as_path()).unwrap();
+        assert_eq!(firmware.code, root.join("FV/OVMF_CODE.fd"));
+        assert_eq!(firmware.vars.unwrap(), root.join("FV/OVMF_VARS.fd"));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn ovmf_prebuilt_layout() {
+        let source = OvmfPrebuilt { tag: "edk2-stable202502-r1".to_string(), sha256: None, signature: None };
+        assert_eq!(source.expected_sha256(), Some(DEFAULT_OVMF_PREBUILT_SHA256));
+        let other = OvmfPrebuilt { tag: "edk2-stable202411-r1".to_string(), sha256: None, signature: None };
+        assert_eq!(other.expected_sha256(), None);
+        assert_eq!(
+            source.urls(&Mirrors::default()),
+            vec!["https://github.com/rust-osdev/ovmf-prebuilt/releases/download/edk2-stable202502-r1/edk2-stable202502-r1-bin.tar.xz"]
+        );
+
+        let mirrors = Mirrors { ovmf_prebuilt: vec!["https://artifacts.corp/ovmf/".to_string()], upstream: false };
+        assert_eq!(
+            source.urls(&mirrors),
+            vec!["https://artifacts.corp/ovmf/edk2-stable202502-r1/edk2-stable202502-r1-bin.tar.xz"]
+        );
+
+        let firmware = source.firmware_in(path::Path::new("/cache"), Arch::X86_64);
+        assert_eq!(firmware.code, path::Path::new("/cache/edk2-stable202502-r1-bin/x64/code.fd"));
+        assert_eq!(firmware.vars.unwrap(), path::Path::new("/cache/edk2-stable202502-r1-bin/x64/vars.fd"));
+        assert_eq!(firmware.shell.unwrap(), path::Path::new("/cache/edk2-stable202502-r1-bin/x64/shell.efi"));
+        let firmware = source.firmware_in(path::Path::new("/cache"), Arch::Aarch64);
+        assert_eq!(firmware.code, path::Path::new("/cache/edk2-stable202502-r1-bin/aarch64/code.fd"));
+    }
+
+    #[test]
+    fn split_firmware_uses_two_pflash_drives() {
+        let firmware = Firmware {
+            code: path::PathBuf::from("/fw/code.fd"),
+            vars: Some(path::PathBuf::from("/run/vars.fd")),
+            shell: None,
+        };
+
+        assert_eq!(firmware.pflash_args(), vec![
+            "-drive", "if=pflash,format=raw,readonly=on,file=/fw/code.fd",
+            "-drive", "if=pflash,format=raw,file=/run/vars.fd",
+        ]);
+        assert_eq!(Firmware::from_code(path::PathBuf::from("/OVMF.fd")).pflash_args().len(), 2);
+    }
+}
@@ -0,0 +1,196 @@
+use std::io;
+use std::net;
+use std::thread;
+use std::time::Duration;
+use serde_json::{json, Value};
+use crate::imagebase::Symbolizer;
+use crate::message::msg;
+use crate::qmp::Qmp;
+
+/// ゲストが停止した理由
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stop {
+    /// ゲスト自身による電源断
+    Shutdown,
+    /// トリプルフォールトまたはゲストによるリセット
+    Reset,
+    /// pvpanicで通知されたゲストのパニック
+    Panic,
+}
+
+impl Stop {
+    fn name(&self) -> &'static str {
+        match self {
+            Stop::Shutdown => "shutdown",
+            Stop::Reset => "reset",
+            Stop::Panic => "panic",
+        }
+    }
+}
+
+/// QMPのイベントから、ゲストが停止した理由を判定する。関係のないイベントは `None`
+pub fn classify(event: &Value) -> Option<Stop> {
+    match event["event"].as_str()? {
+        "GUEST_PANICKED" => Some(Stop::Panic),
+        "SHUTDOWN" => match event["data"]["reason"].as_str()? {
+            "guest-shutdown" => Some(Stop::Shutdown),
+            "guest-reset" => Some(Stop::Reset),
+            "guest-panic" => Some(Stop::Panic),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// ゲストのリセットや停止に対するQEMUの動作
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FreezeOptions {
+    /// リセットの代わりにQEMUを終了する（`-no-reboot`）
+    pub no_reboot: bool,
+    /// ゲストが電源断してもQEMUを終了しない（`-no-shutdown`）
+    pub no_shutdown: bool,
+    /// トリプルフォールトやパニックの時点でVMを一時停止したままにし、GDBで調べられるようにする
+    pub freeze_on_crash: bool,
+    /// VMが停止したらGDBを起動して接続する
+    pub attach_gdb: bool,
+}
+
+impl FreezeOptions {
+    /// QEMUに渡す引数。停止したVMを監視するためのQMPとGDBの待ち受けアドレスは `addrs` で渡す
+    pub fn qemu_args(&self, addrs: Option<(net::SocketAddr, net::SocketAddr)>) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.no_reboot || self.freeze_on_crash {
+            args.push("-no-reboot".to_string());
+        }
+        if self.no_shutdown || self.freeze_on_crash {
+            args.push("-no-shutdown".to_string());
+        }
+
+        if let Some((qmp_addr, gdb_addr)) = addrs {
+            args.extend(["-action".to_string(), "panic=pause".to_string()]);
+            args.extend(["-device".to_string(), "pvpanic".to_string()]);
+            args.extend(["-gdb".to_string(), format!("tcp:{}", gdb_addr)]);
+            args.extend(crate::qmp::qmp_args(qmp_addr));
+        }
+
+        args
+    }
+}
+
+/// 停止したVMを監視し、クラッシュであれば利用者に知らせる。
+/// `--no-shutdown` が明示されていなければ、通常の電源断ではそのままQEMUを終了させる
+pub struct CrashMonitor {
+    handle: thread::JoinHandle<Result<Option<Stop>, io::Error>>,
+}
+
+impl CrashMonitor {
+    pub fn start(options: FreezeOptions, qmp_addr: net::SocketAddr, gdb_addr: net::SocketAddr) -> CrashMonitor {
+        let handle = thread::spawn(move || {
+            let mut qmp = Qmp::connect(qmp_addr, Duration::from_secs(10))?;
+            let stop = loop {
+                // QEMUが終了して接続が閉じられたら監視も終える
+                let event = match qmp.next_event() {
+                    Ok(event) => event,
+                    Err(_) => return Ok(None),
+                };
+                if let Some(stop) = classify(&event) {
+                    break stop;
+                }
+            };
+
+            if stop == Stop::Shutdown && !options.no_shutdown {
+                let _ = qmp.execute("quit", None);
+                return Ok(Some(stop));
+            }
+
+            let target = format!("target remote {}", gdb_addr);
+            crate::output::status(msg!(VmFrozen, stop.name(), target));
+            let symbolizer = crate::imagebase::symbolizer();
+            let (pc, location) = stopped_at(&mut qmp, symbolizer.as_ref());
+            let mut fields = frozen_fields(stop, gdb_addr);
+            fields["pc"] = json!(pc);
+            fields["location"] = json!(location);
+            crate::output::event("vm-frozen", fields);
+            if options.attach_gdb {
+                let commands = symbolizer.map(|s| s.gdb_commands()).unwrap_or_default();
+                attach_gdb(target.as_str(), &commands);
+                let _ = qmp.execute("quit", None);
+            }
+
+            Ok(Some(stop))
+        });
+
+        CrashMonitor { handle }
+    }
+
+    /// QEMUの終了後に呼び、ゲストが停止した理由を返す
+    pub fn finish(self) -> Result<Option<Stop>, io::Error> {
+        self.handle.join().unwrap_or_else(|_| Err(io::Error::other("crash monitor panicked")))
+    }
+}
+
+/// `vm-frozen` イベントの内容。`reason` はイベントの種類に使われるため、停止した理由は `stop` に入れる
+fn frozen_fields(stop: Stop, gdb_addr: net::SocketAddr) -> Value {
+    json!({ "stop": stop.name(), "gdb": gdb_addr.to_string() })
+}
+
+/// 停止したVMのプログラムカウンタを読み、ゲストが読み込み先を報告していれば、停止した位置をアプリケーションのシンボルで示す
+pub fn stopped_at(qmp: &mut Qmp, symbolizer: Option<&Symbolizer>) -> (Option<u64>, Option<String>) {
+    let pc = qmp.execute("human-monitor-command", Some(json!({ "command-line": "info registers" }))).ok()
+        .and_then(|registers| crate::imagebase::program_counter(registers.as_str().unwrap_or_default()));
+    let location = symbolizer.zip(pc).and_then(|(s, pc)| s.locate(pc));
+    if let (Some(pc), Some(location)) = (pc, &location) {
+        crate::output::status(msg!(VmFrozenAt, format!("{:#x}", pc), location));
+    }
+
+    (pc, location)
+}
+
+/// GDBを起動し、`commands` を実行してから接続する。終了するまで待ち、GDBがCtrl-Cを扱えるよう、その間はSIGINTで終了しない
+pub fn attach_gdb(target: &str, commands: &[String]) {
+    crate::janitor::set_interrupt_ignored(true);
+    let status = std::process::Command::new("gdb")
+        .arg("-q")
+        .args(commands.iter().flat_map(|c| ["-ex", c.as_str()]))
+        .args(["-ex", target])
+        .status();
+    crate::janitor::set_interrupt_ignored(false);
+
+    if let Err(e) = status {
+        crate::output::warning(msg!(RunFailed, "gdb", e));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use crate::freeze::{classify, frozen_fields, FreezeOptions, Stop};
+
+    #[test]
+    fn classify_stop_events() {
+        assert_eq!(classify(&json!({ "event": "SHUTDOWN", "data": { "guest": true, "reason": "guest-reset" } })), Some(Stop::Reset));
+        assert_eq!(classify(&json!({ "event": "SHUTDOWN", "data": { "guest": true, "reason": "guest-shutdown" } })), Some(Stop::Shutdown));
+        assert_eq!(classify(&json!({ "event": "GUEST_PANICKED", "data": { "action": "pause" } })), Some(Stop::Panic));
+        assert_eq!(classify(&json!({ "event": "SHUTDOWN", "data": { "guest": false, "reason": "host-qmp-quit" } })), None);
+        assert_eq!(classify(&json!({ "event": "STOP" })), None);
+    }
+
+    #[test]
+    fn frozen_event_keeps_reason_and_stop() {
+        let event = crate::output::event_object("vm-frozen", frozen_fields(Stop::Reset, "127.0.0.1:1234".parse().unwrap()));
+        assert_eq!((event["reason"].as_str(), event["stop"].as_str()), (Some("vm-frozen"), Some("reset")));
+        assert_eq!(event["gdb"], json!("127.0.0.1:1234"));
+    }
+
+    #[test]
+    fn freeze_implies_no_reboot_and_no_shutdown() {
+        let addr = "127.0.0.1:4444".parse().unwrap();
+        let gdb = "127.0.0.1:1234".parse().unwrap();
+        let options = FreezeOptions { freeze_on_crash: true, ..FreezeOptions::default() };
+
+        let args = options.qemu_args(Some((addr, gdb)));
+        assert_eq!(args[..2], ["-no-reboot", "-no-shutdown"]);
+        assert!(args.windows(2).any(|w| w == ["-gdb", "tcp:127.0.0.1:1234"]));
+        assert_eq!(FreezeOptions { no_reboot: true, ..FreezeOptions::default() }.qemu_args(None), ["-no-reboot"]);
+    }
+}
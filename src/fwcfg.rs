@@ -0,0 +1,146 @@
+use std::path;
+use serde::Deserialize;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+
+/// QEMUの fw_cfg のファイル名の最大長（終端のNULを除く）
+const MAX_NAME_LEN: usize = 55;
+
+/// ファームウェアやQEMU自身が使うため、アプリケーションの設定には使わせない名前空間
+const RESERVED_PREFIXES: &[&str] = &["opt/ovmf/", "opt/org.tianocore/", "opt/cargo-uefi/"];
+
+/// fw_cfg を通してゲストに渡すデータ
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+pub struct FwCfgEntry {
+    /// `opt/` で始まる fw_cfg のファイル名（例: `opt/com.example.myapp/config`）
+    pub name: String,
+    /// 内容とするファイル。設定ファイル中の相対パスはプロジェクトルートを基準にする
+    pub file: Option<path::PathBuf>,
+    /// 内容とする文字列
+    pub string: Option<String>,
+}
+
+impl FwCfgEntry {
+    /// 名前が `opt/` の名前空間にあり、内容がファイルか文字列のどちらか一方であることを確認する
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidArgument, msg));
+        let name = self.name.as_str();
+
+        if !name.starts_with("opt/") || name.len() <= "opt/".len() {
+            return invalid(msg!(FwCfgNamespace, name));
+        }
+        if name.len() > MAX_NAME_LEN {
+            return invalid(msg!(FwCfgNameTooLong, name, MAX_NAME_LEN));
+        }
+        if let Some(prefix) = RESERVED_PREFIXES.iter().find(|p| name.starts_with(*p)) {
+            return invalid(msg!(FwCfgReserved, name, prefix));
+        }
+        if !name.bytes().all(|b| b.is_ascii_graphic() && b != b',') {
+            return invalid(msg!(FwCfgNameChars, name));
+        }
+
+        match (&self.file, &self.string) {
+            (Some(file), None) if !file.is_file() => invalid(msg!(NotFound, file.display())),
+            (Some(_), None) | (None, Some(_)) => Ok(()),
+            _ => invalid(msg!(FwCfgContent, name)),
+        }
+    }
+
+    /// 相対パスを `root` からのパスにする
+    pub fn resolve(mut self, root: &path::Path) -> FwCfgEntry {
+        self.file = self.file.map(|f| root.join(f));
+        self
+    }
+
+    fn qemu_arg(&self) -> String {
+        match (&self.file, &self.string) {
+            (Some(file), _) => format!("name={},file={}", self.name, escape(file.display().to_string().as_str())),
+            (None, Some(string)) => format!("name={},string={}", self.name, escape(string)),
+            (None, None) => unreachable!("validated fw_cfg entry has no content"),
+        }
+    }
+}
+
+/// QEMUのオプションの値に含まれる `,` をエスケープする
+pub fn escape(value: &str) -> String {
+    value.replace(',', ",,")
+}
+
+/// `name=opt/myapp/config,file=config.json` または `name=...,string=...` の形式の引数を読み取る
+pub fn parse_entry(s: &str) -> Result<FwCfgEntry, Error> {
+    let mut entry = FwCfgEntry { name: String::new(), file: None, string: None };
+    for part in s.split(',') {
+        match part.split_once('=') {
+            Some(("name", name)) => entry.name = name.to_string(),
+            Some(("file", file)) => entry.file = Some(path::PathBuf::from(file)),
+            Some(("string", string)) => entry.string = Some(string.to_string()),
+            _ => return Err(Error::new(ErrorKind::InvalidArgument, msg!(FwCfgInvalidOption, part))),
+        }
+    }
+
+    if entry.name.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidArgument, msg!(FwCfgNoName, s)));
+    }
+
+    Ok(entry)
+}
+
+/// 全ての項目を検証し、QEMUに渡す引数にする。同じ名前を複数回指定することはできない
+pub fn fw_cfg_args(entries: &[FwCfgEntry]) -> Result<Vec<String>, Error> {
+    let mut args = Vec::new();
+    for (idx, entry) in entries.iter().enumerate() {
+        entry.validate()?;
+        if entries[..idx].iter().any(|e| e.name == entry.name) {
+            return Err(Error::new(ErrorKind::InvalidArgument, msg!(FwCfgDuplicate, entry.name)));
+        }
+
+        args.push("-fw_cfg".to_string());
+        args.push(entry.qemu_arg());
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::fwcfg::{fw_cfg_args, parse_entry, FwCfgEntry};
+
+    #[test]
+    fn parse_and_build_args() {
+        let entry = parse_entry("name=opt/com.example.app/mode,string=fast,verbose").unwrap_err();
+        assert!(entry.to_string().contains("verbose"));
+
+        let string = parse_entry("name=opt/com.example.app/mode,string=fast").unwrap();
+        assert_eq!(string.string.as_deref(), Some("fast"));
+
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-test-fwcfg-{}", std::process::id()));
+        std::fs::create_dir_all(dir.as_path()).unwrap();
+        std::fs::write(dir.join("config,1.json"), b"{}").unwrap();
+        let file = parse_entry("name=opt/com.example.app/config,file=config,1.json");
+        assert!(file.is_err());
+        let file = FwCfgEntry { name: "opt/com.example.app/config".to_string(), file: Some(path::PathBuf::from("config,1.json")), string: None }
+            .resolve(dir.as_path());
+
+        let args = fw_cfg_args(&[file.clone(), string]).unwrap();
+        assert_eq!(args[0], "-fw_cfg");
+        assert_eq!(args[1], format!("name=opt/com.example.app/config,file={}", dir.join("config,,1.json").display()));
+        assert_eq!(args[3], "name=opt/com.example.app/mode,string=fast");
+
+        assert!(fw_cfg_args(&[file.clone(), file]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_names() {
+        let entry = |name: &str| FwCfgEntry { name: name.to_string(), file: None, string: Some("x".to_string()) };
+        assert!(entry("opt/com.example.app/config").validate().is_ok());
+        assert!(entry("etc/boot-fail-wait").validate().is_err());
+        assert!(entry("opt/").validate().is_err());
+        assert!(entry("opt/ovmf/X-PciMmio64Mb").validate().is_err());
+        assert!(entry(&format!("opt/{}", "a".repeat(52))).validate().is_err());
+
+        let both = FwCfgEntry { file: Some(path::PathBuf::from("a")), ..entry("opt/a/b") };
+        assert!(both.validate().is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// EFIシステムパーティションのパーティションタイプGUID（C12A7328-F81F-11D2-BA4B-00A0C93EC93B）
+pub const ESP_TYPE_GUID: [u8; 16] = [0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b];
+
+const SECTOR: u64 = 512;
+const ENTRY_COUNT: u64 = 128;
+const ENTRY_SIZE: u64 = 128;
+/// パーティションエントリが占めるセクタ数
+const ENTRY_SECTORS: u64 = ENTRY_COUNT * ENTRY_SIZE / SECTOR;
+
+/// ESPの開始位置。1MiBに揃える
+pub const ESP_OFFSET: u64 = 1024 * 1024;
+/// ESPの後ろに確保する、バックアップのGPTのための領域
+pub const TRAILER_SIZE: u64 = 1024 * 1024;
+
+/// GPTのヘッダとパーティションエントリに使われるCRC32（IEEE 802.3）
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// `disk_size` の大きさのディスクに、ESPを1つだけ持つ保護MBRとGPTを書き込む。
+/// ESPは `ESP_OFFSET` から `disk_size - TRAILER_SIZE` までを占める。
+/// `id` からディスクとパーティションのGUIDを作るため、同じ `id` なら同じイメージになる
+pub fn write_gpt<T: Write + Seek>(disk: &mut T, disk_size: u64, id: &[u8; 32]) -> Result<(), io::Error> {
+    let last_lba = disk_size / SECTOR - 1;
+    let disk_guid = guid(&id[..16]);
+    let esp_guid = guid(&id[16..]);
+
+    let mut mbr = [0u8; 512];
+    let protective = &mut mbr[446..462];
+    protective[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    protective[4] = 0xee;
+    protective[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    protective[8..12].copy_from_slice(&1u32.to_le_bytes());
+    protective[12..16].copy_from_slice(&(last_lba.min(u32::MAX as u64) as u32).to_le_bytes());
+    mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+    disk.seek(SeekFrom::Start(0))?;
+    disk.write_all(&mbr)?;
+
+    let mut entries = vec![0u8; (ENTRY_COUNT * ENTRY_SIZE) as usize];
+    entries[..16].copy_from_slice(&ESP_TYPE_GUID);
+    entries[16..32].copy_from_slice(&esp_guid);
+    entries[32..40].copy_from_slice(&(ESP_OFFSET / SECTOR).to_le_bytes());
+    entries[40..48].copy_from_slice(&((disk_size - TRAILER_SIZE) / SECTOR - 1).to_le_bytes());
+    for (i, c) in "EFI System Partition".encode_utf16().enumerate() {
+        entries[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+    }
+    let entries_crc = crc32(&entries);
+
+    let header = |my_lba: u64, alternate_lba: u64, entries_lba: u64| {
+        let mut header = [0u8; 512];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&my_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&(2 + ENTRY_SECTORS).to_le_bytes());
+        header[48..56].copy_from_slice(&(last_lba - 1 - ENTRY_SECTORS).to_le_bytes());
+        header[56..72].copy_from_slice(&disk_guid);
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(ENTRY_COUNT as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    };
+
+    let backup_entries_lba = last_lba - ENTRY_SECTORS;
+    for (header_lba, header, entries_lba) in [
+        (1, header(1, last_lba, 2), 2),
+        (last_lba, header(last_lba, 1, backup_entries_lba), backup_entries_lba),
+    ] {
+        disk.seek(SeekFrom::Start(entries_lba * SECTOR))?;
+        disk.write_all(&entries)?;
+        disk.seek(SeekFrom::Start(header_lba * SECTOR))?;
+        disk.write_all(&header)?;
+    }
+
+    Ok(())
+}
+
+/// 乱数の代わりに `bytes` を使った、バージョン4の形式のGUID
+fn guid(bytes: &[u8]) -> [u8; 16] {
+    let mut guid: [u8; 16] = bytes.try_into().expect("GUID is 16 bytes");
+    // GUIDの3番目のフィールドはリトルエンディアンで格納される
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    guid
+}
+
+/// ディスクのうちパーティションの範囲だけを見せる。fatfsにパーティションをそのまま渡すために使う
+pub struct Partition<'a, T> {
+    disk: &'a mut T,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a, T: Seek> Partition<'a, T> {
+    pub fn new(disk: &'a mut T, start: u64, len: u64) -> Partition<'a, T> {
+        Partition { disk, start, len, pos: 0 }
+    }
+
+    /// 残りの長さに収まるよう、読み書きする長さを切り詰める
+    fn limit(&mut self, len: usize) -> Result<usize, io::Error> {
+        self.disk.seek(SeekFrom::Start(self.start + self.pos))?;
+        Ok((len as u64).min(self.len.saturating_sub(self.pos)) as usize)
+    }
+}
+
+impl<T: Read + Seek> Read for Partition<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.limit(buf.len())?;
+        let n = self.disk.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Write + Seek> Write for Partition<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.limit(buf.len())?;
+        if n == 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "write beyond the end of the partition"));
+        }
+        let n = self.disk.write(&buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.flush()
+    }
+}
+
+impl<T> Seek for Partition<'_, T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::Current(d) => self.pos as i64 + d,
+            SeekFrom::End(d) => self.len as i64 + d,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the partition"));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
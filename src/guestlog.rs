@@ -0,0 +1,148 @@
+use clap::ValueEnum;
+
+/// ゲストが行の先頭に付けるタグの始まり。タグは `ESC _ uefi:<タグ> ESC \` で、端末はAPCとして表示せずに読み飛ばす。
+/// タグは `stdout`、`stderr` と、ログのレベル（`error`、`warn`、`info`、`debug`、`trace`）のいずれか
+pub const TAG_START: &[u8] = b"\x1b_uefi:";
+const TAG_END: &[u8] = b"\x1b\\";
+
+/// ゲストのログのレベル。後のものほど詳しい
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, ValueEnum)]
+pub enum Level {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+/// ゲストの出力の行の行き先
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Route {
+    Stdout,
+    Stderr,
+    /// ログはホストの標準エラー出力に流す
+    Log(Level),
+}
+
+impl Route {
+    /// JSON形式のイベントの `stream`
+    pub fn stream(self) -> &'static str {
+        match self {
+            Route::Stdout => "stdout",
+            Route::Stderr | Route::Log(_) => "stderr",
+        }
+    }
+}
+
+/// 行の先頭のタグを読み、行き先とタグを除いた行を返す。タグのない行や知らないタグの行は、そのまま標準出力に流す
+pub fn parse(line: &[u8]) -> (Route, &[u8]) {
+    let tagged = line.strip_prefix(TAG_START).and_then(|rest| {
+        let end = rest.windows(TAG_END.len()).position(|w| w == TAG_END)?;
+        let route = match &rest[..end] {
+            b"stdout" => Route::Stdout,
+            b"stderr" => Route::Stderr,
+            tag => Route::Log(Level::value_variants().iter().copied().find(|l| l.name().as_bytes() == tag)?),
+        };
+        Some((route, &rest[end + TAG_END.len()..]))
+    });
+
+    tagged.unwrap_or((Route::Stdout, line))
+}
+
+/// ゲストの出力を行に分け、行き先ごとに振り分ける
+pub struct Demux {
+    level: Level,
+    /// 端末で対話できるよう、タグの付きようのない書きかけの行をすぐに流すか
+    interactive: bool,
+    pending: Vec<u8>,
+    /// 書きかけのまま流した行の続きを読んでいる
+    passing: bool,
+}
+
+impl Demux {
+    pub fn new(level: Level, interactive: bool) -> Demux {
+        Demux { level, interactive, pending: Vec::new(), passing: false }
+    }
+
+    /// 読み取った出力を渡し、流すものを行き先と共に返す。`level` より詳しいログは捨てる
+    pub fn feed(&mut self, data: &[u8]) -> Vec<(Route, Vec<u8>)> {
+        self.pending.extend_from_slice(data);
+
+        let mut routed = Vec::new();
+        while let Some(pos) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<_> = self.pending.drain(..=pos).collect();
+            if std::mem::take(&mut self.passing) {
+                routed.push((Route::Stdout, line));
+            } else {
+                routed.extend(self.route(line.as_slice()));
+            }
+        }
+
+        let may_be_tagged = !self.passing && TAG_START.starts_with(&self.pending[..self.pending.len().min(TAG_START.len())]);
+        if self.interactive && !self.pending.is_empty() && !may_be_tagged {
+            routed.push((Route::Stdout, std::mem::take(&mut self.pending)));
+            self.passing = true;
+        }
+
+        routed
+    }
+
+    /// 出力の終わりに、改行のないまま残った行を流す
+    pub fn finish(&mut self) -> Vec<(Route, Vec<u8>)> {
+        let rest = std::mem::take(&mut self.pending);
+        match rest.is_empty() {
+            true => Vec::new(),
+            false if self.passing => vec![(Route::Stdout, rest)],
+            false => self.route(rest.as_slice()).into_iter().collect(),
+        }
+    }
+
+    fn route(&self, line: &[u8]) -> Option<(Route, Vec<u8>)> {
+        match parse(line) {
+            (Route::Log(level), _) if level > self.level => None,
+            (route, text) => Some((route, text.to_vec())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::guestlog::{parse, Demux, Level, Route};
+
+    #[test]
+    fn tagged_lines_are_routed_and_filtered() {
+        assert_eq!(parse(b"\x1b_uefi:stderr\x1b\\oops\n"), (Route::Stderr, &b"oops\n"[..]));
+        assert_eq!(parse(b"\x1b_uefi:warn\x1b\\low memory\n"), (Route::Log(Level::Warn), &b"low memory\n"[..]));
+        // 知らないタグは付いたまま標準出力に流す
+        assert_eq!(parse(b"\x1b_uefi:fatal\x1b\\x\n").0, Route::Stdout);
+
+        let mut demux = Demux::new(Level::Info, false);
+        let routed = demux.feed(b"plain\n\x1b_uefi:debug\x1b\\hidden\n\x1b_uefi:error\x1b\\shown\n\x1b_uefi:std");
+        assert_eq!(routed, [(Route::Stdout, b"plain\n".to_vec()), (Route::Log(Level::Error), b"shown\n".to_vec())]);
+        assert_eq!(demux.feed(b"err\x1b\\partial"), []);
+        assert_eq!(demux.finish(), [(Route::Stderr, b"partial".to_vec())]);
+    }
+
+    #[test]
+    fn interactive_prompt_is_passed_through() {
+        let mut demux = Demux::new(Level::Info, true);
+        assert_eq!(demux.feed(b"Shell> "), [(Route::Stdout, b"Shell> ".to_vec())]);
+        // 流した行の続きは、タグのように見えてもそのまま流す
+        assert_eq!(demux.feed(b"\x1b_uefi:stderr\x1b\\ls\n"), [(Route::Stdout, b"\x1b_uefi:stderr\x1b\\ls\n".to_vec())]);
+        assert_eq!(demux.feed(b"\x1b_uefi"), []);
+        assert_eq!(demux.feed(b":stderr\x1b\\x\n"), [(Route::Stderr, b"x\n".to_vec())]);
+    }
+}
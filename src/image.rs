@@ -0,0 +1,250 @@
+use std::io;
+use std::io::Write;
+use std::path;
+use sha2::{Digest, Sha256};
+
+/// QEMUに渡す起動ドライブ
+pub enum BootDrive {
+    /// ディレクトリをQEMUの仮想FAT(vvfat)として渡す
+    Directory(path::PathBuf),
+    /// 生成したディスクイメージを、指定したI/O設定で渡す
+    Image(path::PathBuf, crate::disk::DriveOptions),
+}
+
+impl BootDrive {
+    pub fn drive_arg(&self) -> String {
+        match self {
+            BootDrive::Directory(dir) => format!("format=raw,file=fat:rw:{}", dir.display()),
+            // キャッシュされたイメージをゲストに書き換えられないよう、snapshotモードで接続する
+            BootDrive::Image(image, options) => format!("format=raw,snapshot=on,file={}{}", image.display(), options.suffix()),
+        }
+    }
+}
+
+/// 配置済みのESPディレクトリ内容をFAT32のESPとして持つ、GPTのディスクイメージを返す。
+/// 同じ内容のイメージがキャッシュに既にあればそれを再利用する。
+/// `min_size` を指定した場合、イメージはそのサイズ以上になる（未使用領域はスパースに確保される）。
+pub fn cached_image(esp_root: &path::Path, cache_dir: &path::Path, min_size: Option<u64>, wait_lock: bool) -> Result<path::PathBuf, io::Error> {
+    let hash = content_hash(esp_root)?;
+    // パーティションのないFATイメージを作っていた頃のキャッシュと区別する
+    let key = match min_size {
+        Some(size) => format!("gpt-{}-{}", hash, size),
+        None => format!("gpt-{}", hash),
+    };
+    let image_path = cache_dir.join(format!("{}.img", key));
+    if image_path.is_file() {
+        return Ok(image_path);
+    }
+
+    // 他のプロセスが同じイメージを生成中の場合は、その完了を待ってから再確認する
+    let _lock = crate::lock::FileLock::acquire(crate::lock::lock_path_for(cache_dir).as_path(), wait_lock)?;
+    if image_path.is_file() {
+        return Ok(image_path);
+    }
+
+    // 生成途中のイメージを再利用しないよう、一時ファイルに書き出してから名前を変える
+    std::fs::create_dir_all(cache_dir)?;
+    let tmp_path = cache_dir.join(format!("{}.img.tmp", key));
+    let tmp = crate::janitor::register_path(tmp_path.as_path());
+    build_disk_image(esp_root, tmp_path.as_path(), min_size.unwrap_or(0), &Sha256::digest(key.as_bytes()).into())?;
+    std::fs::rename(tmp_path, image_path.as_path())?;
+    tmp.release();
+
+    Ok(image_path)
+}
+
+/// ディレクトリ以下のパスとファイル内容から SHA-256 のハッシュ値を計算する
+pub fn content_hash(root: &path::Path) -> Result<String, io::Error> {
+    let mut hasher = Sha256::new();
+    for entry in walk(root)? {
+        let relative = entry.strip_prefix(root).expect("walked entry is not under root");
+        let name = relative.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        if entry.is_dir() {
+            hasher.update(b"d\0");
+            hasher.update(name.as_bytes());
+            hasher.update(b"\0");
+        } else {
+            let mut file = std::fs::File::open(entry.as_path())?;
+            hasher.update(b"f\0");
+            hasher.update(name.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(file.metadata()?.len().to_le_bytes());
+            io::copy(&mut file, &mut hasher)?;
+        }
+    }
+
+    let digest = hasher.finalize();
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// ディレクトリ以下の全エントリを、パス順に並べて返す
+fn walk(root: &path::Path) -> Result<Vec<path::PathBuf>, io::Error> {
+    let mut entries = Vec::new();
+    let mut children = std::fs::read_dir(root)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    children.sort();
+
+    for child in children {
+        let is_dir = child.is_dir();
+        entries.push(child.clone());
+        if is_dir {
+            entries.extend(walk(child.as_path())?);
+        }
+    }
+
+    Ok(entries)
+}
+
+const MIB: u64 = 1024 * 1024;
+/// FAT32には65525個以上のクラスタが必要なため、512バイトのクラスタでも収まる大きさにする
+const MIN_ESP_SIZE: u64 = 64 * MIB;
+
+fn image_size(root: &path::Path) -> Result<u64, io::Error> {
+    let mut total = 0;
+    for entry in walk(root)? {
+        // ファイルごとにクラスタの端数分の余裕を持たせる
+        total += 4096;
+        if entry.is_file() {
+            total += entry.metadata()?.len();
+        }
+    }
+
+    let size = (total + total / 10 + MIB).max(8 * MIB);
+    Ok(size.div_ceil(MIB) * MIB)
+}
+
+/// `id` はディスクとパーティションのGUIDの元にする値
+fn build_disk_image(root: &path::Path, image_path: &path::Path, min_size: u64, id: &[u8; 32]) -> Result<(), io::Error> {
+    let overhead = crate::gpt::ESP_OFFSET + crate::gpt::TRAILER_SIZE;
+    let esp_size = image_size(root)?
+        .max(MIN_ESP_SIZE)
+        .max((min_size.div_ceil(MIB) * MIB).saturating_sub(overhead));
+    let size = esp_size + overhead;
+
+    // set_lenで確保した領域はファイルシステム上の穴となり、書き込んだ部分だけがディスクを消費する
+    let mut image = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(image_path)?;
+    image.set_len(size)?;
+    crate::gpt::write_gpt(&mut image, size, id)?;
+
+    let mut esp = crate::gpt::Partition::new(&mut image, crate::gpt::ESP_OFFSET, esp_size);
+    fatfs::format_volume(&mut esp, fatfs::FormatVolumeOptions::new().fat_type(fatfs::FatType::Fat32))?;
+    let fs = fatfs::FileSystem::new(&mut esp, fatfs::FsOptions::new())?;
+    copy_dir(root, &fs.root_dir())?;
+    fs.unmount()?;
+
+    image.flush()
+}
+
+fn copy_dir<T: fatfs::ReadWriteSeek>(src: &path::Path, dst: &fatfs::Dir<T>) -> Result<(), io::Error> {
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        if entry.file_type()?.is_dir() {
+            let dir = dst.create_dir(&name)?;
+            copy_dir(entry.path().as_path(), &dir)?;
+        } else {
+            let mut src_file = std::fs::File::open(entry.path())?;
+            let len = src_file.metadata()?.len();
+            let mut file = dst.create_file(&name)?;
+            file.truncate()?;
+
+            let (written, _) = crate::copy::copy_stream(&mut src_file, &mut file, len, &name)?;
+            if written != len {
+                return Err(io::Error::other(
+                    format!("size mismatch while writing {} into image: expected {} bytes, got {}", name, len, written)
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::gpt::{Partition, ESP_OFFSET};
+    use crate::image::{build_disk_image, content_hash};
+
+    fn scratch_dir(name: &str) -> path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(dir.as_path());
+        std::fs::create_dir_all(dir.join("EFI").join("BOOT")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn hash_follows_contents() {
+        let dir = scratch_dir("hash");
+        let app = dir.join("EFI").join("BOOT").join("BOOTX64.EFI");
+
+        std::fs::write(app.as_path(), b"hoge").unwrap();
+        let first = content_hash(dir.as_path()).unwrap();
+        assert_eq!(first, content_hash(dir.as_path()).unwrap());
+
+        std::fs::write(app.as_path(), b"fuga").unwrap();
+        assert_ne!(first, content_hash(dir.as_path()).unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn disk_image_contains_staged_files() {
+        let dir = scratch_dir("fat");
+        std::fs::write(dir.join("EFI").join("BOOT").join("BOOTX64.EFI"), b"hoge").unwrap();
+
+        let image_path = dir.with_extension("img");
+        build_disk_image(dir.as_path(), image_path.as_path(), 0, &[7; 32]).unwrap();
+
+        // 中身はPEイメージではないが、パーティションテーブルとESPはverify-imageの検査を満たす
+        let checks = crate::verify::verify(image_path.as_path(), &[], std::env::temp_dir().as_path()).unwrap();
+        assert!(checks.iter().filter(|c| !c.name.starts_with("PE image")).all(|c| c.problem.is_none()), "{:?}", checks);
+
+        let mut image = std::fs::File::open(image_path.as_path()).unwrap();
+        let len = image.metadata().unwrap().len();
+        let mut esp = Partition::new(&mut image, ESP_OFFSET, len - 2 * ESP_OFFSET);
+        let fs = fatfs::FileSystem::new(&mut esp, fatfs::FsOptions::new()).unwrap();
+        assert_eq!(fs.fat_type(), fatfs::FatType::Fat32);
+        let mut file = fs.root_dir().open_file("EFI/BOOT/BOOTX64.EFI").unwrap();
+        let mut content = Vec::new();
+        std::io::Read::read_to_end(&mut file, &mut content).unwrap();
+        assert_eq!(content, b"hoge");
+
+        drop(file);
+        drop(fs);
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_file(image_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn large_image_is_sparse() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = scratch_dir("sparse");
+        std::fs::write(dir.join("EFI").join("BOOT").join("BOOTX64.EFI"), b"hoge").unwrap();
+
+        let image_path = dir.with_extension("img");
+        let size = 4 * 1024 * 1024 * 1024;
+        build_disk_image(dir.as_path(), image_path.as_path(), size, &[7; 32]).unwrap();
+
+        let metadata = std::fs::metadata(image_path.as_path()).unwrap();
+        assert_eq!(metadata.len(), size);
+        assert!(metadata.blocks() * 512 < size / 16);
+
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_file(image_path).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+use std::path;
+use std::sync::Mutex;
+use crate::pe::{PeFile, Symbol};
+
+/// ゲストがguest-controlの `IMAGE` で報告した、アプリケーションが読み込まれたアドレスと大きさ。
+/// ファームウェアのログを読まずに済むため、デバッグ出力のないリリース版のOVMFでも使える
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LoadedImage {
+    pub base: u64,
+    pub size: u64,
+}
+
+impl LoadedImage {
+    /// `IMAGE` の引数（16進数のアドレスと大きさ。`0x` は省略できる）を読む
+    pub fn parse(args: &str) -> Option<LoadedImage> {
+        let hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x").trim_start_matches("0X"), 16).ok();
+        let (base, size) = args.split_once(' ')?;
+        Some(LoadedImage { base: hex(base.trim())?, size: hex(size.trim())? })
+    }
+}
+
+/// 実行中のアプリケーションと、ゲストが報告した読み込み先。guest-controlとクラッシュの監視の両方から使う
+static CURRENT: Mutex<(Option<path::PathBuf>, Option<LoadedImage>)> = Mutex::new((None, None));
+
+/// これから実行するアプリケーションを設定し、前の実行で報告された読み込み先を消す
+pub fn expect(app: &path::Path) {
+    *CURRENT.lock().unwrap_or_else(|e| e.into_inner()) = (Some(app.to_path_buf()), None);
+}
+
+pub fn record(image: LoadedImage) {
+    CURRENT.lock().unwrap_or_else(|e| e.into_inner()).1 = Some(image);
+}
+
+/// 報告された読み込み先があれば、アプリケーションのシンボルと合わせて返す
+pub fn symbolizer() -> Option<Symbolizer> {
+    let (app, image) = CURRENT.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let app = app?;
+    let pe = PeFile::parse(std::fs::read(app.as_path()).ok()?).ok()?;
+    let symbols = crate::bloat::find_symbols(app.as_path(), &pe, None).ok().flatten().map(|s| s.symbols).unwrap_or_default();
+    Some(Symbolizer { app, pe, symbols, image: image? })
+}
+
+/// 読み込み先をもとに、ゲストのアドレスをアプリケーションのシンボルに対応させる
+pub struct Symbolizer {
+    app: path::PathBuf,
+    pe: PeFile,
+    symbols: Vec<Symbol>,
+    image: LoadedImage,
+}
+
+impl Symbolizer {
+    /// `addr` をシンボルからのオフセット（例: `hoge::main+0x1a`）にする。アプリケーションの外のアドレスは `None`
+    pub fn locate(&self, addr: u64) -> Option<String> {
+        let end = self.image.base.saturating_add(self.image.size.max(self.pe.size_of_image as u64));
+        if addr < self.image.base || addr >= end {
+            return None;
+        }
+        let rva = addr - self.image.base;
+        let app = self.app.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+
+        let section = self.pe.sections.iter().enumerate().find(|(_, s)| {
+            (s.virtual_address as u64..s.virtual_address as u64 + s.virtual_size.max(s.raw_size) as u64).contains(&rva)
+        });
+        let symbol = section.and_then(|(idx, section)| {
+            let offset = rva - section.virtual_address as u64;
+            self.symbols.iter()
+                .filter(|s| s.section as usize == idx + 1 && s.offset as u64 <= offset)
+                .max_by_key(|s| s.offset)
+                .map(|s| (crate::bloat::demangle(s.name.as_str()).0, offset - s.offset as u64))
+        });
+
+        Some(match symbol {
+            Some((name, 0)) => name,
+            Some((name, offset)) => format!("{}+{:#x}", name, offset),
+            None => format!("{}+{:#x}", app, rva),
+        })
+    }
+
+    /// 読み込み先に合わせてアプリケーションのシンボルを読むGDBのコマンド
+    pub fn gdb_commands(&self) -> Vec<String> {
+        // リンク時のイメージベースとの差を、全てのセクションのアドレスに足す
+        let offset = match self.image.base.checked_sub(self.pe.image_base) {
+            Some(offset) => format!("{:#x}", offset),
+            None => format!("-{:#x}", self.pe.image_base - self.image.base),
+        };
+        vec![format!("add-symbol-file {} -o {}", self.app.display(), offset)]
+    }
+
+    /// GDBの `-x` で読み込むスクリプト
+    pub fn gdb_script(&self) -> String {
+        let mut script = format!("# {} loaded at {:#x} ({:#x} bytes)\n", self.app.display(), self.image.base, self.image.size);
+        for command in self.gdb_commands() {
+            script.push_str(command.as_str());
+            script.push('\n');
+        }
+        script
+    }
+
+    pub fn image(&self) -> LoadedImage {
+        self.image
+    }
+}
+
+/// QEMUのモニタの `info registers` の出力から、最初のCPUのプログラムカウンタを読む
+pub fn program_counter(registers: &str) -> Option<u64> {
+    registers.split_whitespace()
+        .find_map(|token| token.strip_prefix("RIP=").or_else(|| token.strip_prefix("EIP=")).or_else(|| token.strip_prefix("PC=")))
+        .and_then(|value| u64::from_str_radix(value, 16).ok())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::imagebase::{program_counter, LoadedImage, Symbolizer};
+    use crate::pe::{PeFile, Symbol};
+
+    #[test]
+    fn locate_addresses_from_reported_image() {
+        assert_eq!(LoadedImage::parse("0x3e5a0000 2000"), Some(LoadedImage { base: 0x3e5a0000, size: 0x2000 }));
+        assert_eq!(LoadedImage::parse("0x3e5a0000"), None);
+        assert_eq!(program_counter("RAX=0000000000000000 RBX=0000000000000001\nRIP=000000003e5a1010 RFL=00000046"), Some(0x3e5a1010));
+        assert_eq!(program_counter(" PC=0000000040001234 X00=0000000000000000"), Some(0x40001234));
+
+        let pe = PeFile::parse(crate::pe::test::sample_image()).unwrap();
+        let text = pe.sections.iter().position(|s| s.name == ".text").unwrap();
+        let rva = pe.sections[text].virtual_address as u64;
+        let symbols = vec![
+            Symbol { name: "efi_main".to_string(), section: text as u16 + 1, offset: 0, is_function: true },
+            Symbol { name: "helper".to_string(), section: text as u16 + 1, offset: 0x10, is_function: true },
+        ];
+        let image = LoadedImage { base: 0x3e5a0000, size: pe.size_of_image as u64 };
+        let symbolizer = Symbolizer { app: "/target/hoge.efi".into(), pe, symbols, image };
+
+        assert_eq!(symbolizer.locate(image.base + rva).as_deref(), Some("efi_main"));
+        assert_eq!(symbolizer.locate(image.base + rva + 0x14).as_deref(), Some("helper+0x4"));
+        assert_eq!(symbolizer.locate(image.base).as_deref(), Some("hoge.efi+0x0"));
+        assert_eq!(symbolizer.locate(image.base - 1), None);
+        // リンク時のイメージベース（0x140000000）より低い位置に読み込まれている
+        assert_eq!(symbolizer.gdb_commands(), ["add-symbol-file /target/hoge.efi -o -0x101a60000"]);
+    }
+}
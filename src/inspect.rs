@@ -0,0 +1,179 @@
+use std::path;
+use clap::Args;
+use serde_json::{json, Value};
+use crate::pe::{debug_type_name, relocation_type_name, PeFile};
+use crate::size::format_size;
+
+#[derive(Args)]
+pub struct InspectArgs {
+    /// 調べるEFIファイル。省略した場合は `--bin` などで選んだビルド済みのバイナリ
+    #[arg(value_name = "EFI_FILE")]
+    pub file: Option<path::PathBuf>,
+
+    /// セクション・クレート・関数ごとのサイズの内訳を表示する
+    #[arg(long)]
+    pub size: bool,
+
+    /// `--size` で表示するクレートと関数の数
+    #[arg(long, value_name = "N", default_value_t = 20, requires = "size")]
+    pub top: usize,
+
+    /// `--size` でシンボルを読み取るPDBファイル。省略した場合はデバッグ情報に記録された場所やEFIファイルの隣を探す
+    #[arg(long, value_name = "PDB_FILE", requires = "size")]
+    pub pdb: Option<path::PathBuf>,
+}
+
+/// EFIファイルを読み込んで、ヘッダ・セクション・インポート・再配置・デバッグ情報を出力する
+pub fn inspect(file: &path::Path, args: &InspectArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let pe = PeFile::parse(std::fs::read(file)?)?;
+    if args.size {
+        let symbols = crate::bloat::find_symbols(file, &pe, args.pdb.as_deref())?;
+        let report = crate::bloat::attribute(&pe, symbols);
+        if crate::output::json() {
+            crate::output::event("size-report", crate::bloat::to_json(file, &report, args.top));
+        } else {
+            print!("{}", crate::bloat::render(&report, args.top));
+        }
+        return Ok(());
+    }
+
+    if crate::output::json() {
+        crate::output::event("inspect", to_json(file, &pe));
+    } else {
+        print!("{}", render(file, &pe));
+    }
+
+    Ok(())
+}
+
+fn render(file: &path::Path, pe: &PeFile) -> String {
+    let mut out = String::new();
+    let mut line = |label: &str, value: String| out.push_str(&format!("{:<14}{}\n", format!("{}:", label), value));
+
+    line("File", format!("{} ({})", file.display(), format_size(pe.file_size())));
+    line("Machine", format!("{} (0x{:04x})", pe.machine_name(), pe.machine));
+    line("Format", if pe.pe32_plus { "PE32+" } else { "PE32" }.to_string());
+    line("Timestamp", format!("0x{:08x}", pe.timestamp));
+    line("COFF flags", pe.coff_flags().join(", "));
+    line("Subsystem", format!("{} ({})", pe.subsystem_name(), pe.subsystem));
+    line("Entry point", format!("0x{:x}", pe.entry_point));
+    line("Image base", format!("0x{:x}", pe.image_base));
+    line("Alignment", format!("section 0x{:x}, file 0x{:x}", pe.section_alignment, pe.file_alignment));
+    let flags = pe.dll_flags();
+    line("DLL flags", if flags.is_empty() { "-".to_string() } else { flags.join(", ") });
+    if let Some(info) = crate::buildinfo::read(pe) {
+        line("Build", info.summary());
+    }
+    line("Load size", format!("{} (SizeOfImage 0x{:x}, {} pages)", format_size(pe.size_of_image as u64), pe.size_of_image, pe.size_of_image.div_ceil(4096)));
+
+    out.push_str("\nSections:\n");
+    out.push_str(&format!("  {:<10} {:>10} {:>10} {:>10} {:>10}  {}\n", "Name", "VirtAddr", "VirtSize", "RawSize", "RawOffset", "Flags"));
+    for section in pe.sections.iter() {
+        out.push_str(&format!(
+            "  {:<10} {:>10} {:>10} {:>10} {:>10}  {}\n",
+            section.name,
+            format!("0x{:x}", section.virtual_address),
+            format!("0x{:x}", section.virtual_size),
+            format!("0x{:x}", section.raw_size),
+            format!("0x{:x}", section.raw_offset),
+            section.flags().join(", "),
+        ));
+    }
+
+    let imports = pe.imports();
+    out.push_str("\nImports:");
+    if imports.is_empty() {
+        out.push_str(" none\n");
+    } else {
+        out.push('\n');
+        for import in imports {
+            out.push_str(&format!("  {} ({})\n", import.dll, import.functions.join(", ")));
+        }
+    }
+
+    let relocations = pe.relocations();
+    let by_type: Vec<String> = relocations.by_type.iter().map(|(k, n)| format!("{} x{}", relocation_type_name(*k), n)).collect();
+    out.push_str(&format!("\nRelocations:  {} blocks, {} entries", relocations.blocks, relocations.total()));
+    if by_type.is_empty() {
+        // 再配置がないと、ファームウェアは推奨のイメージベース以外にロードできない
+        out.push_str(" (the image cannot be relocated)\n");
+    } else {
+        out.push_str(&format!(" ({})\n", by_type.join(", ")));
+    }
+
+    for entry in pe.debug_entries() {
+        match entry.pdb {
+            Some(pdb) => out.push_str(&format!("Debug:        {} {}\n", debug_type_name(entry.kind), pdb)),
+            None => out.push_str(&format!("Debug:        {}\n", debug_type_name(entry.kind))),
+        }
+    }
+
+    let unaccounted = pe.unaccounted_bytes();
+    if unaccounted > 0 {
+        out.push_str(&format!("Other data:   {} outside of headers and sections (symbol table or trailing data)\n", format_size(unaccounted)));
+    }
+
+    out
+}
+
+fn to_json(file: &path::Path, pe: &PeFile) -> Value {
+    let sections: Vec<Value> = pe.sections.iter().map(|s| json!({
+        "name": s.name,
+        "virtual-address": s.virtual_address,
+        "virtual-size": s.virtual_size,
+        "raw-size": s.raw_size,
+        "raw-offset": s.raw_offset,
+        "flags": s.flags(),
+    })).collect();
+    let imports: Vec<Value> = pe.imports().into_iter().map(|i| json!({ "dll": i.dll, "functions": i.functions })).collect();
+    let relocations = pe.relocations();
+    let relocation_types: serde_json::Map<String, Value> = relocations.by_type.iter()
+        .map(|(k, n)| (relocation_type_name(*k).to_string(), json!(n)))
+        .collect();
+    let debug: Vec<Value> = pe.debug_entries().into_iter().map(|d| json!({ "type": debug_type_name(d.kind), "pdb": d.pdb })).collect();
+
+    json!({
+        "file": file,
+        "file-size": pe.file_size(),
+        "machine": pe.machine_name(),
+        "format": if pe.pe32_plus { "PE32+" } else { "PE32" },
+        "timestamp": pe.timestamp,
+        "coff-characteristics": pe.coff_flags(),
+        "subsystem": pe.subsystem,
+        "entry-point": pe.entry_point,
+        "image-base": pe.image_base,
+        "section-alignment": pe.section_alignment,
+        "file-alignment": pe.file_alignment,
+        "dll-characteristics": pe.dll_flags(),
+        "size-of-image": pe.size_of_image,
+        "sections": sections,
+        "imports": imports,
+        "relocations": { "blocks": relocations.blocks, "types": relocation_types },
+        "debug": debug,
+        "unaccounted-bytes": pe.unaccounted_bytes(),
+        "build": crate::buildinfo::read(pe).map(|i| i.to_json()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::inspect::{render, to_json};
+    use crate::pe::PeFile;
+
+    #[test]
+    fn render_sample_image() {
+        let pe = PeFile::parse(crate::pe::test::sample_image()).unwrap();
+        let text = render(path::Path::new("hoge.efi"), &pe);
+
+        assert!(text.contains("Machine:      x86_64 (0x8664)\n"));
+        assert!(text.contains("Subsystem:    EFI application (10)\n"));
+        assert!(text.contains("Load size:    12.0 KiB (SizeOfImage 0x3000, 3 pages)\n"));
+        assert!(text.contains("Imports: none\n"));
+        assert!(text.contains("1 blocks, 1 entries (DIR64 x1)"));
+        assert!(text.contains("Debug:        CodeView hoge.pdb\n"));
+
+        let json = to_json(path::Path::new("hoge.efi"), &pe);
+        assert_eq!(json["sections"][1]["name"], ".rdata");
+    }
+}
@@ -0,0 +1,315 @@
+use std::io;
+use std::path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 実行中に作成し、終了時に片付ける必要があるもの
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Resource {
+    /// 一時ファイルまたは一時ディレクトリ
+    Path(path::PathBuf),
+    /// QEMUなどの補助プロセス。プロセスIDと実行ファイル名
+    Process(u32, String),
+}
+
+impl Resource {
+    fn cleanup(&self) {
+        match self {
+            Resource::Path(path) if path.is_dir() => {
+                let _ = std::fs::remove_dir_all(path);
+            }
+            Resource::Path(path) => {
+                let _ = std::fs::remove_file(path);
+            }
+            Resource::Process(pid, _) => kill(*pid),
+        }
+    }
+
+    fn journal_line(&self) -> String {
+        match self {
+            Resource::Path(path) => format!("path {}", path.display()),
+            Resource::Process(pid, name) => format!("process {} {}", pid, name),
+        }
+    }
+
+    fn from_journal_line(line: &str) -> Option<Resource> {
+        let (kind, rest) = line.split_once(' ')?;
+        match kind {
+            "path" => Some(Resource::Path(path::PathBuf::from(rest))),
+            "process" => {
+                let (pid, name) = rest.split_once(' ')?;
+                Some(Resource::Process(pid.parse().ok()?, name.to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// 登録された資源
+struct Entry {
+    id: u64,
+    resource: Resource,
+}
+
+struct Registry {
+    entries: Vec<Entry>,
+    /// 登録内容を書き出すファイル。SIGKILLなどで片付けられなかったものを次の実行で片付けるために使う
+    journal: Option<path::PathBuf>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { entries: Vec::new(), journal: None });
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn with_registry<T>(f: impl FnOnce(&mut Registry) -> T) -> T {
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut registry)
+}
+
+impl Registry {
+    fn write_journal(&self) {
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+
+        let content: String = self.entries.iter()
+            .map(|e| format!("{}\n", e.resource.journal_line()))
+            .collect();
+        if content.is_empty() {
+            let _ = std::fs::remove_file(journal);
+            return;
+        }
+
+        let _ = std::fs::write(journal, content);
+    }
+}
+
+/// 登録した資源の後始末を担う。ドロップされると資源を片付ける
+#[must_use]
+pub struct Registration {
+    id: u64,
+}
+
+impl Registration {
+    /// 資源を片付けずに登録だけを解除する（一時ファイルをリネームして残す場合や、プロセスの終了を待った後など）
+    pub fn release(self) {
+        remove(self.id);
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(resource) = remove(self.id) {
+            resource.cleanup();
+        }
+    }
+}
+
+fn register(resource: Resource) -> Registration {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    with_registry(|registry| {
+        registry.entries.push(Entry { id, resource });
+        registry.write_journal();
+    });
+
+    Registration { id }
+}
+
+fn remove(id: u64) -> Option<Resource> {
+    with_registry(|registry| {
+        let pos = registry.entries.iter().position(|e| e.id == id)?;
+        let entry = registry.entries.remove(pos);
+        registry.write_journal();
+        Some(entry.resource)
+    })
+}
+
+/// 終了時に削除する一時ファイルまたは一時ディレクトリを登録する
+pub fn register_path(path: &path::Path) -> Registration {
+    register(Resource::Path(path.to_path_buf()))
+}
+
+/// 終了時に強制終了する子プロセスを登録する。終了を待った後は必ず `release` すること
+pub fn register_process(child: &std::process::Child, program: &path::Path) -> Registration {
+    let name = program.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    register(Resource::Process(child.id(), name))
+}
+
+/// 登録されている全ての資源を片付ける。シグナルやパニックで異常終了する直前に呼ぶ
+pub fn cleanup_all() {
+    // プロセスを先に止めてから、使っていたファイルを削除する
+    let mut entries = with_registry(|registry| {
+        let entries = std::mem::take(&mut registry.entries);
+        registry.write_journal();
+        entries
+    });
+    entries.sort_by_key(|e| !matches!(e.resource, Resource::Process(..)));
+
+    for entry in entries {
+        entry.resource.cleanup();
+    }
+}
+
+/// 登録された資源を片付けてからプロセスを終了する。`std::process::exit` はデストラクタを実行しないため、その代わりに使う
+pub fn exit(code: i32) -> ! {
+    cleanup_all();
+    std::process::exit(code)
+}
+
+/// `dir` に登録内容を記録するようにし、既に終了したプロセスが残した資源を片付ける
+pub fn set_journal_dir(dir: &path::Path) -> Result<(), io::Error> {
+    std::fs::create_dir_all(dir)?;
+    sweep(dir);
+
+    with_registry(|registry| {
+        registry.journal = Some(dir.join(format!("{}.list", std::process::id())));
+        registry.write_journal();
+    });
+
+    Ok(())
+}
+
+/// 異常終了したcargo-uefiの記録を読み、残された資源を片付ける
+fn sweep(dir: &path::Path) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let pid = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u32>().ok());
+        let pid = match pid {
+            Some(pid) if path.extension().is_some_and(|e| e == "list") => pid,
+            _ => continue,
+        };
+        if pid == std::process::id() || process_alive(pid) {
+            continue;
+        }
+
+        let content = std::fs::read_to_string(path.as_path()).unwrap_or_default();
+        for resource in content.lines().filter_map(Resource::from_journal_line) {
+            match &resource {
+                // プロセスIDが再利用されている可能性があるため、同じ実行ファイルの場合のみ止める
+                Resource::Process(pid, name) if process_name(*pid).as_deref() == Some(name.as_str()) => resource.cleanup(),
+                Resource::Process(..) => {}
+                Resource::Path(_) => resource.cleanup(),
+            }
+        }
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(unix)]
+fn kill(pid: u32) {
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(_pid: u32) {}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // シグナル0は送信せずに存在だけを確認する。権限がない場合も存在はしている
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+/// プロセスの実行ファイル名。取得できるのはLinuxのみ
+fn process_name(pid: u32) -> Option<String> {
+    let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim().to_string())
+}
+
+#[cfg(unix)]
+static SIGNALED: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+
+/// デバッガなど、Ctrl-Cを自身で扱う対話的な子プロセスを実行している間は、SIGINTで終了しない
+static INTERRUPT_IGNORED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+pub fn set_interrupt_ignored(ignored: bool) {
+    INTERRUPT_IGNORED.store(ignored, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    if signal == libc::SIGINT && INTERRUPT_IGNORED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    // シグナルハンドラでは記録だけを行い、片付けは監視スレッドに任せる
+    SIGNALED.store(signal, Ordering::SeqCst);
+}
+
+/// SIGINT・SIGTERM・SIGHUPを受け取った場合に、登録された資源を片付けてから終了するようにする
+#[cfg(unix)]
+pub fn install_signal_handlers() {
+    let signals = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+    for signal in signals {
+        unsafe {
+            libc::signal(signal, on_signal as *const () as libc::sighandler_t);
+        }
+    }
+
+    std::thread::spawn(|| loop {
+        let signal = SIGNALED.load(Ordering::SeqCst);
+        if signal != 0 {
+            cleanup_all();
+            // 既定の動作に戻して同じシグナルで終了し、親プロセスに終了の理由を伝える
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+            std::process::exit(128 + signal);
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    });
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handlers() {}
+
+#[cfg(test)]
+mod test {
+    use crate::janitor::{register_path, sweep, Resource};
+
+    #[test]
+    fn registration_removes_on_drop_unless_released() {
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-test-janitor-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("esp")).unwrap();
+        std::fs::write(dir.join("kept"), b"").unwrap();
+
+        drop(register_path(dir.join("esp").as_path()));
+        register_path(dir.join("kept").as_path()).release();
+
+        assert!(!dir.join("esp").exists());
+        assert!(dir.join("kept").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sweep_cleans_up_after_dead_process() {
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-test-sweep-{}", std::process::id()));
+        let stale = dir.join("stale");
+        std::fs::create_dir_all(stale.as_path()).unwrap();
+
+        // 存在しないプロセスIDの記録
+        let journal = format!("{}\n{}\n", Resource::Path(stale.clone()).journal_line(), Resource::Process(u32::MAX - 1, "qemu".to_string()).journal_line());
+        std::fs::write(dir.join(format!("{}.list", i32::MAX)), journal).unwrap();
+        assert_eq!(Resource::from_journal_line("process 12 qemu-system-x86_64"), Some(Resource::Process(12, "qemu-system-x86_64".to_string())));
+
+        sweep(dir.as_path());
+        assert!(!stale.exists());
+        assert!(!dir.join(format!("{}.list", i32::MAX)).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
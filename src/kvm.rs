@@ -0,0 +1,150 @@
+use std::io;
+use std::path;
+use serde_json::json;
+use crate::message::msg;
+use crate::target::Arch;
+
+const KVM_DEVICE: &str = "/dev/kvm";
+
+/// `/dev/kvm` を使えるかどうか
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum KvmAccess {
+    Available,
+    /// KVMのデバイスがない
+    Missing,
+    /// デバイスはあるが開く権限がない
+    Denied(Denied),
+}
+
+/// 権限がなくて `/dev/kvm` を開けなかった理由
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Denied {
+    /// デバイスを所有するグループ
+    pub group: Option<String>,
+    /// 実行しているユーザーの名前
+    pub user: Option<String>,
+    /// ユーザーはグループに登録されているが、今のログインセッションには反映されていない
+    pub needs_relogin: bool,
+}
+
+impl Denied {
+    /// 権限を得るための対処
+    fn fix(&self) -> String {
+        match (self.group.as_deref(), self.needs_relogin) {
+            // rootのグループに加えるのではなく、デバイスの権限を見直してもらう
+            (None | Some("root"), _) => msg!(KvmAskAdmin, KVM_DEVICE),
+            (Some(group), true) => msg!(KvmRelogin, group),
+            (Some(group), false) => msg!(KvmUsermod, group, self.user.as_deref().unwrap_or("$USER")),
+        }
+    }
+}
+
+/// `/dev/kvm` を読み書きできるかを調べる
+pub fn probe() -> KvmAccess {
+    probe_device(path::Path::new(KVM_DEVICE))
+}
+
+fn probe_device(device: &path::Path) -> KvmAccess {
+    match std::fs::OpenOptions::new().read(true).write(true).open(device) {
+        Ok(_) => KvmAccess::Available,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => KvmAccess::Denied(denied(device)),
+        Err(_) => KvmAccess::Missing,
+    }
+}
+
+#[cfg(unix)]
+fn denied(device: &path::Path) -> Denied {
+    use std::ffi::CStr;
+    use std::os::unix::fs::MetadataExt;
+
+    let gid = std::fs::metadata(device).map(|m| m.gid()).ok();
+    // getgrgidとgetpwuidの結果は次の呼び出しで上書きされるため、すぐに複製する
+    let (group, members) = match gid.map(|gid| unsafe { libc::getgrgid(gid) }) {
+        Some(entry) if !entry.is_null() => unsafe {
+            let name = CStr::from_ptr((*entry).gr_name).to_string_lossy().into_owned();
+            let mut members = Vec::new();
+            let mut member = (*entry).gr_mem;
+            while !member.is_null() && !(*member).is_null() {
+                members.push(CStr::from_ptr(*member).to_string_lossy().into_owned());
+                member = member.add(1);
+            }
+            (Some(name), members)
+        },
+        _ => (None, Vec::new()),
+    };
+    let user = unsafe {
+        let entry = libc::getpwuid(libc::getuid());
+        (!entry.is_null()).then(|| CStr::from_ptr((*entry).pw_name).to_string_lossy().into_owned())
+    };
+    let needs_relogin = user.as_ref().is_some_and(|u| members.contains(u));
+
+    Denied { group, user, needs_relogin }
+}
+
+#[cfg(not(unix))]
+fn denied(_device: &path::Path) -> Denied {
+    Denied { group: None, user: None, needs_relogin: false }
+}
+
+/// ホストと同じアーキテクチャのゲストであれば、KVMで実行できる
+fn host_supports(arch: Arch) -> bool {
+    match arch {
+        Arch::X86_64 | Arch::I686 => cfg!(target_arch = "x86_64"),
+        Arch::Aarch64 => cfg!(target_arch = "aarch64"),
+    }
+}
+
+/// 利用者がアクセラレータを指定しているか、KVMと両立しない指定をしている
+fn accel_specified(options: &[String]) -> bool {
+    options.iter().any(|o| {
+        matches!(o.as_str(), "-accel" | "-enable-kvm" | "-icount") || o.contains("accel=")
+    })
+}
+
+/// 使えればKVMを有効にするQEMUの引数を返す。`/dev/kvm` を開く権限がなければ、対処を示してTCGで実行する
+pub fn accel_args(arch: Arch, options: &[String], access: impl FnOnce() -> KvmAccess) -> Vec<String> {
+    if !host_supports(arch) || accel_specified(options) {
+        return Vec::new();
+    }
+
+    let access = access();
+    match &access {
+        KvmAccess::Available => {}
+        KvmAccess::Missing => crate::output::status(msg!(KvmMissing)),
+        KvmAccess::Denied(denied) => crate::output::warning(msg!(KvmDenied, KVM_DEVICE, denied.group.as_deref().unwrap_or("?"), denied.fix())),
+    }
+    let (reason, group) = match &access {
+        KvmAccess::Available => ("available", None),
+        KvmAccess::Missing => ("missing", None),
+        KvmAccess::Denied(denied) => ("denied", denied.group.clone()),
+    };
+    let enabled = access == KvmAccess::Available;
+    crate::output::event("kvm", json!({ "enabled": enabled, "access": reason, "group": group }));
+
+    match (enabled, arch) {
+        (false, _) => Vec::new(),
+        // virtマシンでKVMを使う場合は、ホストのCPUをそのまま見せる必要がある
+        (true, Arch::Aarch64) => ["-accel", "kvm", "-cpu", "host"].iter().map(|a| a.to_string()).collect(),
+        (true, _) => vec!["-accel".to_string(), "kvm".to_string()],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kvm::{accel_args, probe_device, Denied, KvmAccess};
+    use crate::target::Arch;
+
+    #[test]
+    fn accel_follows_kvm_access() {
+        let host = if cfg!(target_arch = "aarch64") { Arch::Aarch64 } else { Arch::X86_64 };
+        assert!(accel_args(host, &[], || KvmAccess::Available).windows(2).any(|w| w == ["-accel", "kvm"]));
+        assert!(accel_args(host, &["-accel".to_string(), "tcg".to_string()], || panic!("not probed")).is_empty());
+        assert!(accel_args(host, &["-machine".to_string(), "q35,accel=tcg".to_string()], || panic!("not probed")).is_empty());
+        let denied = Denied { group: Some("kvm".to_string()), user: Some("hoge".to_string()), needs_relogin: false };
+        assert!(accel_args(host, &[], || KvmAccess::Denied(denied.clone())).is_empty());
+        assert!(denied.fix().contains("usermod -aG kvm hoge"));
+        assert!(!Denied { group: Some("root".to_string()), ..denied }.fix().contains("usermod"));
+
+        assert_eq!(probe_device(std::env::temp_dir().join("cargo-uefi-no-kvm").as_path()), KvmAccess::Missing);
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path;
+use std::thread;
+use crate::firmware::Firmware;
+use crate::message::msg;
+
+/// 失敗の理由を探すために残しておく、QEMUの標準エラー出力の末尾の行数
+const KEEP_LINES: usize = 200;
+
+/// QEMU自身の標準エラー出力を利用者に流しつつ、`log` に記録し、末尾の行を残しておく
+pub struct StderrCapture {
+    handle: thread::JoinHandle<Vec<String>>,
+}
+
+impl StderrCapture {
+    pub fn start<R: io::Read + Send + 'static>(reader: R, log: Option<&path::Path>) -> StderrCapture {
+        // ログを作れなくても、QEMUの出力を流すことは続ける
+        let mut log = log.and_then(|log| {
+            log.parent().map(std::fs::create_dir_all);
+            std::fs::File::create(log).ok()
+        });
+        let handle = thread::spawn(move || {
+            let mut reader = io::BufReader::new(reader);
+            let mut lines = VecDeque::with_capacity(KEEP_LINES);
+            let mut line = Vec::new();
+            while let Ok(n) = reader.read_until(b'\n', &mut line) {
+                if n == 0 {
+                    break;
+                }
+
+                crate::output::child_stderr_line(line.as_slice());
+                if let Some(log) = log.as_mut() {
+                    let _ = log.write_all(line.as_slice());
+                }
+                if lines.len() == KEEP_LINES {
+                    lines.pop_front();
+                }
+                lines.push_back(String::from_utf8_lossy(line.as_slice()).trim_end().to_string());
+                line.clear();
+            }
+
+            Vec::from(lines)
+        });
+
+        StderrCapture { handle }
+    }
+
+    /// QEMUの終了後に呼び、残しておいた行を返す
+    pub fn finish(self) -> Vec<String> {
+        self.handle.join().unwrap_or_default()
+    }
+}
+
+/// QEMUがよく出すエラーを、対処の方法が分かるメッセージに置き換える。知らないエラーであれば `None` を返す
+pub fn translate(lines: &[String], qemu: &path::Path, firmware: &Firmware) -> Option<String> {
+    lines.iter().find_map(|line| {
+        if line.contains("KVM kernel module") || line.to_lowercase().contains("failed to initialize kvm") {
+            if line.contains("Permission denied") {
+                return Some(msg!(QemuKvmDenied));
+            }
+            if line.contains("No such file or directory") {
+                return Some(msg!(QemuKvmMissing));
+            }
+        }
+        if line.contains("device requires") && line.contains("block backend provides") {
+            let vars = firmware.vars.as_ref().map(|v| v.display().to_string()).unwrap_or_default();
+            return Some(msg!(QemuPflashSize, firmware.code.display(), vars));
+        }
+        if line.contains("is not a valid device model name") || (line.contains("Device '") && line.contains("not found")) {
+            return Some(msg!(QemuUnknownDevice, quoted(line).unwrap_or_default(), qemu.display()));
+        }
+        if line.contains("Could not open '") && line.contains("No such file or directory") {
+            return Some(msg!(QemuFileMissing, quoted(line).unwrap_or_default()));
+        }
+        if line.contains("Failed to get \"write\" lock") {
+            return Some(msg!(QemuImageLocked));
+        }
+        if line.contains("Address already in use") {
+            return Some(msg!(QemuAddressInUse));
+        }
+
+        None
+    })
+}
+
+/// 行の中で最初に `'` で囲まれた部分
+fn quoted(line: &str) -> Option<&str> {
+    let (_, rest) = line.split_once('\'')?;
+    rest.split_once('\'').map(|(quoted, _)| quoted)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::firmware::Firmware;
+    use crate::launch::translate;
+
+    #[test]
+    fn translate_common_qemu_errors() {
+        let qemu = path::Path::new("qemu-system-x86_64");
+        let firmware = Firmware { code: "OVMF_CODE.fd".into(), vars: Some("OVMF_VARS.fd".into()), shell: None };
+        let translate = |line: &str| translate(&["VNC server running on ::1:5900".to_string(), line.to_string()], qemu, &firmware);
+
+        let pflash = translate("qemu-system-x86_64: Initialization of device cfi.pflash01 failed: device requires 540672 bytes, block backend provides 131072 bytes").unwrap();
+        assert!(pflash.contains("OVMF_CODE.fd") && pflash.contains("OVMF_VARS.fd"));
+        let device = translate("qemu-system-x86_64: -device virtio-nett-pci: 'virtio-nett-pci' is not a valid device model name").unwrap();
+        assert!(device.contains("`virtio-nett-pci`") && device.contains("qemu-system-x86_64 -device help"));
+        assert!(translate("Could not access KVM kernel module: Permission denied").unwrap().contains("kvm"));
+        assert!(translate("qemu-system-x86_64: -drive file=data.img: Could not open 'data.img': No such file or directory").unwrap().contains("data.img"));
+        assert_eq!(translate("qemu-system-x86_64: terminating on signal 15"), None);
+    }
+}
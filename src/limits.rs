@@ -0,0 +1,404 @@
+use std::process::{Child, Command, ExitStatus};
+use std::sync::Mutex;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use serde::Deserialize;
+use serde_json::json;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::size::format_size;
+
+/// Cargo.toml の `limits` に書く、QEMUのプロセスに課す資源の制限
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// CPU時間の上限（秒）
+    pub cpu_time: Option<u64>,
+    /// 常駐メモリの上限（例: `2G`）
+    pub memory: Option<String>,
+    /// nice値（-20〜19）。大きいほど優先度が低い
+    pub nice: Option<i32>,
+}
+
+/// QEMUのプロセスに課す資源の制限。TCGで暴走した実行が共有のCIマシンで他のジョブを妨げないようにする
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ResourceLimits {
+    pub cpu_time: Option<Duration>,
+    /// 常駐メモリの上限（バイト）
+    pub memory: Option<u64>,
+    pub nice: Option<i32>,
+}
+
+/// nice値として受け付ける範囲
+const NICE_RANGE: std::ops::RangeInclusive<i32> = -20..=19;
+
+/// CPU時間の上限に達してSIGXCPUを受けてから、強制終了されるまでの猶予
+#[cfg(unix)]
+const CPU_GRACE_SECS: u64 = 5;
+
+/// 常駐メモリを確かめる間隔
+#[cfg(target_os = "linux")]
+const RSS_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+impl ResourceLimits {
+    /// コマンドラインの指定を、設定ファイルの `limits` より優先して組み合わせる
+    pub fn resolve(cpu_time: Option<u64>, memory: Option<u64>, nice: Option<i32>, config: &LimitsConfig) -> Result<ResourceLimits, Error> {
+        let memory = match (memory, &config.memory) {
+            (Some(memory), _) => Some(memory),
+            (None, Some(memory)) => Some(crate::size::parse_size(memory)?),
+            (None, None) => None,
+        };
+        let nice = nice.or(config.nice);
+        if let Some(nice) = nice.filter(|n| !NICE_RANGE.contains(n)) {
+            return Err(Error::new(ErrorKind::InvalidArgument, msg!(NiceOutOfRange, nice)));
+        }
+        if memory == Some(0) || cpu_time.or(config.cpu_time) == Some(0) {
+            return Err(Error::new(ErrorKind::InvalidArgument, msg!(ResourceLimitZero)));
+        }
+
+        Ok(ResourceLimits { cpu_time: cpu_time.or(config.cpu_time).map(Duration::from_secs), memory, nice })
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == ResourceLimits::default()
+    }
+
+    /// 課す制限の一覧
+    fn summary(&self) -> Vec<String> {
+        let mut items = Vec::new();
+        if let Some(cpu_time) = self.cpu_time {
+            items.push(format!("cpu-time={}s", cpu_time.as_secs()));
+        }
+        if let Some(memory) = self.memory {
+            items.push(format!("memory={}", format_size(memory)));
+        }
+        if let Some(nice) = self.nice {
+            items.push(format!("nice={}", nice));
+        }
+        items
+    }
+}
+
+/// 以降に起動する全てのQEMUに課す制限
+static LIMITS: Mutex<ResourceLimits> = Mutex::new(ResourceLimits { cpu_time: None, memory: None, nice: None });
+
+#[cfg(unix)]
+static RSS_KILLED: AtomicBool = AtomicBool::new(false);
+
+/// 最後に起動したQEMUを起動した時点での、回収済みの子プロセスのCPU時間の合計
+#[cfg(unix)]
+static CHILDREN_CPU_BEFORE: Mutex<Duration> = Mutex::new(Duration::ZERO);
+
+/// 以降に起動するQEMUに課す制限を設定する
+pub fn set(limits: ResourceLimits) {
+    *LIMITS.lock().unwrap_or_else(|e| e.into_inner()) = limits;
+    if limits.is_empty() {
+        return;
+    }
+    crate::output::status(msg!(ResourceLimitsApplied, limits.summary().join(" ")));
+    crate::output::event("resource-limits", json!({
+        "cpu-time": limits.cpu_time.map(|d| d.as_secs()),
+        "memory": limits.memory,
+        "nice": limits.nice,
+    }));
+}
+
+fn current() -> ResourceLimits {
+    *LIMITS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// 起動する前のコマンドに、CPU時間とnice値の制限を設定する
+#[cfg(unix)]
+pub fn apply(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    let limits = current();
+    if limits.cpu_time.is_none() && limits.nice.is_none() {
+        return;
+    }
+    // forkした子プロセスの中で呼ばれるため、システムコールだけを使う
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(cpu_time) = limits.cpu_time {
+                // ソフトリミットでSIGXCPUを送り、終了しなければ猶予の後にハードリミットで強制終了させる
+                let rlimit = libc::rlimit {
+                    rlim_cur: cpu_time.as_secs() as libc::rlim_t,
+                    rlim_max: (cpu_time.as_secs() + CPU_GRACE_SECS) as libc::rlim_t,
+                };
+                if libc::setrlimit(libc::RLIMIT_CPU, &rlimit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(nice) = limits.nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Windowsでは起動した後にジョブオブジェクトで制限する
+#[cfg(not(unix))]
+pub fn apply(_command: &mut Command) {}
+
+/// 起動したQEMUに、起動前に設定できない制限を課す
+#[cfg(unix)]
+pub fn attach(process: &Child) {
+    RSS_KILLED.store(false, Ordering::SeqCst);
+    *CHILDREN_CPU_BEFORE.lock().unwrap_or_else(|e| e.into_inner()) = children_cpu_time();
+    let memory = match current().memory {
+        Some(memory) => memory,
+        None => return,
+    };
+    watch_rss(process.id(), memory);
+}
+
+/// Linuxでは/procから常駐メモリを読み、上限を超えたら強制終了する。
+/// cgroupは委譲されたものを書き込める環境が限られるため使わない
+#[cfg(target_os = "linux")]
+fn watch_rss(pid: u32, limit: u64) {
+    std::thread::spawn(move || loop {
+        let status = match std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+            Ok(status) => status,
+            // 回収された
+            Err(_) => return,
+        };
+        let (state, rss) = parse_status(status.as_str());
+        if state == Some('Z') || state == Some('X') {
+            return;
+        }
+        if let Some(rss) = rss.filter(|rss| *rss > limit) {
+            RSS_KILLED.store(true, Ordering::SeqCst);
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+            crate::output::warning(msg!(MemoryLimitExceeded, format_size(rss), format_size(limit)));
+            crate::output::event("resource-limit-exceeded", json!({ "limit": "memory", "rss": rss, "max": limit }));
+            return;
+        }
+        std::thread::sleep(RSS_POLL_INTERVAL);
+    });
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn watch_rss(_pid: u32, _limit: u64) {
+    crate::output::warning(msg!(MemoryLimitUnsupported));
+}
+
+/// `/proc/<pid>/status` からプロセスの状態と常駐メモリ（バイト）を読む
+#[cfg(target_os = "linux")]
+fn parse_status(status: &str) -> (Option<char>, Option<u64>) {
+    let field = |name: &str| status.lines().find_map(|line| line.strip_prefix(name)).map(str::trim);
+    let state = field("State:").and_then(|s| s.chars().next());
+    let rss = field("VmRSS:")
+        .and_then(|s| s.trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kib| kib * 1024);
+    (state, rss)
+}
+
+/// 起動したQEMUをジョブオブジェクトに入れて、CPU時間、メモリ、優先度を制限する。
+/// ジョブのハンドルはcargo-uefiが終了するまで閉じないため、cargo-uefiが終了するとQEMUも終了する
+#[cfg(windows)]
+pub fn attach(process: &Child) {
+    use std::os::windows::io::AsRawHandle;
+
+    let limits = current();
+    if limits.is_empty() {
+        return;
+    }
+    if let Err(e) = job::assign(process.as_raw_handle(), &limits) {
+        crate::output::warning(msg!(JobObjectFailed, e));
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn attach(_process: &Child) {}
+
+#[cfg(windows)]
+mod job {
+    use std::ffi::c_void;
+    use std::io;
+    use crate::limits::ResourceLimits;
+
+    const JOB_OBJECT_LIMIT_PROCESS_TIME: u32 = 0x0000_0002;
+    const JOB_OBJECT_LIMIT_PRIORITY_CLASS: u32 = 0x0000_0020;
+    const JOB_OBJECT_LIMIT_PROCESS_MEMORY: u32 = 0x0000_0100;
+    const JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE: u32 = 0x0000_2000;
+    const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS: i32 = 9;
+
+    const IDLE_PRIORITY_CLASS: u32 = 0x0000_0040;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+    const NORMAL_PRIORITY_CLASS: u32 = 0x0000_0020;
+    const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x0000_8000;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct BasicLimitInformation {
+        per_process_user_time_limit: i64,
+        per_job_user_time_limit: i64,
+        limit_flags: u32,
+        minimum_working_set_size: usize,
+        maximum_working_set_size: usize,
+        active_process_limit: u32,
+        affinity: usize,
+        priority_class: u32,
+        scheduling_class: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct IoCounters {
+        read_operation_count: u64,
+        write_operation_count: u64,
+        other_operation_count: u64,
+        read_transfer_count: u64,
+        write_transfer_count: u64,
+        other_transfer_count: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct ExtendedLimitInformation {
+        basic_limit_information: BasicLimitInformation,
+        io_info: IoCounters,
+        process_memory_limit: usize,
+        job_memory_limit: usize,
+        peak_process_memory_used: usize,
+        peak_job_memory_used: usize,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateJobObjectW(attributes: *mut c_void, name: *const u16) -> *mut c_void;
+        fn SetInformationJobObject(job: *mut c_void, class: i32, info: *mut c_void, length: u32) -> i32;
+        fn AssignProcessToJobObject(job: *mut c_void, process: *mut c_void) -> i32;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+    }
+
+    /// nice値に近い優先度クラス
+    fn priority_class(nice: i32) -> u32 {
+        match nice {
+            n if n >= 15 => IDLE_PRIORITY_CLASS,
+            n if n > 0 => BELOW_NORMAL_PRIORITY_CLASS,
+            0 => NORMAL_PRIORITY_CLASS,
+            _ => ABOVE_NORMAL_PRIORITY_CLASS,
+        }
+    }
+
+    pub fn assign(process: *mut c_void, limits: &ResourceLimits) -> Result<(), io::Error> {
+        let mut info = ExtendedLimitInformation::default();
+        info.basic_limit_information.limit_flags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        if let Some(cpu_time) = limits.cpu_time {
+            // 100ナノ秒単位
+            info.basic_limit_information.per_process_user_time_limit = (cpu_time.as_nanos() / 100) as i64;
+            info.basic_limit_information.limit_flags |= JOB_OBJECT_LIMIT_PROCESS_TIME;
+        }
+        if let Some(memory) = limits.memory {
+            // ジョブオブジェクトが制限できるのはコミットしたメモリの量
+            info.process_memory_limit = memory as usize;
+            info.basic_limit_information.limit_flags |= JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+        }
+        if let Some(nice) = limits.nice {
+            info.basic_limit_information.priority_class = priority_class(nice);
+            info.basic_limit_information.limit_flags |= JOB_OBJECT_LIMIT_PRIORITY_CLASS;
+        }
+
+        unsafe {
+            let job = CreateJobObjectW(std::ptr::null_mut(), std::ptr::null());
+            if job.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let size = std::mem::size_of::<ExtendedLimitInformation>() as u32;
+            let configured = SetInformationJobObject(job, JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS, &mut info as *mut _ as *mut c_void, size) != 0;
+            if !configured || AssignProcessToJobObject(job, process) == 0 {
+                let e = io::Error::last_os_error();
+                CloseHandle(job);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 制限によってQEMUが終了させられた場合に知らせる
+#[cfg(unix)]
+pub fn report_exit(status: &ExitStatus) {
+    use std::os::unix::process::ExitStatusExt;
+
+    let cpu_time = match current().cpu_time {
+        Some(cpu_time) => cpu_time,
+        None => return,
+    };
+    // SIGKILLは利用者や他のプロセスが送ったものかもしれないため、上限までCPU時間を使っていた場合だけ制限によるものとみなす
+    let used = children_cpu_time().saturating_sub(*CHILDREN_CPU_BEFORE.lock().unwrap_or_else(|e| e.into_inner()));
+    let hard_limited = status.signal() == Some(libc::SIGKILL) && !rss_killed() && used >= cpu_time;
+    if status.signal() == Some(libc::SIGXCPU) || hard_limited {
+        crate::output::warning(msg!(CpuTimeLimitExceeded, cpu_time.as_secs()));
+        crate::output::event("resource-limit-exceeded", json!({ "limit": "cpu-time", "max": cpu_time.as_secs() }));
+    }
+}
+
+#[cfg(not(unix))]
+pub fn report_exit(_status: &ExitStatus) {}
+
+/// 回収済みの子プロセスが使ったCPU時間（ユーザーとシステムの合計）
+#[cfg(unix)]
+fn children_cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) } != 0 {
+        return Duration::ZERO;
+    }
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// nice値を下げる（優先度を上げる）には特権が要るため、起動に失敗した理由を補う
+pub fn explain_spawn_error(e: std::io::Error) -> std::io::Error {
+    explain_nice(current().nice, e)
+}
+
+fn explain_nice(nice: Option<i32>, e: std::io::Error) -> std::io::Error {
+    match nice.filter(|nice| *nice < 0) {
+        Some(nice) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            std::io::Error::new(e.kind(), msg!(NiceNotPermitted, nice, e))
+        }
+        _ => e,
+    }
+}
+
+/// メモリの監視が最後に起動したQEMUを強制終了したか。SIGKILLの原因を見分けるために使う
+#[cfg(unix)]
+fn rss_killed() -> bool {
+    RSS_KILLED.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use std::io;
+    use crate::limits::{explain_nice, LimitsConfig, ResourceLimits};
+
+    #[test]
+    fn resolve_limits_and_read_rss() {
+        let config = LimitsConfig { cpu_time: Some(600), memory: Some("4G".to_string()), nice: Some(10) };
+        let limits = ResourceLimits::resolve(None, Some(1 << 30), None, &config).unwrap();
+        assert_eq!(limits, ResourceLimits { cpu_time: Some(Duration::from_secs(600)), memory: Some(1 << 30), nice: Some(10) });
+        assert_eq!(limits.summary(), ["cpu-time=600s", "memory=1.0 GiB", "nice=10"]);
+        assert!(ResourceLimits::resolve(None, None, None, &LimitsConfig::default()).unwrap().is_empty());
+        assert!(ResourceLimits::resolve(None, None, Some(20), &LimitsConfig::default()).is_err());
+        assert!(ResourceLimits::resolve(Some(0), None, None, &LimitsConfig::default()).is_err());
+
+        // 特権のない利用者が負のnice値を指定した場合は、理由を添える
+        let denied = || io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(explain_nice(Some(-5), denied()).to_string().contains("CAP_SYS_NICE"));
+        assert!(!explain_nice(Some(5), denied()).to_string().contains("CAP_SYS_NICE"));
+        assert!(!explain_nice(Some(-5), io::Error::from(io::ErrorKind::NotFound)).to_string().contains("CAP_SYS_NICE"));
+
+        #[cfg(target_os = "linux")]
+        {
+            let status = "Name:\tqemu-system-x86\nState:\tS (sleeping)\nVmPeak:\t 2000000 kB\nVmRSS:\t  524288 kB\n";
+            assert_eq!(crate::limits::parse_status(status), (Some('S'), Some(512 << 20)));
+        }
+    }
+}
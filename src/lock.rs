@@ -0,0 +1,75 @@
+use std::fs;
+use std::io;
+use std::path;
+
+/// 複数のcargo-uefiプロセス間で共有する状態を守るためのアドバイザリロック。
+/// ロックはこの値がdropされるか、プロセスが終了した時点で解放される。
+pub struct FileLock {
+    _file: fs::File,
+}
+
+impl FileLock {
+    /// `path` のロックファイルを排他ロックする。
+    /// 他のプロセスがロックを保持している場合、`wait` が真なら解放を待ち、偽ならエラーを返す。
+    pub fn acquire(path: &path::Path, wait: bool) -> Result<FileLock, io::Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) if wait => {
+                crate::output::status(crate::message::msg!(WaitingForLock, path.display()));
+                file.lock()?;
+            }
+            Err(fs::TryLockError::WouldBlock) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    crate::message::msg!(Locked, path.display())
+                ));
+            }
+            Err(fs::TryLockError::Error(e)) => return Err(e),
+        }
+
+        Ok(FileLock { _file: file })
+    }
+}
+
+/// ディレクトリに対応するロックファイルのパスを返す。
+/// ディレクトリの中に置くとESPの内容に含まれてしまうため、隣に置く。
+pub fn lock_path_for(dir: &path::Path) -> path::PathBuf {
+    let mut name = dir.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".lock");
+    dir.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::lock::{lock_path_for, FileLock};
+
+    #[test]
+    fn lock_path_is_sibling_of_dir() {
+        let path = lock_path_for(path::Path::new("/tmp/UEFI"));
+        assert_eq!(path, path::Path::new("/tmp/UEFI.lock"));
+    }
+
+    #[test]
+    fn second_lock_fails_without_wait() {
+        let path = std::env::temp_dir().join(format!("cargo-uefi-test-lock-{}", std::process::id()));
+        let first = FileLock::acquire(path.as_path(), false).unwrap();
+        let second = FileLock::acquire(path.as_path(), false);
+        assert_eq!(second.err().map(|e| e.kind()), Some(std::io::ErrorKind::WouldBlock));
+
+        drop(first);
+        assert!(FileLock::acquire(path.as_path(), false).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,24 +1,354 @@
+mod backend;
+mod bloat;
+mod breakpoint;
+mod build;
+mod buildinfo;
+mod cache;
+mod checkpoint;
+mod compare;
+mod config;
+mod control;
+mod copy;
+mod crash;
+mod disk;
 mod error;
+mod exit;
+mod export;
+mod fetch;
+mod firmware;
+mod freeze;
+mod fwcfg;
+mod gpt;
+mod guestlog;
+mod image;
+mod imagebase;
+mod inspect;
+mod janitor;
+mod kvm;
+mod launch;
+mod limits;
+mod lock;
+mod member;
+mod message;
+mod netem;
+mod output;
+mod pdb;
+mod pe;
+mod ports;
+mod powercut;
+mod preset;
+mod probe;
+mod provider;
+mod provision;
+mod qmp;
+mod report;
+mod runner;
+mod scenario;
+mod seriallog;
+mod shelltools;
+mod shim;
+mod signature;
+mod size;
+mod staging;
+mod supervise;
+mod sweep;
+mod target;
+mod trace;
+mod varstore;
+mod verdict;
+mod verify;
+mod watch;
 
+use std::collections::HashMap;
 use std::io;
 use std::env;
 use std::io::Read;
 use std::path;
 use std::process::ExitStatus;
-use clap::Parser;
+use std::time::Duration;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use toml_edit::easy;
 use serde::Deserialize;
+use backend::{VmBackend, VmConfig};
+use message::msg;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(long, value_name = "FILE")]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// 実行するEFIファイル。cargoのrunnerとして起動された場合に、ビルドされたファイルが渡される
+    #[arg(value_name = "EFI_FILE", conflicts_with_all = ["bin", "all"])]
+    app: Option<path::PathBuf>,
+
+    #[arg(long, value_name = "FILE", global = true)]
     bin: Option<String>,
 
+    /// ワークスペース内の全バイナリをビルドし、順番に実行する
+    #[arg(long, conflicts_with = "bin")]
+    all: bool,
+
+    /// 実行ごとのシリアルとファームウェアのログ、スクリーンショット、結果のJSON、生成したイメージを、
+    /// CIの成果物としてそのままアップロードできる構成でこのディレクトリに書き出す
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["watch", "memory_sweep"])]
+    artifacts_dir: Option<path::PathBuf>,
+
+    /// `--all` で、全バイナリの結果をまとめたHTMLのレポートを書き出す
+    #[arg(long, value_name = "FILE", requires = "all")]
+    html_report: Option<path::PathBuf>,
+
+    #[command(flatten)]
+    build: build::BuildFlags,
+
+    #[arg(long, value_enum, global = true)]
+    target: Option<target::Arch>,
+
+    #[arg(long, value_enum, default_value_t = staging::Layout::Direct, global = true)]
+    layout: staging::Layout,
+
+    #[arg(long, value_name = "FILE", global = true)]
+    systemd_boot: Option<path::PathBuf>,
+
+    /// 一時的な配置先のディレクトリ（省略時は target/uefi/tmp）
+    #[arg(long, value_name = "DIR", global = true)]
+    temp_root: Option<path::PathBuf>,
+
+    /// 他のcargo-uefiプロセスが共有ディレクトリをロックしている場合、待たずにエラーにする
+    #[arg(long, global = true)]
+    no_lock_wait: bool,
+
+    /// 配置したファイルからFAT32のESPを持つGPTのディスクイメージを生成して起動する（同じ内容のイメージはキャッシュを再利用する）
+    #[arg(long, global = true)]
+    image: bool,
+
+    /// 生成するディスクイメージの最小サイズ（例: 64G）。未使用領域はスパースに確保する。`--image` を含意する
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_size, global = true)]
+    image_size: Option<u64>,
+
+    /// データディスクとして接続するイメージファイル（複数指定可）
+    #[arg(long = "disk", value_name = "FILE", global = true)]
+    disks: Vec<path::PathBuf>,
+
+    /// ドライブのキャッシュモード
+    #[arg(long, value_enum, value_name = "MODE", global = true)]
+    drive_cache: Option<disk::CacheMode>,
+
+    /// ドライブの非同期I/Oバックエンド
+    #[arg(long, value_enum, value_name = "BACKEND", global = true)]
+    drive_aio: Option<disk::AioMode>,
+
+    /// ゲストからのdiscard(TRIM/UNMAP)要求をホストのイメージに反映する
+    #[arg(long, global = true)]
+    drive_discard: bool,
+
+    /// 実行後に、ゲストが各データディスクにdiscard(TRIM/UNMAP)を発行したかを報告する
+    #[arg(long, global = true)]
+    report_discard: bool,
+
+    /// isa-debug-exitデバイスを追加し、ゲストが書き込んだ値を終了コードとして返す
+    #[arg(long, global = true)]
+    exit_device: bool,
+
+    /// isa-debug-exitデバイスのI/Oポート（既定値: 0xf4）。`--exit-device` を含意する
+    #[arg(long, value_name = "PORT", value_parser = exit::parse_iobase, global = true)]
+    exit_iobase: Option<u16>,
+
+    /// 成功とみなすQEMUの終了コード（既定値: 33）。`--exit-device` を含意する
+    #[arg(long, value_name = "CODE", global = true)]
+    exit_success: Option<i32>,
+
+    /// ネットワークに一切アクセスしない。ファームウェアはキャッシュか明示したパスから取得する
+    #[arg(long, global = true)]
+    offline: bool,
+
+    /// 起動に使うファームウェアのイメージ（CODE、またはCODEとVARSを結合したイメージ）
+    #[arg(long, value_name = "FILE", conflicts_with = "ovmf_prebuilt", global = true)]
+    ovmf: Option<path::PathBuf>,
+
+    /// `--ovmf` と組で使うVARSイメージ。実行ごとの複製を書き込み可能なpflashとして接続する
+    #[arg(long, value_name = "FILE", requires = "ovmf", global = true)]
+    ovmf_vars: Option<path::PathBuf>,
+
+    /// ローカルにファームウェアが見つからなければ、ビルド済みのものを取得してキャッシュする
+    #[arg(long, global = true)]
+    download_ovmf: bool,
+
+    /// rust-osdev/ovmf-prebuilt のリリース（タグ名）からファームウェアを取得して使う
+    #[arg(long, value_name = "TAG", global = true)]
+    ovmf_prebuilt: Option<String>,
+
+    /// `--ovmf-prebuilt` で取得するtarballの SHA-256
+    #[arg(long, value_name = "HEX", requires = "ovmf_prebuilt", global = true)]
+    ovmf_prebuilt_sha256: Option<String>,
+
+    /// COM2を通してゲストからの要求（ログ、イベント、チェックポイント、ファイルの保存、スクリーンショット、読み込み先の報告）を受け付ける
+    #[arg(long, global = true)]
+    guest_control: bool,
+
+    /// 検証用のシムを通してアプリケーションを起動し、返したEFI_STATUSで成否を判定する（x86_64のみ）
+    #[arg(long, conflicts_with_all = ["memory_sweep", "boots", "power_cut"])]
+    shim: bool,
+
+    /// 進捗などのメッセージを出力しない
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// 標準出力に出すメッセージの形式。`json` ではゲストの出力も行ごとのイベントになる
+    #[arg(long, value_enum, value_name = "FMT", default_value_t = output::MessageFormat::Human, global = true)]
+    message_format: output::MessageFormat,
+
+    /// 表示するゲストのログのレベル。これより詳しいレベルのタグが付いた行は表示しない
+    #[arg(long, value_enum, value_name = "LEVEL", default_value_t = guestlog::Level::Info, global = true)]
+    log_level: guestlog::Level,
+
+    /// 設定された鍵でファームウェアの署名を検証できなくても、警告を出して使用する
+    #[arg(long, global = true)]
+    allow_unverified_firmware: bool,
+
+    /// fw_cfgを通してゲストに渡すデータ（`name=opt/...,file=FILE` または `name=opt/...,string=STRING`。複数指定可）
+    #[arg(long = "fw-cfg", value_name = "OPTIONS", value_parser = fwcfg::parse_entry, global = true)]
+    fw_cfg: Vec<fwcfg::FwCfgEntry>,
+
+    /// target/uefi/varstores に保存したこのプロファイルのUEFI変数で起動する
+    #[arg(long, value_name = "NAME", global = true)]
+    vars_profile: Option<String>,
+
+    /// 実行後のUEFI変数を target/uefi/varstores にこのプロファイルとして保存する
+    #[arg(long, value_name = "NAME", conflicts_with = "all")]
+    save_vars_profile: Option<String>,
+
+    /// CIでテストを実行するrunner向けに、終了コードを受け渡すデバイスを追加し（x86のみ）、画面を出さずにシリアルを標準出力に流し、
+    /// リセットではQEMUを終了させ、タイムアウト（既定値: 300秒）を設ける
+    #[arg(long)]
+    ci: bool,
+
+    /// QEMUを終了させるまでの秒数
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// QEMUがこの秒数のCPU時間を使ったら終了させる
+    #[arg(long, value_name = "SECS", global = true)]
+    cpu_time_limit: Option<u64>,
+
+    /// QEMUの常駐メモリがこのサイズ（例: `2G`）を超えたら強制終了する
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_size, global = true)]
+    memory_limit: Option<u64>,
+
+    /// QEMUをこのnice値（-20〜19）で実行し、他のジョブより優先度を下げる
+    #[arg(long, value_name = "N", allow_negative_numbers = true, global = true)]
+    nice: Option<i32>,
+
+    /// メモリサイズを変えながら起動し、起動できる最小のサイズを報告する（例: `64M..1G step 64M`）
+    #[arg(long, value_name = "RANGE", value_parser = sweep::parse_sweep, conflicts_with_all = ["all", "boots", "power_cut"])]
+    memory_sweep: Option<sweep::MemorySweep>,
+
+    /// UEFI変数とディスクを引き継いだままVMをN回起動する。シリアルの出力は起動ごとにログへ記録する
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "all")]
+    boots: Option<u32>,
+
+    /// 最後以外の起動で電源断を起こす（`after=SECS`、`random=MIN-MAX`、`mode=off|reset`、`marker=TEXT` をカンマで区切る。`marker` は最後に書く）
+    #[arg(long, value_name = "SPEC", value_parser = powercut::parse_power_cut, conflicts_with = "all")]
+    power_cut: Option<powercut::PowerCut>,
+
+    /// `--power-cut` の `random` で時刻を選ぶ乱数のシード。同じシードなら同じ時刻に電源を断つ
+    #[arg(long, value_name = "SEED", requires = "power_cut")]
+    power_cut_seed: Option<u64>,
+
+    /// ファイルの変更を監視し、変更があればVMを起動し直す。ESPに配置するデータだけが変わった場合は、cargoでビルドせずに配置し直す
+    #[arg(long, conflicts_with_all = ["all", "memory_sweep", "boots", "power_cut", "save_vars_profile"])]
+    watch: bool,
+
+    /// トリプルフォールトなどでゲストがリセットした場合に、再起動せずQEMUを終了する
+    #[arg(long, global = true)]
+    no_reboot: bool,
+
+    /// ゲストが電源断してもQEMUを終了せず、VMを停止したままにする
+    #[arg(long, global = true)]
+    no_shutdown: bool,
+
+    /// トリプルフォールトやゲストのパニックの時点でVMを停止したままにし、GDBの接続を待つ
+    #[arg(long, global = true)]
+    freeze_on_crash: bool,
+
+    /// VMが停止したらGDBを起動して接続する。`--freeze-on-crash` を含意する
+    #[arg(long, global = true)]
+    attach_gdb: bool,
+
+    /// ゲストの出力にこの文字列が現れたらVMを一時停止し、GDBの接続を待つ（複数指定可）。`--attach-gdb` ではGDBを起動して接続する
+    #[arg(long, value_name = "TEXT", global = true)]
+    break_on: Vec<String>,
+
+    /// `int,guest_errors,unimp` などのQEMUのログをファイルに記録し、実行後に集計する
+    #[arg(long, value_name = "ITEMS", value_parser = trace::parse_items, global = true)]
+    qemu_trace: Option<trace::TraceItems>,
+
+    /// ローカルホストの空いているポートでVNCによって画面を公開する
+    #[arg(long, global = true)]
+    vnc: bool,
+
+    /// ローカルホストの空いているポートでシリアルコンソールをTCPで公開する
+    #[arg(long, global = true)]
+    serial_tcp: bool,
+
+    /// 空いているポートの代わりに固定のポートを使う（例: gdb=1234。複数指定可）
+    #[arg(long = "port", value_name = "NAME=PORT", value_parser = ports::parse_override, global = true)]
+    ports: Vec<(String, u16)>,
+
+    /// 想定するハードウェアの構成（メモリ、vCPU、画面、ディスクなど）でVMを起動する
+    #[arg(long, value_enum, value_name = "NAME", global = true)]
+    preset: Option<preset::Preset>,
+
+    /// 組み合わせて追加するデバイスプリセット（カンマ区切り、複数指定可）
+    #[arg(long, value_name = "NAME,...", value_delimiter = ',', global = true)]
+    with: Vec<String>,
+
+    /// `/dev/kvm` を使える場合でもKVMを有効にせず、TCGで実行する
+    #[arg(long, global = true)]
+    no_kvm: bool,
+
+    /// ユーザーモードネットワークに遅延、損失、帯域の制限を加える（latency=MS,jitter=MS,loss=PERCENT,rate=KBPS,device=DEVICE）
+    #[arg(long, value_name = "SPEC", value_parser = netem::parse_impairment, global = true)]
+    net_impair: Option<netem::Impairment>,
+
+    /// gitのリビジョン、ビルド時刻、プロファイルを `.build` セクションとして埋め込んだバイナリを配置する
+    #[arg(long, global = true)]
+    stamp_build_info: bool,
+
+    /// ファームウェアに付属するUEFI Shellを \EFI\tools\Shell.efi に配置する
+    #[arg(long, global = true)]
+    stage_shell: bool,
+
+    /// ESPに配置するファイルと前回の実行から変わる内容を表示し、配置や起動はしない
+    #[arg(long, global = true)]
+    explain_staging: bool,
+
+    /// アプリケーションと一緒にESPへ配置するファイル（`HOST_PATH:/ESP/PATH` の形式、複数指定可）
+    #[arg(long = "file", value_name = "HOST_PATH:/ESP/PATH", value_parser = staging::parse_extra_file, global = true)]
+    extra_files: Vec<staging::ExtraFile>,
+
     #[arg(last = true)]
     qemu_cmd: Vec<String>,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// ファームウェア、ディスクイメージ、UEFI変数のプロファイルのキャッシュを表示・削除する
+    Cache(cache::CacheArgs),
+    /// 同じアプリケーションを2つのファームウェアで実行し、結果を比較する
+    Compare(compare::CompareArgs),
+    /// `.cargo/config.toml` にcargo-uefiをUEFIターゲットのrunnerとして登録する
+    InstallRunner(runner::InstallRunnerArgs),
+    /// ビルドしたEFIファイルのPEヘッダ・セクション・インポート・再配置・デバッグ情報を表示する
+    Inspect(inspect::InspectArgs),
+    /// シナリオファイルに書かれた、複数回の起動にまたがる手順を実行する
+    Scenario(scenario::ScenarioArgs),
+    /// UEFI Shell（組み込みの memmap、dmpstore、setvar を含む）と `shell-tools` のバンドルのツールをアプリケーションと一緒に \EFI\tools に配置して実行する
+    ShellTools(shelltools::ShellToolsArgs),
+    /// ディスクイメージやISOのパーティション、ESP、ブートファイル、署名を検証する
+    VerifyImage(verify::VerifyArgs),
+}
+
 #[derive(Deserialize)]
 struct TomlConfig {
     package: Option<TomlPackage>,
@@ -42,106 +372,1313 @@ struct TomlWorkspace {
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    crash::install();
+    janitor::install_signal_handlers();
+    let result = run();
+    // JSON形式ではエラーもイベントとして出力し、標準エラー出力には何も出さない
+    if let Err(e) = &result {
+        if output::json() {
+            output::event("error", serde_json::json!({ "message": e.to_string() }));
+            janitor::exit(1);
+        }
+    }
+
+    result
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    // `cargo uefi` として起動された場合、cargoがサブコマンド名を第1引数として渡すので取り除く
+    let mut raw_args: Vec<_> = env::args_os().collect();
+    if raw_args.get(1).map(|a| a == "uefi").unwrap_or(false) {
+        raw_args.remove(1);
+    }
+    let matches = message::localize(Args::command(), message::lang()).get_matches_from(raw_args);
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    output::init(args.quiet, args.message_format, args.log_level);
 
     let project_root = get_project_root()?;
     let project_root = project_root.as_path();
-    let qemu_path = get_qemu_executable()?;
-    let ovmf_path = get_ovmf(project_root)?;
+    janitor::set_journal_dir(temp_root(&args, project_root).join("janitor").as_path())?;
+
+    if let Some(Command::Cache(cache_args)) = &args.command {
+        return cache::run(cache_args, project_root, !args.no_lock_wait);
+    }
+    if let Some(Command::InstallRunner(install_args)) = &args.command {
+        let arch = args.target.unwrap_or_default();
+        let installed = runner::install_runner(project_root, arch, install_args.force)?;
+        if installed {
+            output::status(msg!(RunnerInstalled, arch.rust_target()));
+        } else {
+            output::status(msg!(RunnerAlreadyInstalled, arch.rust_target()));
+        }
+        output::event("runner-installed", serde_json::json!({ "target": arch.rust_target(), "changed": installed }));
+
+        return Ok(());
+    }
 
     // 実行するアプリケーションを選択する
     let cargo_toml_path = project_root.join("Cargo.toml");
     let mut cargo_toml = std::fs::File::open(cargo_toml_path.as_path())?;
     let mut toml = String::new();
     let _ = cargo_toml.read_to_string(&mut toml)?;
-    let app_name = find_binary_name(&args.bin, toml.as_str(), project_root)?;
-    let app_path = get_uefi_app(project_root, app_name.as_str())?;
+    let config = config::from_manifest(toml.as_str())?;
+    crash::set_config(&config);
+    breakpoint::set_markers(config.break_on.iter().chain(args.break_on.iter()).cloned().collect());
+    limits::set(limits::ResourceLimits::resolve(args.cpu_time_limit, args.memory_limit, args.nice, &config.limits)?);
+
+    if let Some(Command::Inspect(inspect_args)) = &args.command {
+        let file = match &inspect_args.file {
+            Some(file) => file.clone(),
+            None => app_artifact(&args, project_root, find_binary_name(&args.bin, toml.as_str(), project_root)?.as_str())?,
+        };
+
+        return inspect::inspect(file.as_path(), inspect_args);
+    }
+    if let Some(Command::VerifyImage(verify_args)) = &args.command {
+        // コマンドラインの証明書は現在のディレクトリから、設定ファイルの証明書はプロジェクトルートからのパス
+        let db = match (verify_args.db_certs.is_empty(), &config.secure_boot) {
+            (true, Some(secure_boot)) => secure_boot.db.iter().map(|c| project_root.join(c)).collect(),
+            _ => verify_args.db_certs.clone(),
+        };
+        let work_dir = temp_root(&args, project_root);
+        std::fs::create_dir_all(work_dir.as_path())?;
+        let checks = verify::verify(verify_args.disk_image.as_path(), &db, work_dir.as_path())?;
+        if !verify::report(verify_args.disk_image.as_path(), &checks) {
+            janitor::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    if args.watch && args.command.is_some() {
+        return Err(Box::new(error::Error::new(error::ErrorKind::InvalidArgument, msg!(WatchCommandUnsupported))));
+    }
+
+    // ビルドやファームウェアの取得より前に、用意する内容の誤りを知らせる
+    config.provision.validate(project_root)?;
+    let arch = target::resolve(args.target, args.app.as_deref())?;
+    let qemu = backend::Qemu::find(arch)?;
+    crash::set_qemu(qemu.program());
+
+    if args.all {
+        let names = get_binary_name(toml.as_str(), project_root)?;
+        let overrides = member_overrides(toml.as_str(), project_root)?;
+        let all_passed = run_all(&args, &config, &names, &overrides, project_root, arch, &qemu)?;
+        if !all_passed {
+            janitor::exit(1);
+        }
+
+        return Ok(());
+    }
 
-    // UEFIアプリケーションを配置するための一時ディレクトリを作成
-    let uefi_root = env::temp_dir().join("UEFI");
-    let uefi_app_dir = env::temp_dir().join("UEFI").join("EFI").join("BOOT");
-    std::fs::create_dir_all(uefi_app_dir.as_path())?;
+    check_shim_arch(&args, arch)?;
+    let (app_name, app_path) = match &args.app {
+        Some(app) => {
+            let name = app.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+            if !app.is_file() {
+                return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, msg!(NotFound, app.display()))));
+            }
+
+            (name, app.clone())
+        }
+        None => {
+            let name = find_binary_name(&args.bin, toml.as_str(), project_root)?;
+            let path = app_artifact(&args, project_root, name.as_str())?;
+            (name, path)
+        }
+    };
+
+    // UEFIアプリケーションを配置するための一時ディレクトリにUEFIアプリケーションを配置
+    // 同時に実行した他のプロセスに書き換えられないよう、実行ごとに別のディレクトリを使う
+    let uefi_root = run_dir(&args, project_root, "UEFI")?;
+    let _staging = janitor::register_path(uefi_root.as_path());
+    // compareでは比較対象のファームウェアを使うため、UEFI Shellを配置する場合のみ解決する
+    let firmware = match &args.command {
+        _ if args.explain_staging && !needs_shell(&args) => None,
+        Some(Command::Compare(_)) if !args.stage_shell => None,
+        _ => Some(resolve_firmware(&args, &config, project_root, arch, None)?),
+    };
+    let plan = staging_plan(&args, &config, project_root, arch, app_path.as_path(), app_name.as_str(), firmware.as_ref())?;
+    if args.explain_staging {
+        return explain_staging(&plan, project_root, app_name.as_str());
+    }
+    stage(&plan, project_root, app_name.as_str(), uefi_root.as_path())?;
+    imagebase::expect(app_path.as_path());
+    let drive = boot_drive(&args, &config, project_root, uefi_root.as_path())?;
+    let provision_dir = run_dir(&args, project_root, "provision")?;
+    let _provision = janitor::register_path(provision_dir.as_path());
+    config.provision.create_disks(provision_dir.as_path(), project_root)?;
+    let disks = data_disks(&args, &config, provision_dir.as_path())?;
+    let mut machine_options = arch.machine_args();
+    machine_options.extend(disk::disk_args(&disks, &drive_options(&args, &config), project_root)?);
 
-    // 作成したディレクトリにUEFIアプリケーションを配置
-    let uefi_app_path = uefi_app_dir.join("BOOTX64.EFI");  
-    std::fs::copy(app_path, uefi_app_path)?;
+    if let Some(Command::Compare(compare_args)) = &args.command {
+        let log_dir = project_root.join("target").join("uefi").join("compare");
+        let same = compare::compare(compare_args, &qemu, &drive, &machine_options, log_dir.as_path())?;
+        if !same {
+            janitor::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    let vars_dir = run_dir(&args, project_root, "vars")?;
+    let _vars = janitor::register_path(vars_dir.as_path());
+    let firmware = vars_profile(&args, firmware.expect("firmware is resolved except for compare"), project_root)?
+        .with_vars_copy(vars_dir.as_path())?;
+    // `--boots` では起動をまたいで状態を引き継ぐため、用意するのは最初の1回だけにする
+    config.provision.write_variables(&firmware, project_root)?;
+    config.provision.report();
 
     // QEMU向けのコマンドライン引数を取得
-    let qemu_options = args.qemu_cmd;
+    let convention = exit_convention(&args, &config, arch)?;
+    let mut qemu_options = machine_options;
+    qemu_options.extend(config.provision.qemu_args());
+    qemu_options.extend(preset_args(&args, arch));
+    qemu_options.extend(device_preset_args(&args, &config, &[])?);
+    if let Some(convention) = &convention {
+        qemu_options.extend(convention.device_args(arch)?);
+    }
+    qemu_options.extend(fw_cfg_args(&args, &config, project_root)?);
+    // `--power-cut` だけを指定した場合は、電源を断つ起動と回復を確かめる起動の2回にする
+    let boots = args.boots.or(args.power_cut.as_ref().map(|_| 2));
+    // チェックポイントとホストからの検査は、1回だけ起動する場合に行う
+    let single_boot = args.command.is_none() && args.memory_sweep.is_none() && boots.is_none() && !args.watch;
+    let probes = match config.probes.is_empty() || !single_boot {
+        true => None,
+        false => Some(probe::HostProbes::new(&config.probes, ports::PortAllocator::new(ports::lock_dir(), &args.ports))?),
+    };
+    let network = impaired_network(&args, &config)?;
+    match (&network, &probes) {
+        (Some((_, network)), probes) => qemu_options.extend(network.qemu_args(probes.as_ref().map(|p| p.hostfwd()).unwrap_or_default().as_str())),
+        (None, Some(probes)) => qemu_options.extend(probes.qemu_args()),
+        (None, None) => {}
+    }
+    qemu_options.extend(config.qemu_args.iter().cloned());
+    qemu_options.extend(args.qemu_cmd.iter().cloned());
+    if !args.no_kvm {
+        qemu_options.extend(kvm::accel_args(arch, &qemu_options, kvm::probe));
+    }
+
+    if let Some(Command::Scenario(scenario_args)) = &args.command {
+        let scenario = scenario::Scenario::load(scenario_args.file.as_path())?;
+        let varstores = varstore::varstore_dir(project_root);
+        let machine = scenario::Machine {
+            backend: &qemu,
+            firmware: &firmware,
+            drive: &drive,
+            options: &qemu_options,
+            convention: convention.as_ref(),
+            varstores: varstores.as_path(),
+            ports: &args.ports,
+        };
+        let passed = scenario::run(&scenario, &machine)?;
+        finish_network(network);
+        if !passed {
+            janitor::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    if args.watch {
+        let session = WatchSession {
+            args: &args,
+            config: &config,
+            project_root,
+            arch,
+            backend: &qemu,
+            app_name: app_name.as_str(),
+            uefi_root: uefi_root.as_path(),
+            firmware: &firmware,
+            disks: &disks,
+            options: &qemu_options,
+            convention: convention.as_ref(),
+            artifacts: artifacts_dir(project_root, app_name.as_str()),
+        };
+        return run_watch(&session, app_path, &plan, drive);
+    }
 
     // QEMUを実行
-    run_qemu(qemu_path.as_path(), ovmf_path.as_path(), uefi_root.as_path(), qemu_options)?;
+    output::event("run-started", serde_json::json!({ "app": app_name }));
+    let artifacts = artifacts_dir(project_root, app_name.as_str());
+    if let Some(memory_sweep) = &args.memory_sweep {
+        let sweep_dir = run_dir(&args, project_root, "sweep")?;
+        let _sweep = janitor::register_path(sweep_dir.as_path());
+        let attempts = sweep::sweep(memory_sweep, |status| qemu.exit_code(status, convention.as_ref()), |memory_args| {
+            // メモリの構成が変わるとファームウェアがUEFI変数を書き換えるため、毎回同じVARSイメージから始める
+            let run_firmware = firmware.with_vars_copy(sweep_dir.as_path())?;
+            provision(&config, project_root, &run_firmware, provision_dir.as_path()).map_err(|e| io::Error::other(e.to_string()))?;
+            let mut options = qemu_options.clone();
+            options.extend(memory_args);
+            run_machine(&args, &disks, &qemu, &run_firmware, &drive, options, artifacts.as_path())
+        })?;
+        finish_network(network);
+        if sweep::report(&attempts).is_none() {
+            janitor::exit(1);
+        }
+
+        return Ok(());
+    }
+    let mut export = args.artifacts_dir.as_deref().map(export::Export::new).transpose()?;
+    if export.is_some() {
+        std::fs::create_dir_all(artifacts.as_path())?;
+        qemu_options.extend(export::firmware_log_args(arch, artifacts.join(export::FIRMWARE_LOG).as_path(), &qemu_options));
+        // チェックポイントと `--boots` は自身でシリアルの出力を記録する
+        if boots.is_none() && config.checkpoints.is_empty() && seriallog::available(args.serial_tcp, &qemu_options) {
+            let log = artifacts.join("serial.log");
+            seriallog::prepare(log.as_path())?;
+            qemu_options.extend(seriallog::qemu_args(log.as_path()));
+        }
+    }
+    let started_at = std::time::SystemTime::now();
+    let mut checkpoints_passed = true;
+    let probe_run = probes.as_ref().map(|p| p.start());
+    let status = match boots {
+        Some(boots) => run_boots(&args, &qemu, boots, &qemu_options, artifacts.as_path(), convention.as_ref(), |options| {
+            run_machine(&args, &disks, &qemu, &firmware, &drive, options, artifacts.as_path())
+        })?,
+        None if !config.checkpoints.is_empty() => {
+            // 利用者がシリアルの出力先を指定している場合は変更しない
+            let logged = seriallog::available(args.serial_tcp, &qemu_options);
+            let log = artifacts.join("serial.log");
+            let (status, passed) = checkpoint::run(&config.checkpoints, qemu_options, logged.then_some(log.as_path()), |options| {
+                run_machine(&args, &disks, &qemu, &firmware, &drive, options, artifacts.as_path())
+            })?;
+            checkpoints_passed = passed;
+            status
+        }
+        None => run_machine(&args, &disks, &qemu, &firmware, &drive, qemu_options, artifacts.as_path())?,
+    };
+    let probes_passed = probe_run.is_none_or(|run| run.finish());
+    finish_network(network);
+    if let Some(name) = &args.save_vars_profile {
+        save_vars_profile(&firmware, project_root, name)?;
+    }
+    let verdict = run_verdict(&args, &qemu, status, convention.as_ref(), artifacts.as_path());
+    let code = match verdict.exit_code() {
+        0 if !checkpoints_passed || !probes_passed => 1,
+        code => code,
+    };
+    output::status(verdict.describe());
+    output::event("run-finished", run_finished(app_name.as_str(), status, code, &verdict));
+    if let Some(mut export) = export.take() {
+        let run = export::Run {
+            artifacts: artifacts.as_path(),
+            since: started_at,
+            images: export::generated_images(&drive, firmware.vars.as_deref(), &config.provision.disk_paths(provision_dir.as_path())),
+            result: run_finished(app_name.as_str(), status, code, &verdict),
+        };
+        export.collect(app_name.as_str(), &run)?;
+        export.finish()?;
+    }
+    if code != 0 {
+        janitor::exit(code);
+    }
 
     Ok(())
 }
 
-fn get_project_root() -> Result<path::PathBuf, io::Error> {
-    let cargo_name = "Cargo.lock";
+/// 全バイナリを先にまとめてビルドし、その後に1つずつQEMUで実行する。
+/// ワークスペースのメンバーが自身の設定でアーキテクチャ、ファームウェア、メモリ、デバイスを変えていれば、それに従う。
+/// 全てのビルドと実行が成功した場合に `true` を返す。
+fn run_all(
+    args: &Args,
+    config: &config::Config,
+    names: &[String],
+    overrides: &HashMap<String, member::MemberOverrides>,
+    project_root: &path::Path,
+    arch: target::Arch,
+    backend: &dyn VmBackend,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let no_overrides = member::MemberOverrides::default();
+    let member = |name: &str| overrides.get(name).unwrap_or(&no_overrides);
+    // コマンドラインの `--target` は、メンバーの設定より優先する
+    let member_arch = |name: &str| args.target.or(member(name).target).unwrap_or(arch);
+
+    let mut groups: Vec<(target::Arch, Vec<String>)> = Vec::new();
+    for name in names {
+        match groups.iter_mut().find(|(a, _)| *a == member_arch(name)) {
+            Some((_, group)) => group.push(name.clone()),
+            None => groups.push((member_arch(name), vec![name.clone()])),
+        }
+    }
+    for (arch, _) in groups.iter() {
+        check_shim_arch(args, *arch)?;
+    }
+    let output = match groups.as_slice() {
+        [(only, _)] => build::build_workspace(project_root, &args.build, *only, offline(args))?,
+        groups => {
+            let mut output = build::BuildOutput { artifacts: HashMap::new(), success: true };
+            for (arch, names) in groups {
+                let built = build::build_bins(project_root, names, &args.build, *arch, offline(args))?;
+                output.artifacts.extend(built.artifacts);
+                output.success &= built.success;
+            }
+            output
+        }
+    };
+    let failed = output.missing(names);
+    for name in failed.iter() {
+        output::status(msg!(BuildFailed, name));
+    }
+    output::event("build-finished", serde_json::json!({ "success": output.success, "failed": failed }));
+    if args.explain_staging {
+        for name in names.iter().filter(|n| !failed.contains(n)) {
+            let firmware = match needs_shell(args) {
+                true => Some(resolve_firmware(args, config, project_root, member_arch(name), Some(member(name)))?),
+                false => None,
+            };
+            let plan = staging_plan(args, config, project_root, member_arch(name), &output.artifacts[name], name, firmware.as_ref())?;
+            explain_staging(&plan, project_root, name)?;
+        }
+
+        return Ok(failed.is_empty());
+    }
+
+    // メンバーの設定でファームウェアを変えない限り、同じアーキテクチャでは同じファームウェアを使う
+    let default_arch = arch;
+    let mut shared_firmware: Vec<(target::Arch, firmware::Firmware)> = Vec::new();
+    let uefi_root = run_dir(args, project_root, "UEFI")?;
+    let vars_dir = run_dir(args, project_root, "vars")?;
+    let _vars = janitor::register_path(vars_dir.as_path());
+    let _staging = janitor::register_path(uefi_root.as_path());
+    let provision_dir = run_dir(args, project_root, "provision")?;
+    let _provision = janitor::register_path(provision_dir.as_path());
+    config.provision.create_disks(provision_dir.as_path(), project_root)?;
+    let disks = data_disks(args, config, provision_dir.as_path())?;
+    let mut common_options = disk::disk_args(&disks, &drive_options(args, config), project_root)?;
+    common_options.extend(config.provision.qemu_args());
+    let shared_devices = device_preset_args(args, config, &[])?;
+    let mut device_options = fw_cfg_args(args, config, project_root)?;
+    let network = impaired_network(args, config)?;
+    if let Some((_, network)) = &network {
+        device_options.extend(network.qemu_args(""));
+    }
+
+    let mut export = args.artifacts_dir.as_deref().map(export::Export::new).transpose()?;
+    for name in failed.iter() {
+        if let Some(export) = export.as_mut() {
+            export.build_failed(name);
+        }
+    }
+    let mut results = Vec::new();
+    let mut records: Vec<_> = failed.iter().map(|name| report::Record {
+        app: name.to_string(),
+        arch: member_arch(name),
+        firmware: None,
+        outcome: report::Outcome::BuildFailed,
+        duration: None,
+        serial_log: None,
+        screenshots: Vec::new(),
+    }).collect();
+    for name in names.iter().filter(|n| !failed.contains(n)) {
+        let (arch, member) = (member_arch(name), member(name));
+        member.report(name);
+        let firmware = match shared_firmware.iter().find(|(a, _)| *a == arch) {
+            Some((_, firmware)) if !member.overrides_firmware() => firmware.clone(),
+            _ => {
+                let firmware = vars_profile(args, resolve_firmware(args, config, project_root, arch, Some(member))?, project_root)?;
+                if !member.overrides_firmware() {
+                    shared_firmware.push((arch, firmware.clone()));
+                }
+                firmware
+            }
+        };
+        // `backend` は既定のアーキテクチャのQEMU
+        let member_qemu;
+        let backend = match arch == default_arch {
+            true => backend,
+            false => {
+                member_qemu = backend::Qemu::find(arch)?;
+                &member_qemu as &dyn VmBackend
+            }
+        };
+
+        let plan = staging_plan(args, config, project_root, arch, &output.artifacts[name], name, Some(&firmware))?;
+        stage(&plan, project_root, name, uefi_root.as_path())?;
+        imagebase::expect(&output.artifacts[name]);
+        let drive = boot_drive(args, config, project_root, uefi_root.as_path())?;
+        // 前のアプリケーションが書き換えたUEFI変数を引き継がないよう、実行ごとに複製し直す
+        let run_firmware = firmware.with_vars_copy(vars_dir.as_path())?;
+        provision(config, project_root, &run_firmware, provision_dir.as_path())?;
+
+        // プリセットと終了コードの受け渡しのデバイスは、メンバーのアーキテクチャに合わせる
+        let convention = exit_convention(args, config, arch)?;
+        let mut options = arch.machine_args();
+        options.extend(common_options.iter().cloned());
+        options.extend(preset_args(args, arch));
+        match member.with.is_empty() {
+            true => options.extend(shared_devices.iter().cloned()),
+            false => options.extend(device_preset_args(args, config, &member.with)?),
+        }
+        if let Some(convention) = &convention {
+            options.extend(convention.device_args(arch)?);
+        }
+        options.extend(device_options.iter().cloned());
+        // 利用者が `qemu-args` やコマンドラインで `-m` を指定していれば、そちらが後に来て優先される
+        options.extend(member.memory_args());
+        options.extend(config.qemu_args.iter().cloned());
+        options.extend(args.qemu_cmd.iter().cloned());
+        if !args.no_kvm {
+            options.extend(kvm::accel_args(arch, &options, kvm::probe));
+        }
+        // レポートに載せるシリアルの出力を記録する。利用者がシリアルの出力先を指定している場合は変更しない
+        let serial_logged = (args.html_report.is_some() || export.is_some()) && seriallog::available(args.serial_tcp, &options);
+
+        output::status(msg!(Running, name));
+        output::event("run-started", serde_json::json!({ "app": name }));
+        let artifacts = artifacts_dir(project_root, name);
+        let serial_log = artifacts.join("serial.log");
+        if serial_logged {
+            seriallog::prepare(serial_log.as_path())?;
+            options.extend(seriallog::qemu_args(serial_log.as_path()));
+        }
+        if export.is_some() {
+            std::fs::create_dir_all(artifacts.as_path())?;
+            options.extend(export::firmware_log_args(arch, artifacts.join(export::FIRMWARE_LOG).as_path(), &options));
+        }
+        let (started, started_at) = (std::time::Instant::now(), std::time::SystemTime::now());
+        let status = run_machine(args, &disks, backend, &run_firmware, &drive, options, artifacts.as_path())?;
+        let verdict = run_verdict(args, backend, status, convention.as_ref(), artifacts.as_path());
+        output::event("run-finished", run_finished(name, status, verdict.exit_code(), &verdict));
+        if let Some(export) = export.as_mut() {
+            let run = export::Run {
+                artifacts: artifacts.as_path(),
+                since: started_at,
+                images: export::generated_images(&drive, run_firmware.vars.as_deref(), &config.provision.disk_paths(provision_dir.as_path())),
+                result: run_finished(name, status, verdict.exit_code(), &verdict),
+            };
+            export.collect(name, &run)?;
+        }
+        results.push((name, verdict.clone()));
+        records.push(report::Record {
+            app: name.clone(),
+            arch,
+            firmware: Some(firmware.code.clone()),
+            outcome: report::Outcome::Finished(verdict),
+            duration: Some(started.elapsed()),
+            serial_log: serial_logged.then(|| serial_log.clone()),
+            screenshots: report::screenshots_since(artifacts.as_path(), started_at),
+        });
+    }
+    finish_network(network);
+
+    output::status("");
+    for name in failed.iter() {
+        output::status(msg!(BuildFailedSummary, name));
+    }
+    for (name, verdict) in results.iter() {
+        output::status(format!("{}: {}", name, verdict.describe()));
+    }
+
+    if let Some(path) = &args.html_report {
+        report::write(path.as_path(), &records)?;
+        output::status(msg!(ReportWritten, path.display()));
+        output::event("html-report", serde_json::json!({ "path": path }));
+    }
+
+    if let Some(export) = export {
+        export.finish()?;
+    }
+
+    let runs_passed = results.iter().all(|(_, verdict)| verdict.exit_code() == 0);
+    Ok(failed.is_empty() && runs_passed)
+}
+
+/// 実行の結果を分類する。`--shim` では、QEMUの終了状態ではなくアプリケーションが返したEFI_STATUSで決める
+fn run_verdict(args: &Args, backend: &dyn VmBackend, status: Option<ExitStatus>, convention: Option<&exit::ExitConvention>, artifacts: &path::Path) -> verdict::Verdict {
+    let shim = args.shim.then(|| shim::Outcome::read(artifacts.join(shim::LOG_NAME).as_path()));
+    if let Some(shim) = &shim {
+        shim.report();
+    }
+    verdict::classify(status, backend.exit_code(status, convention), shim.as_ref())
+}
+
+/// `run-finished` イベントの内容
+fn run_finished(app: &str, status: Option<ExitStatus>, exit_code: i32, verdict: &verdict::Verdict) -> serde_json::Value {
+    serde_json::json!({
+        "app": app,
+        "qemu-exit-code": status.and_then(|s| s.code()),
+        "timed-out": status.is_none(),
+        "exit-code": exit_code,
+        "result": verdict.to_json(),
+    })
+}
+
+fn offline(args: &Args) -> bool {
+    args.offline || fetch::offline_from_env()
+}
+
+/// 使用するファームウェアを決める。コマンドライン、環境変数 `OVMF_PATH`、設定ファイル、
+/// プロジェクトルートとシステムの既知の場所の順に探し、見つからなければ `--download-ovmf` の指定に従って取得する。
+/// `member` はワークスペースのメンバーの設定で、ファームウェアの指定があればワークスペースの設定より優先する
+fn resolve_firmware(
+    args: &Args,
+    config: &config::Config,
+    project_root: &path::Path,
+    arch: target::Arch,
+    member: Option<&member::MemberOverrides>,
+) -> Result<firmware::Firmware, Box<dyn std::error::Error>> {
+    let (firmware_paths, ovmf_prebuilt) = match member.filter(|m| m.overrides_firmware()) {
+        Some(member) => (member.firmware.clone(), member.ovmf_prebuilt.as_ref()),
+        None => (config.firmware.clone().map(|paths| paths.resolve(project_root)), config.ovmf_prebuilt.as_ref()),
+    };
+    // 署名の鍵はリリースではなく配布元に固定するものなので、タグを指定した場合も設定の鍵を使う
+    let configured_signature = ovmf_prebuilt.and_then(|p| p.signature.clone()).map(|mut signature| {
+        signature.resolve_key(project_root);
+        signature
+    });
+    let fetch = provider::Fetch {
+        mirrors: &config.mirrors,
+        wait_lock: !args.no_lock_wait,
+        allow_unverified: args.allow_unverified_firmware,
+        network: fetch::Network { offline: offline(args), proxy: config.proxy.clone() },
+    };
+
+    // コマンドラインで指定されたファームウェアは、設定の順序によらずそれだけを使う
+    let registry = if let Some(code) = &args.ovmf {
+        let paths = firmware::FirmwarePaths { code: code.clone(), vars: args.ovmf_vars.clone() };
+        provider::Registry::new(vec![Box::new(provider::LocalPath(Some(paths.resolve(env::current_dir()?.as_path()))))])
+    } else if let Some(tag) = &args.ovmf_prebuilt {
+        let source = firmware::OvmfPrebuilt { tag: tag.clone(), sha256: args.ovmf_prebuilt_sha256.clone(), signature: configured_signature };
+        provider::Registry::new(vec![Box::new(provider::Prebuilt { source: Some(source), fetch })])
+    } else {
+        let configured = ovmf_prebuilt.cloned().map(|source| firmware::OvmfPrebuilt { signature: configured_signature.clone(), ..source });
+        let order = config.firmware_order.as_deref();
+        // `firmware-order` に書かれていない方法は使わないため、`--download-ovmf` を黙って無視しないようにする
+        if args.download_ovmf && order.is_some_and(|o| !o.iter().any(|n| n == "download")) {
+            return Err(Box::new(error::Error::new(error::ErrorKind::InvalidArgument, msg!(DownloadOvmfNotInOrder))));
+        }
+        let registry = provider::Registry::new(vec![
+            Box::new(provider::EnvVar),
+            Box::new(provider::LocalPath(firmware_paths)),
+            Box::new(provider::Prebuilt { source: configured, fetch: fetch.clone() }),
+            Box::new(provider::ProjectFile(project_root)),
+            Box::new(provider::SystemSearch),
+            Box::new(provider::Nix { offline: offline(args) }),
+            Box::new(provider::Plugin { command: &config.firmware_plugin, root: project_root }),
+            Box::new(provider::Download {
+                enabled: args.download_ovmf || order.is_some_and(|o| o.iter().any(|n| n == "download")),
+                signature: configured_signature,
+                fetch,
+            }),
+        ]);
+        match order {
+            Some(order) => registry.ordered(order)?,
+            None => registry,
+        }
+    };
+    let firmware = registry.resolve(arch);
+
+    if let Ok(firmware) = &firmware {
+        crash::set_firmware(firmware);
+    }
+    firmware
+}
+
+/// 一時ファイルの配置先を返す。
+/// /tmp が小さなtmpfsであることが多いため、既定ではプロジェクトのtargetディレクトリ以下を使う。
+fn temp_root(args: &Args, project_root: &path::Path) -> path::PathBuf {
+    args.temp_root.clone()
+        .unwrap_or_else(|| project_root.join("target").join("uefi").join("tmp"))
+}
+
+/// `--ci` で `--timeout` を省略した場合の秒数
+const CI_TIMEOUT_SECS: u64 = 300;
+
+/// `--memory-sweep` で `--timeout` を省略した場合の、1回の起動の秒数
+const SWEEP_TIMEOUT_SECS: u64 = 60;
+
+/// QEMUを終了させるまでの時間。`--ci` では、ゲストが止まってもパイプラインが止まらないよう既定で制限する。
+/// `--memory-sweep` でも、メモリが足りずに止まった起動を失敗として次に進めるよう制限する
+fn qemu_timeout(args: &Args) -> Option<Duration> {
+    args.timeout
+        .or(args.ci.then_some(CI_TIMEOUT_SECS))
+        .or(args.memory_sweep.map(|_| SWEEP_TIMEOUT_SECS))
+        .map(Duration::from_secs)
+}
+
+/// 設定ファイルのI/O設定に、コマンドラインで指定された設定を重ねる
+fn drive_options(args: &Args, config: &config::Config) -> disk::DriveOptions {
+    let cli = disk::DriveOptions {
+        cache: args.drive_cache,
+        aio: args.drive_aio,
+        discard: args.drive_discard.then_some(true),
+    };
+
+    config.drive.merge(&cli)
+}
+
+/// 設定ファイルとコマンドラインで指定された、ゲストからの終了コードの受け渡し方法を返す。
+/// `--ci` が暗黙に有効にするものは、isa-debug-exitのないaarch64では使わない
+fn exit_convention(args: &Args, config: &config::Config, arch: target::Arch) -> Result<Option<exit::ExitConvention>, error::Error> {
+    let requested = args.exit_device || args.exit_iobase.is_some() || args.exit_success.is_some();
+    let enabled = requested || (args.ci && arch != target::Arch::Aarch64);
+    let mut convention = match (&config.exit, enabled) {
+        (Some(convention), _) => convention.clone(),
+        (None, true) => exit::ExitConvention::default(),
+        (None, false) => return Ok(None),
+    };
+
+    if let Some(iobase) = args.exit_iobase {
+        convention.iobase = iobase;
+    }
+    if let Some(success) = args.exit_success {
+        convention.success = success;
+    }
+    convention.validate()?;
+
+    Ok(Some(convention))
+}
+
+/// 設定ファイルとコマンドラインで指定されたデータディスクと、`provision_dir` に用意するデータディスクを返す。
+/// 設定ファイル中の相対パスはプロジェクトルート、コマンドライン引数の相対パスはカレントディレクトリを基準にする
+fn data_disks(args: &Args, config: &config::Config, provision_dir: &path::Path) -> Result<Vec<disk::DiskConfig>, io::Error> {
     let current_dir = env::current_dir()?;
-    let mut ancestors = current_dir.ancestors();
+    let mut disks = config.disks.clone();
+    disks.extend(args.disks.iter().map(|p| disk::DiskConfig::from_path(current_dir.join(p))));
+    disks.extend(config.provision.disk_paths(provision_dir).into_iter().map(disk::DiskConfig::from_path));
 
-    ancestors.find(|path| path.join(cargo_name).is_file())
-        .map(|p| p.to_path_buf())
-        .ok_or(io::Error::new(io::ErrorKind::NotFound, "project root directory not found"))
+    Ok(disks)
+}
+
+/// 前の実行の状態を引き継がないよう、データディスクを作り直し、UEFI変数を書き込む
+fn provision(config: &config::Config, project_root: &path::Path, firmware: &firmware::Firmware, provision_dir: &path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    config.provision.create_disks(provision_dir, project_root)?;
+    config.provision.write_variables(firmware, project_root)?;
+    config.provision.report();
+
+    Ok(())
+}
+
+/// ESPの内容やUEFI変数の複製を置く、この実行だけが使うディレクトリを返す。
+/// 異常終了したプロセスと同じプロセスIDになった場合に備え、残っていた内容は消しておく
+fn run_dir(args: &Args, project_root: &path::Path, name: &str) -> Result<path::PathBuf, io::Error> {
+    let dir = temp_root(args, project_root).join(format!("{}-{}", name, std::process::id()));
+    match std::fs::remove_dir_all(dir.as_path()) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(dir),
+    }
 }
 
-fn get_qemu_executable() -> Result<path::PathBuf, io::Error> {
-    let qemu_name = "qemu-system-x86_64";
+/// アプリケーションと、設定ファイルやコマンドラインで指定されたファイルをESPに配置する計画を立てる。
+/// `--stamp-build-info` では、ビルド情報を埋め込みながらアプリケーションを配置する
+fn staging_plan(
+    args: &Args,
+    config: &config::Config,
+    project_root: &path::Path,
+    arch: target::Arch,
+    app_path: &path::Path,
+    app_name: &str,
+    firmware: Option<&firmware::Firmware>,
+) -> Result<staging::Plan, Box<dyn std::error::Error>> {
+    let transform = args.stamp_build_info
+        .then(|| staging::Transform::StampBuildInfo(buildinfo::BuildInfo::collect(project_root, app_path)));
+    let mut plan = staging::Plan::new(args.layout, arch, app_path, app_name, args.systemd_boot.as_deref(), transform)?;
+
+    let current_dir = env::current_dir()?;
+    let mut files: Vec<_> = config.files.iter().cloned().map(|f| f.resolve(project_root)).collect();
+    files.extend(args.extra_files.iter().cloned().map(|f| f.resolve(current_dir.as_path())));
+    plan.add_files(&files)?;
 
-    let exec_path = env::var_os("PATH").and_then(|paths| {
-        env::split_paths(&paths).filter_map(|path| {
-            let full_path = path.join(qemu_name);
-            if full_path.is_file() {
-                Some(full_path)
-            } else {
-                None
+    if args.stage_shell {
+        match firmware.and_then(|f| f.shell.as_ref()) {
+            Some(shell) => plan.add_shell(shell),
+            None => return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, msg!(ShellNotProvided)))),
+        }
+    }
+    if let Some(Command::ShellTools(tools_args)) = &args.command {
+        let network = fetch::Network { offline: offline(args), proxy: config.proxy.clone() };
+        let bundle = config.shell_tools.as_ref()
+            .map(|bundle| shelltools::fetch_bundle(bundle, !args.no_lock_wait, &network))
+            .transpose()?;
+        // バンドルの設定がなく、ファームウェアにもUEFI Shellがなければ、SHA-256を固定した既定のリリースに含まれるものを使う
+        let fallback = match (&bundle, firmware.and_then(|f| f.shell.as_ref())) {
+            (None, None) => Some(firmware::ovmf_prebuilt(
+                &firmware::OvmfPrebuilt::default_release(None),
+                arch,
+                &config.mirrors,
+                !args.no_lock_wait,
+                args.allow_unverified_firmware,
+                &network,
+            )?),
+            _ => None,
+        };
+        let tools = shelltools::select(&tools_args.tools, bundle.as_deref(), arch, fallback.as_ref().or(firmware))?;
+        shelltools::report(&tools);
+        plan.add_files(&tools)?;
+    }
+    if args.shim {
+        plan.interpose_shim(arch, app_path)?;
+    }
+
+    Ok(plan)
+}
+
+/// 検証用のシムを使えないアーキテクチャでは、ビルドする前に知らせる
+fn check_shim_arch(args: &Args, arch: target::Arch) -> Result<(), error::Error> {
+    match args.shim && arch != target::Arch::X86_64 {
+        true => Err(error::Error::new(error::ErrorKind::InvalidArgument, msg!(ShimUnsupportedArch, arch.rust_target()))),
+        false => Ok(()),
+    }
+}
+
+/// ファームウェアに付属するUEFI Shellを配置するか
+fn needs_shell(args: &Args) -> bool {
+    args.stage_shell || matches!(args.command, Some(Command::ShellTools(_)))
+}
+
+/// 前回配置したESPの内容の記録
+fn staged_manifest(project_root: &path::Path, app_name: &str) -> path::PathBuf {
+    project_root.join("target").join("uefi").join("staged").join(format!("{}.json", app_name))
+}
+
+/// 配置計画に従ってESPにファイルを配置し、`--explain-staging` で比べられるよう配置した内容を記録する
+fn stage(plan: &staging::Plan, project_root: &path::Path, app_name: &str, esp_root: &path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    plan.apply(esp_root)?;
+    if let Some(info) = plan.build_info() {
+        output::status(msg!(BuildInfoStamped, app_name, info.summary()));
+        output::event("build-info-stamped", serde_json::json!({ "app": app_name, "build": info.to_json() }));
+    }
+    plan.manifest(esp_root)?.save(staged_manifest(project_root, app_name).as_path())?;
+
+    Ok(())
+}
+
+/// `--explain-staging` で、配置計画と前回の配置から変わる内容を表示する
+fn explain_staging(plan: &staging::Plan, project_root: &path::Path, app_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let previous = staging::Manifest::load(staged_manifest(project_root, app_name).as_path())?;
+    staging::explain(plan, app_name, previous.as_ref())
+}
+
+/// 設定ファイルとコマンドラインで指定されたfw_cfgのデータを渡すQEMUの引数を返す。
+/// 相対パスの基準は `data_disks` と同じ
+fn fw_cfg_args(args: &Args, config: &config::Config, project_root: &path::Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let current_dir = env::current_dir()?;
+    let mut entries: Vec<_> = config.fw_cfg.iter().cloned().map(|e| e.resolve(project_root)).collect();
+    entries.extend(args.fw_cfg.iter().cloned().map(|e| e.resolve(current_dir.as_path())));
+
+    Ok(fwcfg::fw_cfg_args(&entries)?)
+}
+
+/// `--preset` で選んだ構成のQEMUの引数
+fn preset_args(args: &Args, arch: target::Arch) -> Vec<String> {
+    match args.preset {
+        Some(preset) => {
+            let qemu_args = preset.qemu_args(arch);
+            output::status(msg!(PresetApplied, preset.name(), qemu_args.join(" ")));
+            output::event("preset", serde_json::json!({ "name": preset.name(), "qemu-args": qemu_args }));
+            qemu_args
+        }
+        None => Vec::new(),
+    }
+}
+
+/// 設定ファイルの `with`、ワークスペースのメンバーの `with`（`member_with`）と `--with` で選んだデバイスプリセットのQEMUの引数
+fn device_preset_args(args: &Args, config: &config::Config, member_with: &[String]) -> Result<Vec<String>, error::Error> {
+    let mut names = config.with.clone();
+    names.extend(member_with.iter().cloned());
+    names.extend(args.with.iter().cloned());
+
+    let mut qemu_args = Vec::new();
+    for (name, device_args) in preset::device_args(&names, &config.device_presets)? {
+        output::status(msg!(DevicePresetApplied, name, device_args.join(" ")));
+        output::event("device-preset", serde_json::json!({ "name": name, "qemu-args": device_args }));
+        qemu_args.extend(device_args);
+    }
+
+    Ok(qemu_args)
+}
+
+/// 設定ファイルかコマンドラインでネットワークの制限が指定されていれば、中継を開始する。コマンドラインの指定を優先する
+fn impaired_network(args: &Args, config: &config::Config) -> Result<Option<(netem::Impairment, netem::ImpairedNetwork)>, Box<dyn std::error::Error>> {
+    let impairment = match args.net_impair.as_ref().or(config.network_impairment.as_ref()) {
+        Some(impairment) => impairment.clone(),
+        None => return Ok(None),
+    };
+    impairment.validate()?;
+    let network = netem::ImpairedNetwork::start(&impairment, ports::PortAllocator::new(ports::lock_dir(), &args.ports))?;
+
+    Ok(Some((impairment, network)))
+}
+
+fn finish_network(network: Option<(netem::Impairment, netem::ImpairedNetwork)>) {
+    if let Some((impairment, network)) = network {
+        netem::report(&impairment, &network.finish());
+    }
+}
+
+/// `--vars-profile` が指定されていれば、VARSイメージをそのプロファイルのものに置き換える
+fn vars_profile(args: &Args, firmware: firmware::Firmware, project_root: &path::Path) -> Result<firmware::Firmware, error::Error> {
+    match &args.vars_profile {
+        Some(name) => varstore::apply(&firmware, varstore::varstore_dir(project_root).as_path(), name),
+        None => Ok(firmware),
+    }
+}
+
+/// 実行後のVARSイメージを `--save-vars-profile` のプロファイルとして保存する
+fn save_vars_profile(firmware: &firmware::Firmware, project_root: &path::Path, name: &str) -> Result<(), error::Error> {
+    let vars = firmware.vars.as_deref().ok_or_else(|| error::Error::new(
+        error::ErrorKind::InvalidArgument,
+        msg!(VarsProfileNoVars, firmware.code.display())
+    ))?;
+    let profile = varstore::save(vars, varstore::varstore_dir(project_root).as_path(), name)?;
+    output::status(msg!(VarsProfileSaved, name, profile.display()));
+    output::event("vars-profile-saved", serde_json::json!({ "name": name, "path": profile }));
+
+    Ok(())
+}
+
+/// ゲストの要求で書き出すファイルの保存先
+fn artifacts_dir(project_root: &path::Path, app_name: &str) -> path::PathBuf {
+    project_root.join("target").join("uefi").join("artifacts").join(app_name)
+}
+
+/// `--boots` の回数だけQEMUを起動し直す。VARSイメージとディスクは同じものを使い続けるので、前回の起動で書き換えた内容が次の起動に残る。
+/// シリアルの出力は標準入出力に出しつつ、起動ごとに `boot-<N>.log` へ記録する。
+/// `--power-cut` が指定されていれば、最後以外の起動では条件を満たした時点で電源を断ち、最後の起動で回復できるかを確かめる。
+/// 成功しなかった起動があればそこで止め、その終了状態を返す
+fn run_boots(
+    args: &Args,
+    backend: &dyn VmBackend,
+    boots: u32,
+    options: &[String],
+    artifacts: &path::Path,
+    convention: Option<&exit::ExitConvention>,
+    mut run: impl FnMut(Vec<String>) -> Result<Option<ExitStatus>, io::Error>,
+) -> Result<Option<ExitStatus>, io::Error> {
+    // 利用者がシリアルの出力先を指定している場合は変更しない
+    let logged = seriallog::available(args.serial_tcp, options);
+    if let Some(cut) = &args.power_cut {
+        if boots < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg!(PowerCutNeedsBoots)));
+        }
+        if cut.marker.is_some() && !logged {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg!(PowerCutNeedsSerialLog)));
+        }
+    }
+    let seed = args.power_cut_seed.unwrap_or_else(powercut::Rng::seed_from_time);
+    let mut rng = powercut::Rng::new(seed);
+    // 同じ時刻で再現できるよう、無作為に選ぶ場合はシードを示す
+    if let Some(powercut::Delay::Random(..)) = args.power_cut.as_ref().map(|c| c.delay) {
+        output::status(msg!(PowerCutSeed, seed));
+    }
+
+    let mut status = None;
+    for boot in 1..=boots {
+        let log = artifacts.join(format!("boot-{}.log", boot));
+        let mut options = options.to_vec();
+        if logged {
+            seriallog::prepare(log.as_path())?;
+            options.extend(seriallog::qemu_args(log.as_path()));
+        }
+        // ポートは電源を断つまで予約しておく
+        let mut ports = ports::PortAllocator::new(ports::lock_dir(), &args.ports);
+        let cut = match &args.power_cut {
+            Some(cut) if boot < boots => {
+                let addr = ports.allocate("power-cut-qmp")?;
+                options.extend(backend.control_args(addr));
+                let delay = cut.delay.resolve(&mut rng);
+                Some((cut, powercut::PowerCutMonitor::start(cut, delay, addr, logged.then(|| log.clone()))))
             }
-        }).next()
-    });
+            _ => None,
+        };
+
+        output::status(msg!(BootStarted, boot, boots));
+        output::event("boot-started", serde_json::json!({ "boot": boot, "boots": boots, "serial-log": logged.then_some(&log) }));
+        status = run(options)?;
+        let cut_after = match cut {
+            Some((cut, monitor)) => match monitor.finish()? {
+                Some(elapsed) => {
+                    output::status(msg!(PowerCutInjected, boot, format!("{:.3}", elapsed.as_secs_f64())));
+                    output::event("power-cut", serde_json::json!({
+                        "boot": boot,
+                        "mode": cut.mode.name(),
+                        "marker": cut.marker,
+                        "elapsed": elapsed.as_secs_f64(),
+                    }));
+                    Some(elapsed)
+                }
+                None => {
+                    output::warning(msg!(PowerCutMissed, boot));
+                    None
+                }
+            },
+            None => None,
+        };
+
+        // 電源を断った起動の終了状態はゲストの結果ではないので、成否を問わない
+        let code = match cut_after {
+            Some(_) => 0,
+            None => backend.exit_code(status, convention),
+        };
+        output::event("boot-finished", serde_json::json!({
+            "boot": boot,
+            "qemu-exit-code": status.and_then(|s| s.code()),
+            "timed-out": status.is_none(),
+            "power-cut": cut_after.is_some(),
+            "exit-code": code,
+        }));
+        if code != 0 {
+            output::status(msg!(BootFailed, boot, boots, code));
+            break;
+        }
+    }
+
+    Ok(status)
+}
+
+/// `--watch` で起動し直すたびに使う、実行の設定
+struct WatchSession<'a> {
+    args: &'a Args,
+    config: &'a config::Config,
+    project_root: &'a path::Path,
+    arch: target::Arch,
+    backend: &'a dyn VmBackend,
+    app_name: &'a str,
+    uefi_root: &'a path::Path,
+    firmware: &'a firmware::Firmware,
+    disks: &'a [disk::DiskConfig],
+    options: &'a [String],
+    convention: Option<&'a exit::ExitConvention>,
+    artifacts: path::PathBuf,
+}
+
+impl WatchSession<'_> {
+    /// cargoでビルドするアプリケーションか。ビルドしない場合はアプリケーション自体も配置するデータとして監視する
+    fn builds(&self) -> bool {
+        self.args.app.is_none() && !self.args.build.no_build
+    }
+
+    fn assets(&self, plan: &staging::Plan, app_path: &path::Path) -> Vec<path::PathBuf> {
+        watch::assets(plan, self.builds().then_some(app_path))
+    }
+
+    /// 変更に従ってビルドし直すか配置し直し、起動に使うドライブと監視するデータを返す
+    fn reload(&self, change: &watch::Change, app_path: &mut path::PathBuf) -> Result<(image::BootDrive, Vec<path::PathBuf>), Box<dyn std::error::Error>> {
+        let rebuild = matches!(change, watch::Change::Sources(_));
+        match rebuild {
+            true => output::status(msg!(WatchRebuild, change.path().display())),
+            false => output::status(msg!(WatchRestage, change.path().display())),
+        }
+        output::event("watch-reload", serde_json::json!({ "app": self.app_name, "trigger": change.path(), "rebuild": rebuild }));
+        if rebuild {
+            *app_path = app_artifact(self.args, self.project_root, self.app_name)?;
+        }
+
+        let plan = staging_plan(self.args, self.config, self.project_root, self.arch, app_path, self.app_name, Some(self.firmware))?;
+        stage(&plan, self.project_root, self.app_name, self.uefi_root)?;
+        imagebase::expect(app_path);
+        let drive = boot_drive(self.args, self.config, self.project_root, self.uefi_root)?;
+
+        Ok((drive, self.assets(&plan, app_path)))
+    }
+}
 
-    exec_path.ok_or(io::Error::new(io::ErrorKind::NotFound, format!("{} is not found", qemu_name)))
+/// `--watch` でVMを起動し、ファイルが変わるたびにVMを止めて起動し直す。中断されるまで戻らない
+fn run_watch(session: &WatchSession, mut app_path: path::PathBuf, plan: &staging::Plan, mut drive: image::BootDrive) -> Result<(), Box<dyn std::error::Error>> {
+    let sources = match session.builds() {
+        true => {
+            let manifest = std::fs::read_to_string(session.project_root.join("Cargo.toml"))?;
+            watch::package_inputs(&package_dirs(manifest.as_str(), session.project_root)?)
+        }
+        false => Vec::new(),
+    };
+    // VMが書き込むディスクイメージや一時ファイルの変更で、起動し直し続けないようにする
+    // 設定ファイルのディスクはプロジェクトルートからの相対パス
+    let mut excluded: Vec<_> = session.disks.iter().map(|d| session.project_root.join(d.file.as_path())).collect();
+    excluded.push(env::current_dir()?.join(temp_root(session.args, session.project_root)));
+    let mut watcher = watch::Watcher::new(sources, excluded, session.assets(plan, app_path.as_path()));
+    loop {
+        output::event("run-started", serde_json::json!({ "app": session.app_name }));
+        let running = std::sync::atomic::AtomicBool::new(true);
+        let (status, change) = std::thread::scope(|scope| {
+            let watching = scope.spawn(|| {
+                let change = watcher.wait_while(|| running.load(std::sync::atomic::Ordering::SeqCst));
+                if change.is_some() {
+                    supervise::request_restart();
+                }
+                change
+            });
+            let status = run_machine(session.args, session.disks, session.backend, session.firmware, &drive, session.options.to_vec(), session.artifacts.as_path());
+            running.store(false, std::sync::atomic::Ordering::SeqCst);
+            (status, watching.join().expect("the watcher does not panic"))
+        });
+        let restarted = supervise::take_restart();
+
+        match status {
+            // 変更を見つけて止めた実行は結果を報告しない
+            Ok(None) if restarted => {}
+            Ok(status) => {
+                let verdict = run_verdict(session.args, session.backend, status, session.convention, session.artifacts.as_path());
+                output::status(verdict.describe());
+                output::event("run-finished", run_finished(session.app_name, status, verdict.exit_code(), &verdict));
+            }
+            Err(e) => output::warning(e),
+        }
+        let mut change = change.unwrap_or_else(|| {
+            output::status(msg!(WatchWaiting));
+            watcher.wait()
+        });
+
+        // ビルドや配置に失敗した場合は、次の変更で直されるのを待つ
+        loop {
+            match session.reload(&change, &mut app_path) {
+                Ok((reloaded, assets)) => {
+                    drive = reloaded;
+                    watcher.set_assets(assets);
+                    break;
+                }
+                Err(e) => {
+                    output::warning(msg!(WatchReloadFailed, e));
+                    change = watcher.wait();
+                }
+            }
+        }
+    }
 }
 
-fn get_ovmf(project_root_dir: &path::Path) -> Result<path::PathBuf, io::Error> {
-    let ovmf_name = "OVMF.fd";
+/// QEMUを実行する。`--report-discard` が指定されていれば、実行中のブロックデバイスの統計を集めて報告する。
+/// `--guest-control` が指定されていれば、ゲストからの要求を処理する
+fn run_machine(
+    args: &Args,
+    disks: &[disk::DiskConfig],
+    backend: &dyn VmBackend,
+    firmware: &firmware::Firmware,
+    drive: &image::BootDrive,
+    mut options: Vec<String>,
+    artifacts: &path::Path,
+) -> Result<Option<ExitStatus>, io::Error> {
+    verdict::reset();
+    // ポートはQEMUが終了するまで予約しておく
+    let mut ports = ports::PortAllocator::new(ports::lock_dir(), &args.ports);
+    if args.vnc {
+        // VNCはディスプレイ番号で指定するため、5900番以上のポートに限る
+        let addr = ports.allocate_matching("vnc", |p| p >= ports::VNC_BASE)?;
+        let display = addr.port().checked_sub(ports::VNC_BASE)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, msg!(PortNotAccepted, addr.port(), "vnc")))?;
+        options.extend(["-vnc".to_string(), format!("{}:{}", addr.ip(), display)]);
+    }
+    if args.serial_tcp {
+        let addr = ports.allocate("serial")?;
+        options.extend(["-serial".to_string(), format!("tcp:{},server=on,wait=off", addr)]);
+    }
+    // `--boots` やチェックポイント、成果物の書き出しがシリアルの出力を記録する設定を加えた後で、残りを補う
+    if args.ci {
+        options.extend(output::headless_args(&options));
+    }
+
+    let control = match args.guest_control {
+        true => Some(control::ControlServer::start(artifacts.to_path_buf(), &mut ports)?),
+        false => None,
+    };
+    if let Some(control) = &control {
+        options.extend(control.qemu_args());
+    }
+
+    let freeze = freeze::FreezeOptions {
+        // `--boots` ではゲストのリセットでQEMUを終了させ、次の起動として数える
+        // `--ci` ではリセットを繰り返してタイムアウトまで待つより、失敗として早く終える
+        no_reboot: args.no_reboot || args.boots.is_some() || args.power_cut.is_some() || args.ci,
+        no_shutdown: args.no_shutdown,
+        freeze_on_crash: args.freeze_on_crash || args.attach_gdb,
+        attach_gdb: args.attach_gdb,
+    };
+    let markers = breakpoint::markers();
+    if !markers.is_empty() && breakpoint::serial_redirected(args.serial_tcp, &options) {
+        output::warning(msg!(BreakOnSerialRedirected));
+    }
+    let gdb_addr = match freeze.freeze_on_crash || !markers.is_empty() {
+        true => Some(ports.allocate("gdb")?),
+        false => None,
+    };
+    let crash_monitor = match (freeze.freeze_on_crash, gdb_addr) {
+        (true, Some(gdb_addr)) => {
+            let qmp_addr = ports.allocate("crash-qmp")?;
+            options.extend(freeze.qemu_args(Some((qmp_addr, gdb_addr))));
+            Some(freeze::CrashMonitor::start(freeze, qmp_addr, gdb_addr))
+        }
+        _ => {
+            options.extend(freeze.qemu_args(None));
+            None
+        }
+    };
+    let break_monitor = match (markers.is_empty(), gdb_addr) {
+        (false, Some(gdb_addr)) => {
+            let qmp_addr = ports.allocate("break-qmp")?;
+            options.extend(breakpoint::qemu_args(qmp_addr, (!freeze.freeze_on_crash).then_some(gdb_addr)));
+            Some(breakpoint::BreakMonitor::start(markers, args.attach_gdb, qmp_addr, gdb_addr))
+        }
+        _ => None,
+    };
+
+    if args.shim {
+        // 前の実行の結果を読まないよう、毎回空にする
+        std::fs::create_dir_all(artifacts)?;
+        let log = artifacts.join(shim::LOG_NAME);
+        std::fs::write(log.as_path(), b"")?;
+        options.extend(shim::qemu_args(log.as_path()));
+    }
+
+    let trace_log = artifacts.join("qemu-trace.log");
+    if let Some(items) = &args.qemu_trace {
+        std::fs::create_dir_all(artifacts)?;
+        options.extend(items.qemu_args(trace_log.as_path()));
+    }
 
-    let ovmf_path = project_root_dir.join(ovmf_name); 
-    if ovmf_path.is_file() {
-        Ok(ovmf_path)
+    let stderr_log = artifacts.join("qemu-stderr.log");
+    if !args.report_discard {
+        ports.report();
+        let vm = VmConfig { firmware, drive, options };
+        let status = supervise::run_vm(backend, &vm, qemu_timeout(args), Some(stderr_log.as_path()))?;
+        finish_control(control, artifacts);
+        finish_crash_monitor(crash_monitor);
+        finish_break_monitor(break_monitor);
+        finish_trace(args, trace_log.as_path());
+        return Ok(status);
+    }
+
+    let addr = ports.allocate("qmp")?;
+    options.extend(backend.control_args(addr));
+    ports.report();
+    let monitor = qmp::BlockStatsMonitor::start(addr);
+    let vm = VmConfig { firmware, drive, options };
+    let status = supervise::run_vm(backend, &vm, qemu_timeout(args), Some(stderr_log.as_path()))?;
+    finish_control(control, artifacts);
+    finish_crash_monitor(crash_monitor);
+    finish_break_monitor(break_monitor);
+    finish_trace(args, trace_log.as_path());
+
+    match monitor.finish() {
+        Ok(stats) => {
+            if !output::quiet() {
+                eprint!("{}", disk::discard_report(disks, &stats));
+            }
+            let devices: Vec<_> = stats.iter().map(|s| serde_json::json!({
+                "device": s.device,
+                "unmap-operations": s.stats.unmap_operations,
+                "unmap-bytes": s.stats.unmap_bytes,
+            })).collect();
+            output::event("block-stats", serde_json::json!({ "devices": devices }));
+        }
+        Err(e) => output::warning(msg!(BlockStatsFailed, e)),
+    }
+
+    Ok(status)
+}
+
+fn finish_control(control: Option<control::ControlServer>, artifacts: &path::Path) {
+    let summary = match control.map(|c| c.finish()) {
+        Some(Ok(summary)) => summary,
+        Some(Err(e)) => return output::warning(e),
+        None => return,
+    };
+    if !summary.checkpoints.is_empty() {
+        output::status(msg!(CheckpointsSummary));
+        for (name, elapsed) in summary.checkpoints {
+            output::status(format!("  {:<24} {:>10.3}s", name, elapsed.as_secs_f64()));
+        }
+    }
+    // ゲストが報告した読み込み先で、後からGDBでシンボルを読めるようにする
+    if let Some(symbolizer) = summary.image.and_then(|_| imagebase::symbolizer()) {
+        let script = artifacts.join("image.gdb");
+        match std::fs::create_dir_all(artifacts).and_then(|_| std::fs::write(script.as_path(), symbolizer.gdb_script())) {
+            Ok(()) => {
+                output::status(msg!(GdbScriptWritten, script.display()));
+                output::event("gdb-script", serde_json::json!({ "path": script, "base": symbolizer.image().base }));
+            }
+            Err(e) => output::warning(e),
+        }
+    }
+}
+
+fn finish_crash_monitor(monitor: Option<freeze::CrashMonitor>) {
+    match monitor.map(|m| m.finish()) {
+        Some(Ok(Some(stop))) => verdict::record_stop(stop),
+        Some(Err(e)) => output::warning(e),
+        _ => {}
+    }
+}
+
+fn finish_break_monitor(monitor: Option<breakpoint::BreakMonitor>) {
+    if let Some(Err(e)) = monitor.map(|m| m.finish()) {
+        output::warning(e);
+    }
+}
+
+/// `--qemu-trace` のログを集計して報告する
+fn finish_trace(args: &Args, log: &path::Path) {
+    if args.qemu_trace.is_none() {
+        return;
+    }
+
+    let summary = std::fs::File::open(log).and_then(|f| trace::summarize(io::BufReader::new(f)));
+    match summary {
+        Ok(summary) => {
+            if !output::quiet() {
+                eprint!("{}", summary.render(log));
+            }
+            output::event("trace-summary", summary.to_json(log));
+        }
+        Err(e) => output::warning(msg!(TraceSummaryFailed, log.display(), e)),
+    }
+}
+
+fn boot_drive(args: &Args, config: &config::Config, project_root: &path::Path, uefi_root: &path::Path) -> Result<image::BootDrive, Box<dyn std::error::Error>> {
+    if args.image || args.image_size.is_some() {
+        let cache_dir = project_root.join("target").join("uefi").join("images");
+        let options = drive_options(args, config);
+        options.validate()?;
+
+        let image = image::cached_image(uefi_root, cache_dir.as_path(), args.image_size, !args.no_lock_wait)?;
+        Ok(image::BootDrive::Image(image, options))
     } else {
-        Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not found", ovmf_name)))
+        Ok(image::BootDrive::Directory(uefi_root.to_path_buf()))
     }
 }
 
-fn get_uefi_app(project_root_dir: &path::Path, app_name: &str) -> Result<path::PathBuf, io::Error> {
+fn get_project_root() -> Result<path::PathBuf, io::Error> {
+    let cargo_name = "Cargo.lock";
+    let current_dir = env::current_dir()?;
+    let mut ancestors = current_dir.ancestors();
+
+    ancestors.find(|path| path.join(cargo_name).is_file())
+        .map(|p| p.to_path_buf())
+        .ok_or(io::Error::new(io::ErrorKind::NotFound, msg!(ProjectRootNotFound)))
+}
+
+/// アプリケーションのEFIファイルを返す。`--no-build` が指定されていなければ、先にcargoでビルドする
+fn app_artifact(args: &Args, project_root: &path::Path, app_name: &str) -> Result<path::PathBuf, Box<dyn std::error::Error>> {
+    let arch = args.target.unwrap_or_default();
+    if args.build.no_build {
+        return Ok(get_uefi_app(project_root, arch, args.build.profile_dir(), app_name)?);
+    }
+
+    output::status(msg!(Building, app_name));
+    let artifact = build::build_bin(project_root, app_name, &args.build, arch, offline(args));
+    output::event("build-finished", serde_json::json!({
+        "success": artifact.is_ok(),
+        "failed": if artifact.is_ok() { vec![] } else { vec![app_name] },
+    }));
+
+    artifact
+}
+
+fn get_uefi_app(project_root_dir: &path::Path, arch: target::Arch, profile_dir: &str, app_name: &str) -> Result<path::PathBuf, io::Error> {
     let mut app_path = project_root_dir.to_path_buf();
     app_path.push("target");
-    app_path.push("x86_64-unknown-uefi");
-    app_path.push("debug");
+    app_path.push(arch.rust_target());
+    app_path.push(profile_dir);
     app_path.push(format!("{}.efi", app_name));
 
     if app_path.is_file() {
         Ok(app_path)
     } else {
-        Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not found", app_name)))
+        Err(io::Error::new(io::ErrorKind::NotFound, msg!(NotFound, app_name)))
     }
 }
 
-fn run_qemu(qemu: &path::Path, ovmf: &path::Path, uefi_root: &path::Path, options: Vec<String>) -> Result<ExitStatus, io::Error> { 
-    let mut process = std::process::Command::new(qemu.display().to_string())
-        .arg("-drive")
-        .arg(format!("if=pflash,format=raw,readonly=on,file={}", ovmf.display())) 
-        .arg("-drive")
-        .arg(format!("format=raw,file=fat:rw:{}", uefi_root.display()))
-        .args(options)
-        .stdin(std::process::Stdio::inherit())
-        .stdout(std::process::Stdio::inherit())
-        .stderr(std::process::Stdio::inherit())
-        .spawn()?;
-
-   process.wait()
-}
-
 fn find_binary_name(app_name: &Option<String>, toml: &str, root: &path::Path) -> Result<String, Box<dyn std::error::Error>> {
     let names = get_binary_name(toml, root)?;
     
@@ -149,16 +1686,45 @@ fn find_binary_name(app_name: &Option<String>, toml: &str, root: &path::Path) ->
         None if names.len() == 1 => Ok(names[0].clone()),
         None => Err(crate::error::Error::new(
             error::ErrorKind::NotAbleDetermineBinary, 
-            format!("multiple candidates exists, not ablt to determine. {:?}", names)
+            msg!(MultipleCandidates, format!("{:?}", names))
         )),
-        Some(name) if names.contains(&name) => Ok(name.clone()),
+        Some(name) if names.contains(name) => Ok(name.clone()),
         Some(name) => Err(error::Error::new(
             error::ErrorKind::BinaryNotFound,
-            format!("binary {} is not found", name)
+            msg!(BinaryNotFound, name)
         ))
     };
 
-    result.map_err(|e| Box::<dyn std::error::Error>::from(e))
+    result.map_err(Box::<dyn std::error::Error>::from)
+}
+
+/// プロジェクトルートとワークスペースのメンバーのディレクトリ
+fn package_dirs(toml: &str, project_root: &path::Path) -> Result<Vec<path::PathBuf>, toml_edit::de::Error> {
+    let toml = easy::from_str::<TomlConfig>(toml)?;
+    let members = toml.workspace.and_then(|w| w.members).unwrap_or_default();
+    let mut dirs = vec![project_root.to_path_buf()];
+    dirs.extend(members.iter().map(|m| project_root.join(m)));
+    Ok(dirs)
+}
+
+/// ワークスペースのメンバーが自身の Cargo.toml に書いた設定を、メンバーのバイナリ名ごとに読む
+fn member_overrides(toml: &str, project_root: &path::Path) -> Result<HashMap<String, member::MemberOverrides>, Box<dyn std::error::Error>> {
+    let toml = easy::from_str::<TomlConfig>(toml)?;
+    let members = toml.workspace.and_then(|w| w.members).unwrap_or_default();
+
+    let mut overrides = HashMap::new();
+    for dir in members.iter().map(|m| project_root.join(m)) {
+        let manifest = match std::fs::read_to_string(dir.join("Cargo.toml")) {
+            Ok(manifest) => manifest,
+            Err(_) => continue,
+        };
+        let member = member::MemberOverrides::from_manifest(manifest.as_str(), dir.as_path())?;
+        for name in get_binary_name(manifest.as_str(), dir.as_path())? {
+            overrides.insert(name, member.clone());
+        }
+    }
+
+    Ok(overrides)
 }
 
 fn get_binary_name(toml: &str, project_root: &path::Path) -> Result<Vec<String>, toml_edit::de::Error> {
@@ -177,16 +1743,14 @@ fn get_binary_name(toml: &str, project_root: &path::Path) -> Result<Vec<String>,
 
                     (ws, buf)
                 }) 
-                .map(|(w, b)| get_binary_name(&b, w.as_path()))
-                .map(|r| r.ok())
-                .flatten()
+                .filter_map(|(w, b)| get_binary_name(&b, w.as_path()).ok())
                 .flatten()
                 .collect()
         })
     }
 
     fn get_name_fron_bins(toml: &TomlConfig) -> Option<Vec<String>> {
-        toml.bin.as_ref().map(|bins| bins.into_iter().filter_map(|b| b.name.clone()).collect()) 
+        toml.bin.as_ref().map(|bins| bins.iter().filter_map(|b| b.name.clone()).collect()) 
     }
 
     fn get_name_from_package(toml: &TomlConfig) -> Option<Vec<String>> {
@@ -199,7 +1763,7 @@ fn get_binary_name(toml: &str, project_root: &path::Path) -> Result<Vec<String>,
         get_name_from_workspace(&toml, project_root)
         .or(get_name_fron_bins(&toml))
         .or(get_name_from_package(&toml))
-        .unwrap_or(Vec::new());
+        .unwrap_or_default();
     
     Ok(names)
 }
@@ -208,6 +1772,13 @@ fn get_binary_name(toml: &str, project_root: &path::Path) -> Result<Vec<String>,
 mod test {
     use crate::get_binary_name;
     use std::path;
+    use clap::CommandFactory;
+
+    #[test]
+    fn command_definition_is_valid() {
+        // サブコマンドに伝播するグローバル引数の制約も含めて検証する
+        crate::Args::command().debug_assert();
+    }
 
     #[test]
     fn parse_one_bin_pattern() {
@@ -263,4 +1834,25 @@ mod test {
         assert_eq!(names.len(), 1);
         assert_eq!(names[0], "hoge");
     }
-}
\ No newline at end of file
+
+    #[cfg(unix)]
+    #[test]
+    fn boots_stop_at_first_failure() {
+        use clap::Parser;
+        use std::os::unix::process::ExitStatusExt;
+
+        let args = crate::Args::parse_from(["cargo-uefi", "--boots", "3"]);
+        let artifacts = std::env::temp_dir().join(format!("cargo-uefi-boots-{}", std::process::id()));
+        let mut runs = Vec::new();
+        let status = crate::run_boots(&args, &crate::backend::Qemu::new("qemu-system-x86_64".into()), 3, &[], artifacts.as_path(), None, |options| {
+            runs.push(options);
+            // 2回目の起動は終了コード1で失敗する
+            Ok(Some(std::process::ExitStatus::from_raw((runs.len() as i32 - 1) << 8)))
+        }).unwrap();
+
+        assert_eq!(runs.len(), 2);
+        assert_eq!(status.and_then(|s| s.code()), Some(1));
+        assert!(runs[1][1].contains(&format!("logfile={}", artifacts.join("boot-2.log").display())));
+        let _ = std::fs::remove_dir_all(artifacts);
+    }
+}
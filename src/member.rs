@@ -0,0 +1,157 @@
+use std::path;
+use serde::Deserialize;
+use serde_json::json;
+use toml_edit::easy;
+use crate::error::{Error, ErrorKind};
+use crate::firmware::{FirmwarePaths, OvmfPrebuilt};
+use crate::message::msg;
+use crate::target::Arch;
+
+const MIB: u64 = 1024 * 1024;
+
+/// ワークスペースのメンバーが自身の `[package.metadata.cargo-uefi]` に書く、`--all` で実行するときの設定。
+/// ワークスペースの設定より優先し、コマンドラインで指定したものはさらに優先する
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MemberOverrides {
+    /// 実行するアーキテクチャ（`x86_64`、`aarch64`、`i686`）
+    pub target: Option<Arch>,
+    /// 起動に使うファームウェア。相対パスはメンバーのディレクトリからのパスとみなす
+    pub firmware: Option<FirmwarePaths>,
+    /// rust-osdev/ovmf-prebuilt から取得するファームウェア
+    pub ovmf_prebuilt: Option<OvmfPrebuilt>,
+    /// VMのメモリサイズ（例: `512M`）。MiBの倍数で指定する
+    pub memory: Option<String>,
+    /// ワークスペースの `with` に加えるデバイスプリセット
+    #[serde(default)]
+    pub with: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    package: Option<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    metadata: Option<Metadata>,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    #[serde(rename = "cargo-uefi")]
+    cargo_uefi: Option<MemberOverrides>,
+}
+
+impl MemberOverrides {
+    /// `dir` にあるメンバーの Cargo.toml の内容から読む。ワークスペースの設定と同じ表に書くため、関係のない項目は無視する
+    pub fn from_manifest(toml: &str, dir: &path::Path) -> Result<MemberOverrides, Box<dyn std::error::Error>> {
+        let manifest = easy::from_str::<Manifest>(toml)?;
+        let mut overrides = manifest.package.and_then(|p| p.metadata).and_then(|m| m.cargo_uefi).unwrap_or_default();
+        overrides.firmware = overrides.firmware.map(|f| f.resolve(dir));
+        if let Some(signature) = overrides.ovmf_prebuilt.as_mut().and_then(|p| p.signature.as_mut()) {
+            signature.resolve_key(dir);
+        }
+        overrides.memory_mib()?;
+
+        Ok(overrides)
+    }
+
+    fn memory_mib(&self) -> Result<Option<u64>, Error> {
+        let memory = match &self.memory {
+            Some(memory) => memory,
+            None => return Ok(None),
+        };
+        match crate::size::parse_size(memory)? {
+            bytes if bytes != 0 && bytes % MIB == 0 => Ok(Some(bytes / MIB)),
+            _ => Err(Error::new(ErrorKind::InvalidArgument, msg!(MemberMemoryInvalid, memory))),
+        }
+    }
+
+    /// ファームウェアをワークスペースの設定から変えるか
+    pub fn overrides_firmware(&self) -> bool {
+        self.firmware.is_some() || self.ovmf_prebuilt.is_some()
+    }
+
+    /// メモリサイズを指定するQEMUの引数
+    pub fn memory_args(&self) -> Vec<String> {
+        match self.memory_mib() {
+            Ok(Some(mib)) => vec!["-m".to_string(), format!("{}M", mib)],
+            _ => Vec::new(),
+        }
+    }
+
+    /// 変更する設定の一覧
+    fn summary(&self) -> Vec<String> {
+        let mut items = Vec::new();
+        if let Some(target) = self.target {
+            items.push(format!("target={}", target.rust_target()));
+        }
+        if let Some(firmware) = &self.firmware {
+            items.push(format!("firmware={}", firmware.code.display()));
+        }
+        if let Some(prebuilt) = &self.ovmf_prebuilt {
+            items.push(format!("ovmf-prebuilt={}", prebuilt.tag));
+        }
+        if let Some(memory) = &self.memory {
+            items.push(format!("memory={}", memory));
+        }
+        if !self.with.is_empty() {
+            items.push(format!("with={}", self.with.join(",")));
+        }
+        items
+    }
+
+    /// メンバーが設定を変えていれば報告する
+    pub fn report(&self, name: &str) {
+        let items = self.summary();
+        if items.is_empty() {
+            return;
+        }
+        crate::output::status(msg!(MemberOverridesApplied, name, items.join(" ")));
+        crate::output::event("member-overrides", json!({
+            "app": name,
+            "target": self.target.map(|t| t.rust_target()),
+            "firmware": self.firmware.as_ref().map(|f| &f.code),
+            "ovmf-prebuilt": self.ovmf_prebuilt.as_ref().map(|p| &p.tag),
+            "memory": self.memory,
+            "with": self.with,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::member::MemberOverrides;
+    use crate::target::Arch;
+
+    #[test]
+    fn read_member_overrides() {
+        let toml = r#"
+        [package]
+        name = "hoge"
+
+        [package.metadata.cargo-uefi]
+        target = "aarch64"
+        memory = "1G"
+        with = ["net"]
+        qemu-args = ["-smp", "2"]
+
+        [package.metadata.cargo-uefi.firmware]
+        code = "fw/AAVMF_CODE.fd"
+        "#;
+
+        let overrides = MemberOverrides::from_manifest(toml, path::Path::new("/ws/hoge")).unwrap();
+        assert_eq!(overrides.target, Some(Arch::Aarch64));
+        assert_eq!(overrides.firmware.as_ref().unwrap().code, path::Path::new("/ws/hoge/fw/AAVMF_CODE.fd"));
+        assert!(overrides.overrides_firmware());
+        assert_eq!(overrides.memory_args(), ["-m", "1024M"]);
+        assert_eq!(overrides.summary(), ["target=aarch64-unknown-uefi", "firmware=/ws/hoge/fw/AAVMF_CODE.fd", "memory=1G", "with=net"]);
+
+        let plain = MemberOverrides::from_manifest("[package]\nname = \"fuga\"\n", path::Path::new("/ws/fuga")).unwrap();
+        assert!(plain.summary().is_empty() && plain.memory_args().is_empty());
+        assert!(MemberOverrides::from_manifest("[package.metadata.cargo-uefi]\nmemory = \"1500K\"\n", path::Path::new("/ws")).is_err());
+        assert!(MemberOverrides::from_manifest("[package.metadata.cargo-uefi]\ntarget = \"riscv64\"\n", path::Path::new("/ws")).is_err());
+    }
+}
@@ -0,0 +1,891 @@
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+/// 利用者に表示するメッセージの言語
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Lang {
+    En,
+    Ja,
+}
+
+impl Lang {
+    /// `CARGO_UEFI_LANG` を優先し、なければPOSIXのロケールの環境変数から言語を決める
+    pub fn detect(lookup: impl Fn(&str) -> Option<String>) -> Lang {
+        let value = ["CARGO_UEFI_LANG", "LC_ALL", "LC_MESSAGES", "LANG"]
+            .into_iter()
+            .filter_map(&lookup)
+            .find(|v| !v.is_empty());
+
+        match value {
+            Some(v) if v.to_ascii_lowercase().starts_with("ja") => Lang::Ja,
+            _ => Lang::En,
+        }
+    }
+}
+
+/// 実行中のプロセスで使う言語
+pub fn lang() -> Lang {
+    static LANG: OnceLock<Lang> = OnceLock::new();
+    *LANG.get_or_init(|| Lang::detect(|name| std::env::var(name).ok()))
+}
+
+/// メッセージカタログのキー
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Key {
+    Warning,
+    NotFound,
+    RunFailed,
+    RunnerInstalled,
+    RunnerConflict,
+    RunnerAlreadyInstalled,
+    BuildFailed,
+    BuildFailedSummary,
+    Running,
+    RunTimedOut,
+    BlockStatsFailed,
+    ShellNotProvided,
+    ShellToolsNone,
+    ShellToolUnknown,
+    ShellToolMissing,
+    ShellToolBuiltin,
+    ShellToolsStaged,
+    VmFrozenAt,
+    GdbScriptWritten,
+    CheckpointsSummary,
+    GuestLog,
+    GuestEvent,
+    GuestCheckpoint,
+    GuestImageLoaded,
+    GuestWrote,
+    ShimUnsupportedArch,
+    ShimAppNotStaged,
+    ShimStatus,
+    ShimLoadFailed,
+    ShimNoStatus,
+    ShimNotStarted,
+    ProjectRootNotFound,
+    MultipleCandidates,
+    BinaryNotFound,
+    Downloading,
+    DownloadFailed,
+    NoDownloadUrl,
+    Offline,
+    ChecksumMismatch,
+    ProxyPasswordUnset,
+    ProxyPasswordNeedsUser,
+    FirmwareNotFound,
+    FirmwareNotInDir,
+    DownloadingFirmware,
+    FirmwareProviderUnknown,
+    DownloadOvmfNotInOrder,
+    FirmwarePluginFailed,
+    StagingPlan,
+    StagingPlanNoPrevious,
+    StagingGenerated,
+    StagingShim,
+    StagingSummary,
+    ReportTitle,
+    ReportSummary,
+    ReportBinary,
+    ReportArch,
+    ReportFirmware,
+    ReportResult,
+    ReportDuration,
+    ReportSerialLog,
+    ReportScreenshots,
+    ReportTiming,
+    ReportBuildFailed,
+    VerdictSuccess,
+    VerdictEfiStatus,
+    VerdictPanic,
+    VerdictException,
+    VerdictTimeout,
+    VerdictFailed,
+    ReportWritten,
+    TarballUnverified,
+    UnverifiedContinuing,
+    TarballMissingFile,
+    ExtractFailed,
+    WaitingForLock,
+    Locked,
+    SigningKeyMissing,
+    SignatureInvalid,
+    SigningKeyImportFailed,
+    CreateDirFailed,
+    ExitSuccessOdd,
+    ExitIosize,
+    ExitDeviceUnsupported,
+    InvalidIoPort,
+    NativeAioCache,
+    InvalidSize,
+    CacheAgeInvalid,
+    CacheKindTotal,
+    CacheRemoveFailed,
+    CachePruned,
+    NiceOutOfRange,
+    ResourceLimitZero,
+    ResourceLimitsApplied,
+    MemoryLimitExceeded,
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    MemoryLimitUnsupported,
+    CpuTimeLimitExceeded,
+    NiceNotPermitted,
+    #[cfg_attr(not(windows), allow(dead_code))]
+    JobObjectFailed,
+    WatchCommandUnsupported,
+    ArtifactsExported,
+    ArtifactsSummaryFailed,
+    RunIdInvalid,
+    WatchWaiting,
+    WatchRebuild,
+    WatchRestage,
+    WatchReloadFailed,
+    CompareFirmwareCount,
+    CompareResult,
+    CrashHeader,
+    FwCfgInvalidOption,
+    FwCfgNoName,
+    FwCfgNamespace,
+    FwCfgNameTooLong,
+    FwCfgReserved,
+    FwCfgNameChars,
+    FwCfgContent,
+    FwCfgDuplicate,
+    VarsProfileInvalidName,
+    VarsProfileNoVars,
+    VarsProfileNotFound,
+    VarsProfileSizeMismatch,
+    VarsProfileSaved,
+    VmFrozen,
+    BreakpointHit,
+    BreakOnSerialRedirected,
+    TraceUnknownItem,
+    TraceSummaryHeader,
+    TraceSummaryFailed,
+    PortInvalidOverride,
+    PortExhausted,
+    PortInUse,
+    PortConflict,
+    PortVncBelowBase,
+    PortNotAccepted,
+    PortsAllocated,
+    PeMalformed,
+    PdbMalformed,
+    PeCannotAddSection,
+    PeSectionNameTooLong,
+    PeSectionExists,
+    PeImageSigned,
+    PeNoSectionHeaderRoom,
+    PeImageTooLarge,
+    BuildInfoStamped,
+    VerifyImagePassed,
+    VerifyImageFailed,
+    VerifyReadOnly,
+    VerifySeekBeforeStart,
+    VerifyProtectiveMbr,
+    VerifyNoProtectivePartition,
+    VerifyPrimaryGpt,
+    VerifyBackupGpt,
+    VerifyBackupBeyondEnd,
+    VerifyGptHeadersAgree,
+    VerifyGptHeadersDiffer,
+    VerifyGptEntries,
+    VerifyGptTableUnsupported,
+    VerifyEntriesBeyondEnd,
+    VerifyGptEntriesChecksum,
+    VerifyChecksumMismatch,
+    VerifyEsp,
+    VerifyNoEspType,
+    VerifyEspOutOfRange,
+    VerifyNoGptSignature,
+    VerifyHeaderChecksum,
+    VerifyBootSector,
+    VerifyNotDiskImage,
+    VerifyFatImage,
+    VerifyMbrEsp,
+    VerifyNoEspFound,
+    VerifyIsoDescriptor,
+    VerifyElToritoRecord,
+    VerifyIsoNotBootable,
+    VerifyElToritoCatalog,
+    VerifyInvalidValidationEntry,
+    VerifyElToritoEntry,
+    VerifyNoEfiEntry,
+    VerifyEspFat,
+    VerifyFatDirty,
+    VerifyFatTableUnreadable,
+    VerifyEspFiles,
+    VerifyEspFilesReadable,
+    VerifyFileTruncated,
+    VerifyDefaultBootFile,
+    VerifyNoDefaultBootFile,
+    VerifyPeImage,
+    VerifyNotEfiSubsystem,
+    VerifyBootFileArchMismatch,
+    VerifySignature,
+    VerifySignedBy,
+    VerifyNotSigned,
+    VerifyNoMatchingCert,
+    ScenarioInvalid,
+    ScenarioStepKind,
+    ScenarioSleepInvalid,
+    ScenarioNoBoot,
+    ScenarioStdinClosed,
+    ScenarioBoot,
+    ScenarioStepPassed,
+    ScenarioExpectTimeout,
+    ScenarioExitCode,
+    ScenarioFailed,
+    ScenarioPassed,
+    BootStarted,
+    BootFailed,
+    PowerCutInvalid,
+    PowerCutNeedsBoots,
+    PowerCutNeedsSerialLog,
+    PowerCutSeed,
+    PowerCutInjected,
+    PowerCutMissed,
+    BlkdebugInvalid,
+    Building,
+    NetImpairInvalid,
+    NetImpairSummary,
+    PresetApplied,
+    DevicePresetApplied,
+    DevicePresetUnknown,
+    Provisioned,
+    ProvisionVariableInvalid,
+    ProvisionGuidInvalid,
+    ProvisionAttributeUnknown,
+    ProvisionHexInvalid,
+    ProvisionFileUnreadable,
+    ProvisionDataKind,
+    ProvisionRtcInvalid,
+    ProvisionNeedsVars,
+    ProvisionVarsFormat,
+    ProvisionVarsFull,
+    QemuTimedOut,
+    MemorySweepInvalid,
+    MemberMemoryInvalid,
+    MemberOverridesApplied,
+    MemorySweepBooted,
+    MemorySweepFailed,
+    MemorySweepMinimum,
+    MemorySweepNone,
+    UnsupportedArch,
+    CheckpointNeedsSerialLog,
+    CheckpointFailed,
+    CheckpointsPassed,
+    CheckpointMissed,
+    CheckpointMissedAfter,
+    CheckpointOutOfOrder,
+    CheckpointLate,
+    CheckpointDeadlineStop,
+    ExtraFileInvalid,
+    EspPathInvalid,
+    ProbeInvalid,
+    ProbeInvalidExpect,
+    ProbeClosed,
+    ProbeUnexpected,
+    ProbeNotStarted,
+    ProbePassed,
+    ProbeFailed,
+    QemuFailed,
+    QemuStderrSaved,
+    QemuKvmDenied,
+    QemuKvmMissing,
+    QemuPflashSize,
+    QemuUnknownDevice,
+    QemuFileMissing,
+    QemuImageLocked,
+    QemuAddressInUse,
+    KvmMissing,
+    KvmDenied,
+    KvmUsermod,
+    KvmRelogin,
+    KvmAskAdmin,
+}
+
+/// キーに対応する英語と日本語のテンプレート。`{0}` `{1}` ... は引数で置き換えられる
+fn template(key: Key) -> (&'static str, &'static str) {
+    match key {
+        Key::Warning => ("warning: {0}", "警告: {0}"),
+        Key::NotFound => ("{0} is not found", "{0} が見つかりません"),
+        Key::RunFailed => ("failed to run {0}: {1}", "{0} を実行できませんでした: {1}"),
+        Key::RunnerInstalled => ("installed cargo-uefi as the runner for {0}", "cargo-uefi を {0} のrunnerとして登録しました"),
+        Key::RunnerConflict => ("{2} already sets the runner for {1} to {0}; pass --force to replace it with cargo-uefi", "{2} で {1} のrunnerに {0} が登録されています。cargo-uefi で置き換える場合は --force を指定してください"),
+        Key::RunnerAlreadyInstalled => ("cargo-uefi is already installed as the runner for {0}", "cargo-uefi は既に {0} のrunnerとして登録されています"),
+        Key::BuildFailed => ("build failed: {0}", "ビルドに失敗しました: {0}"),
+        Key::BuildFailedSummary => ("{0}: build failed", "{0}: ビルド失敗"),
+        Key::Building => ("building: {0}", "ビルド中: {0}"),
+        Key::Running => ("running: {0}", "実行中: {0}"),
+        Key::RunTimedOut => ("timed out", "タイムアウト"),
+        Key::BlockStatsFailed => ("failed to collect block statistics: {0}", "ブロックデバイスの統計情報を取得できませんでした: {0}"),
+        Key::ShellNotProvided => ("the selected firmware does not provide a UEFI Shell", "選択したファームウェアにはUEFI Shellが含まれていません"),
+        Key::ShellToolsNone => ("no shell tools are available: neither the firmware nor the `shell-tools` bundle has a UEFI Shell or other tools", "使えるツールがありません: ファームウェアにも `shell-tools` のバンドルにも、UEFI Shellや他のツールが含まれていません"),
+        Key::ShellToolUnknown => ("unknown shell tool `{0}` (available: {1})", "ツール `{0}` はありません（使えるもの: {1}）"),
+        Key::ShellToolMissing => ("no UEFI Shell for this architecture in the `shell-tools` bundle, the firmware or the default ovmf-prebuilt release", "このアーキテクチャのUEFI Shellが、`shell-tools` のバンドルにもファームウェアにも既定の ovmf-prebuilt のリリースにもありません"),
+        Key::ShellToolBuiltin => ("`{0}` is a command built into the UEFI Shell; stage `shell` and run `{0}` there", "`{0}` はUEFI Shellに組み込まれたコマンドです。`shell` を配置し、その中で `{0}` を実行してください"),
+        Key::ShellToolsStaged => ("shell tools: {0}", "ツール: {0}"),
+        Key::VmFrozenAt => ("stopped at {0} ({1})", "{0}（{1}）で停止しています"),
+        Key::GdbScriptWritten => ("GDB script for the reported image base: {0} (load it with `gdb -x`)", "報告された読み込み先に合わせたGDBのスクリプト: {0}（`gdb -x` で読み込めます）"),
+        Key::CheckpointsSummary => ("checkpoints:", "チェックポイント:"),
+        Key::GuestLog => ("[guest] {0}", "[ゲスト] {0}"),
+        Key::GuestEvent => ("[guest] event {0} {1}", "[ゲスト] イベント {0} {1}"),
+        Key::GuestCheckpoint => ("[guest] checkpoint {0} at {1}s", "[ゲスト] チェックポイント {0}（{1}秒）"),
+        Key::GuestImageLoaded => ("[guest] image loaded at {0} ({1} bytes)", "[ゲスト] イメージを {0} に読み込みました（{1} バイト）"),
+        Key::GuestWrote => ("[guest] wrote {0}", "[ゲスト] {0} に書き込みました"),
+        Key::ShimUnsupportedArch => ("the validation shim is only available for x86_64-unknown-uefi, not {0}", "検証用のシムは x86_64-unknown-uefi でのみ使えます（{0} には対応していません）"),
+        Key::ShimAppNotStaged => ("the validation shim cannot start {0} because it is not staged on the ESP", "{0} がESPに配置されないため、検証用のシムから起動できません"),
+        Key::ShimStatus => ("the application returned {0} (exit data: {1})", "アプリケーションが {0} を返しました（終了データ: {1}）"),
+        Key::ShimLoadFailed => ("the validation shim could not load the application: {0}", "検証用のシムがアプリケーションを読み込めませんでした: {0}"),
+        Key::ShimNoStatus => ("the application did not return to the validation shim", "アプリケーションが検証用のシムに戻りませんでした"),
+        Key::ShimNotStarted => ("the validation shim did not start", "検証用のシムが起動しませんでした"),
+        Key::ProjectRootNotFound => ("project root directory not found", "プロジェクトのルートディレクトリが見つかりません"),
+        Key::MultipleCandidates => (
+            "multiple candidates exist, not able to determine which to run. {0}\nhint: select one with `--bin <NAME>`",
+            "候補が複数あるため実行するバイナリを決められません。{0}\nヒント: `--bin <NAME>` で指定してください"
+        ),
+        Key::BinaryNotFound => ("binary {0} is not found", "バイナリ {0} が見つかりません"),
+        Key::Downloading => ("Downloading {0}", "ダウンロード中 {0}"),
+        Key::DownloadFailed => ("failed to download {0} ({1})", "{0} をダウンロードできませんでした ({1})"),
+        Key::NoDownloadUrl => ("no download URL is available for {0}", "{0} のダウンロード元がありません"),
+        Key::Offline => (
+            "{0} is not cached and network access is disabled (--offline or CARGO_NET_OFFLINE).\n\
+             Download {1} on a connected machine and place it at {2}, or point to a local firmware image instead",
+            "{0} はキャッシュになく、ネットワークへのアクセスが無効になっています (--offline または CARGO_NET_OFFLINE)。\n\
+             ネットワークに接続できる環境で {1} をダウンロードして {2} に配置するか、ローカルのファームウェアを指定してください"
+        ),
+        Key::ChecksumMismatch => ("checksum mismatch for {0}: expected {1}, got {2}", "{0} のチェックサムが一致しません: 期待値 {1}、実際 {2}"),
+        Key::ProxyPasswordUnset => ("proxy password environment variable {0} is not set", "プロキシのパスワードの環境変数 {0} が設定されていません"),
+        Key::ProxyPasswordNeedsUser => ("proxy password-env requires user", "プロキシの password-env には user の指定が必要です"),
+        Key::FirmwareNotFound => (
+            "no firmware is found (tried: {1}); place {0} in the project root, \
+             specify the firmware with --ovmf, OVMF_PATH or `firmware` in the config, or fetch it with --download-ovmf",
+            "ファームウェアが見つかりません（試した方法: {1}）。プロジェクトのルートに {0} を置くか、\
+             --ovmf、OVMF_PATH、設定ファイルの `firmware` でファームウェアを指定するか、--download-ovmf で取得してください"
+        ),
+        Key::FirmwareNotInDir => ("no firmware image is found in {0}", "{0} にファームウェアのイメージが見つかりません"),
+        Key::DownloadOvmfNotInOrder => (
+            "--download-ovmf has no effect because `firmware-order` does not include \"download\"; add it to `firmware-order` or drop --download-ovmf",
+            "`firmware-order` に \"download\" がないため --download-ovmf は使われません。`firmware-order` に加えるか、--download-ovmf を外してください"
+        ),
+        Key::FirmwareProviderUnknown => ("unknown firmware provider `{0}` in `firmware-order`; expected one of {1}", "`firmware-order` のファームウェアの取得方法 `{0}` が不明です。{1} のいずれかを指定してください"),
+        Key::FirmwarePluginFailed => ("the firmware plugin {0} failed: {1}", "ファームウェアのプラグイン {0} が失敗しました: {1}"),
+        Key::StagingPlan => ("Staging plan for {0} (compared with the last staged tree):", "{0} の配置計画（前回配置した内容との比較）:"),
+        Key::StagingPlanNoPrevious => ("Staging plan for {0} (nothing has been staged yet):", "{0} の配置計画（まだ配置したことがありません）:"),
+        Key::StagingGenerated => ("(generated)", "（生成）"),
+        Key::StagingShim => ("(validation shim starting {0})", "（{0} を起動する検証用のシム）"),
+        Key::StagingSummary => ("{0} added, {1} changed, {2} unchanged, {3} removed", "追加 {0} 件、変更 {1} 件、変更なし {2} 件、削除 {3} 件"),
+        Key::ReportTitle => ("cargo-uefi run report", "cargo-uefi 実行レポート"),
+        Key::ReportSummary => ("{0} passed, {1} failed", "成功 {0} 件、失敗 {1} 件"),
+        Key::ReportBinary => ("Binary", "バイナリ"),
+        Key::ReportArch => ("Architecture", "アーキテクチャ"),
+        Key::ReportFirmware => ("Firmware", "ファームウェア"),
+        Key::ReportResult => ("Result", "結果"),
+        Key::ReportDuration => ("Time", "実行時間"),
+        Key::ReportSerialLog => ("Serial output", "シリアルの出力"),
+        Key::ReportScreenshots => ("Screenshots", "スクリーンショット"),
+        Key::ReportTiming => ("Run time", "実行時間"),
+        Key::ReportBuildFailed => ("build failed", "ビルドに失敗"),
+        Key::VerdictSuccess => ("passed", "成功"),
+        Key::VerdictEfiStatus => ("returned {0} (exit code {1})", "{0} を返しました（終了コード {1}）"),
+        Key::VerdictPanic => ("panicked (exit code {0})", "パニックしました（終了コード {0}）"),
+        Key::VerdictException => ("CPU exception {0} (exit code {1})", "CPU例外 {0} が発生しました（終了コード {1}）"),
+        Key::VerdictTimeout => ("timed out (exit code {0})", "タイムアウトしました（終了コード {0}）"),
+        Key::VerdictFailed => ("failed with exit code {0}", "終了コード {0} で失敗"),
+        Key::ReportWritten => ("Wrote the HTML report to {0}", "HTMLのレポートを {0} に書き出しました"),
+        Key::DownloadingFirmware => ("Downloading the firmware from ovmf-prebuilt {0}", "ovmf-prebuilt {0} からファームウェアを取得します"),
+        Key::TarballUnverified => ("{0} is not verified; pin it with `sha256 = \"{1}\"`", "{0} は検証されていません。`sha256 = \"{1}\"` で固定してください"),
+        Key::UnverifiedContinuing => (
+            "{0}; continuing because --allow-unverified-firmware is given",
+            "{0}。--allow-unverified-firmware が指定されているため続行します"
+        ),
+        Key::TarballMissingFile => ("{0} does not contain {1}", "{0} に {1} が含まれていません"),
+        Key::ExtractFailed => ("failed to extract {0} ({1})", "{0} を展開できませんでした ({1})"),
+        Key::WaitingForLock => ("Blocking waiting for lock on {0}", "{0} のロックを待っています"),
+        Key::Locked => ("{0} is locked by another cargo-uefi process", "{0} は他のcargo-uefiプロセスがロックしています"),
+        Key::SigningKeyMissing => ("signing key {0} does not exist", "署名の鍵 {0} が存在しません"),
+        Key::SignatureInvalid => ("signature of {0} is not valid for key {1}", "{0} の署名は鍵 {1} で検証できません"),
+        Key::SigningKeyImportFailed => ("failed to import signing key {0}", "署名の鍵 {0} を読み込めませんでした"),
+        Key::CreateDirFailed => ("failed to create {0}: {1}", "{0} を作成できませんでした: {1}"),
+        Key::ExitSuccessOdd => ("exit success code must be odd, got {0}", "成功を表す終了コードは奇数である必要があります: {0}"),
+        Key::ExitDeviceUnsupported => ("the isa-debug-exit device is only available on x86; remove --exit-device, --exit-iobase, --exit-success and the `exit` config for {0}", "isa-debug-exitデバイスはx86でのみ使えます。{0} では --exit-device、--exit-iobase、--exit-success と設定ファイルの `exit` を外してください"),
+        Key::ExitIosize => ("exit device iosize must be 1, 2 or 4, got {0}", "終了デバイスの iosize は 1, 2, 4 のいずれかである必要があります: {0}"),
+        Key::InvalidIoPort => ("invalid I/O port: {0}", "不正なI/Oポート: {0}"),
+        Key::NativeAioCache => (
+            "aio=native requires cache mode `none` or `directsync`",
+            "aio=native にはキャッシュモード `none` または `directsync` が必要です"
+        ),
+        Key::InvalidSize => ("invalid size: {0}", "不正なサイズ: {0}"),
+        Key::CacheAgeInvalid => ("invalid age `{0}`: expected a number followed by s, m, h, d or w (e.g. `30d`)", "期間 `{0}` が不正です: 数値に s、m、h、d、w のいずれかを続けて指定してください（例: `30d`）"),
+        Key::CacheKindTotal => ("{0}: {1} in {2} entries ({3})", "{0}: {2} 件、{1}（{3}）"),
+        Key::CacheRemoveFailed => ("failed to remove {0}: {1}", "{0} を削除できませんでした: {1}"),
+        Key::CachePruned => ("removed {0} entries, freed {1}", "{0} 件を削除し、{1} を空けました"),
+        Key::NiceOutOfRange => ("nice value must be between -20 and 19, got {0}", "nice値は -20 から 19 の範囲で指定してください: {0}"),
+        Key::ResourceLimitZero => ("CPU time and memory limits must be greater than zero", "CPU時間とメモリの上限には0より大きい値を指定してください"),
+        Key::ResourceLimitsApplied => ("limiting QEMU: {0}", "QEMUの資源を制限します: {0}"),
+        Key::MemoryLimitExceeded => (
+            "QEMU was killed: resident memory {0} exceeded the limit of {1}",
+            "常駐メモリ {0} が上限 {1} を超えたため、QEMUを強制終了しました"
+        ),
+        Key::MemoryLimitUnsupported => (
+            "the memory limit is only enforced on Linux and Windows; ignoring it",
+            "メモリの上限はLinuxとWindowsでのみ課すことができます。無視します"
+        ),
+        Key::NiceNotPermitted => (
+            "could not start QEMU with nice value {0}: a negative nice value needs root or CAP_SYS_NICE; use 0 to 19 instead ({1})",
+            "nice値 {0} でQEMUを起動できませんでした。負のnice値にはrootかCAP_SYS_NICEが必要です。0 から 19 を指定してください（{1}）"
+        ),
+        Key::CpuTimeLimitExceeded => ("QEMU was stopped after using its CPU time limit of {0} seconds", "CPU時間の上限 {0} 秒に達したため、QEMUが終了させられました"),
+        Key::ArtifactsExported => ("artifacts exported to {0}", "成果物を {0} に書き出しました"),
+        Key::RunIdInvalid => ("ignoring {0}=`{1}`: it cannot be used as a directory name; using a generated run ID instead", "{0}=`{1}` はディレクトリの名前に使えないため無視し、生成した実行IDを使います"),
+        Key::ArtifactsSummaryFailed => ("failed to write the summary of the artifacts in {0}: {1}", "{0} に成果物の概要を書き出せませんでした: {1}"),
+        Key::WatchCommandUnsupported => ("--watch cannot be used with a subcommand", "--watch はサブコマンドと同時に指定できません"),
+        Key::WatchWaiting => ("watching for changes (press Ctrl-C to stop)", "変更を監視しています（Ctrl-Cで終了します）"),
+        Key::WatchRebuild => ("{0} changed, rebuilding", "{0} が変更されたため、ビルドし直します"),
+        Key::WatchRestage => ("{0} changed, re-staging without rebuilding", "{0} が変更されたため、ビルドせずに配置し直します"),
+        Key::WatchReloadFailed => ("reload failed: {0}; waiting for further changes", "再読み込みに失敗しました: {0}。次の変更を待ちます"),
+        Key::JobObjectFailed => ("failed to apply resource limits through a job object: {0}", "ジョブオブジェクトで資源を制限できませんでした: {0}"),
+        Key::CompareFirmwareCount => (
+            "compare requires exactly two --firmware images, {0} given",
+            "compare には --firmware をちょうど2つ指定する必要があります（{0} 個指定されています）"
+        ),
+        Key::CompareResult => ("result: {0}", "結果: {0}"),
+        Key::CrashHeader => (
+            "cargo-uefi crashed unexpectedly. This is a bug; please report it at {0} with the information below.",
+            "cargo-uefi が予期せず異常終了しました。これは不具合です。以下の情報を添えて {0} に報告してください。"
+        ),
+        Key::FwCfgInvalidOption => (
+            "invalid fw_cfg option `{0}`: expected name=NAME,file=FILE or name=NAME,string=STRING",
+            "不正なfw_cfgのオプション `{0}`: name=NAME,file=FILE または name=NAME,string=STRING の形式で指定してください"
+        ),
+        Key::FwCfgNoName => ("fw_cfg option `{0}` has no name", "fw_cfgのオプション `{0}` に name がありません"),
+        Key::FwCfgNamespace => (
+            "fw_cfg {0}: name must start with `opt/`, e.g. `opt/com.example.myapp/config`",
+            "fw_cfg {0}: 名前は `opt/` で始める必要があります（例: `opt/com.example.myapp/config`）"
+        ),
+        Key::FwCfgNameTooLong => ("fw_cfg {0}: name must be at most {1} bytes", "fw_cfg {0}: 名前は {1} バイト以下である必要があります"),
+        Key::FwCfgReserved => ("fw_cfg {0}: `{1}` is reserved for the firmware and cargo-uefi", "fw_cfg {0}: `{1}` はファームウェアとcargo-uefiが使用するため指定できません"),
+        Key::FwCfgNameChars => (
+            "fw_cfg {0}: name must consist of printable ASCII characters other than `,`",
+            "fw_cfg {0}: 名前には `,` 以外の表示可能なASCII文字のみを使用できます"
+        ),
+        Key::FwCfgContent => ("fw_cfg {0}: give exactly one of `file` and `string`", "fw_cfg {0}: `file` と `string` のどちらか一方を指定してください"),
+        Key::FwCfgDuplicate => ("fw_cfg {0} is given more than once", "fw_cfg {0} が複数回指定されています"),
+        Key::VarsProfileInvalidName => (
+            "invalid vars profile name `{0}`: use letters, digits, `.`, `_` and `-`",
+            "不正なUEFI変数のプロファイル名 `{0}`: 英数字と `.` `_` `-` のみを使用できます"
+        ),
+        Key::VarsProfileNoVars => (
+            "{0} has no separate VARS image, so vars profiles cannot be used",
+            "{0} には独立したVARSイメージがないため、UEFI変数のプロファイルを使用できません"
+        ),
+        Key::VarsProfileNotFound => (
+            "vars profile {0} does not exist in {1} (available: {2})\nhint: create one with `--save-vars-profile {0}`",
+            "UEFI変数のプロファイル {0} が {1} にありません（保存済み: {2}）\nヒント: `--save-vars-profile {0}` で作成できます"
+        ),
+        Key::VarsProfileSizeMismatch => (
+            "vars profile {0} ({1} bytes) does not match the VARS image of the firmware ({2} bytes); it was saved with a different firmware",
+            "UEFI変数のプロファイル {0}（{1} バイト）がファームウェアのVARSイメージ（{2} バイト）と一致しません。別のファームウェアで保存されたものです"
+        ),
+        Key::VmFrozen => (
+            "the VM is frozen after a guest {0}. Inspect it with `gdb -ex \"{1}\"`, then quit QEMU or press Ctrl-C to finish",
+            "ゲストの {0} によりVMを停止しました。`gdb -ex \"{1}\"` で調べた後、QEMUを終了するかCtrl-Cで終了してください"
+        ),
+        Key::BreakpointHit => (
+            "`{0}` appeared in the guest output and the VM is paused. Inspect it with `gdb -ex \"{1}\"` and `continue` to resume",
+            "ゲストの出力に `{0}` が現れたため、VMを一時停止しました。`gdb -ex \"{1}\"` で調べ、`continue` で再開してください"
+        ),
+        Key::BreakOnSerialRedirected => (
+            "--break-on only watches guest output forwarded to the terminal; with --serial-tcp or -serial the markers are never seen",
+            "--break-on は端末に転送したゲストの出力しか調べないため、--serial-tcp や -serial を指定すると文字列を見つけられません"
+        ),
+        Key::TraceUnknownItem => ("unknown QEMU trace item `{0}` (available: {1})", "不明なQEMUのトレース項目 `{0}`（指定できる項目: {1}）"),
+        Key::TraceSummaryHeader => ("QEMU trace: {0}", "QEMUのトレース: {0}"),
+        Key::TraceSummaryFailed => ("failed to summarize the QEMU trace {0}: {1}", "QEMUのトレース {0} を集計できませんでした: {1}"),
+        Key::PortInvalidOverride => ("invalid port `{0}`: expected NAME=PORT with NAME one of {1}", "不正なポートの指定 `{0}`: NAME=PORT の形式で、NAME は {1} のいずれかを指定してください"),
+        Key::PortExhausted => ("no free port is available for {0}", "{0} に割り当てられる空きポートがありません"),
+        Key::PortInUse => ("port {0} for {1} is not available: {2}", "{1} のポート {0} を使用できません: {2}"),
+        Key::PortConflict => ("port {0} for {1} is already used for {2}", "{1} のポート {0} は既に {2} に使われています"),
+        Key::PortVncBelowBase => ("invalid VNC port {0}: VNC uses display numbers, so the port must be {1} or above", "不正なVNCのポート {0}: VNCはディスプレイ番号で指定するため、{1} 以上のポートを指定してください"),
+        Key::PortNotAccepted => ("port {0} cannot be used for {1}", "ポート {0} は {1} に使用できません"),
+        Key::PortsAllocated => ("ports: {0}", "ポート: {0}"),
+        Key::PeMalformed => ("not a valid PE/COFF image: {0}", "PE/COFFイメージとして不正です: {0}"),
+        Key::PeCannotAddSection => ("cannot add the `{0}` section to the EFI file: {1}", "EFIファイルに `{0}` セクションを追加できません: {1}"),
+        Key::PeSectionNameTooLong => ("section name longer than 8 bytes", "セクションの名前が8バイトより長くなっています"),
+        Key::PeSectionExists => ("the section already exists", "同じ名前のセクションが既にあります"),
+        Key::PeImageSigned => ("the image is signed", "イメージが署名されています"),
+        Key::PeNoSectionHeaderRoom => ("no room for another section header", "セクションヘッダを追加する空きがありません"),
+        Key::PeImageTooLarge => ("the image is too large", "イメージが大きすぎます"),
+        Key::BuildInfoStamped => ("Stamped build info into {0}: {1}", "{0} にビルド情報を埋め込みました: {1}"),
+        Key::VerifyImagePassed => ("All {0} checks passed", "{0} 件の検査に全て合格しました"),
+        Key::VerifyImageFailed => ("{0} of {1} checks failed", "{1} 件中 {0} 件の検査に失敗しました"),
+        Key::VerifyReadOnly => ("the image is opened read-only", "イメージは読み取り専用で開いています"),
+        Key::VerifySeekBeforeStart => ("seek before the start of the partition", "パーティションの先頭より前には移動できません"),
+        Key::VerifyProtectiveMbr => ("Protective MBR", "保護MBR"),
+        Key::VerifyNoProtectivePartition => ("no partition of type 0xEE in the MBR", "MBRにタイプ0xEEのパーティションがありません"),
+        Key::VerifyPrimaryGpt => ("Primary GPT", "プライマリGPT"),
+        Key::VerifyBackupGpt => ("Backup GPT", "バックアップGPT"),
+        Key::VerifyBackupBeyondEnd => ("backup header LBA {0} is beyond the end of the image", "バックアップのヘッダのLBA {0} がイメージの末尾を超えています"),
+        Key::VerifyGptHeadersAgree => ("GPT headers agree", "GPTヘッダの一致"),
+        Key::VerifyGptHeadersDiffer => ("disk GUID or partition table checksum differs between the primary and backup headers", "プライマリとバックアップのヘッダで、ディスクのGUIDかパーティションテーブルのチェックサムが異なります"),
+        Key::VerifyGptEntries => ("GPT partition entries", "GPTのパーティションエントリ"),
+        Key::VerifyGptTableUnsupported => ("unsupported partition table of {0} entries of {1} bytes", "{1} バイトのエントリが {0} 個あるパーティションテーブルには対応していません"),
+        Key::VerifyEntriesBeyondEnd => ("partition entry LBA {0} is beyond the end of the image", "パーティションエントリのLBA {0} がイメージの末尾を超えています"),
+        Key::VerifyGptEntriesChecksum => ("GPT partition entries checksum", "GPTのパーティションエントリのチェックサム"),
+        Key::VerifyChecksumMismatch => ("expected {0}, computed {1}", "{0} のはずが、計算すると {1} です"),
+        Key::VerifyEsp => ("EFI system partition", "EFIシステムパーティション"),
+        Key::VerifyNoEspType => ("no partition with the ESP type GUID", "ESPのタイプGUIDを持つパーティションがありません"),
+        Key::VerifyEspOutOfRange => ("LBA {0}..{1} is outside of the usable range {2}..{3}", "LBA {0}..{1} が使用できる範囲 {2}..{3} の外にあります"),
+        Key::VerifyNoGptSignature => ("missing `EFI PART` signature", "`EFI PART` のシグネチャがありません"),
+        Key::VerifyHeaderChecksum => ("{0} header checksum", "{0} のヘッダのチェックサム"),
+        Key::VerifyBootSector => ("Boot sector", "ブートセクタ"),
+        Key::VerifyNotDiskImage => ("missing 0x55AA signature; not a disk image", "0x55AA のシグネチャがなく、ディスクイメージではありません"),
+        Key::VerifyFatImage => ("FAT image without a partition table", "パーティションテーブルのないFATイメージ"),
+        Key::VerifyMbrEsp => ("MBR with an EFI system partition", "EFIシステムパーティションを含むMBR"),
+        Key::VerifyNoEspFound => ("no GPT, no MBR partition of type 0xEF, and not a FAT image", "GPTもMBRのタイプ0xEFのパーティションもなく、FATイメージでもありません"),
+        Key::VerifyIsoDescriptor => ("ISO 9660 volume descriptor", "ISO 9660のボリューム記述子"),
+        Key::VerifyElToritoRecord => ("El Torito boot record", "El Toritoのブートレコード"),
+        Key::VerifyIsoNotBootable => ("the ISO is not bootable", "ISOが起動できるようになっていません"),
+        Key::VerifyElToritoCatalog => ("El Torito boot catalog", "El Toritoのブートカタログ"),
+        Key::VerifyInvalidValidationEntry => ("invalid validation entry", "検証エントリが不正です"),
+        Key::VerifyElToritoEntry => ("El Torito UEFI entry", "El ToritoのUEFIのエントリ"),
+        Key::VerifyNoEfiEntry => ("no bootable entry for the EFI platform", "EFIのプラットフォームの起動できるエントリがありません"),
+        Key::VerifyEspFat => ("ESP FAT file system", "ESPのFATファイルシステム"),
+        Key::VerifyFatDirty => ("the volume is marked dirty or as having I/O errors", "ボリュームがダーティ、またはI/Oエラーありと記録されています"),
+        Key::VerifyFatTableUnreadable => ("cannot read the allocation table: {0}", "アロケーションテーブルを読み取れません: {0}"),
+        Key::VerifyEspFiles => ("ESP file contents", "ESPのファイルの内容"),
+        Key::VerifyEspFilesReadable => ("ESP file contents ({0} files readable)", "ESPのファイルの内容（{0} 個のファイルを読み取れました）"),
+        Key::VerifyFileTruncated => ("{0}: read {1} of {2} bytes", "{0}: {2} バイト中 {1} バイトしか読み取れません"),
+        Key::VerifyDefaultBootFile => ("Default boot file", "既定のブートファイル"),
+        Key::VerifyNoDefaultBootFile => ("no \\EFI\\BOOT\\BOOT<arch>.EFI", "\\EFI\\BOOT\\BOOT<arch>.EFI がありません"),
+        Key::VerifyPeImage => ("PE image {0}", "PEイメージ {0}"),
+        Key::VerifyNotEfiSubsystem => ("subsystem is {0} rather than an EFI subsystem", "サブシステムがEFIのものではなく {0} です"),
+        Key::VerifyBootFileArchMismatch => ("{0} image under a boot file name for another architecture", "{0} のイメージが別のアーキテクチャのブートファイルの名前で置かれています"),
+        Key::VerifySignature => ("Secure Boot signature {0}", "Secure Bootの署名 {0}"),
+        Key::VerifySignedBy => ("{0} ({1})", "{0}（{1}）"),
+        Key::VerifyNotSigned => ("the image is not signed", "イメージが署名されていません"),
+        Key::VerifyNoMatchingCert => ("not signed by any of the configured certificates", "設定したどの証明書でも署名されていません"),
+        Key::ScenarioInvalid => ("invalid scenario file {0}: {1}", "シナリオファイル {0} が不正です: {1}"),
+        Key::ScenarioStepKind => ("step {1} of boot {0} must have exactly one of `expect`, `send`, `keys` and `sleep`", "起動 {0} のステップ {1} には `expect`、`send`、`keys`、`sleep` のいずれか1つを指定してください"),
+        Key::ScenarioBoot => ("Boot {0}/{1}: {2}", "起動 {0}/{1}: {2}"),
+        Key::ScenarioSleepInvalid => ("step {1} of boot {0} has an invalid `sleep` of {2}: expected a non-negative number of seconds", "起動 {0} のステップ {1} の `sleep` の値 {2} が不正です: 0以上の秒数を指定してください"),
+        Key::ScenarioNoBoot => ("no [[boot]]", "[[boot]] がありません"),
+        Key::ScenarioStdinClosed => ("the standard input of QEMU is closed", "QEMUの標準入力が閉じられています"),
+        Key::ScenarioStepPassed => ("  step {0}: {1} ok ({2}s)", "  ステップ {0}: {1} 成功 ({2}秒)"),
+        Key::ScenarioExpectTimeout => ("`{0}` did not appear within {1} seconds", "{1} 秒以内に `{0}` が出力されませんでした"),
+        Key::ScenarioExitCode => ("expected exit code {0}, got {1}", "終了コード {0} を期待しましたが {1} でした"),
+        Key::ScenarioFailed => ("Scenario failed at {0}, step {1}: {2}", "シナリオが {0} のステップ {1} で失敗しました: {2}"),
+        Key::ScenarioPassed => ("Scenario passed: {0} boots in {1}s", "シナリオに成功しました: {0} 回の起動、{1}秒"),
+        Key::BootStarted => ("Boot {0}/{1}", "起動 {0}/{1}"),
+        Key::BootFailed => ("Boot {0}/{1} failed with exit code {2}; skipping the remaining boots", "起動 {0}/{1} が終了コード {2} で失敗したため、残りの起動を中止します"),
+        Key::PowerCutInvalid => ("invalid power cut `{0}`: expected comma separated after=SECS, random=MIN-MAX, mode=off|reset and marker=TEXT (marker last)", "電源断の指定 `{0}` が不正です: after=SECS、random=MIN-MAX、mode=off|reset、marker=TEXT をカンマで区切って指定してください（marker は最後）"),
+        Key::PowerCutNeedsBoots => ("`--power-cut` needs at least 2 boots to check the recovery", "`--power-cut` には回復を確かめるため2回以上の起動が必要です"),
+        Key::PowerCutNeedsSerialLog => ("`--power-cut` with a marker cannot be used when the serial output is redirected", "シリアルの出力先を変更している場合は、マーカーを指定した `--power-cut` を使えません"),
+        Key::PowerCutSeed => ("Power cut seed: {0}", "電源断の乱数シード: {0}"),
+        Key::PowerCutInjected => ("Cut the power of boot {0} after {1}s", "起動 {0} の電源を {1}秒後に断ちました"),
+        Key::PowerCutMissed => ("boot {0} finished before the power cut", "起動 {0} は電源を断つ前に終了しました"),
+        Key::BlkdebugInvalid => ("invalid value in the I/O errors of a data disk: {0}", "データディスクのI/Oエラーの設定に不正な値があります: {0}"),
+        Key::NetImpairInvalid => ("invalid network impairment `{0}`: expected comma separated latency=MS, jitter=MS, loss=PERCENT (0-100), rate=KBPS (above 0) and device=DEVICE", "ネットワークの制限 `{0}` が不正です: latency=MS、jitter=MS、loss=PERCENT（0〜100）、rate=KBPS（1以上）、device=DEVICE をカンマで区切って指定してください"),
+        Key::NetImpairSummary => ("network: {0} frames forwarded and {1} dropped from the guest, {2} forwarded and {3} dropped to the guest", "ネットワーク: ゲストからのフレームを {0} 個転送し {1} 個破棄、ゲストへのフレームを {2} 個転送し {3} 個破棄しました"),
+        Key::PresetApplied => ("preset {0}: {1}", "プリセット {0}: {1}"),
+        Key::DevicePresetApplied => ("device preset {0}: {1}", "デバイスプリセット {0}: {1}"),
+        Key::DevicePresetUnknown => ("unknown device preset `{0}` (available: {1})", "デバイスプリセット `{0}` はありません（使えるもの: {1}）"),
+        Key::Provisioned => ("provisioned {0} UEFI variables and {1} data disks (rtc: {2})", "UEFI変数を {0} 個、データディスクを {1} 個用意しました（時計: {2}）"),
+        Key::ProvisionVariableInvalid => ("invalid UEFI variable `{0}` to provision: {1}", "用意するUEFI変数 `{0}` が不正です: {1}"),
+        Key::ProvisionGuidInvalid => ("invalid guid `{0}`", "guid `{0}` が不正です"),
+        Key::ProvisionAttributeUnknown => ("unknown attribute `{0}` (available: {1})", "不明な属性 `{0}`（指定できる属性: {1}）"),
+        Key::ProvisionHexInvalid => ("invalid hex `{0}`", "hex `{0}` が不正です"),
+        Key::ProvisionFileUnreadable => ("cannot read {0}: {1}", "{0} を読み込めません: {1}"),
+        Key::ProvisionDataKind => ("exactly one of hex, string, utf16 and file is required", "hex、string、utf16、file のいずれか1つを指定してください"),
+        Key::ProvisionRtcInvalid => ("invalid rtc `{0}` to provision: expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS", "用意する時計の値 `{0}` が不正です: YYYY-MM-DD または YYYY-MM-DDTHH:MM:SS の形式で指定してください"),
+        Key::ProvisionNeedsVars => ("provisioning UEFI variables needs a firmware with a separate VARS image", "UEFI変数を用意するには、VARSイメージが分かれたファームウェアが必要です"),
+        Key::ProvisionVarsFormat => ("the VARS image does not contain a variable store in a known format", "VARSイメージに既知の形式の変数ストアがありません"),
+        Key::ProvisionVarsFull => ("the variable store in the VARS image is full", "VARSイメージの変数ストアに空きがありません"),
+        Key::QemuTimedOut => ("QEMU did not exit within {0} seconds and was stopped", "QEMUが {0} 秒以内に終了しなかったため停止しました"),
+        Key::MemberMemoryInvalid => ("invalid member memory size `{0}`: expected a multiple of 1 MiB", "メンバーのメモリサイズ `{0}` が不正です: 1MiBの倍数で指定してください"),
+        Key::MemberOverridesApplied => ("{0}: member settings {1}", "{0}: メンバーの設定 {1}"),
+        Key::MemorySweepInvalid => ("invalid memory sweep `{0}`: expected MIN..MAX [step STEP] in multiples of 1 MiB", "メモリの範囲 `{0}` が不正です: 1MiBの倍数で MIN..MAX [step STEP] の形式で指定してください"),
+        Key::MemorySweepBooted => ("booted", "起動しました"),
+        Key::MemorySweepFailed => ("failed with exit code {0}", "終了コード {0} で失敗しました"),
+        Key::MemorySweepMinimum => ("Minimum memory to boot: {0}", "起動できる最小のメモリサイズ: {0}"),
+        Key::MemorySweepNone => ("The application did not boot with any memory size in the range", "範囲内のどのメモリサイズでも起動できませんでした"),
+        Key::ExtraFileInvalid => ("invalid file to place on the ESP: {0} (expected HOST_PATH:/ESP/PATH)", "ESPに配置するファイルの指定が不正です: {0}（HOST_PATH:/ESP/PATH の形式で指定してください）"),
+        Key::EspPathInvalid => ("invalid path on the ESP: {0}", "ESP上のパスが不正です: {0}"),
+        Key::CheckpointNeedsSerialLog => (
+            "checkpoints with a marker need the serial output logged; remove -serial, -nographic and --serial-tcp",
+            "マーカーを持つチェックポイントにはシリアルの出力のログが必要です。-serial、-nographic、--serial-tcp を外してください"
+        ),
+        Key::CheckpointFailed => ("checkpoint failed: {0}", "チェックポイントの検査に失敗しました: {0}"),
+        Key::CheckpointsPassed => ("All {0} checkpoints were reached in order and in time", "{0} 個のチェックポイントを順番どおり期限内に通過しました"),
+        Key::CheckpointMissed => ("`{0}` was not reached", "`{0}` を通過しませんでした"),
+        Key::CheckpointMissedAfter => ("`{0}` was not reached after `{1}` at {2}", "`{1}`（{2}）の後に `{0}` を通過しませんでした"),
+        Key::CheckpointOutOfOrder => ("`{0}` was reached at {1}, before `{2}` at {3}", "`{0}` を {1} に通過しましたが、これは `{2}`（{3}）より前です"),
+        Key::CheckpointLate => ("`{0}` was reached at {1}, after its deadline of {2}s", "`{0}` を {1} に通過しましたが、期限の {2}秒 を過ぎています"),
+        Key::CheckpointDeadlineStop => ("`{0}` was not reached within its deadline of {1}s; stopping the VM", "`{0}` を期限の {1}秒 までに通過しなかったため、VMを止めます"),
+        Key::ProbeInvalid => ("invalid `{1}` of the probe `{0}`", "検査 `{0}` の `{1}` が不正です"),
+        Key::ProbeInvalidExpect => ("invalid `expect` regex of the probe `{0}`: {1}", "検査 `{0}` の `expect` の正規表現が不正です: {1}"),
+        Key::ProbeClosed => ("the connection was closed without a response", "応答がないまま接続が閉じられました"),
+        Key::ProbeUnexpected => ("unexpected response `{0}`", "期待しない応答です: `{0}`"),
+        Key::ProbeNotStarted => ("the guest stopped before the probe started", "検査を始める前にゲストが停止しました"),
+        Key::ProbePassed => ("probe `{0}` passed at {1}", "検査 `{0}` に {1} で成功しました"),
+        Key::ProbeFailed => ("probe `{0}` failed: {1}", "検査 `{0}` に失敗しました: {1}"),
+        Key::QemuFailed => ("QEMU failed: {0}{1}", "QEMUの実行に失敗しました: {0}{1}"),
+        Key::QemuStderrSaved => (" (QEMU's output is saved in {0})", "（QEMUの出力は {0} に保存されています）"),
+        Key::QemuKvmDenied => (
+            "no permission to open /dev/kvm; add yourself to the `kvm` group (e.g. `sudo usermod -aG kvm $USER`) and log in again, or run with --no-kvm",
+            "/dev/kvm を開く権限がありません。`kvm` グループに追加して（例: `sudo usermod -aG kvm $USER`）ログインし直すか、--no-kvm を指定して実行してください"
+        ),
+        Key::QemuKvmMissing => (
+            "KVM is not available on this host; load the kvm module, enable virtualization in the BIOS, or run with --no-kvm",
+            "このホストではKVMを使えません。kvmモジュールを読み込むか、BIOSで仮想化を有効にするか、--no-kvm を指定して実行してください"
+        ),
+        Key::QemuPflashSize => (
+            "the firmware image does not fit its flash device; {0} and {1} must come from the same firmware build (use --ovmf and --ovmf-vars together)",
+            "ファームウェアのイメージがフラッシュデバイスの大きさに合いません。{0} と {1} は同じビルドのファームウェアである必要があります（--ovmf と --ovmf-vars を組み合わせて指定してください）"
+        ),
+        Key::QemuUnknownDevice => (
+            "this QEMU does not have the device `{0}`; list the available devices with `{1} -device help`",
+            "このQEMUにはデバイス `{0}` がありません。`{1} -device help` で使えるデバイスを確認してください"
+        ),
+        Key::QemuFileMissing => ("QEMU cannot find {0}; check the path in the config or the QEMU arguments", "QEMUが {0} を見つけられません。設定ファイルかQEMUの引数のパスを確認してください"),
+        Key::QemuImageLocked => (
+            "a disk image is in use by another QEMU; stop the other instance or use a copy of the image",
+            "ディスクイメージを他のQEMUが使用しています。そのQEMUを終了するか、イメージのコピーを使ってください"
+        ),
+        Key::QemuAddressInUse => ("a port QEMU tried to listen on is in use; choose another one with --ports", "QEMUが待ち受けようとしたポートが使用中です。--ports で別のポートを指定してください"),
+        Key::KvmMissing => ("KVM is not available; running with TCG, which is much slower", "KVMを使えないため、TCGで実行します（大幅に遅くなります）"),
+        Key::KvmDenied => (
+            "{0} is not accessible (owned by group `{1}`), running with TCG, which is much slower. {2}",
+            "{0} を開く権限がない（所有グループ: `{1}`）ため、TCGで実行します（大幅に遅くなります）。{2}"
+        ),
+        Key::KvmUsermod => ("To use KVM, run `sudo usermod -aG {0} {1}` and log in again", "KVMを使うには `sudo usermod -aG {0} {1}` を実行してログインし直してください"),
+        Key::KvmRelogin => (
+            "You are in group `{0}`, but this session is not; log in again or run `newgrp {0}`",
+            "グループ `{0}` に登録されていますが、今のセッションには反映されていません。ログインし直すか `newgrp {0}` を実行してください"
+        ),
+        Key::KvmAskAdmin => (
+            "Ask the administrator to make {0} accessible, usually to the `kvm` group",
+            "{0} を使えるよう、管理者に権限の設定（通常は `kvm` グループ）を依頼してください"
+        ),
+        Key::UnsupportedArch => ("{0} EFI files are not supported; specify the architecture with --target", "{0} のEFIファイルには対応していません。--target でアーキテクチャを指定してください"),
+        Key::PdbMalformed => ("not a valid PDB file or an unsupported PDB format", "PDBファイルとして不正か、対応していない形式です"),
+        Key::VarsProfileSaved => ("saved UEFI variables as profile {0} ({1})", "UEFI変数をプロファイル {0} として保存しました ({1})"),
+    }
+}
+
+/// 指定した言語でメッセージを組み立てる
+pub fn text_in(lang: Lang, key: Key, args: &[&dyn Display]) -> String {
+    let (en, ja) = template(key);
+    let mut text = match lang {
+        Lang::En => en,
+        Lang::Ja => ja,
+    }.to_string();
+
+    for (idx, arg) in args.iter().enumerate() {
+        text = text.replace(format!("{{{}}}", idx).as_str(), arg.to_string().as_str());
+    }
+
+    text
+}
+
+pub fn text(key: Key, args: &[&dyn Display]) -> String {
+    text_in(lang(), key, args)
+}
+
+/// `msg!(Key, 引数...)` でカタログのメッセージを実行時の言語で組み立てる
+macro_rules! msg {
+    ($key:ident) => {
+        $crate::message::text($crate::message::Key::$key, &[])
+    };
+    ($key:ident, $($arg:expr),+ $(,)?) => {
+        $crate::message::text($crate::message::Key::$key, &[$(&$arg as &dyn std::fmt::Display),+])
+    };
+}
+
+pub(crate) use msg;
+
+/// コマンドラインのヘルプ。サブコマンド名（トップレベルは空文字列）、引数またはサブコマンドのID、英語、日本語の順
+const HELP: &[(&str, &str, &str, &str)] = &[
+    ("", "cache", "Show or remove the firmware, disk image and UEFI variable profile caches", "ファームウェア、ディスクイメージ、UEFI変数のプロファイルのキャッシュを表示・削除する"),
+    ("", "compare", "Run the same application on two firmwares and compare the results", "同じアプリケーションを2つのファームウェアで実行し、結果を比較する"),
+    ("", "install-runner", "Register cargo-uefi as the runner for the UEFI target in `.cargo/config.toml`", "`.cargo/config.toml` にcargo-uefiをUEFIターゲットのrunnerとして登録する"),
+    ("", "inspect", "Show the PE headers, sections, imports, relocations and debug information of a built EFI file", "ビルドしたEFIファイルのPEヘッダ・セクション・インポート・再配置・デバッグ情報を表示する"),
+    ("", "app", "EFI file to run. Cargo passes the built file when cargo-uefi is used as a runner", "実行するEFIファイル。cargoのrunnerとして起動された場合に、ビルドされたファイルが渡される"),
+    ("", "bin", "Name of the binary to run", "実行するバイナリの名前"),
+    ("", "release", "Build with the release profile", "releaseプロファイルでビルドする"),
+    ("", "profile", "Build with the given profile", "指定したプロファイルでビルドする"),
+    ("", "features", "Features to enable (comma separated; can be repeated)", "有効にするフィーチャー（カンマ区切り、複数指定可）"),
+    ("", "all_features", "Enable all features", "全てのフィーチャーを有効にする"),
+    ("", "no_default_features", "Do not enable the default features", "デフォルトのフィーチャーを有効にしない"),
+    ("", "package", "Package to build", "ビルドするパッケージ"),
+    ("", "no_build", "Do not build; use the EFI file already built for the selected profile", "ビルドせず、選んだプロファイルでビルド済みのEFIファイルを使う"),
+    ("", "html_report", "With `--all`, write a self-contained HTML report of every binary (results, serial output, screenshots and run times) to FILE", "`--all` で、全バイナリの結果、シリアルの出力、スクリーンショット、実行時間をまとめた単体で閲覧できるHTMLのレポートをFILEに書き出す"),
+    ("", "all", "Build every binary in the workspace and run them in turn", "ワークスペース内の全バイナリをビルドし、順番に実行する"),
+    ("", "layout", "How to place the application on the ESP", "ESPへのアプリケーションの配置方法"),
+    ("", "systemd_boot", "systemd-boot loader to use with `--layout systemd-boot`", "`--layout systemd-boot` で使うsystemd-bootのローダー"),
+    ("", "temp_root", "Directory to stage files in (default: target/uefi/tmp)", "一時的な配置先のディレクトリ（省略時は target/uefi/tmp）"),
+    ("", "no_lock_wait", "Fail instead of waiting when another cargo-uefi process holds the shared directory lock", "他のcargo-uefiプロセスが共有ディレクトリをロックしている場合、待たずにエラーにする"),
+    ("", "image", "Boot from a GPT disk image with a FAT32 ESP built from the staged files (images with the same content are reused from the cache)", "配置したファイルからFAT32のESPを持つGPTのディスクイメージを生成して起動する（同じ内容のイメージはキャッシュを再利用する）"),
+    ("", "image_size", "Minimum size of the disk image (e.g. 64G). Unused space is allocated sparsely. Implies `--image`", "生成するディスクイメージの最小サイズ（例: 64G）。未使用領域はスパースに確保する。`--image` を含意する"),
+    ("", "disks", "Image file to attach as a data disk (can be repeated)", "データディスクとして接続するイメージファイル（複数指定可）"),
+    ("", "drive_cache", "Cache mode of the drives", "ドライブのキャッシュモード"),
+    ("", "drive_aio", "Asynchronous I/O backend of the drives", "ドライブの非同期I/Oバックエンド"),
+    ("", "drive_discard", "Pass discard (TRIM/UNMAP) requests from the guest through to the host images", "ゲストからのdiscard(TRIM/UNMAP)要求をホストのイメージに反映する"),
+    ("", "report_discard", "After the run, report whether the guest issued discard (TRIM/UNMAP) on each data disk", "実行後に、ゲストが各データディスクにdiscard(TRIM/UNMAP)を発行したかを報告する"),
+    ("", "exit_device", "Add an isa-debug-exit device and return the value written by the guest as the exit code", "isa-debug-exitデバイスを追加し、ゲストが書き込んだ値を終了コードとして返す"),
+    ("", "exit_iobase", "I/O port of the isa-debug-exit device (default: 0xf4). Implies `--exit-device`", "isa-debug-exitデバイスのI/Oポート（既定値: 0xf4）。`--exit-device` を含意する"),
+    ("", "exit_success", "QEMU exit code treated as success (default: 33). Implies `--exit-device`", "成功とみなすQEMUの終了コード（既定値: 33）。`--exit-device` を含意する"),
+    ("", "offline", "Never access the network. Firmware must come from the cache or explicit paths", "ネットワークに一切アクセスしない。ファームウェアはキャッシュか明示したパスから取得する"),
+    ("", "ovmf", "Firmware image to boot with (CODE image, or combined CODE and VARS)", "起動に使うファームウェアのイメージ（CODE、またはCODEとVARSを結合したイメージ）"),
+    ("", "ovmf_vars", "VARS image used with `--ovmf`. A copy is attached as a writable pflash drive on each run", "`--ovmf` と組で使うVARSイメージ。実行ごとの複製を書き込み可能なpflashとして接続する"),
+    ("", "download_ovmf", "Fetch a prebuilt firmware into the cache if none is found locally", "ローカルにファームウェアが見つからなければ、ビルド済みのものを取得してキャッシュする"),
+    ("", "ovmf_prebuilt", "Fetch the firmware from a rust-osdev/ovmf-prebuilt release (tag name)", "rust-osdev/ovmf-prebuilt のリリース（タグ名）からファームウェアを取得して使う"),
+    ("", "ovmf_prebuilt_sha256", "SHA-256 of the tarball fetched with `--ovmf-prebuilt`", "`--ovmf-prebuilt` で取得するtarballの SHA-256"),
+    ("", "guest_control", "Accept requests from the guest over COM2 (log, event, checkpoint, file write, screenshot, image base)", "COM2を通してゲストからの要求（ログ、イベント、チェックポイント、ファイルの保存、スクリーンショット、読み込み先の報告）を受け付ける"),
+    ("", "quiet", "Do not print progress messages", "進捗などのメッセージを出力しない"),
+    ("", "message_format", "Format of messages on stdout. With `json`, guest output also becomes per-line events", "標準出力に出すメッセージの形式。`json` ではゲストの出力も行ごとのイベントになる"),
+    ("", "shim", "Boot the application through a validation shim and judge the run by the EFI_STATUS it returns (x86_64 only)", "検証用のシムを通してアプリケーションを起動し、返したEFI_STATUSで成否を判定する（x86_64のみ）"),
+    ("", "log_level", "Most detailed level of guest logs to show. Lines tagged with a more detailed level are hidden", "表示するゲストのログのレベル。これより詳しいレベルのタグが付いた行は表示しない"),
+    ("", "allow_unverified_firmware", "Use the firmware with a warning even if its signature cannot be verified with the configured key", "設定された鍵でファームウェアの署名を検証できなくても、警告を出して使用する"),
+    ("", "fw_cfg", "Pass a file or string to the guest through fw_cfg (name=opt/...,file=FILE or name=opt/...,string=STRING; can be repeated)", "fw_cfgを通してファイルまたは文字列をゲストに渡す（name=opt/...,file=FILE または name=opt/...,string=STRING。複数指定可）"),
+    ("", "vars_profile", "Start with the UEFI variables saved as this profile under target/uefi/varstores", "target/uefi/varstores に保存したこのプロファイルのUEFI変数で起動する"),
+    ("", "save_vars_profile", "Save the UEFI variables after the run as this profile under target/uefi/varstores", "実行後のUEFI変数を target/uefi/varstores にこのプロファイルとして保存する"),
+    ("", "ci", "Run as a CI test runner: add the exit device (x86 only), stream the serial output to stdout without a display, exit QEMU on reset and apply a timeout (default: 300 seconds)", "CIでテストを実行するrunner向けに、終了コードを受け渡すデバイスを追加し（x86のみ）、画面を出さずにシリアルを標準出力に流し、リセットではQEMUを終了させ、タイムアウト（既定値: 300秒）を設ける"),
+    ("", "timeout", "Seconds before QEMU is stopped", "QEMUを終了させるまでの秒数"),
+    ("", "artifacts_dir", "Export the serial and firmware logs, screenshots, result JSON and generated images of each run to this directory, laid out for CI artifact upload", "実行ごとのシリアルとファームウェアのログ、スクリーンショット、結果のJSON、生成したイメージを、CIの成果物としてそのままアップロードできる構成でこのディレクトリに書き出す"),
+    ("", "watch", "Watch for file changes and reboot the VM. Re-stage without invoking cargo when only staged data files change", "ファイルの変更を監視し、変更があればVMを起動し直す。ESPに配置するデータだけが変わった場合は、cargoでビルドせずに配置し直す"),
+    ("", "break_on", "Pause the VM and wait for GDB when this text appears in the guest output (repeatable). With --attach-gdb, GDB is started and attached", "ゲストの出力にこの文字列が現れたらVMを一時停止し、GDBの接続を待つ（複数指定可）。`--attach-gdb` ではGDBを起動して接続する"),
+    ("", "cpu_time_limit", "Kill QEMU after it has used this many seconds of CPU time", "QEMUがこの秒数のCPU時間を使ったら終了させる"),
+    ("", "memory_limit", "Kill QEMU when its resident memory exceeds this size (e.g. `2G`)", "QEMUの常駐メモリがこのサイズ（例: `2G`）を超えたら強制終了する"),
+    ("", "nice", "Run QEMU with this nice value (-20 to 19), lowering its priority against other jobs", "QEMUをこのnice値（-20〜19）で実行し、他のジョブより優先度を下げる"),
+    ("", "target", "Architecture to build for and boot (x86_64, aarch64 or i686). Detected from EFI_FILE if omitted", "ビルドして起動するアーキテクチャ（x86_64、aarch64、i686）。省略した場合はEFI_FILEから判定する"),
+    ("", "memory_sweep", "Boot with increasing memory sizes and report the smallest that boots (e.g. `64M..1G step 64M`)", "メモリサイズを変えながら起動し、起動できる最小のサイズを報告する（例: `64M..1G step 64M`）"),
+    ("", "boots", "Boot the VM N times, keeping the UEFI variables and disks between boots. Serial output of each boot is logged separately", "UEFI変数とディスクを引き継いだままVMをN回起動する。シリアルの出力は起動ごとにログへ記録する"),
+    ("", "power_cut", "Cut the power in every boot but the last (comma separated after=SECS, random=MIN-MAX, mode=off|reset and marker=TEXT; marker last)", "最後以外の起動で電源断を起こす（`after=SECS`、`random=MIN-MAX`、`mode=off|reset`、`marker=TEXT` をカンマで区切る。`marker` は最後に書く）"),
+    ("", "power_cut_seed", "Seed for the times picked by `random` in `--power-cut`. The same seed cuts at the same times", "`--power-cut` の `random` で時刻を選ぶ乱数のシード。同じシードなら同じ時刻に電源を断つ"),
+    ("", "no_reboot", "Exit QEMU instead of rebooting when the guest resets, e.g. on a triple fault", "トリプルフォールトなどでゲストがリセットした場合に、再起動せずQEMUを終了する"),
+    ("", "no_shutdown", "Keep the VM stopped instead of exiting QEMU when the guest powers off", "ゲストが電源断してもQEMUを終了せず、VMを停止したままにする"),
+    ("", "freeze_on_crash", "Keep the VM paused at a triple fault or guest panic and wait for GDB to attach", "トリプルフォールトやゲストのパニックの時点でVMを停止したままにし、GDBの接続を待つ"),
+    ("", "attach_gdb", "Start GDB and attach it when the VM freezes. Implies `--freeze-on-crash`", "VMが停止したらGDBを起動して接続する。`--freeze-on-crash` を含意する"),
+    ("", "qemu_trace", "Record QEMU logs such as `int,guest_errors,unimp` to a file and summarize them after the run", "`int,guest_errors,unimp` などのQEMUのログをファイルに記録し、実行後に集計する"),
+    ("", "vnc", "Expose the display over VNC on a free local port", "ローカルホストの空いているポートでVNCによって画面を公開する"),
+    ("", "serial_tcp", "Expose the serial console over TCP on a free local port", "ローカルホストの空いているポートでシリアルコンソールをTCPで公開する"),
+    ("", "ports", "Use a fixed port for a feature instead of a free one (e.g. gdb=1234, or probe-<NAME>=8080 for the probe NAME; can be repeated)", "空いているポートの代わりに固定のポートを使う（例: gdb=1234。検査 NAME には probe-<NAME>=8080。複数指定可）"),
+    ("", "no_kvm", "Do not enable KVM even if /dev/kvm is accessible, and run with TCG", "/dev/kvm を使える場合でもKVMを有効にせず、TCGで実行する"),
+    ("", "with", "Add vetted device groups (comma separated; repeatable): net-virtio, net-e1000, storage-nvme, storage-virtio, gfx-virtio, rng, usb-input, or those defined in `device-presets` of the config", "検証済みのデバイスの組を追加する（カンマ区切り、複数指定可）: net-virtio、net-e1000、storage-nvme、storage-virtio、gfx-virtio、rng、usb-input、または設定ファイルの `device-presets` で定義したもの"),
+    ("", "preset", "Boot the VM as a typical machine (memory, vCPUs, display, disks). low-end: 128 MiB, one slow TCG vCPU, 800x600; server: 16 vCPUs over 2 NUMA nodes, 8 GiB, NVMe", "想定するハードウェアの構成でVMを起動する。low-end: 128MiB、遅くしたTCGの1 vCPU、800x600の画面。server: 2つのNUMAノードに分けた16 vCPUと8GiB、NVMe"),
+    ("", "net_impair", "Add latency, loss and bandwidth limits to the user-mode network (latency=MS,jitter=MS,loss=PERCENT,rate=KBPS,device=DEVICE). Overrides `network-impairment` in the config", "ユーザーモードネットワークに遅延、損失、帯域の制限を加える（latency=MS,jitter=MS,loss=PERCENT,rate=KBPS,device=DEVICE）。設定ファイルの `network-impairment` より優先する"),
+    ("", "stamp_build_info", "Stage a copy of the binary with the git revision, build time and profile embedded as a `.build` section", "gitのリビジョン、ビルド時刻、プロファイルを `.build` セクションとして埋め込んだバイナリを配置する"),
+    ("", "extra_files", "Place a file on the ESP next to the application (HOST_PATH:/ESP/PATH, e.g. `initrd.img:/EFI/myapp/initrd.img`; repeatable)", "アプリケーションと一緒にESPへファイルを配置する（HOST_PATH:/ESP/PATH の形式。例: `initrd.img:/EFI/myapp/initrd.img`。複数指定可）"),
+    ("", "explain_staging", "Print the files that would be placed on the ESP and what would change since the last run, without staging or booting", "ESPに配置するファイルと前回の実行から変わる内容を表示し、配置や起動はしない"),
+    ("", "stage_shell", "Place the UEFI Shell bundled with the firmware at \\EFI\\tools\\Shell.efi", "ファームウェアに付属するUEFI Shellを \\EFI\\tools\\Shell.efi に配置する"),
+    ("", "qemu_cmd", "Extra arguments passed to QEMU", "QEMUに渡す追加の引数"),
+    ("", "scenario", "Run the ordered multi-boot steps described in a scenario file", "シナリオファイルに書かれた、複数回の起動にまたがる手順を実行する"),
+    ("scenario", "file", "Scenario file (TOML) to run", "実行するシナリオファイル（TOML）"),
+    ("", "shell-tools", "Stage the UEFI Shell (with its built-in memmap, dmpstore and setvar) and the tools from the `shell-tools` bundle in \\EFI\\tools alongside the application and run it", "UEFI Shell（組み込みの memmap、dmpstore、setvar を含む）と `shell-tools` のバンドルのツールをアプリケーションと一緒に \\EFI\\tools に配置して実行する"),
+    ("shell-tools", "tools", "Tools to stage, comma separated (`shell`, or the file name without `.efi` of a tool in the `shell-tools` bundle). Defaults to every available tool", "配置するツール（カンマ区切り。`shell` か、`shell-tools` のバンドルのツールのファイル名から `.efi` を除いたもの）。省略した場合は、用意できるツールを全て配置する"),
+    ("install-runner", "force", "Replace a different runner that is already configured with cargo-uefi", "別のrunnerが登録されていても、cargo-uefiで置き換える"),
+    ("", "verify-image", "Validate the partition table, ESP, boot files and signatures of a disk image or ISO", "ディスクイメージやISOのパーティション、ESP、ブートファイル、署名を検証する"),
+    ("verify-image", "disk_image", "Disk image to validate (GPT or MBR disk, FAT image, or ISO)", "検証するディスクイメージ（GPT/MBRのディスク、FATイメージ、ISO）"),
+    ("verify-image", "db_certs", "Certificate (PEM) the boot files must be signed with. Overrides `secure-boot.db` in the config", "ブートファイルのSecure Boot署名を検証する証明書（PEM）。設定ファイルの `secure-boot.db` より優先する"),
+    ("cache", "action", "`list` shows the cache entries and their sizes, `dir` shows the cache directories and `prune` removes old entries", "`list` はキャッシュの項目と大きさを、`dir` はキャッシュのディレクトリを表示し、`prune` は古い項目を削除する"),
+    ("cache", "older_than", "Remove entries with `prune` that have not been updated for this long (e.g. `30d`, `12h`)", "`prune` で削除する項目の、最後に更新されてからの期間（例: `30d`、`12h`）"),
+    ("inspect", "file", "EFI file to inspect. Defaults to the built binary selected with `--bin` and friends", "調べるEFIファイル。省略した場合は `--bin` などで選んだビルド済みのバイナリ"),
+    ("inspect", "size", "Show a size breakdown per section, crate and function", "セクション・クレート・関数ごとのサイズの内訳を表示する"),
+    ("inspect", "top", "Number of crates and functions shown by `--size`", "`--size` で表示するクレートと関数の数"),
+    ("inspect", "pdb", "PDB file to read symbols from for `--size`. Defaults to the path recorded in the debug directory, then the PDB next to the EFI file", "`--size` でシンボルを読み取るPDBファイル。省略した場合はデバッグ情報に記録された場所、次にEFIファイルの隣のPDBを使う"),
+    ("compare", "firmware", "Firmware images to compare (give exactly two)", "比較するファームウェアイメージ（2つ指定する）"),
+    ("compare", "timeout", "Seconds before each QEMU run is killed", "各実行でQEMUを強制終了するまでの秒数"),
+    ("compare", "qemu_cmd", "Extra arguments passed to QEMU", "QEMUに渡す追加の引数"),
+];
+
+fn help(command: &str, id: &str, lang: Lang) -> Option<&'static str> {
+    HELP.iter()
+        .find(|(c, i, _, _)| *c == command && *i == id)
+        .map(|(_, _, en, ja)| match lang {
+            Lang::En => *en,
+            Lang::Ja => *ja,
+        })
+}
+
+/// clapのコマンド定義のヘルプを、指定した言語のものに置き換える
+pub fn localize(command: clap::Command, lang: Lang) -> clap::Command {
+    fn localize_args(mut command: clap::Command, name: &str, lang: Lang) -> clap::Command {
+        let ids: Vec<String> = command.get_arguments().map(|a| a.get_id().to_string()).collect();
+        for id in ids {
+            if let Some(text) = help(name, id.as_str(), lang) {
+                command = command.mut_arg(id, |a| a.help(text));
+            }
+        }
+
+        command
+    }
+
+    let mut command = localize_args(command, "", lang);
+    let subcommands: Vec<String> = command.get_subcommands().map(|c| c.get_name().to_string()).collect();
+    for name in subcommands {
+        let about = help("", name.as_str(), lang);
+        command = command.mut_subcommand(name.as_str(), |sub| {
+            let sub = localize_args(sub, name.as_str(), lang);
+            match about {
+                Some(about) => sub.about(about),
+                None => sub,
+            }
+        });
+    }
+
+    command
+}
+
+#[cfg(test)]
+mod test {
+    use clap::CommandFactory;
+    use crate::message::{help, text_in, Key, Lang};
+
+    #[test]
+    fn detect_language() {
+        let env = |vars: &'static [(&'static str, &'static str)]| move |name: &str| {
+            vars.iter().find(|(n, _)| *n == name).map(|(_, v)| v.to_string())
+        };
+
+        assert_eq!(Lang::detect(env(&[("LANG", "ja_JP.UTF-8")])), Lang::Ja);
+        assert_eq!(Lang::detect(env(&[("LANG", "ja_JP.UTF-8"), ("LC_ALL", "C")])), Lang::En);
+        assert_eq!(Lang::detect(env(&[("LANG", "en_US.UTF-8"), ("CARGO_UEFI_LANG", "ja")])), Lang::Ja);
+        assert_eq!(Lang::detect(env(&[("LC_ALL", ""), ("LANG", "ja_JP")])), Lang::Ja);
+        assert_eq!(Lang::detect(env(&[])), Lang::En);
+    }
+
+    #[test]
+    fn substitute_arguments() {
+        let en = text_in(Lang::En, Key::ChecksumMismatch, &[&"a.tar.xz", &"00", &"ff"]);
+        assert_eq!(en, "checksum mismatch for a.tar.xz: expected 00, got ff");
+        let ja = text_in(Lang::Ja, Key::ChecksumMismatch, &[&"a.tar.xz", &"00", &"ff"]);
+        assert_eq!(ja, "a.tar.xz のチェックサムが一致しません: 期待値 00、実際 ff");
+    }
+
+    #[test]
+    fn every_argument_has_help() {
+        let command = crate::Args::command();
+        let builtin = ["help", "version"];
+        let mut missing = Vec::new();
+
+        for arg in command.get_arguments().filter(|a| !builtin.contains(&a.get_id().as_str())) {
+            if help("", arg.get_id().as_str(), Lang::En).is_none() {
+                missing.push(arg.get_id().to_string());
+            }
+        }
+        for sub in command.get_subcommands() {
+            if help("", sub.get_name(), Lang::En).is_none() {
+                missing.push(sub.get_name().to_string());
+            }
+            for arg in sub.get_arguments().filter(|a| !builtin.contains(&a.get_id().as_str())) {
+                if help(sub.get_name(), arg.get_id().as_str(), Lang::Ja).is_none() {
+                    missing.push(format!("{} {}", sub.get_name(), arg.get_id()));
+                }
+            }
+        }
+
+        assert!(missing.is_empty(), "missing help for {:?}", missing);
+    }
+}
@@ -0,0 +1,317 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use serde_json::json;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::ports::PortAllocator;
+use crate::powercut::Rng;
+
+/// 遅延を待っている間に溜めておけるフレームの数。溢れたフレームは捨てる
+const QUEUE_LIMIT: usize = 1000;
+
+/// 受け付けるフレームの最大の長さ。QEMUのsocketネットワークが送るEthernetフレームはこれより短い
+const MAX_FRAME: usize = 65536;
+
+/// ユーザーモードネットワークに加える遅延、損失、帯域の制限
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Impairment {
+    /// 片方向の遅延（ミリ秒）
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// 遅延のゆらぎ（ミリ秒）。フレームごとに `latency-ms` ± `jitter-ms` の範囲で選ぶ
+    #[serde(default)]
+    pub jitter_ms: u64,
+    /// フレームを捨てる割合（パーセント）
+    #[serde(default)]
+    pub loss: f64,
+    /// 片方向の帯域（キロビット毎秒）。省略時は制限しない
+    pub rate_kbps: Option<u64>,
+    /// ゲストに見せるNICのデバイス（省略時は virtio-net-pci）
+    pub device: Option<String>,
+}
+
+impl Impairment {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(0.0..=100.0).contains(&self.loss) || self.rate_kbps == Some(0) || self.device.as_deref() == Some("") {
+            return Err(Error::new(ErrorKind::InvalidArgument, msg!(NetImpairInvalid, "network-impairment")));
+        }
+
+        Ok(())
+    }
+
+    /// フレームを送り出せるようになる時刻。`link_free` は直前のフレームを送り終える時刻で、送り終える時刻に更新する
+    fn due(&self, arrived: Instant, len: usize, link_free: &mut Instant, rng: &mut Rng) -> Instant {
+        let transmit = match self.rate_kbps {
+            Some(rate) => Duration::from_secs_f64(len as f64 * 8.0 / (rate as f64 * 1000.0)),
+            None => Duration::ZERO,
+        };
+        *link_free = arrived.max(*link_free) + transmit;
+
+        let jitter = self.jitter_ms as f64 * (2.0 * rng.next_f64() - 1.0);
+        let delay = (self.latency_ms as f64 + jitter).max(0.0);
+        *link_free + Duration::from_secs_f64(delay / 1000.0)
+    }
+}
+
+/// `latency=MS,jitter=MS,loss=PERCENT,rate=KBPS,device=DEVICE` の形式の引数を読み取る
+pub fn parse_impairment(s: &str) -> Result<Impairment, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidArgument, msg!(NetImpairInvalid, s));
+
+    let mut impairment = Impairment::default();
+    for option in s.split(',').filter(|o| !o.is_empty()) {
+        let (key, value) = option.split_once('=').ok_or_else(invalid)?;
+        match key {
+            "latency" => impairment.latency_ms = value.parse().map_err(|_| invalid())?,
+            "jitter" => impairment.jitter_ms = value.parse().map_err(|_| invalid())?,
+            "loss" => impairment.loss = value.trim_end_matches('%').parse().map_err(|_| invalid())?,
+            "rate" => impairment.rate_kbps = Some(value.parse().map_err(|_| invalid())?),
+            "device" => impairment.device = Some(value.to_string()),
+            _ => return Err(invalid()),
+        }
+    }
+    impairment.validate().map_err(|_| invalid())?;
+
+    Ok(impairment)
+}
+
+/// 方向ごとの転送したフレームと捨てたフレームの数
+#[derive(Debug, Default)]
+struct Counters {
+    forwarded: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// 実行中に転送したフレームの集計
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// ゲストから外へ向かう方向の (転送, 破棄)
+    pub from_guest: (u64, u64),
+    /// 外からゲストへ向かう方向の (転送, 破棄)
+    pub to_guest: (u64, u64),
+}
+
+/// ゲストのNICとユーザーモードネットワークの間に入り、フレームを遅らせたり捨てたりしながら中継する。
+///
+/// QEMUのsocketネットデバイスは、4バイトのビッグエンディアンの長さに続けてEthernetフレームを送る。
+/// ゲストのNICはこのプロセスに接続し、ユーザーモードネットワークはハブを介してもう1つのsocketネットデバイスに繋ぐ。
+/// QEMUを起動し直した場合（`--boots` など）は、接続し直されるたびに中継を再開する。
+pub struct ImpairedNetwork {
+    guest_addr: net::SocketAddr,
+    link_addr: net::SocketAddr,
+    device: String,
+    from_guest: Arc<Counters>,
+    to_guest: Arc<Counters>,
+    stop: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+    _ports: PortAllocator,
+}
+
+impl ImpairedNetwork {
+    pub fn start(impairment: &Impairment, mut ports: PortAllocator) -> Result<ImpairedNetwork, io::Error> {
+        // QEMUが接続してくる前に待ち受けを始めておく
+        let guest = ports.listen("net-guest")?;
+        let link = ports.listen("net-link")?;
+        guest.set_nonblocking(true)?;
+        link.set_nonblocking(true)?;
+        let (guest_addr, link_addr) = (guest.local_addr()?, link.local_addr()?);
+
+        let from_guest = Arc::new(Counters::default());
+        let to_guest = Arc::new(Counters::default());
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_impairment, thread_from, thread_to, thread_stop) = (impairment.clone(), from_guest.clone(), to_guest.clone(), stop.clone());
+        let handle = thread::spawn(move || {
+            let mut seed = Rng::seed_from_time();
+            while let (Some(guest), Some(link)) = (accept(&guest, &thread_stop), accept(&link, &thread_stop)) {
+                let relays = [
+                    relay(&guest, &link, &thread_impairment, seed, thread_from.clone()),
+                    relay(&link, &guest, &thread_impairment, seed.wrapping_add(1), thread_to.clone()),
+                ];
+                // QEMUが終了して両方の接続が閉じられるまで中継する
+                for relay in relays.into_iter().flatten().flatten() {
+                    let _ = relay.join();
+                }
+                seed = seed.wrapping_add(2);
+            }
+        });
+
+        Ok(ImpairedNetwork {
+            guest_addr,
+            link_addr,
+            device: impairment.device.clone().unwrap_or_else(|| "virtio-net-pci".to_string()),
+            from_guest,
+            to_guest,
+            stop,
+            handle,
+            _ports: ports,
+        })
+    }
+
+    /// ゲストのNICとユーザーモードネットワークをこの中継に繋ぐQEMUの引数。`user_options` はユーザーモードネットワークに加える
+    pub fn qemu_args(&self, user_options: &str) -> Vec<String> {
+        [
+            "-netdev", &format!("socket,id=impaired-guest,connect={}", self.guest_addr),
+            "-device", &format!("{},netdev=impaired-guest", self.device),
+            "-netdev", &format!("socket,id=impaired-link,connect={}", self.link_addr),
+            "-netdev", &format!("user,id=impaired-user{}", user_options),
+            "-netdev", "hubport,id=impaired-hub0,hubid=0,netdev=impaired-user",
+            "-netdev", "hubport,id=impaired-hub1,hubid=0,netdev=impaired-link",
+        ].iter().map(|a| a.to_string()).collect()
+    }
+
+    /// QEMUの終了後に呼び、中継したフレームの集計を返す
+    pub fn finish(self) -> Stats {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.handle.join();
+        let counts = |c: &Counters| (c.forwarded.load(Ordering::SeqCst), c.dropped.load(Ordering::SeqCst));
+
+        Stats { from_guest: counts(&self.from_guest), to_guest: counts(&self.to_guest) }
+    }
+}
+
+/// QEMUからの接続を待つ。接続される前に `stop` が立てば `None` を返す
+fn accept(listener: &net::TcpListener, stop: &AtomicBool) -> Option<net::TcpStream> {
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => return stream.set_nonblocking(false).ok().map(|_| stream),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if stop.load(Ordering::SeqCst) {
+                    return None;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            Err(_) => return None,
+        }
+    }
+}
+
+/// `from` から読んだフレームを、制限に従って `to` に書き出す。読み取りと書き出しは別のスレッドで行う
+fn relay(from: &net::TcpStream, to: &net::TcpStream, impairment: &Impairment, seed: u64, counters: Arc<Counters>) -> [Option<thread::JoinHandle<()>>; 2] {
+    let (mut reader, mut writer) = match (from.try_clone(), to.try_clone()) {
+        (Ok(reader), Ok(writer)) => (reader, writer),
+        _ => return [None, None],
+    };
+    let (queue, frames) = mpsc::sync_channel::<(Instant, Vec<u8>)>(QUEUE_LIMIT);
+
+    let (impairment, reader_counters) = (impairment.clone(), counters.clone());
+    let receiver = thread::spawn(move || {
+        let mut rng = Rng::new(seed);
+        let mut link_free = Instant::now();
+        while let Ok(frame) = read_frame(&mut reader) {
+            let arrived = Instant::now();
+            if rng.next_f64() * 100.0 < impairment.loss {
+                reader_counters.dropped.fetch_add(1, Ordering::SeqCst);
+                continue;
+            }
+
+            let due = impairment.due(arrived, frame.len(), &mut link_free, &mut rng);
+            if let Err(mpsc::TrySendError::Full(_)) = queue.try_send((due, frame)) {
+                reader_counters.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    });
+    let sender = thread::spawn(move || {
+        // ゆらぎでフレームの順序が入れ替わらないよう、前のフレームより先には送らない
+        let mut last = Instant::now();
+        for (due, frame) in frames {
+            last = last.max(due);
+            thread::sleep(last.saturating_duration_since(Instant::now()));
+            if writer.write_all(&(frame.len() as u32).to_be_bytes()).and_then(|_| writer.write_all(&frame)).is_err() {
+                break;
+            }
+            counters.forwarded.fetch_add(1, Ordering::SeqCst);
+        }
+        let _ = writer.shutdown(net::Shutdown::Write);
+    });
+
+    [Some(receiver), Some(sender)]
+}
+
+fn read_frame(reader: &mut impl Read) -> Result<Vec<u8>, io::Error> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    // 壊れた相手から巨大な長さを受け取っても、そのまま確保しない
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes exceeds {} bytes", len, MAX_FRAME)));
+    }
+    let mut frame = vec![0u8; len];
+    reader.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+/// 集計を報告する
+pub fn report(impairment: &Impairment, stats: &Stats) {
+    crate::output::status(msg!(NetImpairSummary, stats.from_guest.0, stats.from_guest.1, stats.to_guest.0, stats.to_guest.1));
+    crate::output::event("network-impairment", json!({
+        "latency-ms": impairment.latency_ms,
+        "jitter-ms": impairment.jitter_ms,
+        "loss": impairment.loss,
+        "rate-kbps": impairment.rate_kbps,
+        "from-guest": { "forwarded": stats.from_guest.0, "dropped": stats.from_guest.1 },
+        "to-guest": { "forwarded": stats.to_guest.0, "dropped": stats.to_guest.1 },
+    }));
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net;
+    use std::time::{Duration, Instant};
+    use crate::netem::{parse_impairment, read_frame, ImpairedNetwork, Impairment};
+    use crate::ports::PortAllocator;
+
+    #[test]
+    fn parse_impairment_spec() {
+        let impairment = parse_impairment("latency=100,jitter=20,loss=5%,rate=512,device=e1000").unwrap();
+        assert_eq!(impairment, Impairment {
+            latency_ms: 100,
+            jitter_ms: 20,
+            loss: 5.0,
+            rate_kbps: Some(512),
+            device: Some("e1000".to_string()),
+        });
+
+        for invalid in ["latency", "loss=101", "rate=0", "latency=-1", "delay=5"] {
+            assert!(parse_impairment(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn frames_are_delayed_and_dropped() {
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-netem-{}", std::process::id()));
+        let impairment = Impairment { latency_ms: 150, ..Impairment::default() };
+        let network = ImpairedNetwork::start(&impairment, PortAllocator::new(dir.clone(), &[])).unwrap();
+
+        let mut guest = net::TcpStream::connect(network.guest_addr).unwrap();
+        let mut link = net::TcpStream::connect(network.link_addr).unwrap();
+        let sent = Instant::now();
+        guest.write_all(&[0, 0, 0, 3, 1, 2, 3]).unwrap();
+        assert_eq!(read_frame(&mut link).unwrap(), [1, 2, 3]);
+        assert!(sent.elapsed() >= Duration::from_millis(150));
+        assert!(read_frame(&mut &[0xff, 0xff, 0xff, 0xff][..]).is_err());
+
+        drop((guest, link));
+        let stats = network.finish();
+        assert_eq!((stats.from_guest, stats.to_guest), ((1, 0), (0, 0)));
+
+        // 全て捨てる設定では何も届かない
+        let impairment = Impairment { loss: 100.0, ..Impairment::default() };
+        let network = ImpairedNetwork::start(&impairment, PortAllocator::new(dir.clone(), &[])).unwrap();
+        let mut guest = net::TcpStream::connect(network.guest_addr).unwrap();
+        let link = net::TcpStream::connect(network.link_addr).unwrap();
+        guest.write_all(&[0, 0, 0, 1, 9, 0, 0, 0, 1, 9]).unwrap();
+        drop(guest);
+        link.shutdown(net::Shutdown::Write).unwrap();
+        drop(link);
+        assert_eq!(network.finish().from_guest, (0, 2));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,186 @@
+use std::fmt::Display;
+use std::io;
+use std::io::Write;
+use std::process::Stdio;
+use std::sync::OnceLock;
+use std::thread;
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use crate::guestlog::{Demux, Level, Route};
+
+/// 標準出力に出すメッセージの形式
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum MessageFormat {
+    /// 人が読むための形式
+    #[default]
+    Human,
+    /// 1行に1つのJSONオブジェクト。ゲストの出力もイベントとして出力する
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Mode {
+    quiet: bool,
+    format: MessageFormat,
+    log_level: Level,
+}
+
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+/// 出力の方法を設定する。最初の呼び出しのみ有効
+pub fn init(quiet: bool, format: MessageFormat, log_level: Level) {
+    let _ = MODE.set(Mode { quiet, format, log_level });
+}
+
+fn mode() -> Mode {
+    MODE.get().copied().unwrap_or_default()
+}
+
+pub fn quiet() -> bool {
+    mode().quiet
+}
+
+pub fn json() -> bool {
+    mode().format == MessageFormat::Json
+}
+
+/// 進捗などの人向けのメッセージを出力する。`--quiet` では何も出力しない
+pub fn status(text: impl Display) {
+    if !quiet() {
+        eprintln!("{}", text);
+    }
+}
+
+/// 警告を出力する。JSON形式ではイベントとして出力する
+pub fn warning(text: impl Display) {
+    if json() {
+        event("warning", json!({ "message": text.to_string() }));
+    } else {
+        eprintln!("{}", crate::message::msg!(Warning, text));
+    }
+}
+
+/// JSON形式の場合のみ、`reason` と `fields` を持つイベントを標準出力に1行で出力する
+pub fn event(reason: &str, fields: Value) {
+    if !json() {
+        return;
+    }
+
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "{}", event_object(reason, fields));
+    let _ = stdout.flush();
+}
+
+/// `reason` にイベントの種類を持つJSONのオブジェクト
+pub(crate) fn event_object(reason: &str, fields: Value) -> Value {
+    let mut object = serde_json::Map::new();
+    object.insert("reason".to_string(), Value::String(reason.to_string()));
+    if let Value::Object(fields) = fields {
+        object.extend(fields);
+    }
+    Value::Object(object)
+}
+
+/// 子プロセスの標準エラー出力の扱い。`--quiet` では捨てる
+pub fn child_stderr() -> Stdio {
+    if quiet() {
+        Stdio::null()
+    } else {
+        Stdio::inherit()
+    }
+}
+
+/// JSON形式の場合に、QEMUの利用者向けの出力先を標準入出力のシリアルにする。
+/// 利用者が出力先を指定している場合は変更しない。
+pub fn qemu_args(options: &[String]) -> Vec<String> {
+    if !json() {
+        return Vec::new();
+    }
+
+    headless_args(options)
+}
+
+/// 画面を出さず、シリアルの出力を標準出力に流すQEMUの引数。利用者が指定している項目は変更しない
+pub fn headless_args(options: &[String]) -> Vec<String> {
+    let specified = |flags: &[&str]| options.iter().any(|o| flags.contains(&o.as_str()));
+    let mut args = Vec::new();
+    if !specified(&["-serial", "-nographic"]) {
+        args.extend(["-serial".to_string(), "stdio".to_string()]);
+    }
+    if !specified(&["-display", "-nographic"]) {
+        args.extend(["-display".to_string(), "none".to_string()]);
+    }
+
+    args
+}
+
+/// ゲストのシリアルの出力を、行に付けられたタグに従ってホストの標準出力と標準エラー出力に振り分ける。
+/// `--log-level` より詳しいログは捨て、JSON形式ではどちらのストリームかを付けたイベントとして転送する
+pub fn forward_guest<R: io::Read + Send + 'static>(mut reader: R) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut demux = Demux::new(mode().log_level, !json());
+        let mut buf = [0; 4096];
+        loop {
+            let routed = match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    crate::verdict::observe(&buf[..n]);
+                    crate::breakpoint::observe(&buf[..n]);
+                    demux.feed(&buf[..n])
+                }
+            };
+            routed.iter().for_each(|(route, text)| guest_output(*route, text));
+        }
+        demux.finish().iter().for_each(|(route, text)| guest_output(*route, text));
+    })
+}
+
+fn guest_output(route: Route, text: &[u8]) {
+    if json() {
+        let mut line = guest_line(route.stream(), text);
+        if let Route::Log(level) = route {
+            line["level"] = Value::String(level.name().to_string());
+        }
+        event("guest-output", line);
+        return;
+    }
+
+    let _ = match route {
+        Route::Stdout => io::stdout().lock().write_all(text).and_then(|_| io::stdout().flush()),
+        Route::Stderr | Route::Log(_) => io::stderr().lock().write_all(text),
+    };
+}
+
+/// 子プロセスの標準エラー出力の1行を、JSON形式ではイベントとして、それ以外ではそのまま標準エラー出力に出す
+pub fn child_stderr_line(line: &[u8]) {
+    if json() {
+        event("guest-output", guest_line("stderr", line));
+    } else {
+        let _ = io::stderr().lock().write_all(line);
+    }
+}
+
+fn guest_line(stream: &str, line: &[u8]) -> Value {
+    let text = String::from_utf8_lossy(line);
+    json!({ "stream": stream, "line": text.trim_end_matches(['\r', '\n']) })
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use crate::output::{guest_line, headless_args};
+
+    #[test]
+    fn guest_line_is_tagged_without_newline() {
+        assert_eq!(guest_line("stdout", b"BdsDxe: loading Boot0001\r\n"), json!({ "stream": "stdout", "line": "BdsDxe: loading Boot0001" }));
+        assert_eq!(guest_line("stderr", b"\xff\n"), json!({ "stream": "stderr", "line": "\u{fffd}" }));
+    }
+
+    #[test]
+    fn headless_keeps_recorded_serial() {
+        // `--ci` は成果物の書き出しがシリアルの出力を記録する設定を加えた後で補うため、シリアルを重ねて指定しない
+        let options = crate::seriallog::qemu_args(std::path::Path::new("serial.log"));
+        assert_eq!(headless_args(&options), ["-display", "none"]);
+        assert_eq!(headless_args(&[]), ["-serial", "stdio", "-display", "none"]);
+    }
+}
@@ -0,0 +1,199 @@
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::pe::Symbol;
+
+const MSF_MAGIC: &[u8] = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0";
+/// DBIストリームの番号
+const DBI_STREAM: usize = 3;
+/// 公開シンボルのレコードの種類
+const S_PUB32: u16 = 0x110e;
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// MSF形式のファイルを構成するストリーム
+struct Msf<'a> {
+    data: &'a [u8],
+    block_size: usize,
+    streams: Vec<(usize, Vec<u32>)>,
+}
+
+impl<'a> Msf<'a> {
+    fn parse(data: &'a [u8]) -> Option<Msf<'a>> {
+        if data.get(..MSF_MAGIC.len())? != MSF_MAGIC {
+            return None;
+        }
+        let block_size = u32_at(data, 32)? as usize;
+        let directory_size = u32_at(data, 44)? as usize;
+        let block_map = u32_at(data, 52)? as usize;
+        if block_size == 0 {
+            return None;
+        }
+
+        // ストリームのディレクトリ自体も、ブロックマップに列挙されたブロックに分かれて置かれている。
+        // 壊れたファイルではブロックマップの位置が桁あふれすることがある
+        let block_map = block_map.checked_mul(block_size)?;
+        let directory_blocks: Vec<u32> = (0..directory_size.div_ceil(block_size))
+            .map(|i| u32_at(data, block_map.checked_add(i * 4)?))
+            .collect::<Option<_>>()?;
+        let directory = Msf::read_blocks(data, block_size, &directory_blocks, directory_size)?;
+
+        let count = u32_at(&directory, 0)? as usize;
+        let sizes: Vec<usize> = (0..count)
+            .map(|i| u32_at(&directory, 4 + i * 4).map(|s| if s == u32::MAX { 0 } else { s as usize }))
+            .collect::<Option<_>>()?;
+        let mut pos = 4 + count * 4;
+        let mut streams = Vec::with_capacity(count);
+        for size in sizes {
+            let blocks: Vec<u32> = (0..size.div_ceil(block_size))
+                .map(|i| u32_at(&directory, pos + i * 4))
+                .collect::<Option<_>>()?;
+            pos += blocks.len() * 4;
+            streams.push((size, blocks));
+        }
+
+        Some(Msf { data, block_size, streams })
+    }
+
+    fn read_blocks(data: &[u8], block_size: usize, blocks: &[u32], size: usize) -> Option<Vec<u8>> {
+        // 大きさはファイルに書かれた値のため、ファイルより大きな領域は確保しない
+        let mut out = Vec::with_capacity(size.min(data.len()));
+        for block in blocks {
+            let start = (*block as usize).checked_mul(block_size)?;
+            out.extend_from_slice(data.get(start..start.checked_add(block_size)?)?);
+        }
+        out.truncate(size);
+        Some(out)
+    }
+
+    fn stream(&self, idx: usize) -> Option<Vec<u8>> {
+        let (size, blocks) = self.streams.get(idx)?;
+        Msf::read_blocks(self.data, self.block_size, blocks, *size)
+    }
+}
+
+/// PDBから公開シンボルを読み取る
+pub fn public_symbols(data: &[u8]) -> Result<Vec<Symbol>, Error> {
+    let malformed = || Error::new(ErrorKind::MalformedPe, msg!(PdbMalformed));
+
+    let msf = Msf::parse(data).ok_or_else(malformed)?;
+    let dbi = msf.stream(DBI_STREAM).ok_or_else(malformed)?;
+    let records = u16_at(&dbi, 20).ok_or_else(malformed)? as usize;
+    let records = msf.stream(records).ok_or_else(malformed)?;
+
+    let mut symbols = Vec::new();
+    let mut pos = 0;
+    while let (Some(len), Some(kind)) = (u16_at(&records, pos), u16_at(&records, pos + 2)) {
+        let len = len as usize;
+        if len < 2 {
+            break;
+        }
+        if kind == S_PUB32 {
+            let flags = u32_at(&records, pos + 4).ok_or_else(malformed)?;
+            let offset = u32_at(&records, pos + 8).ok_or_else(malformed)?;
+            let section = u16_at(&records, pos + 12).ok_or_else(malformed)?;
+            let name = records.get(pos + 14..pos + 2 + len).ok_or_else(malformed)?;
+            let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+            symbols.push(Symbol {
+                name: String::from_utf8_lossy(name).into_owned(),
+                section,
+                offset,
+                is_function: flags & 0x2 != 0,
+            });
+        }
+        pos += 2 + len;
+    }
+
+    Ok(symbols)
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::pdb::{public_symbols, MSF_MAGIC, S_PUB32};
+
+    /// 公開シンボルのレコードを並べたバイト列
+    pub fn pub32(symbols: &[(&str, u16, u32)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, section, offset) in symbols {
+            let mut record = Vec::new();
+            record.extend_from_slice(&S_PUB32.to_le_bytes());
+            record.extend_from_slice(&2u32.to_le_bytes());
+            record.extend_from_slice(&offset.to_le_bytes());
+            record.extend_from_slice(&section.to_le_bytes());
+            record.extend_from_slice(name.as_bytes());
+            record.push(0);
+            while (record.len() + 2) % 4 != 0 {
+                record.push(0);
+            }
+            out.extend_from_slice(&(record.len() as u16).to_le_bytes());
+            out.extend_from_slice(&record);
+        }
+        out
+    }
+
+    /// ストリームを1つずつブロックに置いた、最小限のMSFファイルを作る
+    pub fn msf(streams: &[Vec<u8>]) -> Vec<u8> {
+        const BLOCK: usize = 512;
+        let mut blocks: Vec<Vec<u8>> = vec![vec![0; BLOCK]];
+        let mut directory = Vec::new();
+        directory.extend_from_slice(&(streams.len() as u32).to_le_bytes());
+        for stream in streams {
+            directory.extend_from_slice(&(stream.len() as u32).to_le_bytes());
+        }
+        for stream in streams {
+            for chunk in stream.chunks(BLOCK) {
+                directory.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+                let mut block = chunk.to_vec();
+                block.resize(BLOCK, 0);
+                blocks.push(block);
+            }
+        }
+
+        let directory_block = blocks.len() as u32;
+        let mut block = directory.clone();
+        block.resize(BLOCK, 0);
+        blocks.push(block);
+        let map_block = blocks.len() as u32;
+        let mut block = directory_block.to_le_bytes().to_vec();
+        block.resize(BLOCK, 0);
+        blocks.push(block);
+
+        let header = &mut blocks[0];
+        header[..MSF_MAGIC.len()].copy_from_slice(MSF_MAGIC);
+        header[32..36].copy_from_slice(&(BLOCK as u32).to_le_bytes());
+        header[40..44].copy_from_slice(&(map_block + 1).to_le_bytes());
+        header[44..48].copy_from_slice(&(directory.len() as u32).to_le_bytes());
+        header[52..56].copy_from_slice(&map_block.to_le_bytes());
+        blocks.concat()
+    }
+
+    /// DBIストリームが4番目のストリームを公開シンボルのレコードとして指すPDBを作る
+    pub fn sample_pdb(symbols: &[(&str, u16, u32)]) -> Vec<u8> {
+        let mut dbi = vec![0u8; 64];
+        dbi[20..22].copy_from_slice(&4u16.to_le_bytes());
+        msf(&[vec![], vec![], vec![], dbi, pub32(symbols)])
+    }
+
+    #[test]
+    fn read_public_symbols() {
+        let pdb = sample_pdb(&[("efi_main", 1, 0x10), ("_ZN4core3fmt5write17h0123456789abcdefE", 1, 0x200)]);
+        let symbols = public_symbols(&pdb).unwrap();
+
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[0].name, "efi_main");
+        assert_eq!((symbols[1].section, symbols[1].offset), (1, 0x200));
+        assert!(symbols[1].is_function);
+        assert!(public_symbols(b"not a pdb").is_err());
+
+        // ブロックの大きさとブロックマップの位置が極端な値でも、エラーとして扱う
+        let mut crafted = pdb.clone();
+        crafted[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        crafted[52..56].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(public_symbols(&crafted).is_err());
+    }
+}
@@ -0,0 +1,663 @@
+use std::collections::BTreeMap;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+
+/// データディレクトリの番号
+pub const DIR_IMPORT: usize = 1;
+pub const DIR_BASERELOC: usize = 5;
+pub const DIR_SECURITY: usize = 4;
+pub const DIR_DEBUG: usize = 6;
+
+const SECTION_HEADER_SIZE: usize = 40;
+/// COFFのシンボルテーブルの1項目の大きさ
+const SYMBOL_SIZE: usize = 18;
+
+/// オプションヘッダのデータディレクトリの1項目
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DataDirectory {
+    pub rva: u32,
+    pub size: u32,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub virtual_size: u32,
+    pub virtual_address: u32,
+    pub raw_size: u32,
+    pub raw_offset: u32,
+    pub characteristics: u32,
+}
+
+impl Section {
+    pub fn flags(&self) -> Vec<&'static str> {
+        const FLAGS: &[(u32, &str)] = &[
+            (0x0000_0020, "code"),
+            (0x0000_0040, "data"),
+            (0x0000_0080, "bss"),
+            (0x0200_0000, "discardable"),
+            (0x2000_0000, "exec"),
+            (0x4000_0000, "read"),
+            (0x8000_0000, "write"),
+        ];
+        FLAGS.iter().filter(|(bit, _)| self.characteristics & bit != 0).map(|(_, name)| *name).collect()
+    }
+}
+
+/// インポートしているDLLと関数
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Import {
+    pub dll: String,
+    pub functions: Vec<String>,
+}
+
+/// ベース再配置の集計
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Relocations {
+    pub blocks: usize,
+    /// 再配置の種類ごとの件数（パディングの `ABSOLUTE` を除く）
+    pub by_type: BTreeMap<u8, usize>,
+}
+
+impl Relocations {
+    pub fn total(&self) -> usize {
+        self.by_type.values().sum()
+    }
+}
+
+pub fn relocation_type_name(kind: u8) -> &'static str {
+    match kind {
+        0 => "ABSOLUTE",
+        1 => "HIGH",
+        2 => "LOW",
+        3 => "HIGHLOW",
+        4 => "HIGHADJ",
+        10 => "DIR64",
+        _ => "unknown",
+    }
+}
+
+/// デバッグディレクトリの項目
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DebugEntry {
+    pub kind: u32,
+    /// CodeView(RSDS)に埋め込まれたPDBのパス
+    pub pdb: Option<String>,
+}
+
+pub fn debug_type_name(kind: u32) -> &'static str {
+    match kind {
+        1 => "COFF",
+        2 => "CodeView",
+        4 => "Misc",
+        12 => "VC_FEATURE",
+        13 => "POGO",
+        14 => "ILTCG",
+        16 => "Repro",
+        20 => "ExDllCharacteristics",
+        _ => "unknown",
+    }
+}
+
+/// COFFのシンボルテーブルやPDBから読み取ったシンボル
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// 1から始まるセクション番号
+    pub section: u16,
+    /// セクションの先頭からのオフセット
+    pub offset: u32,
+    pub is_function: bool,
+}
+
+/// UEFIアプリケーションの検査に必要な範囲で読み取ったPE/COFFイメージ
+#[derive(Clone, Debug)]
+pub struct PeFile {
+    data: Vec<u8>,
+    pub machine: u16,
+    pub timestamp: u32,
+    pub characteristics: u16,
+    pub pe32_plus: bool,
+    pub entry_point: u32,
+    pub image_base: u64,
+    pub section_alignment: u32,
+    pub file_alignment: u32,
+    pub size_of_image: u32,
+    pub size_of_headers: u32,
+    pub subsystem: u16,
+    pub dll_characteristics: u16,
+    pub data_directories: Vec<DataDirectory>,
+    pub sections: Vec<Section>,
+    symbol_table: u32,
+    symbol_count: u32,
+    optional_header: usize,
+    section_table: usize,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+fn c_string(data: &[u8], offset: usize) -> Option<String> {
+    let bytes = data.get(offset..)?;
+    let end = bytes.iter().position(|b| *b == 0)?;
+    Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+/// オプションヘッダのCheckSumの値。チェックサム自体の位置は0として、16ビットずつの和にファイルの長さを足す
+fn checksum(data: &[u8]) -> u32 {
+    let mut sum: u64 = 0;
+    for chunk in data.chunks(2) {
+        sum += u16::from_le_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u64;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    (sum as u32).wrapping_add(data.len() as u32)
+}
+
+impl PeFile {
+    pub fn parse(data: Vec<u8>) -> Result<PeFile, Error> {
+        let malformed = |what: &str| Error::new(ErrorKind::MalformedPe, msg!(PeMalformed, what));
+
+        if data.get(..2) != Some(b"MZ") {
+            return Err(malformed("missing MZ signature"));
+        }
+        let pe = u32_at(&data, 0x3c).ok_or_else(|| malformed("truncated DOS header"))? as usize;
+        if data.get(pe..pe + 4) != Some(b"PE\0\0") {
+            return Err(malformed("missing PE signature"));
+        }
+
+        let coff = pe + 4;
+        let truncated = || malformed("truncated COFF header");
+        let machine = u16_at(&data, coff).ok_or_else(truncated)?;
+        let section_count = u16_at(&data, coff + 2).ok_or_else(truncated)? as usize;
+        let timestamp = u32_at(&data, coff + 4).ok_or_else(truncated)?;
+        let symbol_table = u32_at(&data, coff + 8).ok_or_else(truncated)?;
+        let symbol_count = u32_at(&data, coff + 12).ok_or_else(truncated)?;
+        let optional_size = u16_at(&data, coff + 16).ok_or_else(truncated)? as usize;
+        let characteristics = u16_at(&data, coff + 18).ok_or_else(truncated)?;
+
+        let opt = coff + 20;
+        let truncated = || malformed("truncated optional header");
+        let pe32_plus = match u16_at(&data, opt).ok_or_else(truncated)? {
+            0x10b => false,
+            0x20b => true,
+            _ => return Err(malformed("unknown optional header magic")),
+        };
+        let image_base = match pe32_plus {
+            true => u64_at(&data, opt + 24),
+            false => u32_at(&data, opt + 28).map(u64::from),
+        }.ok_or_else(truncated)?;
+        let (count_offset, dirs_offset) = if pe32_plus { (108, 112) } else { (92, 96) };
+        let dir_count = u32_at(&data, opt + count_offset).ok_or_else(truncated)? as usize;
+        let data_directories = (0..dir_count.min(16))
+            .map(|i| {
+                let offset = opt + dirs_offset + i * 8;
+                Some(DataDirectory { rva: u32_at(&data, offset)?, size: u32_at(&data, offset + 4)? })
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(truncated)?;
+
+        let mut file = PeFile {
+            machine,
+            timestamp,
+            characteristics,
+            pe32_plus,
+            entry_point: u32_at(&data, opt + 16).ok_or_else(truncated)?,
+            image_base,
+            section_alignment: u32_at(&data, opt + 32).ok_or_else(truncated)?,
+            file_alignment: u32_at(&data, opt + 36).ok_or_else(truncated)?,
+            size_of_image: u32_at(&data, opt + 56).ok_or_else(truncated)?,
+            size_of_headers: u32_at(&data, opt + 60).ok_or_else(truncated)?,
+            subsystem: u16_at(&data, opt + 68).ok_or_else(truncated)?,
+            dll_characteristics: u16_at(&data, opt + 70).ok_or_else(truncated)?,
+            data_directories,
+            sections: Vec::new(),
+            symbol_table,
+            symbol_count,
+            optional_header: opt,
+            section_table: opt + optional_size,
+            data: Vec::new(),
+        };
+
+        let sections_offset = opt + optional_size;
+        for idx in 0..section_count {
+            let offset = sections_offset + idx * SECTION_HEADER_SIZE;
+            let header = data.get(offset..offset + SECTION_HEADER_SIZE).ok_or_else(|| malformed("truncated section table"))?;
+            let raw_name = &header[..8];
+            let raw_name = &raw_name[..raw_name.iter().position(|b| *b == 0).unwrap_or(8)];
+            let name = String::from_utf8_lossy(raw_name).into_owned();
+
+            file.sections.push(Section {
+                name,
+                virtual_size: u32_at(header, 8).unwrap_or(0),
+                virtual_address: u32_at(header, 12).unwrap_or(0),
+                raw_size: u32_at(header, 16).unwrap_or(0),
+                raw_offset: u32_at(header, 20).unwrap_or(0),
+                characteristics: u32_at(header, 36).unwrap_or(0),
+            });
+        }
+
+        file.data = data;
+        // `/4` のような長いセクション名は文字列テーブルを参照する
+        for idx in 0..file.sections.len() {
+            if let Some(offset) = file.sections[idx].name.strip_prefix('/').and_then(|n| n.parse::<u32>().ok()) {
+                if let Some(name) = file.string_table_entry(offset) {
+                    file.sections[idx].name = name;
+                }
+            }
+        }
+
+        Ok(file)
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    pub fn machine_name(&self) -> &'static str {
+        match self.machine {
+            0x8664 => "x86_64",
+            0x014c => "i386",
+            0xaa64 => "aarch64",
+            0x01c2 | 0x01c4 => "arm",
+            0x5064 => "riscv64",
+            0x6264 => "loongarch64",
+            0x0ebc => "EBC",
+            _ => "unknown",
+        }
+    }
+
+    pub fn subsystem_name(&self) -> &'static str {
+        match self.subsystem {
+            10 => "EFI application",
+            11 => "EFI boot service driver",
+            12 => "EFI runtime driver",
+            13 => "EFI ROM",
+            2 => "Windows GUI",
+            3 => "Windows console",
+            _ => "unknown",
+        }
+    }
+
+    pub fn coff_flags(&self) -> Vec<&'static str> {
+        const FLAGS: &[(u16, &str)] = &[
+            (0x0001, "RELOCS_STRIPPED"),
+            (0x0002, "EXECUTABLE_IMAGE"),
+            (0x0020, "LARGE_ADDRESS_AWARE"),
+            (0x0100, "32BIT_MACHINE"),
+            (0x0200, "DEBUG_STRIPPED"),
+            (0x2000, "DLL"),
+        ];
+        FLAGS.iter().filter(|(bit, _)| self.characteristics & bit != 0).map(|(_, name)| *name).collect()
+    }
+
+    pub fn dll_flags(&self) -> Vec<&'static str> {
+        const FLAGS: &[(u16, &str)] = &[
+            (0x0020, "HIGH_ENTROPY_VA"),
+            (0x0040, "DYNAMIC_BASE"),
+            (0x0080, "FORCE_INTEGRITY"),
+            (0x0100, "NX_COMPAT"),
+            (0x0200, "NO_ISOLATION"),
+            (0x0400, "NO_SEH"),
+            (0x4000, "GUARD_CF"),
+        ];
+        FLAGS.iter().filter(|(bit, _)| self.dll_characteristics & bit != 0).map(|(_, name)| *name).collect()
+    }
+
+    pub fn directory(&self, idx: usize) -> Option<DataDirectory> {
+        self.data_directories.get(idx).copied().filter(|d| d.rva != 0 && d.size != 0)
+    }
+
+    /// RVAをファイル上のオフセットに変換する。ファイルに実体のない位置は `None`
+    pub fn rva_to_offset(&self, rva: u32) -> Option<usize> {
+        if rva < self.size_of_headers {
+            return Some(rva as usize);
+        }
+
+        self.sections.iter()
+            .find(|s| rva >= s.virtual_address && rva - s.virtual_address < s.raw_size.max(s.virtual_size))
+            .and_then(|s| {
+                let delta = rva - s.virtual_address;
+                // 壊れたイメージでは、ファイル上の位置が32ビットに収まらないことがある
+                (delta < s.raw_size).then_some(s.raw_offset.checked_add(delta)? as usize)
+            })
+    }
+
+    fn string_table_entry(&self, offset: u32) -> Option<String> {
+        let table = self.symbol_table as usize + self.symbol_count as usize * SYMBOL_SIZE;
+        c_string(&self.data, table + offset as usize)
+    }
+
+    /// COFFのシンボルテーブルにある、セクション内に定義されたシンボル。
+    /// 通常のUEFIアプリケーションではシンボルはPDBにあり、このテーブルは空になっている
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let mut symbols = Vec::new();
+        let mut idx = 0;
+        while idx < self.symbol_count as usize {
+            let entry = self.symbol_table as usize + idx * SYMBOL_SIZE;
+            let record = match self.data.get(entry..entry + SYMBOL_SIZE) {
+                Some(record) => record,
+                None => break,
+            };
+            // 補助レコードの数だけ読み飛ばす
+            idx += 1 + record[17] as usize;
+
+            let section = i16::from_le_bytes([record[12], record[13]]);
+            if section <= 0 {
+                continue;
+            }
+            let name = if record[..4] == [0; 4] {
+                match self.string_table_entry(u32::from_le_bytes(record[4..8].try_into().unwrap())) {
+                    Some(name) => name,
+                    None => continue,
+                }
+            } else {
+                let end = record[..8].iter().position(|b| *b == 0).unwrap_or(8);
+                String::from_utf8_lossy(&record[..end]).into_owned()
+            };
+
+            symbols.push(Symbol {
+                name,
+                section: section as u16,
+                offset: u32::from_le_bytes(record[8..12].try_into().unwrap()),
+                is_function: u16::from_le_bytes([record[14], record[15]]) >> 4 == 2,
+            });
+        }
+
+        symbols
+    }
+
+    pub fn imports(&self) -> Vec<Import> {
+        let dir = match self.directory(DIR_IMPORT) {
+            Some(dir) => dir,
+            None => return Vec::new(),
+        };
+        let thunk_size = if self.pe32_plus { 8 } else { 4 };
+        let ordinal_bit = if self.pe32_plus { 1u64 << 63 } else { 1u64 << 31 };
+
+        let mut imports = Vec::new();
+        let mut descriptor = match self.rva_to_offset(dir.rva) {
+            Some(offset) => offset,
+            None => return imports,
+        };
+        while let (Some(lookup), Some(name), Some(first_thunk)) = (
+            u32_at(&self.data, descriptor),
+            u32_at(&self.data, descriptor + 12),
+            u32_at(&self.data, descriptor + 16),
+        ) {
+            if name == 0 {
+                break;
+            }
+
+            let dll = self.rva_to_offset(name).and_then(|o| c_string(&self.data, o)).unwrap_or_default();
+            let mut functions = Vec::new();
+            let thunks = if lookup != 0 { lookup } else { first_thunk };
+            if let Some(mut thunk) = self.rva_to_offset(thunks) {
+                loop {
+                    let value = match self.pe32_plus {
+                        true => u64_at(&self.data, thunk),
+                        false => u32_at(&self.data, thunk).map(u64::from),
+                    };
+                    match value {
+                        None | Some(0) => break,
+                        Some(v) if v & ordinal_bit != 0 => functions.push(format!("#{}", v & 0xffff)),
+                        Some(v) => functions.push(
+                            self.rva_to_offset(v as u32).and_then(|o| c_string(&self.data, o + 2)).unwrap_or_default()
+                        ),
+                    }
+                    thunk += thunk_size;
+                }
+            }
+
+            imports.push(Import { dll, functions });
+            descriptor += 20;
+        }
+
+        imports
+    }
+
+    pub fn relocations(&self) -> Relocations {
+        let mut relocations = Relocations::default();
+        let dir = match self.directory(DIR_BASERELOC) {
+            Some(dir) => dir,
+            None => return relocations,
+        };
+        let (start, end) = match self.rva_to_offset(dir.rva) {
+            Some(start) => (start, start.saturating_add(dir.size as usize)),
+            None => return relocations,
+        };
+
+        let mut block = start;
+        while block + 8 <= end {
+            let size = match u32_at(&self.data, block + 4) {
+                Some(size) if size >= 8 => size as usize,
+                _ => break,
+            };
+            relocations.blocks += 1;
+            for entry in (block + 8..block.saturating_add(size).min(end)).step_by(2) {
+                let kind = (u16_at(&self.data, entry).unwrap_or(0) >> 12) as u8;
+                if kind != 0 {
+                    *relocations.by_type.entry(kind).or_default() += 1;
+                }
+            }
+            block += size;
+        }
+
+        relocations
+    }
+
+    pub fn debug_entries(&self) -> Vec<DebugEntry> {
+        let dir = match self.directory(DIR_DEBUG) {
+            Some(dir) => dir,
+            None => return Vec::new(),
+        };
+        let start = match self.rva_to_offset(dir.rva) {
+            Some(start) => start,
+            None => return Vec::new(),
+        };
+
+        (0..dir.size as usize / 28).filter_map(|idx| {
+            let entry = start + idx * 28;
+            let kind = u32_at(&self.data, entry + 12)?;
+            let pointer = u32_at(&self.data, entry + 24)? as usize;
+            let pdb = match (kind, self.data.get(pointer..pointer + 4)) {
+                (2, Some(b"RSDS")) => c_string(&self.data, pointer + 24),
+                (2, Some(b"NB10")) => c_string(&self.data, pointer + 16),
+                _ => None,
+            };
+            Some(DebugEntry { kind, pdb })
+        }).collect()
+    }
+
+    /// 読み取り専用の初期化済みデータとして `name` のセクションを末尾に追加したイメージを作る。
+    /// 既存のセクションは移動しないため、セクションテーブルの後ろに1項目分の空きが必要になる
+    pub fn with_section(&self, name: &str, contents: &[u8]) -> Result<Vec<u8>, Error> {
+        let fail = |what: String| Error::new(ErrorKind::MalformedPe, msg!(PeCannotAddSection, name, what));
+
+        if name.len() > 8 {
+            return Err(fail(msg!(PeSectionNameTooLong)));
+        }
+        if self.sections.iter().any(|s| s.name == name) {
+            return Err(fail(msg!(PeSectionExists)));
+        }
+        // 署名済みのイメージに追加すると署名が無効になる
+        if self.directory(DIR_SECURITY).is_some() {
+            return Err(fail(msg!(PeImageSigned)));
+        }
+        let header = self.section_table + self.sections.len() * SECTION_HEADER_SIZE;
+        match self.data.get(header..header + SECTION_HEADER_SIZE) {
+            Some(slot) if header + SECTION_HEADER_SIZE <= self.size_of_headers as usize && slot.iter().all(|b| *b == 0) => {}
+            _ => return Err(fail(msg!(PeNoSectionHeaderRoom))),
+        }
+
+        let align = |value: u64, to: u32| if to == 0 { value } else { value.div_ceil(to as u64) * to as u64 };
+        let raw_offset = align(self.data.len() as u64, self.file_alignment);
+        let raw_size = align(contents.len() as u64, self.file_alignment);
+        let end = self.sections.iter().map(|s| s.virtual_address as u64 + s.virtual_size.max(s.raw_size) as u64).max().unwrap_or(0);
+        let virtual_address = align(end.max(self.size_of_headers as u64), self.section_alignment);
+        let size_of_image = align(virtual_address + contents.len() as u64, self.section_alignment);
+        if raw_offset + raw_size > u32::MAX as u64 || size_of_image > u32::MAX as u64 {
+            return Err(fail(msg!(PeImageTooLarge)));
+        }
+
+        let mut data = self.data.clone();
+        let put32 = |d: &mut Vec<u8>, o: usize, v: u32| d[o..o + 4].copy_from_slice(&v.to_le_bytes());
+        data[header..header + name.len()].copy_from_slice(name.as_bytes());
+        put32(&mut data, header + 8, contents.len() as u32);
+        put32(&mut data, header + 12, virtual_address as u32);
+        put32(&mut data, header + 16, raw_size as u32);
+        put32(&mut data, header + 20, raw_offset as u32);
+        // IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ
+        put32(&mut data, header + 36, 0x4000_0040);
+
+        let coff = self.optional_header - 20;
+        data[coff + 2..coff + 4].copy_from_slice(&(self.sections.len() as u16 + 1).to_le_bytes());
+        let opt = self.optional_header;
+        let initialized = u32_at(&data, opt + 8).unwrap_or(0).saturating_add(raw_size as u32);
+        put32(&mut data, opt + 8, initialized);
+        put32(&mut data, opt + 56, size_of_image as u32);
+
+        data.resize(raw_offset as usize, 0);
+        data.extend_from_slice(contents);
+        data.resize((raw_offset + raw_size) as usize, 0);
+
+        // チェックサムが設定されていたイメージでは、追加後の内容で計算し直す
+        if u32_at(&data, opt + 64) != Some(0) {
+            put32(&mut data, opt + 64, 0);
+            let checksum = checksum(&data);
+            put32(&mut data, opt + 64, checksum);
+        }
+
+        Ok(data)
+    }
+
+    /// `name` のセクションのファイル上の内容
+    pub fn section_data(&self, name: &str) -> Option<&[u8]> {
+        let section = self.sections.iter().find(|s| s.name == name)?;
+        let len = section.raw_size.min(section.virtual_size) as usize;
+        self.data.get(section.raw_offset as usize..section.raw_offset as usize + len)
+    }
+
+    /// ヘッダとセクションのどれにも含まれない、ファイル上のバイト数（シンボルテーブルや末尾のデータ）
+    pub fn unaccounted_bytes(&self) -> u64 {
+        let accounted: u64 = self.sections.iter().map(|s| s.raw_size as u64).sum::<u64>() + self.size_of_headers as u64;
+        self.file_size().saturating_sub(accounted)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::pe::PeFile;
+
+    /// テスト用に、1つのセクションとベース再配置、CodeViewのデバッグ情報を持つ最小限のPE32+イメージを作る
+    pub fn sample_image() -> Vec<u8> {
+        let mut data = vec![0u8; 0x600];
+        let put16 = |d: &mut Vec<u8>, o: usize, v: u16| d[o..o + 2].copy_from_slice(&v.to_le_bytes());
+        let put32 = |d: &mut Vec<u8>, o: usize, v: u32| d[o..o + 4].copy_from_slice(&v.to_le_bytes());
+
+        data[..2].copy_from_slice(b"MZ");
+        put32(&mut data, 0x3c, 0x40);
+        data[0x40..0x44].copy_from_slice(b"PE\0\0");
+        let coff = 0x44;
+        put16(&mut data, coff, 0x8664);
+        put16(&mut data, coff + 2, 2);
+        put16(&mut data, coff + 16, 240);
+        put16(&mut data, coff + 18, 0x22);
+
+        let opt = coff + 20;
+        put16(&mut data, opt, 0x20b);
+        put32(&mut data, opt + 16, 0x1010);
+        data[opt + 24..opt + 32].copy_from_slice(&0x1_4000_0000u64.to_le_bytes());
+        put32(&mut data, opt + 32, 0x1000);
+        put32(&mut data, opt + 36, 0x200);
+        put32(&mut data, opt + 56, 0x3000);
+        put32(&mut data, opt + 60, 0x200);
+        put16(&mut data, opt + 68, 10);
+        put16(&mut data, opt + 70, 0x0160);
+        put32(&mut data, opt + 108, 16);
+        // ベース再配置とデバッグディレクトリ
+        put32(&mut data, opt + 112 + 5 * 8, 0x2000);
+        put32(&mut data, opt + 112 + 5 * 8 + 4, 12);
+        put32(&mut data, opt + 112 + 6 * 8, 0x2010);
+        put32(&mut data, opt + 112 + 6 * 8 + 4, 28);
+
+        let sections = opt + 240;
+        data[sections..sections + 5].copy_from_slice(b".text");
+        put32(&mut data, sections + 8, 0x100);
+        put32(&mut data, sections + 12, 0x1000);
+        put32(&mut data, sections + 16, 0x200);
+        put32(&mut data, sections + 20, 0x200);
+        put32(&mut data, sections + 36, 0x6000_0020);
+        let data_section = sections + 40;
+        data[data_section..data_section + 6].copy_from_slice(b".rdata");
+        put32(&mut data, data_section + 8, 0x100);
+        put32(&mut data, data_section + 12, 0x2000);
+        put32(&mut data, data_section + 16, 0x200);
+        put32(&mut data, data_section + 20, 0x400);
+        put32(&mut data, data_section + 36, 0x4000_0040);
+
+        // 再配置: 1ブロック、DIR64が1件とパディング
+        put32(&mut data, 0x400, 0x1000);
+        put32(&mut data, 0x404, 12);
+        put16(&mut data, 0x408, 0xa008);
+        put16(&mut data, 0x40a, 0);
+        // デバッグディレクトリ: CodeView(RSDS)
+        put32(&mut data, 0x410 + 12, 2);
+        put32(&mut data, 0x410 + 16, 32);
+        put32(&mut data, 0x410 + 24, 0x440);
+        data[0x440..0x444].copy_from_slice(b"RSDS");
+        data[0x458..0x458 + 8].copy_from_slice(b"hoge.pdb");
+
+        data
+    }
+
+    #[test]
+    fn parse_headers_and_sections() {
+        let pe = PeFile::parse(sample_image()).unwrap();
+        assert_eq!(pe.machine_name(), "x86_64");
+        assert!(pe.pe32_plus);
+        assert_eq!(pe.image_base, 0x1_4000_0000);
+        assert_eq!(pe.subsystem_name(), "EFI application");
+        assert_eq!(pe.coff_flags(), ["EXECUTABLE_IMAGE", "LARGE_ADDRESS_AWARE"]);
+        assert_eq!(pe.dll_flags(), ["HIGH_ENTROPY_VA", "DYNAMIC_BASE", "NX_COMPAT"]);
+        assert_eq!(pe.sections.len(), 2);
+        assert_eq!(pe.sections[0].name, ".text");
+        assert_eq!(pe.sections[0].flags(), ["code", "exec", "read"]);
+        assert_eq!(pe.rva_to_offset(0x2010), Some(0x410));
+
+        let relocations = pe.relocations();
+        assert_eq!((relocations.blocks, relocations.total()), (1, 1));
+        assert_eq!(relocations.by_type.get(&10), Some(&1));
+        assert_eq!(pe.debug_entries()[0].pdb.as_deref(), Some("hoge.pdb"));
+        assert!(pe.imports().is_empty());
+        assert_eq!(pe.unaccounted_bytes(), 0);
+    }
+
+    #[test]
+    fn out_of_range_section_offsets() {
+        let mut data = sample_image();
+        // .rdataのPointerToRawData
+        let data_section = u32::from_le_bytes(data[0x3c..0x40].try_into().unwrap()) as usize + 24 + 240 + 40;
+        data[data_section + 20..data_section + 24].copy_from_slice(&0xffff_fff8u32.to_le_bytes());
+        let pe = PeFile::parse(data).unwrap();
+        assert_eq!(pe.rva_to_offset(0x2010), None);
+        assert_eq!(pe.relocations().blocks, 0);
+    }
+
+    #[test]
+    fn reject_non_pe() {
+        assert!(PeFile::parse(b"efi\n".to_vec()).is_err());
+        let mut truncated = sample_image();
+        truncated.truncate(0x60);
+        assert!(PeFile::parse(truncated).is_err());
+    }
+}
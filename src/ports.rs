@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net;
+use std::path;
+use crate::error::{Error, ErrorKind};
+use crate::lock::FileLock;
+use crate::message::msg;
+
+/// ポートを割り当てる用途の名前
+pub const NAMES: &[&str] = &["gdb", "vnc", "serial", "qmp", "control", "control-qmp", "crash-qmp", "break-qmp", "power-cut-qmp", "net-guest", "net-link"];
+
+/// ゲストのポートの検査に割り当てるポートの名前の接頭辞。検査の名前を続けて `probe-web` のように指定する
+pub const PROBE_PREFIX: &str = "probe-";
+
+/// 利用者が接続するために知る必要がある用途
+const USER_FACING: &[&str] = &["gdb", "vnc", "serial"];
+
+/// VNCのディスプレイ0のポート。VNCはディスプレイ番号で指定するため、これより小さいポートは使えない
+pub const VNC_BASE: u16 = 5900;
+
+/// 空いているポートを探す試行回数の上限
+const MAX_ATTEMPTS: usize = 64;
+
+/// `gdb=1234` の形式の引数を読み取る
+pub fn parse_override(s: &str) -> Result<(String, u16), Error> {
+    let invalid = || Error::new(ErrorKind::InvalidArgument, msg!(PortInvalidOverride, s, format!("{}, {}<NAME>", NAMES.join(", "), PROBE_PREFIX)));
+
+    let (name, port) = s.split_once('=').ok_or_else(invalid)?;
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    let probe = name.strip_prefix(PROBE_PREFIX).is_some_and(|probe| !probe.is_empty());
+    if !(NAMES.contains(&name) || probe) || port == 0 {
+        return Err(invalid());
+    }
+    if name == "vnc" && port < VNC_BASE {
+        return Err(Error::new(ErrorKind::InvalidArgument, msg!(PortVncBelowBase, port, VNC_BASE)));
+    }
+
+    Ok((name.to_string(), port))
+}
+
+/// 全てのcargo-uefiプロセスが共有する、割り当て中のポートのロックファイルの置き場所
+pub fn lock_dir() -> path::PathBuf {
+    std::env::temp_dir().join("cargo-uefi-ports")
+}
+
+/// 1回の実行で使うローカルホストのポートを割り当てる。
+///
+/// OSに空いているポートを選ばせた後、QEMUが待ち受けを始めるまでの間に並行して動く他のcargo-uefiが
+/// 同じポートを選ばないよう、この値がdropされるまでポートごとのロックファイルを保持する。
+pub struct PortAllocator {
+    dir: path::PathBuf,
+    overrides: BTreeMap<String, u16>,
+    allocated: BTreeMap<String, net::SocketAddr>,
+    _locks: Vec<FileLock>,
+}
+
+impl PortAllocator {
+    pub fn new(dir: path::PathBuf, overrides: &[(String, u16)]) -> PortAllocator {
+        PortAllocator {
+            dir,
+            overrides: overrides.iter().cloned().collect(),
+            allocated: BTreeMap::new(),
+            _locks: Vec::new(),
+        }
+    }
+
+    /// QEMUが待ち受けるポートを `name` の用途に割り当てる
+    pub fn allocate(&mut self, name: &str) -> Result<net::SocketAddr, io::Error> {
+        self.allocate_matching(name, |_| true)
+    }
+
+    /// `accept` を満たすポートを割り当てる。VNCのように番号の範囲に制約がある用途に使う
+    pub fn allocate_matching(&mut self, name: &str, accept: impl Fn(u16) -> bool) -> Result<net::SocketAddr, io::Error> {
+        if let Some(port) = self.overrides.get(name).copied() {
+            if !accept(port) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg!(PortNotAccepted, port, name)));
+            }
+            return self.record(name, net::SocketAddr::from(([127, 0, 0, 1], port)));
+        }
+
+        for _ in 0..MAX_ATTEMPTS {
+            let port = net::TcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port();
+            if !accept(port) || self.allocated.values().any(|a| a.port() == port) {
+                continue;
+            }
+
+            // 他のプロセスが同じポートを割り当て済みであれば、別のポートを選び直す
+            match FileLock::acquire(self.dir.join(format!("{}.lock", port)).as_path(), false) {
+                Ok(lock) => {
+                    self._locks.push(lock);
+                    return self.record(name, net::SocketAddr::from(([127, 0, 0, 1], port)));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::AddrInUse, msg!(PortExhausted, name)))
+    }
+
+    /// cargo-uefi自身が待ち受けるポートを `name` の用途に割り当て、待ち受けを開始する
+    pub fn listen(&mut self, name: &str) -> Result<net::TcpListener, io::Error> {
+        let port = self.overrides.get(name).copied().unwrap_or(0);
+        let listener = net::TcpListener::bind(("127.0.0.1", port))
+            .map_err(|e| io::Error::new(e.kind(), msg!(PortInUse, port, name, e)))?;
+        self.record(name, listener.local_addr()?)?;
+
+        Ok(listener)
+    }
+
+    fn record(&mut self, name: &str, addr: net::SocketAddr) -> Result<net::SocketAddr, io::Error> {
+        if let Some((other, _)) = self.allocated.iter().find(|(_, a)| a.port() == addr.port()) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, msg!(PortConflict, addr.port(), name, other)));
+        }
+
+        self.allocated.insert(name.to_string(), addr);
+        Ok(addr)
+    }
+
+    /// 割り当てたポートを実行の報告に記録する
+    pub fn report(&self) {
+        if self.allocated.is_empty() {
+            return;
+        }
+
+        let user_facing: Vec<String> = self.allocated.iter()
+            .filter(|(name, _)| USER_FACING.contains(&name.as_str()) || name.starts_with(PROBE_PREFIX))
+            .map(|(name, addr)| format!("{}={}", name, addr))
+            .collect();
+        if !user_facing.is_empty() {
+            crate::output::status(msg!(PortsAllocated, user_facing.join(", ")));
+        }
+
+        let ports: BTreeMap<&String, String> = self.allocated.iter().map(|(n, a)| (n, a.to_string())).collect();
+        crate::output::event("ports", serde_json::json!({ "ports": ports }));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ports::{parse_override, PortAllocator, VNC_BASE};
+
+    #[test]
+    fn parse_port_overrides() {
+        assert_eq!(parse_override("gdb=1234").unwrap(), ("gdb".to_string(), 1234));
+        assert!(parse_override("gdb").is_err());
+        assert!(parse_override("gdb=0").is_err());
+        assert!(parse_override("ssh=22").is_err());
+        assert!(parse_override("vnc=5899").is_err());
+        assert_eq!(parse_override("break-qmp=4444").unwrap().1, 4444);
+        assert_eq!(parse_override("probe-web=8080").unwrap(), ("probe-web".to_string(), 8080));
+        assert!(parse_override("probe-=8080").is_err());
+        assert_eq!(parse_override("vnc=5901").unwrap().1, 5901);
+    }
+
+    #[test]
+    fn allocations_do_not_collide() {
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-test-ports-{}", std::process::id()));
+        let mut first = PortAllocator::new(dir.clone(), &[("gdb".to_string(), 1234)]);
+        let mut second = PortAllocator::new(dir.clone(), &[]);
+
+        assert_eq!(first.allocate("gdb").unwrap().port(), 1234);
+        let qmp = first.allocate("qmp").unwrap();
+        let vnc = first.allocate_matching("vnc", |p| p >= VNC_BASE).unwrap();
+        assert!(vnc.port() >= VNC_BASE && vnc.port() != qmp.port());
+        // 指定されたポートも制約を満たさなければ使わない
+        let mut low = PortAllocator::new(dir.clone(), &[("vnc".to_string(), 5000)]);
+        assert!(low.allocate_matching("vnc", |p| p >= VNC_BASE).is_err());
+
+        for _ in 0..16 {
+            let other = second.allocate("qmp").unwrap();
+            assert_ne!(other.port(), qmp.port());
+            assert_ne!(other.port(), vnc.port());
+            second = PortAllocator::new(dir.clone(), &[]);
+        }
+
+        let mut conflict = PortAllocator::new(dir.clone(), &[("gdb".to_string(), 4000), ("vnc".to_string(), 4000)]);
+        conflict.allocate("gdb").unwrap();
+        assert!(conflict.allocate("vnc").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+use std::io;
+use std::net;
+use std::path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::qmp::Qmp;
+
+/// 電源を断つ方法
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CutMode {
+    /// QEMUを即座に終了する。ゲストには何も通知されず、メモリ上の状態は失われる
+    Off,
+    /// VMをリセットする。`--boots` ではリセットでQEMUが終了するため、そのまま次の起動になる
+    Reset,
+}
+
+impl CutMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CutMode::Off => "off",
+            CutMode::Reset => "reset",
+        }
+    }
+
+    fn qmp_command(&self) -> &'static str {
+        match self {
+            CutMode::Off => "quit",
+            CutMode::Reset => "system_reset",
+        }
+    }
+}
+
+/// 起動してから（`marker` があればそれが出力されてから）電源を断つまでの時間
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delay {
+    Fixed(f64),
+    /// 起動ごとに範囲内から無作為に選ぶ
+    Random(f64, f64),
+}
+
+impl Delay {
+    pub fn resolve(&self, rng: &mut Rng) -> Duration {
+        match *self {
+            Delay::Fixed(secs) => Duration::from_secs_f64(secs),
+            Delay::Random(min, max) => Duration::from_secs_f64(min + (max - min) * rng.next_f64()),
+        }
+    }
+}
+
+/// `--power-cut` で指定された、電源断を起こす条件
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerCut {
+    /// シリアルにこの文字列が出力されてから時間を数え始める
+    pub marker: Option<String>,
+    pub delay: Delay,
+    pub mode: CutMode,
+}
+
+/// `after=SECS`、`random=MIN-MAX`、`mode=off|reset`、`marker=TEXT` をカンマで区切った引数を読み取る。
+/// マーカーの文字列にはカンマを含められるよう、`marker=` は以降の全てを値とする
+pub fn parse_power_cut(s: &str) -> Result<PowerCut, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidArgument, msg!(PowerCutInvalid, s));
+    let secs = |v: &str| v.parse::<f64>().ok().filter(|v| v.is_finite() && *v >= 0.0).ok_or_else(invalid);
+
+    let (options, marker) = match s.find("marker=") {
+        Some(idx) => (&s[..idx], Some(s[idx + "marker=".len()..].to_string())),
+        None => (s, None),
+    };
+    let mut delay = None;
+    let mut mode = CutMode::Off;
+    for option in options.split(',').filter(|o| !o.is_empty()) {
+        let (key, value) = option.split_once('=').ok_or_else(invalid)?;
+        match key {
+            "after" if delay.is_none() => delay = Some(Delay::Fixed(secs(value)?)),
+            "random" if delay.is_none() => {
+                let (min, max) = value.split_once('-').ok_or_else(invalid)?;
+                let (min, max) = (secs(min)?, secs(max)?);
+                if min > max {
+                    return Err(invalid());
+                }
+                delay = Some(Delay::Random(min, max));
+            }
+            "mode" => mode = match value {
+                "off" => CutMode::Off,
+                "reset" => CutMode::Reset,
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        }
+    }
+
+    if marker.as_deref() == Some("") || (marker.is_none() && delay.is_none()) {
+        return Err(invalid());
+    }
+
+    Ok(PowerCut { marker, delay: delay.unwrap_or(Delay::Fixed(0.0)), mode })
+}
+
+/// 電源断の時刻を選ぶための疑似乱数（xorshift64*）。シードが同じなら同じ時刻を選ぶ
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // 状態が0のままだと0しか出力しない
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    /// シードを指定しなかった場合に使う、現在時刻から作るシード
+    pub fn seed_from_time() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// `[0, 1)` の一様乱数
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// 条件を満たした時点で、QMPを通してVMの電源を断つ
+pub struct PowerCutMonitor {
+    handle: thread::JoinHandle<Result<Option<Duration>, io::Error>>,
+    finished: Arc<AtomicBool>,
+}
+
+impl PowerCutMonitor {
+    /// `serial_log` はマーカーを探すシリアルのログ。マーカーを指定した場合は必須
+    pub fn start(cut: &PowerCut, delay: Duration, qmp_addr: net::SocketAddr, serial_log: Option<path::PathBuf>) -> PowerCutMonitor {
+        let finished = Arc::new(AtomicBool::new(false));
+        let (marker, mode, flag) = (cut.marker.clone(), cut.mode, finished.clone());
+        let handle = thread::spawn(move || {
+            let started = Instant::now();
+            if let (Some(marker), Some(log)) = (&marker, &serial_log) {
+                if !crate::seriallog::wait_marker(log, marker.as_bytes(), &flag)? {
+                    return Ok(None);
+                }
+            }
+
+            let deadline = Instant::now() + delay;
+            while Instant::now() < deadline {
+                if flag.load(Ordering::SeqCst) {
+                    return Ok(None);
+                }
+                thread::sleep(Duration::from_millis(10).min(deadline - Instant::now()));
+            }
+            if flag.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+
+            // 条件を満たす前にQEMUが終了していれば、接続や実行に失敗する
+            let cut = Qmp::connect(qmp_addr, Duration::from_secs(10))
+                .and_then(|mut qmp| qmp.execute(mode.qmp_command(), None));
+            Ok(cut.ok().map(|_| started.elapsed()))
+        });
+
+        PowerCutMonitor { handle, finished }
+    }
+
+    /// QEMUの終了後に呼び、電源を断った場合は起動からの経過時間を返す
+    pub fn finish(self) -> Result<Option<Duration>, io::Error> {
+        self.finished.store(true, Ordering::SeqCst);
+        self.handle.join().unwrap_or_else(|_| Err(io::Error::other("power cut monitor panicked")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::powercut::{parse_power_cut, CutMode, Delay, Rng};
+
+    #[test]
+    fn parse_power_cut_spec() {
+        let cut = parse_power_cut("random=0.5-2,mode=reset,marker=begin variable write, part 1").unwrap();
+        assert_eq!(cut.marker.as_deref(), Some("begin variable write, part 1"));
+        assert_eq!(cut.delay, Delay::Random(0.5, 2.0));
+        assert_eq!(cut.mode, CutMode::Reset);
+
+        let cut = parse_power_cut("after=3").unwrap();
+        assert_eq!((cut.marker, cut.delay, cut.mode), (None, Delay::Fixed(3.0), CutMode::Off));
+
+        for invalid in ["", "mode=reset", "random=2-1", "after=1,random=0-1", "after=-1", "marker=", "when=1"] {
+            assert!(parse_power_cut(invalid).is_err(), "{}", invalid);
+        }
+
+        let delay = Delay::Random(1.0, 2.0);
+        let (mut a, mut b) = (Rng::new(42), Rng::new(42));
+        let picked = delay.resolve(&mut a);
+        assert_eq!(picked, delay.resolve(&mut b));
+        assert!(picked >= Duration::from_secs(1) && picked < Duration::from_secs(2));
+    }
+}
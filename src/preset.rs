@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use clap::ValueEnum;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::target::Arch;
+
+/// 想定するハードウェアの構成をまとめたQEMUの引数
+#[derive(Copy, Clone, Eq, PartialEq, Debug, ValueEnum)]
+pub enum Preset {
+    /// 128MiBのメモリ、遅くしたTCGの1 vCPU、800x600の画面
+    LowEnd,
+    /// 8GiBのメモリを2つのNUMAノードに分け、16 vCPUとNVMeのディスクを持つq35マシン
+    Server,
+}
+
+impl Preset {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::LowEnd => "low-end",
+            Preset::Server => "server",
+        }
+    }
+
+    /// 利用者が追加の引数で上書きできるよう、他の引数より前に渡す。
+    /// aarch64では `virt` マシンを使い、x86のVGAの代わりにvirtio-gpuで画面の大きさを決める
+    pub fn qemu_args(&self, arch: Arch) -> Vec<String> {
+        let x86 = arch != Arch::Aarch64;
+        let args: &[&str] = match self {
+            Preset::LowEnd => &[
+                "-m", "128M",
+                "-smp", "1",
+                // KVMを使わず、命令数で時間を進めて1 vCPUの処理を遅くする
+                "-accel", "tcg,thread=single",
+                "-icount", "shift=5,sleep=on",
+            ],
+            Preset::Server => &[
+                "-m", "8G",
+                "-smp", "16,sockets=2,cores=8,threads=1",
+                "-object", "memory-backend-ram,id=preset-mem0,size=4G",
+                "-object", "memory-backend-ram,id=preset-mem1,size=4G",
+                "-numa", "node,nodeid=0,cpus=0-7,memdev=preset-mem0",
+                "-numa", "node,nodeid=1,cpus=8-15,memdev=preset-mem1",
+                // 中身を持たない16GiBのNVMeディスク
+                "-blockdev", "driver=null-co,node-name=preset-nvme,size=16G,read-zeroes=on",
+                "-device", "nvme,serial=cargo-uefi,drive=preset-nvme",
+            ],
+        };
+        let machine: &[&str] = match (self, x86) {
+            (Preset::LowEnd, true) => &["-vga", "none", "-device", "VGA,edid=on,xres=800,yres=600"],
+            (Preset::LowEnd, false) => &["-device", "virtio-gpu-pci,xres=800,yres=600"],
+            (Preset::Server, true) => &["-machine", "q35"],
+            (Preset::Server, false) => &[],
+        };
+
+        machine.iter().chain(args).map(|a| a.to_string()).collect()
+    }
+}
+
+/// `--with` で組み合わせて追加する、組み込みのデバイスプリセット。
+/// 他のデバイスと衝突しないよう、IDには `with-` を付ける
+pub const DEVICE_PRESETS: &[(&str, &[&str])] = &[
+    ("net-virtio", &["-netdev", "user,id=with-net-virtio", "-device", "virtio-net-pci,netdev=with-net-virtio"]),
+    ("net-e1000", &["-netdev", "user,id=with-net-e1000", "-device", "e1000,netdev=with-net-e1000"]),
+    // 中身を持たない1GiBのディスク
+    ("storage-nvme", &[
+        "-blockdev", "driver=null-co,node-name=with-nvme,size=1G,read-zeroes=on",
+        "-device", "nvme,serial=cargo-uefi-with,drive=with-nvme",
+    ]),
+    ("storage-virtio", &[
+        "-blockdev", "driver=null-co,node-name=with-virtio-blk,size=1G,read-zeroes=on",
+        "-device", "virtio-blk-pci,drive=with-virtio-blk",
+    ]),
+    ("gfx-virtio", &["-vga", "none", "-device", "virtio-gpu-pci"]),
+    ("rng", &["-object", "rng-random,id=with-rng,filename=/dev/urandom", "-device", "virtio-rng-pci,rng=with-rng"]),
+    ("usb-input", &["-device", "qemu-xhci,id=with-xhci", "-device", "usb-kbd,bus=with-xhci.0", "-device", "usb-tablet,bus=with-xhci.0"]),
+];
+
+/// `names` のデバイスプリセットを、名前とQEMUの引数の組に展開する。
+/// 設定ファイルの `device-presets` で定義したものは、同じ名前の組み込みのものより優先する。同じ名前は1度だけ展開する
+pub fn device_args(names: &[String], defined: &BTreeMap<String, Vec<String>>) -> Result<Vec<(String, Vec<String>)>, Error> {
+    let mut expanded: Vec<(String, Vec<String>)> = Vec::new();
+    for name in names {
+        if expanded.iter().any(|(n, _)| n == name) {
+            continue;
+        }
+
+        let args = match defined.get(name) {
+            Some(args) => args.clone(),
+            None => match DEVICE_PRESETS.iter().find(|(n, _)| n == name) {
+                Some((_, args)) => args.iter().map(|a| a.to_string()).collect(),
+                None => {
+                    let mut available: Vec<_> = DEVICE_PRESETS.iter().map(|(n, _)| n.to_string()).collect();
+                    available.extend(defined.keys().filter(|n| !DEVICE_PRESETS.iter().any(|(b, _)| b == n)).cloned());
+                    return Err(Error::new(ErrorKind::InvalidArgument, msg!(DevicePresetUnknown, name, available.join(", "))));
+                }
+            },
+        };
+        expanded.push((name.clone(), args));
+    }
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use crate::preset::{device_args, Preset};
+    use crate::target::Arch;
+
+    #[test]
+    fn numa_nodes_cover_all_cpus() {
+        let args = Preset::Server.qemu_args(Arch::X86_64);
+        let smp = &args[args.iter().position(|a| a == "-smp").unwrap() + 1];
+        assert!(smp.starts_with("16,"));
+        let nodes: Vec<_> = args.iter().filter(|a| a.starts_with("node,")).collect();
+        assert_eq!(nodes.len(), 2);
+        assert!(nodes[1].contains("cpus=8-15"));
+
+        assert!(Preset::LowEnd.qemu_args(Arch::X86_64).windows(2).any(|w| w == ["-m", "128M"]));
+
+        // aarch64のvirtマシンにはq35やISAのVGAがない
+        let args = Preset::Server.qemu_args(Arch::Aarch64);
+        assert!(!args.iter().any(|a| a == "q35") && args.iter().any(|a| a.starts_with("node,")));
+        let args = Preset::LowEnd.qemu_args(Arch::Aarch64);
+        assert!(!args.iter().any(|a| a.starts_with("VGA")) && args.iter().any(|a| a.starts_with("virtio-gpu-pci")));
+    }
+
+    #[test]
+    fn device_presets_combine_and_can_be_overridden() {
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let mut defined = BTreeMap::new();
+        defined.insert("rng".to_string(), vec!["-device".to_string(), "virtio-rng-pci".to_string()]);
+        defined.insert("tpm".to_string(), vec!["-tpmdev".to_string(), "emulator,id=tpm0,chardev=tpm".to_string()]);
+
+        let expanded = device_args(&names(&["net-virtio", "rng", "net-virtio", "tpm"]), &defined).unwrap();
+        assert_eq!(expanded.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>(), ["net-virtio", "rng", "tpm"]);
+        assert!(expanded[0].1.windows(2).any(|w| w == ["-device", "virtio-net-pci,netdev=with-net-virtio"]));
+        assert_eq!(expanded[1].1, ["-device", "virtio-rng-pci"]);
+
+        let error = device_args(&names(&["storage-scsi"]), &defined).unwrap_err();
+        assert!(error.to_string().contains("storage-nvme") && error.to_string().contains("tpm"));
+    }
+}
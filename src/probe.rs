@@ -0,0 +1,279 @@
+use std::io::{Read, Write};
+use std::net;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::ports::{PortAllocator, PROBE_PREFIX};
+
+/// 接続や応答の読み取りを待つ時間
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+/// 失敗した検査を再び試すまでの間隔
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+/// HTTPの検査で `expect` を省略した場合に求める応答
+const DEFAULT_HTTP_EXPECT: &str = r"^HTTP/1\.[01] 2\d\d";
+
+/// ゲストの動作中に、フォワードしたポートに対してホストから行う検査
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Probe {
+    pub name: String,
+    /// ゲストが待ち受けるTCPのポート
+    pub guest_port: u16,
+    /// 指定するとこのパスにHTTPのGETを送る。省略時はTCPで接続できるかだけを調べる
+    pub http: Option<String>,
+    /// 応答が満たすべき正規表現（`regex` クレートの構文）。応答のどこかに一致すればよく、先頭からの一致を求めるには `^` を付ける。
+    /// TCPの場合は接続直後にゲストが送ってきたデータと照合する
+    pub expect: Option<String>,
+    /// 起動してから検査を始めるまでの時間（秒）
+    #[serde(default)]
+    pub after: f64,
+    /// 起動してから検査に成功するまでの期限（秒）。省略時はQEMUが終了するまで試し続ける
+    pub within: Option<f64>,
+}
+
+impl Probe {
+    /// 検査の設定を確かめ、応答と照合するパターンを返す
+    fn pattern(&self) -> Result<Option<Regex>, Error> {
+        let invalid = |field: &str| Error::new(ErrorKind::InvalidArgument, msg!(ProbeInvalid, self.name, field));
+
+        if self.name.is_empty() {
+            return Err(invalid("name"));
+        }
+        if self.guest_port == 0 {
+            return Err(invalid("guest-port"));
+        }
+        if self.http.as_ref().is_some_and(|p| !p.starts_with('/')) {
+            return Err(invalid("http"));
+        }
+        if !self.after.is_finite() || self.after < 0.0 || self.within.is_some_and(|w| !w.is_finite() || w < self.after) {
+            return Err(invalid("within"));
+        }
+
+        let expect = match (&self.expect, &self.http) {
+            (Some(expect), _) => expect.as_str(),
+            (None, Some(_)) => DEFAULT_HTTP_EXPECT,
+            (None, None) => return Ok(None),
+        };
+        Regex::new(expect).map(Some).map_err(|e| Error::new(ErrorKind::InvalidArgument, msg!(ProbeInvalidExpect, self.name, e)))
+    }
+
+    /// 1回だけ検査し、失敗した場合はその理由を返す
+    fn attempt(&self, addr: net::SocketAddr, pattern: Option<&Regex>) -> Result<(), String> {
+        let mut stream = net::TcpStream::connect_timeout(&addr, IO_TIMEOUT).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(IO_TIMEOUT)).map_err(|e| e.to_string())?;
+        if let Some(path) = &self.http {
+            let request = format!("GET {} HTTP/1.0\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+            stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+        }
+
+        let (response, closed) = read_response(&mut stream, self.http.is_none().then_some(pattern).flatten());
+        let response = String::from_utf8_lossy(&response);
+        // QEMUのhostfwdはゲストが待ち受けていなくても接続を受け付け、すぐに閉じる
+        if closed && response.is_empty() {
+            return Err(msg!(ProbeClosed));
+        }
+        match pattern {
+            Some(pattern) if !pattern.is_match(&response) => {
+                Err(msg!(ProbeUnexpected, response.lines().next().unwrap_or_default()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 応答を読む。相手が閉じるか、TCPの場合は `until` に一致した時点で止め、(読んだデータ, 閉じられたか) を返す
+fn read_response(stream: &mut net::TcpStream, until: Option<&Regex>) -> (Vec<u8>, bool) {
+    let mut response = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return (response, true),
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            // 読み取りが時間切れになった場合は、接続したまま待っているものとみなす
+            Err(_) => return (response, false),
+        }
+        if until.is_some_and(|p| p.is_match(&String::from_utf8_lossy(&response))) {
+            return (response, false);
+        }
+    }
+}
+
+/// 1つの検査の結果
+struct Outcome {
+    name: String,
+    /// 成功した時点の起動からの経過時間
+    passed_at: Option<Duration>,
+    /// 最後に失敗した理由
+    failure: Option<String>,
+    attempts: u32,
+}
+
+/// 検査に使うホスト側のポートを割り当てたもの。ユーザーモードネットワークのhostfwdでゲストのポートに繋ぐ
+pub struct HostProbes {
+    probes: Vec<(Probe, Option<Regex>, net::SocketAddr)>,
+    ports: PortAllocator,
+}
+
+impl HostProbes {
+    pub fn new(probes: &[Probe], mut ports: PortAllocator) -> Result<HostProbes, Box<dyn std::error::Error>> {
+        let mut allocated = Vec::new();
+        for probe in probes {
+            let pattern = probe.pattern()?;
+            if probes.iter().filter(|p| p.name == probe.name).count() > 1 {
+                return Err(Box::new(Error::new(ErrorKind::InvalidArgument, msg!(ProbeInvalid, probe.name, "name"))));
+            }
+            let addr = ports.allocate(format!("{}{}", PROBE_PREFIX, probe.name).as_str())?;
+            allocated.push((probe.clone(), pattern, addr));
+        }
+
+        Ok(HostProbes { probes: allocated, ports })
+    }
+
+    /// ユーザーモードネットワークの `-netdev user` に加える、検査のためのポートのフォワード
+    pub fn hostfwd(&self) -> String {
+        self.probes.iter()
+            .map(|(probe, _, addr)| format!(",hostfwd=tcp:{}:{}-:{}", addr.ip(), addr.port(), probe.guest_port))
+            .collect()
+    }
+
+    /// 他にネットワークを構成しない場合に、検査のために追加するNICとユーザーモードネットワーク
+    pub fn qemu_args(&self) -> Vec<String> {
+        vec![
+            "-netdev".to_string(), format!("user,id=probe-net{}", self.hostfwd()),
+            "-device".to_string(), "virtio-net-pci,netdev=probe-net".to_string(),
+        ]
+    }
+
+    /// QEMUの起動と同時に呼び、検査を始める
+    pub fn start(&self) -> ProbeRun {
+        self.ports.report();
+        let started = Instant::now();
+        let stop = Arc::new(AtomicBool::new(false));
+        let handles = self.probes.iter().map(|(probe, pattern, addr)| {
+            let (probe, pattern, addr, stop) = (probe.clone(), pattern.clone(), *addr, stop.clone());
+            thread::spawn(move || watch(&probe, pattern.as_ref(), addr, started, &stop))
+        }).collect();
+
+        ProbeRun { handles, stop }
+    }
+}
+
+/// 期限を過ぎるか、QEMUが終了するまで検査を繰り返す
+fn watch(probe: &Probe, pattern: Option<&Regex>, addr: net::SocketAddr, started: Instant, stop: &AtomicBool) -> Outcome {
+    let mut outcome = Outcome { name: probe.name.clone(), passed_at: None, failure: None, attempts: 0 };
+    let begin = started + Duration::from_secs_f64(probe.after);
+    let deadline = probe.within.map(|w| started + Duration::from_secs_f64(w));
+    loop {
+        // QEMUが終了した後の接続は必ず失敗するため、試す前に終了を確かめる
+        if stop.load(Ordering::SeqCst) || deadline.is_some_and(|d| Instant::now() >= d) {
+            if outcome.attempts == 0 {
+                outcome.failure = Some(msg!(ProbeNotStarted));
+            }
+            return outcome;
+        }
+        if Instant::now() < begin {
+            thread::sleep(Duration::from_millis(20));
+            continue;
+        }
+
+        outcome.attempts += 1;
+        match probe.attempt(addr, pattern) {
+            Ok(()) => {
+                outcome.passed_at = Some(started.elapsed());
+                outcome.failure = None;
+                return outcome;
+            }
+            Err(reason) => outcome.failure = Some(reason),
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// 実行中の検査
+pub struct ProbeRun {
+    handles: Vec<thread::JoinHandle<Outcome>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ProbeRun {
+    /// QEMUの終了後に呼び、結果を出力する。全ての検査に成功していれば `true` を返す
+    pub fn finish(self) -> bool {
+        self.stop.store(true, Ordering::SeqCst);
+        let outcomes: Vec<Outcome> = self.handles.into_iter().filter_map(|h| h.join().ok()).collect();
+
+        for outcome in outcomes.iter() {
+            match (outcome.passed_at, &outcome.failure) {
+                (Some(at), _) => crate::output::status(msg!(ProbePassed, outcome.name, format!("{:.3}s", at.as_secs_f64()))),
+                (None, failure) => crate::output::status(msg!(ProbeFailed, outcome.name, failure.as_deref().unwrap_or_default())),
+            }
+        }
+        let passed = outcomes.iter().all(|o| o.passed_at.is_some());
+        let results: Vec<_> = outcomes.iter().map(|o| json!({
+            "name": o.name,
+            "passed": o.passed_at.is_some(),
+            "elapsed-ms": o.passed_at.map(|at| at.as_millis() as u64),
+            "attempts": o.attempts,
+            "failure": o.failure,
+        })).collect();
+        crate::output::event("probes", json!({ "passed": passed, "probes": results }));
+
+        passed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net;
+    use std::thread;
+    use regex::Regex;
+    use crate::probe::{Probe, DEFAULT_HTTP_EXPECT};
+
+    #[test]
+    fn expect_is_a_regex() {
+        let http = Regex::new(DEFAULT_HTTP_EXPECT).unwrap();
+        assert!(http.is_match("HTTP/1.1 204 No Content\r\n"));
+        assert!(!http.is_match("HTTP/1.1 404 Not Found\r\n"));
+        assert!(!http.is_match("xHTTP/1.1 200 OK"));
+
+        let mut probe = Probe { name: "web".to_string(), guest_port: 80, http: None, expect: Some(r"HTTP/1\.[01] 200".to_string()), after: 0.0, within: None };
+        let pattern = probe.pattern().unwrap().unwrap();
+        assert!(pattern.is_match("HTTP/1.0 200 OK"));
+        assert!(!pattern.is_match(r"HTTP/1\.[01] 200"));
+        probe.expect = Some("ready: (".to_string());
+        assert!(probe.pattern().is_err());
+    }
+
+    #[test]
+    fn probe_guest_service() {
+        let listener = net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            // HTTPの要求に応答した後、hostfwdのようにすぐ閉じる接続を受け付ける
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 256];
+            let n = stream.read(&mut request).unwrap();
+            assert!(request[..n].starts_with(b"GET /health HTTP/1.0\r\n"));
+            stream.write_all(b"HTTP/1.0 200 OK\r\n\r\nready").unwrap();
+            drop(stream);
+            drop(listener.accept().unwrap());
+        });
+
+        let mut probe = Probe { name: "web".to_string(), guest_port: 80, http: Some("/health".to_string()), expect: None, after: 0.0, within: None };
+        let pattern = probe.pattern().unwrap();
+        assert_eq!(probe.attempt(addr, pattern.as_ref()), Ok(()));
+
+        probe.http = None;
+        assert!(probe.attempt(addr, None).is_err());
+        server.join().unwrap();
+
+        probe.http = Some("health".to_string());
+        assert!(probe.pattern().is_err());
+    }
+}
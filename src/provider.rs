@@ -0,0 +1,282 @@
+use std::io;
+use std::path;
+use std::process::{Command, Stdio};
+use serde::Deserialize;
+use serde_json::json;
+use crate::error::{Error, ErrorKind};
+use crate::fetch::Network;
+use crate::firmware::{Firmware, FirmwarePaths, Mirrors, OvmfPrebuilt};
+use crate::message::msg;
+use crate::target::Arch;
+
+/// ファームウェアを用意する方法の1つ
+pub trait FirmwareProvider {
+    /// 設定ファイルの `firmware-order` で使う名前
+    fn name(&self) -> &'static str;
+
+    /// ファームウェアを用意する。この方法では見つからなければ `Ok(None)` を返し、次の方法に任せる
+    fn provide(&self, arch: Arch) -> Result<Option<Firmware>, Box<dyn std::error::Error>>;
+}
+
+/// ファームウェアを用意する方法を、優先する順に並べたもの。
+/// 設定ファイルで `firmware-order` を省略した場合は、作成したときの順に試す
+pub struct Registry<'a> {
+    providers: Vec<Box<dyn FirmwareProvider + 'a>>,
+}
+
+impl<'a> Registry<'a> {
+    pub fn new(providers: Vec<Box<dyn FirmwareProvider + 'a>>) -> Registry<'a> {
+        Registry { providers }
+    }
+
+    /// `order` に書かれた名前の順に並べ替える。書かれていない方法は使わない
+    pub fn ordered(mut self, order: &[String]) -> Result<Registry<'a>, Error> {
+        let known: Vec<_> = self.providers.iter().map(|p| p.name()).collect();
+        let mut providers = Vec::new();
+        for name in order {
+            if let Some(idx) = self.providers.iter().position(|p| p.name() == name.as_str()) {
+                providers.push(self.providers.remove(idx));
+            } else if !providers.iter().any(|p| p.name() == name.as_str()) {
+                return Err(Error::new(ErrorKind::InvalidArgument, msg!(FirmwareProviderUnknown, name, known.join(", "))));
+            }
+        }
+
+        Ok(Registry { providers })
+    }
+
+    /// 順に試し、最初に見つかったファームウェアを返す
+    pub fn resolve(&self, arch: Arch) -> Result<Firmware, Box<dyn std::error::Error>> {
+        for provider in self.providers.iter() {
+            if let Some(firmware) = provider.provide(arch)? {
+                crate::output::event("firmware", json!({
+                    "provider": provider.name(),
+                    "code": firmware.code,
+                    "vars": firmware.vars,
+                }));
+                return Ok(firmware);
+            }
+        }
+
+        let tried: Vec<_> = self.providers.iter().map(|p| p.name()).collect();
+        Err(Box::new(io::Error::new(
+            io::ErrorKind::NotFound,
+            msg!(FirmwareNotFound, crate::firmware::project_firmware_name(arch), tried.join(", "))
+        )))
+    }
+}
+
+/// `OVMF_PATH` 環境変数で指定されたファームウェア
+pub struct EnvVar;
+
+impl FirmwareProvider for EnvVar {
+    fn name(&self) -> &'static str {
+        "env"
+    }
+
+    fn provide(&self, _arch: Arch) -> Result<Option<Firmware>, Box<dyn std::error::Error>> {
+        Ok(crate::firmware::from_env().transpose()?)
+    }
+}
+
+/// パスで指定されたファームウェア。`--ovmf` や設定ファイルの `firmware` に使う
+pub struct LocalPath(pub Option<FirmwarePaths>);
+
+impl FirmwareProvider for LocalPath {
+    fn name(&self) -> &'static str {
+        "path"
+    }
+
+    fn provide(&self, _arch: Arch) -> Result<Option<Firmware>, Box<dyn std::error::Error>> {
+        Ok(self.0.as_ref().map(|paths| paths.firmware()).transpose()?)
+    }
+}
+
+/// プロジェクトルートに置かれたファームウェア（x86_64では OVMF.fd）
+pub struct ProjectFile<'a>(pub &'a path::Path);
+
+impl FirmwareProvider for ProjectFile<'_> {
+    fn name(&self) -> &'static str {
+        "project"
+    }
+
+    fn provide(&self, arch: Arch) -> Result<Option<Firmware>, Box<dyn std::error::Error>> {
+        let path = self.0.join(crate::firmware::project_firmware_name(arch));
+        Ok(path.is_file().then(|| Firmware::from_code(path)))
+    }
+}
+
+/// ディストリビューションやQEMUのパッケージがインストールしたファームウェア
+pub struct SystemSearch;
+
+impl FirmwareProvider for SystemSearch {
+    fn name(&self) -> &'static str {
+        "system"
+    }
+
+    fn provide(&self, arch: Arch) -> Result<Option<Firmware>, Box<dyn std::error::Error>> {
+        Ok(crate::firmware::find_system_firmware(arch, path::Path::new("/")))
+    }
+}
+
+/// Nixでインストールされたファームウェア
+pub struct Nix {
+    pub offline: bool,
+}
+
+impl FirmwareProvider for Nix {
+    fn name(&self) -> &'static str {
+        "nix"
+    }
+
+    fn provide(&self, arch: Arch) -> Result<Option<Firmware>, Box<dyn std::error::Error>> {
+        Ok(crate::firmware::find_nix_firmware(arch, self.offline))
+    }
+}
+
+/// ovmf-prebuilt からの取得に使う設定
+#[derive(Clone)]
+pub struct Fetch<'a> {
+    pub mirrors: &'a Mirrors,
+    pub wait_lock: bool,
+    pub allow_unverified: bool,
+    pub network: Network,
+}
+
+impl Fetch<'_> {
+    fn fetch(&self, source: &OvmfPrebuilt, arch: Arch) -> Result<Firmware, Box<dyn std::error::Error>> {
+        crate::firmware::ovmf_prebuilt(source, arch, self.mirrors, self.wait_lock, self.allow_unverified, &self.network)
+    }
+}
+
+/// 指定されたリリースの ovmf-prebuilt。キャッシュになければダウンロードする
+pub struct Prebuilt<'a> {
+    pub source: Option<OvmfPrebuilt>,
+    pub fetch: Fetch<'a>,
+}
+
+impl FirmwareProvider for Prebuilt<'_> {
+    fn name(&self) -> &'static str {
+        "ovmf-prebuilt"
+    }
+
+    fn provide(&self, arch: Arch) -> Result<Option<Firmware>, Box<dyn std::error::Error>> {
+        self.source.as_ref().map(|source| self.fetch.fetch(source, arch)).transpose()
+    }
+}
+
+/// 既定のリリースの ovmf-prebuilt をダウンロードしてキャッシュする。
+/// 利用者が許可した場合（`--download-ovmf` か、`firmware-order` に書いた場合）のみ使う
+pub struct Download<'a> {
+    pub enabled: bool,
+    pub signature: Option<crate::signature::SignatureConfig>,
+    pub fetch: Fetch<'a>,
+}
+
+impl FirmwareProvider for Download<'_> {
+    fn name(&self) -> &'static str {
+        "download"
+    }
+
+    fn provide(&self, arch: Arch) -> Result<Option<Firmware>, Box<dyn std::error::Error>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        crate::output::status(msg!(DownloadingFirmware, crate::firmware::DEFAULT_OVMF_PREBUILT_TAG));
+        let source = OvmfPrebuilt::default_release(self.signature.clone());
+        self.fetch.fetch(&source, arch).map(Some)
+    }
+}
+
+/// 利用者のコマンドが用意するファームウェア。
+/// コマンドはプロジェクトルートで `CARGO_UEFI_ARCH` を設定して実行され、標準出力に
+/// `{"code": "...", "vars": "...", "shell": "..."}` を書く（相対パスはプロジェクトルートからのパス）。
+/// 何も書かずに成功した場合は、見つからなかったものとして次の方法に進む
+pub struct Plugin<'a> {
+    pub command: &'a [String],
+    pub root: &'a path::Path,
+}
+
+/// プラグインが出力するファームウェアのパス
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PluginOutput {
+    code: path::PathBuf,
+    vars: Option<path::PathBuf>,
+    shell: Option<path::PathBuf>,
+}
+
+impl FirmwareProvider for Plugin<'_> {
+    fn name(&self) -> &'static str {
+        "plugin"
+    }
+
+    fn provide(&self, arch: Arch) -> Result<Option<Firmware>, Box<dyn std::error::Error>> {
+        let (program, args) = match self.command.split_first() {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        // パスで指定されたコマンドはプロジェクトルートからのパスとみなし、名前だけならPATHから探す
+        let program = match program.contains('/') {
+            true => self.root.join(program),
+            false => path::PathBuf::from(program),
+        };
+        let failed = |reason: String| Box::new(Error::new(ErrorKind::InvalidArgument, msg!(FirmwarePluginFailed, program.display(), reason)));
+
+        let output = Command::new(program.as_path())
+            .args(args)
+            .current_dir(self.root)
+            .env("CARGO_UEFI_ARCH", arch.rust_target().trim_end_matches("-unknown-uefi"))
+            .stdin(Stdio::null())
+            .stderr(crate::output::child_stderr())
+            .output()
+            .map_err(|e| failed(e.to_string()))?;
+        if !output.status.success() {
+            return Err(failed(output.status.to_string()));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if stdout.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let paths: PluginOutput = serde_json::from_str(stdout.trim()).map_err(|e| failed(e.to_string()))?;
+        let firmware = FirmwarePaths { code: paths.code, vars: paths.vars }.resolve(self.root).firmware()?;
+        Ok(Some(Firmware { shell: paths.shell.map(|s| self.root.join(s)), ..firmware }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::firmware::{Firmware, FirmwarePaths};
+    use crate::provider::{FirmwareProvider, LocalPath, Registry};
+    use crate::target::Arch;
+
+    /// 常に決まった結果を返す方法
+    struct Fixed(&'static str, Option<&'static str>);
+
+    impl FirmwareProvider for Fixed {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn provide(&self, _arch: Arch) -> Result<Option<Firmware>, Box<dyn std::error::Error>> {
+            Ok(self.1.map(|code| Firmware::from_code(code.into())))
+        }
+    }
+
+    #[test]
+    fn registry_follows_configured_order() {
+        let registry = || Registry::new(vec![Box::new(Fixed("env", None)), Box::new(Fixed("system", Some("/system.fd"))), Box::new(Fixed("project", Some("/OVMF.fd")))]);
+        assert_eq!(registry().resolve(Arch::X86_64).unwrap().code, std::path::Path::new("/system.fd"));
+
+        let order = ["project".to_string(), "system".to_string()];
+        assert_eq!(registry().ordered(&order).unwrap().resolve(Arch::X86_64).unwrap().code, std::path::Path::new("/OVMF.fd"));
+        let error = registry().ordered(&["env".to_string()]).unwrap().resolve(Arch::X86_64).unwrap_err();
+        assert!(error.to_string().contains("env"));
+        let unknown = registry().ordered(&["cache".to_string()]).err().unwrap().to_string();
+        assert!(unknown.contains("env, system, project"), "{}", unknown);
+
+        let missing = LocalPath(Some(FirmwarePaths { code: "/nonexistent/OVMF_CODE.fd".into(), vars: None }));
+        assert!(missing.provide(Arch::X86_64).is_err());
+    }
+}
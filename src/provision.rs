@@ -0,0 +1,402 @@
+use std::io;
+use std::io::{Seek, Write};
+use std::path;
+use serde::Deserialize;
+use serde_json::json;
+use crate::error::{Error, ErrorKind};
+use crate::firmware::Firmware;
+use crate::message::msg;
+use crate::staging::ExtraFile;
+
+/// 前回の実行の状態を引き継がないよう、実行の前に整えるマシンの状態
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Provision {
+    /// VARSイメージに書き込むUEFI変数
+    #[serde(default)]
+    pub variables: Vec<Variable>,
+    /// 実行ごとに作り直し、データディスクとして接続するFATのディスク
+    #[serde(default)]
+    pub disks: Vec<ProvisionDisk>,
+    /// ゲストの時計の開始時刻（`2024-01-01T09:00:00` または `2024-01-01`）
+    pub rtc: Option<String>,
+}
+
+/// 書き込むUEFI変数。値は `hex`、`string`、`utf16`、`file` のいずれか1つで指定する
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Variable {
+    pub name: String,
+    /// ベンダーのGUID。`global` はEFI_GLOBAL_VARIABLEを表す
+    pub guid: String,
+    /// `non-volatile`、`bootservice-access`、`runtime-access` の組み合わせ。省略した場合は3つ全て
+    pub attributes: Option<Vec<String>>,
+    /// 16進数で書いたバイト列。空白は読み飛ばす
+    pub hex: Option<String>,
+    /// そのままのバイト列として書き込む文字列
+    pub string: Option<String>,
+    /// UCS-2の文字列としてNUL終端を付けて書き込む文字列
+    pub utf16: Option<String>,
+    /// 内容を書き込むファイル。相対パスはプロジェクトルートからのパスとみなす
+    pub file: Option<path::PathBuf>,
+}
+
+/// 実行ごとに作り直すデータディスク
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ProvisionDisk {
+    /// ディスクのサイズ（例: `64M`）
+    pub size: Option<String>,
+    /// ディスクに置くファイル。`path` はディスクのルートからのパス
+    #[serde(default)]
+    pub files: Vec<ExtraFile>,
+}
+
+const DEFAULT_DISK_SIZE: &str = "64M";
+
+const EFI_GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+const ATTRIBUTES: &[(&str, u32)] = &[("non-volatile", 0x1), ("bootservice-access", 0x2), ("runtime-access", 0x4)];
+
+impl Variable {
+    fn invalid(&self, reason: impl std::fmt::Display) -> Error {
+        Error::new(ErrorKind::InvalidArgument, msg!(ProvisionVariableInvalid, self.name, reason))
+    }
+
+    fn guid(&self) -> Result<[u8; 16], Error> {
+        let guid = match self.guid.as_str() {
+            "global" => EFI_GLOBAL_VARIABLE,
+            guid => guid,
+        };
+        parse_guid(guid).ok_or_else(|| self.invalid(msg!(ProvisionGuidInvalid, self.guid)))
+    }
+
+    fn attributes(&self) -> Result<u32, Error> {
+        let names = match &self.attributes {
+            Some(names) => names,
+            None => return Ok(ATTRIBUTES.iter().map(|(_, bit)| bit).sum()),
+        };
+
+        names.iter().try_fold(0, |attributes, name| {
+            match ATTRIBUTES.iter().find(|(n, _)| n == name) {
+                Some((_, bit)) => Ok(attributes | bit),
+                None => Err(self.invalid(msg!(ProvisionAttributeUnknown, name, ATTRIBUTES.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", ")))),
+            }
+        })
+    }
+
+    fn data(&self, root: &path::Path) -> Result<Vec<u8>, Error> {
+        let data = match (&self.hex, &self.string, &self.utf16, &self.file) {
+            (Some(hex), None, None, None) => parse_hex(hex).ok_or_else(|| self.invalid(msg!(ProvisionHexInvalid, hex)))?,
+            (None, Some(string), None, None) => string.clone().into_bytes(),
+            (None, None, Some(text), None) => text.encode_utf16().chain([0]).flat_map(|c| c.to_le_bytes()).collect(),
+            (None, None, None, Some(file)) => std::fs::read(root.join(file)).map_err(|e| self.invalid(msg!(ProvisionFileUnreadable, file.display(), e)))?,
+            _ => return Err(self.invalid(msg!(ProvisionDataKind))),
+        };
+
+        Ok(data)
+    }
+}
+
+impl Provision {
+    pub fn is_empty(&self) -> bool {
+        self.variables.is_empty() && self.disks.is_empty() && self.rtc.is_none()
+    }
+
+    /// 設定を確かめる。ファイルに触れる前に呼ぶ
+    pub fn validate(&self, root: &path::Path) -> Result<(), Error> {
+        for variable in self.variables.iter() {
+            variable.guid()?;
+            variable.attributes()?;
+            variable.data(root)?;
+        }
+        for disk in self.disks.iter() {
+            crate::size::parse_size(disk.size.as_deref().unwrap_or(DEFAULT_DISK_SIZE))?;
+            for file in disk.files.iter() {
+                file.esp_path()?;
+            }
+        }
+        if let Some(rtc) = &self.rtc {
+            if !valid_rtc(rtc) {
+                return Err(Error::new(ErrorKind::InvalidArgument, msg!(ProvisionRtcInvalid, rtc)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `dir` に作るデータディスクのパス
+    pub fn disk_paths(&self, dir: &path::Path) -> Vec<path::PathBuf> {
+        (0..self.disks.len()).map(|idx| dir.join(format!("provision-{}.img", idx))).collect()
+    }
+
+    /// データディスクを `dir` に作り直す
+    pub fn create_disks(&self, dir: &path::Path, root: &path::Path) -> Result<(), Box<dyn std::error::Error>> {
+        if self.disks.is_empty() {
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(dir)?;
+        for (disk, image) in self.disks.iter().zip(self.disk_paths(dir)) {
+            let size = crate::size::parse_size(disk.size.as_deref().unwrap_or(DEFAULT_DISK_SIZE))?;
+            let files: Vec<_> = disk.files.iter().cloned().map(|f| f.resolve(root)).collect();
+            create_disk(image.as_path(), size, &files)?;
+        }
+
+        Ok(())
+    }
+
+    /// 実行ごとに複製したVARSイメージに、UEFI変数を書き込む
+    pub fn write_variables(&self, firmware: &Firmware, root: &path::Path) -> Result<(), Box<dyn std::error::Error>> {
+        if self.variables.is_empty() {
+            return Ok(());
+        }
+        let vars = firmware.vars.as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidArgument, msg!(ProvisionNeedsVars)))?;
+
+        let mut image = std::fs::read(vars)?;
+        for variable in self.variables.iter() {
+            set_variable(&mut image, variable.name.as_str(), &variable.guid()?, variable.attributes()?, &variable.data(root)?)
+                .map_err(|reason| variable.invalid(reason))?;
+        }
+        std::fs::write(vars, image)?;
+
+        Ok(())
+    }
+
+    /// ゲストの時計を合わせるQEMUの引数
+    pub fn qemu_args(&self) -> Vec<String> {
+        match &self.rtc {
+            Some(rtc) => vec!["-rtc".to_string(), format!("base={}", rtc)],
+            None => Vec::new(),
+        }
+    }
+
+    /// 整えた内容を報告する
+    pub fn report(&self) {
+        if self.is_empty() {
+            return;
+        }
+
+        let names: Vec<_> = self.variables.iter().map(|v| v.name.as_str()).collect();
+        crate::output::status(msg!(Provisioned, names.len(), self.disks.len(), self.rtc.as_deref().unwrap_or("-")));
+        crate::output::event("provisioned", json!({ "variables": names, "disks": self.disks.len(), "rtc": self.rtc }));
+    }
+}
+
+/// `YYYY-MM-DD` または `YYYY-MM-DDTHH:MM:SS`
+fn valid_rtc(rtc: &str) -> bool {
+    let pattern = match rtc.len() {
+        10 => "dddd-dd-dd",
+        19 => "dddd-dd-ddTdd:dd:dd",
+        _ => return false,
+    };
+
+    rtc.chars().zip(pattern.chars()).all(|(c, p)| match p {
+        'd' => c.is_ascii_digit(),
+        p => c == p,
+    })
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    let digits: Vec<_> = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+
+    digits.chunks(2)
+        .map(|pair| u8::from_str_radix(pair.iter().collect::<String>().as_str(), 16).ok())
+        .collect()
+}
+
+/// `8be4df61-93ca-11d2-aa0d-00e098032b8c` の形式のGUIDを、UEFIのメモリ上の並びにする
+fn parse_guid(guid: &str) -> Option<[u8; 16]> {
+    let parts: Vec<_> = guid.split('-').collect();
+    if parts.iter().map(|p| p.len()).ne([8, 4, 4, 4, 12]) {
+        return None;
+    }
+
+    let data1 = u32::from_str_radix(parts[0], 16).ok()?;
+    let data2 = u16::from_str_radix(parts[1], 16).ok()?;
+    let data3 = u16::from_str_radix(parts[2], 16).ok()?;
+    let data4 = parse_hex(format!("{}{}", parts[3], parts[4]).as_str())?;
+
+    let mut bytes = [0; 16];
+    bytes[..4].copy_from_slice(&data1.to_le_bytes());
+    bytes[4..6].copy_from_slice(&data2.to_le_bytes());
+    bytes[6..8].copy_from_slice(&data3.to_le_bytes());
+    bytes[8..].copy_from_slice(&data4);
+    Some(bytes)
+}
+
+/// 認証付き変数の形式の変数ストア（gEfiAuthenticatedVariableGuid）
+const AUTHENTICATED_STORE: [u8; 16] = [0x78, 0x2c, 0xf3, 0xaa, 0x7b, 0x94, 0x9a, 0x43, 0xa1, 0x80, 0x2e, 0x14, 0x4e, 0xc3, 0x77, 0x92];
+/// 認証のない形式の変数ストア（gEfiVariableGuid）
+const VARIABLE_STORE: [u8; 16] = [0x16, 0x36, 0xcf, 0xdd, 0x75, 0x32, 0x64, 0x41, 0x98, 0xb6, 0xfe, 0x85, 0x70, 0x7f, 0xfe, 0x7d];
+
+const VARIABLE_STORE_HEADER_SIZE: usize = 28;
+const START_ID: u16 = 0x55aa;
+const VAR_ADDED: u8 = 0x3f;
+/// 状態のビットを落として、変数を削除済みにする
+const VAR_DELETED: u8 = 0xfd;
+
+/// 変数ヘッダの大きさと、NameSize・DataSize・VendorGuidの位置
+struct HeaderLayout {
+    size: usize,
+    name_size: usize,
+    data_size: usize,
+    guid: usize,
+}
+
+const AUTHENTICATED_HEADER: HeaderLayout = HeaderLayout { size: 60, name_size: 36, data_size: 40, guid: 44 };
+const VARIABLE_HEADER: HeaderLayout = HeaderLayout { size: 32, name_size: 8, data_size: 12, guid: 16 };
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]) as usize
+}
+
+/// ファームウェアボリュームに収められた変数ストアに変数を書き込む。
+/// 同じ名前とGUIDの変数があれば削除済みにして、ストアの末尾に新しい変数を追加する（ファームウェアが変数を更新するときと同じ手順）
+fn set_variable(image: &mut [u8], name: &str, guid: &[u8; 16], attributes: u32, data: &[u8]) -> Result<(), String> {
+    if image.len() < 0x40 || &image[0x28..0x2c] != b"_FVH" {
+        return Err(msg!(ProvisionVarsFormat));
+    }
+    let store = u16_at(image, 0x30) as usize;
+    if image.len() < store + VARIABLE_STORE_HEADER_SIZE {
+        return Err(msg!(ProvisionVarsFormat));
+    }
+    let layout = match &image[store..store + 16] {
+        signature if signature == AUTHENTICATED_STORE => AUTHENTICATED_HEADER,
+        signature if signature == VARIABLE_STORE => VARIABLE_HEADER,
+        _ => return Err(msg!(ProvisionVarsFormat)),
+    };
+    let end = (store + u32_at(image, store + 16)).min(image.len());
+
+    let encoded_name: Vec<u8> = name.encode_utf16().chain([0]).flat_map(|c| c.to_le_bytes()).collect();
+    let mut pos = (store + VARIABLE_STORE_HEADER_SIZE).next_multiple_of(4);
+    while pos + layout.size <= end && u16_at(image, pos) == START_ID {
+        let name_size = u32_at(image, pos + layout.name_size);
+        let data_size = u32_at(image, pos + layout.data_size);
+        let name_start = pos + layout.size;
+        if name_start + name_size + data_size > end {
+            return Err(msg!(ProvisionVarsFormat));
+        }
+        // 削除の途中の変数（VAR_IN_DELETED_TRANSITIONのビットが落ちたもの）も、まだ有効な変数として扱う
+        let active = image[pos + 2] | 0x01 == VAR_ADDED;
+        if active && image[pos + layout.guid..pos + layout.guid + 16] == guid[..] && image[name_start..name_start + name_size] == encoded_name[..] {
+            image[pos + 2] &= VAR_DELETED;
+        }
+
+        pos = (name_start + name_size + data_size).next_multiple_of(4);
+    }
+
+    let size = layout.size + encoded_name.len() + data.len();
+    if pos + size > end || image[pos..pos + size].iter().any(|b| *b != 0xff) {
+        return Err(msg!(ProvisionVarsFull));
+    }
+    let header = &mut image[pos..pos + layout.size];
+    header.fill(0);
+    header[..2].copy_from_slice(&START_ID.to_le_bytes());
+    header[2] = VAR_ADDED;
+    header[4..8].copy_from_slice(&attributes.to_le_bytes());
+    header[layout.name_size..layout.name_size + 4].copy_from_slice(&(encoded_name.len() as u32).to_le_bytes());
+    header[layout.data_size..layout.data_size + 4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    header[layout.guid..layout.guid + 16].copy_from_slice(guid);
+    let name_start = pos + layout.size;
+    image[name_start..name_start + encoded_name.len()].copy_from_slice(&encoded_name);
+    image[name_start + encoded_name.len()..pos + size].copy_from_slice(data);
+
+    Ok(())
+}
+
+/// パーティションを持たないFATのディスクイメージを作り、`files` を置く
+fn create_disk(image: &path::Path, size: u64, files: &[ExtraFile]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut disk = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(image)?;
+    disk.set_len(size)?;
+    fatfs::format_volume(&mut disk, fatfs::FormatVolumeOptions::new())?;
+    disk.rewind()?;
+
+    let fs = fatfs::FileSystem::new(&mut disk, fatfs::FsOptions::new())?;
+    for file in files {
+        let dest = file.esp_path()?;
+        let mut dir = fs.root_dir();
+        let components: Vec<_> = dest.iter().map(|c| c.to_string_lossy().into_owned()).collect();
+        let (file_name, dirs) = components.split_last().expect("disk path has a file name");
+        for name in dirs {
+            dir = dir.create_dir(name)?;
+        }
+
+        let mut source = std::fs::File::open(file.source.as_path())
+            .map_err(|e| io::Error::new(e.kind(), msg!(NotFound, file.source.display())))?;
+        let mut dest = dir.create_file(file_name)?;
+        dest.truncate()?;
+        io::copy(&mut source, &mut dest)?;
+        dest.flush()?;
+    }
+    fs.unmount()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use crate::provision::{create_disk, parse_guid, set_variable, u32_at, valid_rtc, AUTHENTICATED_STORE, EFI_GLOBAL_VARIABLE};
+    use crate::staging::ExtraFile;
+
+    /// ファームウェアボリュームのヘッダと空の変数ストアだけを持つVARSイメージ
+    fn empty_vars(size: usize) -> Vec<u8> {
+        let mut image = vec![0xff; size];
+        image[..0x48].fill(0);
+        image[0x28..0x2c].copy_from_slice(b"_FVH");
+        image[0x30..0x32].copy_from_slice(&0x48u16.to_le_bytes());
+        image[0x48..0x58].copy_from_slice(&AUTHENTICATED_STORE);
+        image[0x58..0x5c].copy_from_slice(&((size - 0x48) as u32).to_le_bytes());
+        image[0x5c..0x64].copy_from_slice(&[0x5a, 0xfe, 0, 0, 0, 0, 0, 0]);
+        image
+    }
+
+    #[test]
+    fn variable_update_deletes_previous_copy() {
+        let guid = parse_guid(EFI_GLOBAL_VARIABLE).unwrap();
+        assert_eq!(guid[..4], [0x61, 0xdf, 0xe4, 0x8b]);
+        assert_eq!(guid[8..], [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c]);
+        assert!(parse_guid("8be4df61-93ca-11d2-aa0d").is_none());
+
+        let mut image = empty_vars(4096);
+        set_variable(&mut image, "PlatformLang", &guid, 7, b"en-US\0").unwrap();
+        set_variable(&mut image, "PlatformLang", &guid, 7, b"ja-JP\0").unwrap();
+        // 最初の変数は削除済みになり、4バイト境界に揃えた位置に新しい変数が続く
+        let first = 0x64;
+        assert_eq!(image[first + 2], 0x3d);
+        let second = (first + 60 + u32_at(&image, first + 36) + u32_at(&image, first + 40)).next_multiple_of(4);
+        assert_eq!(image[second + 2], 0x3f);
+        assert_eq!(&image[second + 60 + 26..second + 60 + 32], b"ja-JP\0");
+
+        assert!(set_variable(&mut image, "Big", &guid, 7, &[0; 4096]).is_err());
+        assert!(set_variable(&mut vec![0; 4096], "Boot0000", &guid, 7, &[]).is_err());
+        assert!(valid_rtc("2024-02-29T09:00:00") && valid_rtc("2024-02-29") && !valid_rtc("2024/02/29"));
+    }
+
+    #[test]
+    fn provisioned_disk_contains_files() {
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-provision-{}", std::process::id()));
+        std::fs::create_dir_all(dir.as_path()).unwrap();
+        std::fs::write(dir.join("fixture.txt"), b"fixture").unwrap();
+        let files = [ExtraFile { source: dir.join("fixture.txt"), path: "/data/in/fixture.txt".to_string() }];
+
+        create_disk(dir.join("disk.img").as_path(), 16 * 1024 * 1024, &files).unwrap();
+        let mut image = std::fs::File::open(dir.join("disk.img")).unwrap();
+        let fs = fatfs::FileSystem::new(&mut image, fatfs::FsOptions::new()).unwrap();
+        let mut contents = String::new();
+        fs.root_dir().open_file("data/in/fixture.txt").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "fixture");
+
+        drop(fs);
+        std::fs::remove_dir_all(dir.as_path()).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use std::io;
+use std::io::{BufRead, Write};
+use std::net;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// QEMU Machine Protocol のクライアント
+pub struct Qmp {
+    reader: io::BufReader<net::TcpStream>,
+    writer: net::TcpStream,
+}
+
+impl Qmp {
+    /// QMPサーバーに接続し、capabilitiesのネゴシエーションまで行う。
+    /// QEMUの起動直後はまだ待ち受けていないため、`timeout` の間は接続を再試行する。
+    pub fn connect(addr: net::SocketAddr, timeout: Duration) -> Result<Qmp, io::Error> {
+        let deadline = Instant::now() + timeout;
+        let stream = loop {
+            match net::TcpStream::connect(addr) {
+                Ok(stream) => break stream,
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => thread::sleep(Duration::from_millis(50)),
+            }
+        };
+
+        let mut qmp = Qmp {
+            reader: io::BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        // 接続直後にサーバーから送られるグリーティングを読み捨てる
+        qmp.read_message()?;
+        qmp.execute("qmp_capabilities", None)?;
+
+        Ok(qmp)
+    }
+
+    /// コマンドを実行し、その戻り値を返す。途中で届いた非同期イベントは読み捨てる
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, io::Error> {
+        let request = match arguments {
+            Some(arguments) => json!({ "execute": command, "arguments": arguments }),
+            None => json!({ "execute": command }),
+        };
+        writeln!(self.writer, "{}", request)?;
+
+        loop {
+            let mut msg = self.read_message()?;
+            if let Some(ret) = msg.get_mut("return") {
+                return Ok(ret.take());
+            }
+            if let Some(err) = msg.get("error") {
+                return Err(io::Error::other(format!("QMP command {} failed: {}", command, err)));
+            }
+        }
+    }
+
+    /// 次の非同期イベントを待って返す。QEMUが終了して接続が閉じられた場合はエラーになる
+    pub fn next_event(&mut self) -> Result<Value, io::Error> {
+        loop {
+            let msg = self.read_message()?;
+            if msg.get("event").is_some() {
+                return Ok(msg);
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Value, io::Error> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "QMP connection closed"));
+        }
+
+        serde_json::from_str(line.as_str()).map_err(io::Error::other)
+    }
+}
+
+/// QEMUに `addr` でQMPを待ち受けさせるための引数
+pub fn qmp_args(addr: net::SocketAddr) -> Vec<String> {
+    vec!["-qmp".to_string(), format!("tcp:{},server=on,wait=off", addr)]
+}
+
+/// `query-blockstats` が返すドライブごとの統計のうち、必要な部分
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlockStats {
+    #[serde(default)]
+    pub device: String,
+    pub stats: BlockDeviceStats,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlockDeviceStats {
+    #[serde(default)]
+    pub unmap_operations: u64,
+    #[serde(default)]
+    pub unmap_bytes: u64,
+}
+
+/// QEMUの実行中にブロックデバイスの統計を定期的に取得し続ける。
+/// QEMUの終了後に取得できなくなるため、最後に取得できた統計を結果とする。
+pub struct BlockStatsMonitor {
+    handle: thread::JoinHandle<Result<Vec<BlockStats>, io::Error>>,
+}
+
+impl BlockStatsMonitor {
+    pub fn start(addr: net::SocketAddr) -> BlockStatsMonitor {
+        let handle = thread::spawn(move || {
+            let mut qmp = Qmp::connect(addr, Duration::from_secs(10))?;
+            let mut latest = Vec::new();
+            while let Ok(stats) = qmp.execute("query-blockstats", None) {
+                latest = serde_json::from_value(stats).map_err(io::Error::other)?;
+                thread::sleep(Duration::from_millis(200));
+            }
+
+            Ok(latest)
+        });
+
+        BlockStatsMonitor { handle }
+    }
+
+    pub fn finish(self) -> Result<Vec<BlockStats>, io::Error> {
+        self.handle.join().unwrap_or_else(|_| Err(io::Error::other("block stats monitor panicked")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, Write};
+    use std::time::Duration;
+    use crate::qmp::{BlockStats, Qmp};
+
+    #[test]
+    fn negotiate_and_execute() {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut line = String::new();
+
+            writeln!(writer, r#"{{"QMP": {{"version": {{}}, "capabilities": []}}}}"#).unwrap();
+            reader.read_line(&mut line).unwrap();
+            assert!(line.contains("qmp_capabilities"));
+            writeln!(writer, r#"{{"return": {{}}}}"#).unwrap();
+
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert!(line.contains("query-status"));
+            writeln!(writer, r#"{{"event": "RESUME", "data": {{}}}}"#).unwrap();
+            writeln!(writer, r#"{{"return": {{"status": "running"}}}}"#).unwrap();
+        });
+
+        let mut qmp = Qmp::connect(addr, Duration::from_secs(5)).unwrap();
+        let status = qmp.execute("query-status", None).unwrap();
+        assert_eq!(status["status"], "running");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn parse_blockstats() {
+        let json = r#"[
+            {"device": "disk0", "stats": {"unmap_operations": 3, "unmap_bytes": 12288, "rd_bytes": 512}},
+            {"device": "", "node-name": "block123", "stats": {"rd_bytes": 0}}
+        ]"#;
+
+        let stats: Vec<BlockStats> = serde_json::from_str(json).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].device, "disk0");
+        assert_eq!(stats[0].stats.unmap_operations, 3);
+        assert_eq!(stats[0].stats.unmap_bytes, 12288);
+        assert_eq!(stats[1].stats.unmap_operations, 0);
+    }
+}
@@ -0,0 +1,292 @@
+use std::fmt::Write;
+use std::io;
+use std::path;
+use std::time::{Duration, SystemTime};
+use crate::message::msg;
+use crate::target::Arch;
+use crate::verdict::Verdict;
+
+/// レポートに載せるシリアルの出力の上限。超えた分は先頭を省く
+const SERIAL_LOG_LIMIT: usize = 256 * 1024;
+
+/// 実行時間のグラフの棒の最大の幅
+const CHART_WIDTH: f64 = 480.0;
+
+/// 1つのバイナリの結果
+pub struct Record {
+    pub app: String,
+    pub arch: Arch,
+    pub firmware: Option<path::PathBuf>,
+    pub outcome: Outcome,
+    pub duration: Option<Duration>,
+    pub serial_log: Option<path::PathBuf>,
+    /// ゲストが保存したスクリーンショット（PPM）
+    pub screenshots: Vec<path::PathBuf>,
+}
+
+pub enum Outcome {
+    BuildFailed,
+    Finished(Verdict),
+}
+
+impl Record {
+    fn passed(&self) -> bool {
+        matches!(&self.outcome, Outcome::Finished(verdict) if verdict.exit_code() == 0)
+    }
+
+    fn result(&self) -> String {
+        match &self.outcome {
+            Outcome::BuildFailed => msg!(ReportBuildFailed),
+            Outcome::Finished(verdict) => verdict.describe(),
+        }
+    }
+}
+
+/// `dir` にあるスクリーンショットのうち、`since` 以降に保存されたもの
+pub fn screenshots_since(dir: &path::Path, since: SystemTime) -> Vec<path::PathBuf> {
+    let mut screenshots: Vec<_> = std::fs::read_dir(dir).into_iter().flatten().flatten()
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "ppm"))
+        .filter(|p| p.metadata().and_then(|m| m.modified()).is_ok_and(|m| m >= since))
+        .collect();
+    screenshots.sort();
+    screenshots
+}
+
+/// 全ての結果を1つのHTMLファイルにまとめる。ログと画像は埋め込むので、ファイル単体で閲覧できる
+pub fn write(path: &path::Path, records: &[Record]) -> Result<(), io::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, render(records))
+}
+
+fn render(records: &[Record]) -> String {
+    let passed = records.iter().filter(|r| r.passed()).count();
+    let mut html = String::new();
+    let _ = write!(html, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n",
+        escape(msg!(ReportTitle).as_str()), STYLE);
+    let _ = write!(html, "<h1>{}</h1>\n<p class=\"{}\">{}</p>\n",
+        escape(msg!(ReportTitle).as_str()),
+        if passed == records.len() { "passed" } else { "failed" },
+        escape(msg!(ReportSummary, passed, records.len() - passed).as_str()));
+
+    let _ = write!(html, "<table>\n<tr><th>{}</th><th>{}</th><th>{}</th><th>{}</th><th>{}</th></tr>\n",
+        escape(msg!(ReportBinary).as_str()), escape(msg!(ReportArch).as_str()), escape(msg!(ReportFirmware).as_str()),
+        escape(msg!(ReportResult).as_str()), escape(msg!(ReportDuration).as_str()));
+    for record in records {
+        let firmware = record.firmware.as_ref().map(|f| f.display().to_string()).unwrap_or_default();
+        let duration = record.duration.map(|d| format!("{:.1}s", d.as_secs_f64())).unwrap_or_default();
+        let _ = writeln!(html, "<tr><td><a href=\"#{}\">{}</a></td><td>{}</td><td>{}</td><td class=\"{}\">{}</td><td>{}</td></tr>",
+            anchor(record), escape(record.app.as_str()), arch_name(record.arch), escape(firmware.as_str()),
+            if record.passed() { "passed" } else { "failed" }, escape(record.result().as_str()), duration);
+    }
+    html.push_str("</table>\n");
+
+    html.push_str(timing_chart(records).as_str());
+
+    for record in records {
+        let _ = writeln!(html, "<h2 id=\"{}\">{} ({})</h2>", anchor(record), escape(record.app.as_str()), arch_name(record.arch));
+        if let Some(log) = &record.serial_log {
+            let text = std::fs::read(log).map(|raw| serial_text(raw.as_slice())).unwrap_or_default();
+            let _ = write!(html, "<details>\n<summary>{}</summary>\n<pre>{}</pre>\n</details>\n", escape(msg!(ReportSerialLog).as_str()), escape(text.as_str()));
+        }
+        if !record.screenshots.is_empty() {
+            let _ = write!(html, "<details open>\n<summary>{}</summary>\n", escape(msg!(ReportScreenshots).as_str()));
+            for screenshot in record.screenshots.iter() {
+                let name = screenshot.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                match std::fs::read(screenshot).ok().and_then(|ppm| ppm_to_bmp(ppm.as_slice())) {
+                    Some(bmp) => {
+                        let _ = writeln!(html, "<figure><img alt=\"{0}\" src=\"data:image/bmp;base64,{1}\"><figcaption>{0}</figcaption></figure>", escape(name.as_str()), base64(bmp.as_slice()));
+                    }
+                    None => {
+                        let _ = writeln!(html, "<p>{}</p>", escape(name.as_str()));
+                    }
+                }
+            }
+            html.push_str("</details>\n");
+        }
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}table{border-collapse:collapse}\
+th,td{border:1px solid #ccc;padding:4px 8px;text-align:left}.passed{color:#1a7f37}.failed{color:#cf222e}\
+pre{background:#f6f8fa;padding:8px;overflow:auto;max-height:40em}figure{display:inline-block;margin:8px}img{max-width:640px}";
+
+/// 実行時間を横棒で表すSVG
+fn timing_chart(records: &[Record]) -> String {
+    let runs: Vec<_> = records.iter().filter_map(|r| r.duration.map(|d| (r, d.as_secs_f64()))).collect();
+    let longest = runs.iter().map(|(_, secs)| *secs).fold(0.0, f64::max);
+    if runs.is_empty() || longest <= 0.0 {
+        return String::new();
+    }
+
+    let (label_width, row) = (160.0, 22.0);
+    let mut svg = format!("<h2>{}</h2>\n<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n",
+        escape(msg!(ReportTiming).as_str()), label_width + CHART_WIDTH + 80.0, row * runs.len() as f64);
+    for (idx, (record, secs)) in runs.iter().enumerate() {
+        let y = row * idx as f64;
+        let width = (secs / longest * CHART_WIDTH).max(1.0);
+        let color = if record.passed() { "#1a7f37" } else { "#cf222e" };
+        let _ = writeln!(svg, "<text x=\"0\" y=\"{}\" font-size=\"12\">{}</text><rect x=\"{}\" y=\"{}\" width=\"{:.1}\" height=\"{}\" fill=\"{}\"/><text x=\"{:.1}\" y=\"{}\" font-size=\"12\">{:.1}s</text>",
+            y + 15.0, escape(record.app.as_str()), label_width, y + 3.0, width, row - 6.0, color, label_width + width + 4.0, y + 15.0, secs);
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn arch_name(arch: Arch) -> &'static str {
+    arch.rust_target().trim_end_matches("-unknown-uefi")
+}
+
+fn anchor(record: &Record) -> String {
+    format!("{}-{}", record.app.replace(|c: char| !c.is_ascii_alphanumeric(), "-"), arch_name(record.arch))
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// シリアルの出力から端末の制御シーケンスと復帰を除き、末尾の `SERIAL_LOG_LIMIT` バイトまでにする
+fn serial_text(raw: &[u8]) -> String {
+    // ゲストが行に付けたタグは、端末に流すときと同じく取り除く
+    let mut demux = crate::guestlog::Demux::new(crate::guestlog::Level::Trace, false);
+    let mut lines = demux.feed(raw);
+    lines.extend(demux.finish());
+    let untagged: Vec<u8> = lines.into_iter().flat_map(|(_, text)| text).collect();
+    let tail = &untagged[untagged.len().saturating_sub(SERIAL_LOG_LIMIT)..];
+    let text = String::from_utf8_lossy(tail);
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{1b}' if chars.peek() == Some(&'[') => {
+                chars.next();
+                // CSIはパラメータの後の0x40から0x7eの文字で終わる
+                for c in chars.by_ref() {
+                    if ('\u{40}'..='\u{7e}').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            '\u{1b}' | '\r' => {}
+            c => plain.push(c),
+        }
+    }
+    plain
+}
+
+/// QEMUの `screendump` が書き出すPPM(P6)を、ブラウザで表示できる24ビットのBMPにする
+fn ppm_to_bmp(ppm: &[u8]) -> Option<Vec<u8>> {
+    // ヘッダは空白で区切られた4つの値で、最後の値の後の1文字の空白から画素が始まる
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while ppm.get(pos)?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        if ppm[pos] == b'#' {
+            while *ppm.get(pos)? != b'\n' {
+                pos += 1;
+            }
+            continue;
+        }
+        let start = pos;
+        while !ppm.get(pos)?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        fields.push(std::str::from_utf8(&ppm[start..pos]).ok()?);
+    }
+    let pixels = ppm.get(pos + 1..)?;
+    let (width, height): (usize, usize) = (fields[1].parse().ok()?, fields[2].parse().ok()?);
+    let length = width.checked_mul(height)?.checked_mul(3)?;
+    if fields[0] != "P6" || fields[3] != "255" || width == 0 || height == 0 || pixels.len() < length {
+        return None;
+    }
+
+    let stride = (width * 3).div_ceil(4).checked_mul(4)?;
+    let size = stride.checked_mul(height)?.checked_add(54)?;
+    let (size_field, width_field, height_field) = (u32::try_from(size).ok()?, i32::try_from(width).ok()?, i32::try_from(height).ok()?);
+    let mut bmp = Vec::with_capacity(size);
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&size_field.to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);
+    bmp.extend_from_slice(&54u32.to_le_bytes());
+    bmp.extend_from_slice(&40u32.to_le_bytes());
+    bmp.extend_from_slice(&width_field.to_le_bytes());
+    bmp.extend_from_slice(&height_field.to_le_bytes());
+    bmp.extend_from_slice(&1u16.to_le_bytes());
+    bmp.extend_from_slice(&24u16.to_le_bytes());
+    bmp.extend_from_slice(&[0; 24]);
+    // BMPは下の行から、画素はBGRの順に並べる
+    for row in pixels[..length].chunks(width * 3).rev() {
+        for rgb in row.chunks(3) {
+            bmp.extend_from_slice(&[rgb[2], rgb[1], rgb[0]]);
+        }
+        bmp.resize(bmp.len() + stride - width * 3, 0);
+    }
+
+    Some(bmp)
+}
+
+fn base64(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::report::{base64, ppm_to_bmp, render, serial_text, Outcome, Record};
+    use crate::target::Arch;
+    use crate::verdict::Verdict;
+
+    #[test]
+    fn report_embeds_results_and_logs() {
+        assert_eq!(base64(b"hoge"), "aG9nZQ==");
+        assert_eq!(base64(b"fuga1"), "ZnVnYTE=");
+        assert_eq!(serial_text(b"\x1b[2J\x1b[01;01HBdsDxe: loading\r\n<ok>"), "BdsDxe: loading\n<ok>");
+        assert_eq!(serial_text(b"\x1b_uefi:warn\x1b\\low memory\n\x1b_uefi:stdout\x1b\\done\n"), "low memory\ndone\n");
+
+        let bmp = ppm_to_bmp(b"P6\n# screendump\n2 1\n255\n\xff\x00\x00\x00\x00\xff").unwrap();
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(&bmp[54..], &[0, 0, 255, 255, 0, 0, 0, 0]);
+        assert_eq!(ppm_to_bmp(b"P6\n2 1\n255\n\xff"), None);
+        assert_eq!(ppm_to_bmp(b"P6\n0 1\n255\n"), None);
+        assert_eq!(ppm_to_bmp(b"P6\n18446744073709551615 2\n255\n\xff"), None);
+
+        let records = [
+            Record { app: "hoge".to_string(), arch: Arch::X86_64, firmware: Some("OVMF.fd".into()), outcome: Outcome::Finished(Verdict::Timeout), duration: Some(Duration::from_secs(3)), serial_log: None, screenshots: Vec::new() },
+            Record { app: "<fuga>".to_string(), arch: Arch::Aarch64, firmware: None, outcome: Outcome::BuildFailed, duration: None, serial_log: None, screenshots: Vec::new() },
+        ];
+        let html = render(&records);
+        assert!(html.contains("&lt;fuga&gt;") && !html.contains("<fuga>"));
+        assert!(html.contains("href=\"#hoge-x86_64\"") && html.contains("id=\"hoge-x86_64\""));
+        assert_eq!(html.matches("<rect").count(), 1);
+    }
+}
@@ -0,0 +1,139 @@
+use std::io;
+use std::path;
+use clap::Args;
+use toml_edit::{value, Document, Item, Table};
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::target::Arch;
+
+const RUNNER_NAME: &str = "cargo-uefi";
+
+#[derive(Args)]
+pub struct InstallRunnerArgs {
+    /// 別のrunnerが登録されていても、cargo-uefiで置き換える
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// プロジェクトの `.cargo/config.toml` に、cargo-uefiを `arch` のUEFIターゲットのrunnerとして登録する。
+/// 既存の設定は保持し、内容を変更した場合に `true` を返す。
+/// 別のrunnerが登録されている場合は、`force` が指定されていなければ変更しない
+pub fn install_runner(project_root: &path::Path, arch: Arch, force: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    let cargo_dir = project_root.join(".cargo");
+    // 拡張子のない古い形式の設定ファイルしかない場合は、そちらを編集する
+    let legacy_path = cargo_dir.join("config");
+    let config_path = if legacy_path.is_file() && !cargo_dir.join("config.toml").is_file() {
+        legacy_path
+    } else {
+        cargo_dir.join("config.toml")
+    };
+
+    let original = match std::fs::read_to_string(config_path.as_path()) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    if let Some(runner) = other_runner(original.as_str(), arch.rust_target())? {
+        if !force {
+            return Err(Box::new(Error::new(ErrorKind::InvalidArgument, msg!(RunnerConflict, runner, arch.rust_target(), config_path.display()))));
+        }
+    }
+
+    let configured = configure(original.as_str(), arch.rust_target())?;
+    if configured == original {
+        return Ok(false);
+    }
+
+    std::fs::create_dir_all(cargo_dir)?;
+    std::fs::write(config_path, configured)?;
+
+    Ok(true)
+}
+
+/// 設定ファイルの内容に、runnerとビルドターゲットの設定を加えたものを返す
+fn configure(config: &str, rust_target: &str) -> Result<String, toml_edit::TomlError> {
+    let mut doc = config.parse::<Document>()?;
+
+    let build = sub_table(doc.as_table_mut(), "build", false);
+    build["target"] = value(rust_target);
+
+    let target = sub_table(doc.as_table_mut(), "target", true);
+    let uefi = sub_table(target, rust_target, false);
+    uefi["runner"] = value(RUNNER_NAME);
+
+    Ok(doc.to_string())
+}
+
+/// `rust_target` にcargo-uefi以外のrunnerが登録されていれば、その内容を返す
+fn other_runner(config: &str, rust_target: &str) -> Result<Option<String>, toml_edit::TomlError> {
+    let doc = config.parse::<Document>()?;
+    let runner = match doc.get("target").and_then(|t| t.get(rust_target)).and_then(|t| t.get("runner")) {
+        Some(runner) => runner,
+        None => return Ok(None),
+    };
+    if runner.as_str() == Some(RUNNER_NAME) {
+        return Ok(None);
+    }
+
+    // 配列で書かれたrunnerも、その内容のまま示す
+    let shown = runner.as_str().map(str::to_string).unwrap_or_else(|| runner.to_string().trim().to_string());
+    Ok(Some(shown))
+}
+
+/// `parent` 内のテーブル `key` を返す。存在しない場合は作成する。
+/// `implicit` が真の場合、作成したテーブル自体のヘッダーは出力されない。
+fn sub_table<'a>(parent: &'a mut Table, key: &str, implicit: bool) -> &'a mut Table {
+    let item = parent.entry(key).or_insert_with(|| {
+        let mut table = Table::new();
+        table.set_implicit(implicit);
+        Item::Table(table)
+    });
+
+    if !item.is_table() {
+        *item = Item::Table(Table::new());
+    }
+
+    item.as_table_mut().expect("item is replaced with a table")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::runner::{configure, other_runner};
+
+    const X64: &str = "x86_64-unknown-uefi";
+
+    #[test]
+    fn configure_empty_config() {
+        let config = configure("", X64).unwrap();
+        assert_eq!(config, "[build]\ntarget = \"x86_64-unknown-uefi\"\n\n[target.x86_64-unknown-uefi]\nrunner = \"cargo-uefi\"\n");
+    }
+
+    #[test]
+    fn configure_preserves_existing_content() {
+        let original = "# my settings\n[build]\njobs = 4 # keep this\n\n[alias]\nb = \"build\"\n";
+        let config = configure(original, X64).unwrap();
+
+        assert!(config.starts_with("# my settings\n[build]\njobs = 4 # keep this\ntarget = \"x86_64-unknown-uefi\"\n"));
+        assert!(config.contains("[alias]\nb = \"build\"\n"));
+        assert!(config.contains("[target.x86_64-unknown-uefi]\nrunner = \"cargo-uefi\"\n"));
+    }
+
+    #[test]
+    fn configure_is_idempotent() {
+        let once = configure("[target.x86_64-unknown-uefi]\nrunner = \"uefi-run\"\n", X64).unwrap();
+        let twice = configure(once.as_str(), X64).unwrap();
+        assert_eq!(once, twice);
+        assert!(!once.contains("uefi-run"));
+    }
+
+    #[test]
+    fn detect_other_runner() {
+        assert_eq!(other_runner("", X64).unwrap(), None);
+        assert_eq!(other_runner("[target.x86_64-unknown-uefi]\nrunner = \"cargo-uefi\"\n", X64).unwrap(), None);
+        assert_eq!(other_runner("[target.x86_64-unknown-uefi]\nrunner = \"uefi-run\"\n", X64).unwrap().as_deref(), Some("uefi-run"));
+        assert_eq!(other_runner("[target.x86_64-unknown-uefi]\nrunner = [\"qemu-wrap\", \"-x\"]\n", X64).unwrap().as_deref(), Some("[\"qemu-wrap\", \"-x\"]"));
+        // 他のターゲットのrunnerは関係ない
+        assert_eq!(other_runner("[target.aarch64-unknown-uefi]\nrunner = \"uefi-run\"\n", X64).unwrap(), None);
+    }
+}
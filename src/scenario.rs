@@ -0,0 +1,410 @@
+use std::io;
+use std::io::{Read, Write};
+use std::path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use clap::Args;
+use serde::Deserialize;
+use serde_json::json;
+use toml_edit::easy;
+use crate::error::{Error, ErrorKind};
+use crate::firmware::Firmware;
+use crate::fwcfg::FwCfgEntry;
+use crate::image::BootDrive;
+use crate::message::msg;
+use crate::qmp::Qmp;
+
+#[derive(Args)]
+pub struct ScenarioArgs {
+    /// 実行するシナリオファイル（TOML）
+    #[arg(value_name = "FILE")]
+    pub file: path::PathBuf,
+}
+
+/// 既定の1ステップあたりの待ち時間
+const DEFAULT_TIMEOUT: u64 = 60;
+
+/// 複数回の起動にまたがるテストの手順
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Scenario {
+    /// 各ステップの既定の待ち時間（秒）
+    pub timeout: Option<u64>,
+    /// 順に行う起動
+    #[serde(rename = "boot")]
+    pub boots: Vec<Boot>,
+}
+
+/// 1回の起動。UEFI変数は前の起動から引き継ぐ
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Boot {
+    pub name: Option<String>,
+    /// 起動前にUEFI変数をこのプロファイルの内容に置き換える
+    pub vars_profile: Option<String>,
+    /// 終了後のUEFI変数をこのプロファイルとして保存する
+    pub save_vars_profile: Option<String>,
+    /// この起動だけに追加するQEMUの引数
+    #[serde(default)]
+    pub qemu_args: Vec<String>,
+    /// この起動だけに渡すfw_cfgのデータ。相対パスはシナリオファイルからのパスとみなす
+    #[serde(default)]
+    pub fw_cfg: Vec<FwCfgEntry>,
+    /// 指定した場合、全てのステップの後でゲストが自ら終了するのを待ち、その終了コードを確認する。
+    /// 指定しなければ、最後のステップの後でQEMUを終了させる
+    pub exit_code: Option<i32>,
+    /// この起動のステップの既定の待ち時間（秒）
+    pub timeout: Option<u64>,
+    #[serde(default, rename = "step")]
+    pub steps: Vec<Step>,
+}
+
+/// 起動中に行う操作。いずれか1つを指定する
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Step {
+    /// 前のステップ以降のシリアル出力にこの文字列が現れるまで待つ
+    pub expect: Option<String>,
+    /// シリアルにこの文字列を入力する
+    pub send: Option<String>,
+    /// `ret` や `ctrl-alt-delete` のようなキーを順に押す
+    pub keys: Option<Vec<String>>,
+    /// 指定した秒数待つ
+    pub sleep: Option<f64>,
+    pub timeout: Option<u64>,
+}
+
+impl Step {
+    fn describe(&self) -> String {
+        match (&self.expect, &self.send, &self.keys, self.sleep) {
+            (Some(pattern), _, _, _) => format!("expect `{}`", pattern),
+            (_, Some(text), _, _) => format!("send `{}`", text.escape_debug()),
+            (_, _, Some(keys), _) => format!("keys {}", keys.join(" ")),
+            (_, _, _, Some(secs)) => format!("sleep {}s", secs),
+            _ => "-".to_string(),
+        }
+    }
+}
+
+impl Scenario {
+    /// シナリオファイルを読み込み、内容を検証する
+    pub fn load(file: &path::Path) -> Result<Scenario, Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(file)?;
+        let mut scenario: Scenario = easy::from_str(text.as_str())
+            .map_err(|e| Error::new(ErrorKind::InvalidArgument, msg!(ScenarioInvalid, file.display(), e)))?;
+        let base = file.parent().unwrap_or(path::Path::new("."));
+
+        if scenario.boots.is_empty() {
+            return Err(Box::new(Error::new(ErrorKind::InvalidArgument, msg!(ScenarioInvalid, file.display(), msg!(ScenarioNoBoot)))));
+        }
+        for (idx, boot) in scenario.boots.iter_mut().enumerate() {
+            for (step_idx, step) in boot.steps.iter().enumerate() {
+                let kinds = [step.expect.is_some(), step.send.is_some(), step.keys.is_some(), step.sleep.is_some()];
+                if kinds.iter().filter(|k| **k).count() != 1 {
+                    return Err(Box::new(Error::new(ErrorKind::InvalidArgument, msg!(ScenarioStepKind, idx + 1, step_idx + 1))));
+                }
+                // `inf` などはTOMLとして正しくても、待つ時間にできない
+                if let Some(secs) = step.sleep.filter(|secs| Duration::try_from_secs_f64(*secs).is_err()) {
+                    return Err(Box::new(Error::new(ErrorKind::InvalidArgument, msg!(ScenarioSleepInvalid, idx + 1, step_idx + 1, secs))));
+                }
+            }
+            boot.fw_cfg = boot.fw_cfg.drain(..).map(|e| e.resolve(base)).collect();
+        }
+
+        Ok(scenario)
+    }
+}
+
+/// シナリオの実行に必要な、起動ごとに変わらない設定
+pub struct Machine<'a> {
+    pub backend: &'a dyn crate::backend::VmBackend,
+    /// 起動の間で引き継ぐVARSイメージの作業用コピーを持つファームウェア
+    pub firmware: &'a Firmware,
+    pub drive: &'a BootDrive,
+    pub options: &'a [String],
+    pub convention: Option<&'a crate::exit::ExitConvention>,
+    pub varstores: &'a path::Path,
+    pub ports: &'a [(String, u16)],
+}
+
+/// ゲストのシリアル出力を蓄積し、新しい出力を待つ
+#[derive(Default)]
+struct Console {
+    output: Mutex<(Vec<u8>, bool)>,
+    updated: Condvar,
+}
+
+impl Console {
+    /// `from` 以降の出力に `pattern` が現れるまで待ち、その直後の位置を返す。位置は `compare::normalize` した出力のもの
+    fn expect(&self, pattern: &str, from: usize, deadline: Instant) -> Option<usize> {
+        let mut output = self.output.lock().unwrap();
+        loop {
+            let text = crate::compare::normalize(String::from_utf8_lossy(&output.0).as_ref()).join("\n");
+            if let Some(pos) = text.get(from..).and_then(|t| t.find(pattern)) {
+                return Some(from + pos + pattern.len());
+            }
+
+            let now = Instant::now();
+            // QEMUが終了した後は、新しい出力は来ない
+            if now >= deadline || output.1 {
+                return None;
+            }
+            output = self.updated.wait_timeout(output, deadline - now).unwrap().0;
+        }
+    }
+}
+
+fn forward_console<R: Read + Send + 'static>(mut reader: R, console: Arc<Console>) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        let mut line = Vec::new();
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+
+            if crate::output::json() {
+                for byte in &buf[..n] {
+                    line.push(*byte);
+                    if *byte == b'\n' {
+                        let text = String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string();
+                        crate::output::event("guest-output", json!({ "stream": "stdout", "line": text }));
+                        line.clear();
+                    }
+                }
+            } else if !crate::output::quiet() {
+                let mut stdout = io::stdout().lock();
+                let _ = stdout.write_all(&buf[..n]);
+                let _ = stdout.flush();
+            }
+
+            console.output.lock().unwrap().0.extend_from_slice(&buf[..n]);
+            console.updated.notify_all();
+        }
+
+        console.output.lock().unwrap().1 = true;
+        console.updated.notify_all();
+    })
+}
+
+/// `ctrl-alt-delete` のような組み合わせを、QMPの `send-key` の引数にする
+fn key_combo(combo: &str) -> serde_json::Value {
+    let keys: Vec<_> = combo.split('-').map(|k| json!({ "type": "qcode", "data": k })).collect();
+    json!({ "keys": keys })
+}
+
+/// シナリオを最初の起動から順に実行し、全てのステップが成功したかを返す
+pub fn run(scenario: &Scenario, machine: &Machine) -> Result<bool, Box<dyn std::error::Error>> {
+    let vars = machine.firmware.vars.as_deref();
+    let started = Instant::now();
+
+    for (idx, boot) in scenario.boots.iter().enumerate() {
+        let label = boot.name.clone().unwrap_or_else(|| format!("boot {}", idx + 1));
+        crate::output::status(msg!(ScenarioBoot, idx + 1, scenario.boots.len(), label));
+        crate::output::event("scenario-boot", json!({ "boot": idx + 1, "name": label }));
+
+        if let Some(name) = &boot.vars_profile {
+            let profile = crate::varstore::apply(machine.firmware, machine.varstores, name)?;
+            let (source, working) = (profile.vars.as_deref(), vars);
+            if let (Some(source), Some(working)) = (source, working) {
+                std::fs::copy(source, working)?;
+            }
+        }
+
+        let failure = run_boot(scenario, boot, machine)?;
+        if let Some(name) = &boot.save_vars_profile {
+            let working = vars.ok_or_else(|| Error::new(ErrorKind::InvalidArgument, msg!(VarsProfileNoVars, machine.firmware.code.display())))?;
+            crate::varstore::save(working, machine.varstores, name)?;
+        }
+
+        if let Some((step, reason)) = failure {
+            crate::output::status(msg!(ScenarioFailed, label, step, reason));
+            crate::output::event("scenario-finished", json!({ "passed": false, "boot": idx + 1, "step": step, "problem": reason }));
+            return Ok(false);
+        }
+    }
+
+    crate::output::status(msg!(ScenarioPassed, scenario.boots.len(), format!("{:.1}", started.elapsed().as_secs_f64())));
+    crate::output::event("scenario-finished", json!({ "passed": true, "boots": scenario.boots.len() }));
+    Ok(true)
+}
+
+/// 1回の起動を行う。失敗した場合はステップの番号（ステップの後の終了の確認は0）と理由を返す
+fn run_boot(scenario: &Scenario, boot: &Boot, machine: &Machine) -> Result<Option<(usize, String)>, Box<dyn std::error::Error>> {
+    let mut ports = crate::ports::PortAllocator::new(crate::ports::lock_dir(), machine.ports);
+    let qmp_addr = ports.allocate("qmp")?;
+
+    let mut options = machine.options.to_vec();
+    options.extend(crate::fwcfg::fw_cfg_args(&boot.fw_cfg)?);
+    options.extend(boot.qemu_args.iter().cloned());
+    options.extend(["-serial".to_string(), "stdio".to_string(), "-display".to_string(), "none".to_string()]);
+    options.extend(machine.backend.control_args(qmp_addr));
+
+    let vm = crate::backend::VmConfig { firmware: machine.firmware, drive: machine.drive, options };
+    let mut process = machine.backend.spawn(&vm, crate::backend::Serial::Piped)?;
+    let registration = crate::janitor::register_process(&process, machine.backend.program());
+    let console = Arc::new(Console::default());
+    let forwarder = process.stdout.take().map(|r| forward_console(r, console.clone()));
+    let capture = process.stderr.take().map(|r| crate::launch::StderrCapture::start(r, None));
+    let mut stdin = process.stdin.take();
+
+    let default_timeout = boot.timeout.or(scenario.timeout).unwrap_or(DEFAULT_TIMEOUT);
+    let mut qmp: Option<Qmp> = None;
+    let mut cursor = 0;
+    let mut failure = None;
+    for (idx, step) in boot.steps.iter().enumerate() {
+        let timeout = Duration::from_secs(step.timeout.unwrap_or(default_timeout));
+        let started = Instant::now();
+        let result: Result<(), String> = if let Some(pattern) = &step.expect {
+            match console.expect(pattern, cursor, started + timeout) {
+                Some(end) => {
+                    cursor = end;
+                    Ok(())
+                }
+                None => Err(msg!(ScenarioExpectTimeout, pattern, timeout.as_secs())),
+            }
+        } else if let Some(text) = &step.send {
+            stdin.as_mut()
+                .ok_or_else(|| msg!(ScenarioStdinClosed))
+                .and_then(|s| s.write_all(text.as_bytes()).and_then(|_| s.flush()).map_err(|e| e.to_string()))
+        } else if let Some(keys) = &step.keys {
+            // QMPには最初にキーを押すときに接続する
+            let connected = match qmp.take() {
+                Some(connected) => Ok(connected),
+                None => Qmp::connect(qmp_addr, timeout),
+            };
+            match connected {
+                Ok(mut connected) => {
+                    let result = keys.iter().try_for_each(|combo| {
+                        connected.execute("send-key", Some(key_combo(combo))).map(|_| ()).map_err(|e| e.to_string())
+                    });
+                    qmp = Some(connected);
+                    result
+                }
+                Err(e) => Err(e.to_string()),
+            }
+        } else {
+            std::thread::sleep(Duration::from_secs_f64(step.sleep.unwrap_or(0.0)));
+            Ok(())
+        };
+
+        let elapsed = started.elapsed().as_secs_f64();
+        crate::output::event("scenario-step", json!({
+            "step": idx + 1,
+            "action": step.describe(),
+            "ok": result.is_ok(),
+            "elapsed": elapsed,
+        }));
+        match result {
+            Ok(()) => crate::output::status(msg!(ScenarioStepPassed, idx + 1, step.describe(), format!("{:.1}", elapsed))),
+            Err(reason) => {
+                failure = Some((idx + 1, reason));
+                break;
+            }
+        }
+    }
+
+    // 最後のステップの後は、ゲストの終了を待つか、QEMUを終了させる
+    let status = match (failure.is_none(), boot.exit_code) {
+        (true, Some(_)) => crate::supervise::wait(&mut process, Some(Duration::from_secs(default_timeout)))?,
+        _ => {
+            drop(stdin);
+            if let Some(mut qmp) = qmp.take().or_else(|| Qmp::connect(qmp_addr, Duration::from_secs(1)).ok()) {
+                let _ = qmp.execute("quit", None);
+            }
+            crate::supervise::wait(&mut process, Some(Duration::from_secs(5)))?
+        }
+    };
+    registration.release();
+    if let Some(forwarder) = forwarder {
+        let _ = forwarder.join();
+    }
+    if let Some(capture) = capture {
+        capture.finish();
+    }
+
+    if let (None, Some(expected)) = (&failure, boot.exit_code) {
+        let code = machine.backend.exit_code(status, machine.convention);
+        if status.is_none() {
+            failure = Some((0, msg!(RunTimedOut)));
+        } else if code != expected {
+            failure = Some((0, msg!(ScenarioExitCode, expected, code)));
+        }
+    }
+
+    Ok(failure)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use toml_edit::easy;
+    use crate::scenario::{key_combo, Console, Scenario};
+
+    #[test]
+    fn parse_scenario() {
+        let text = r#"
+        timeout = 30
+
+        [[boot]]
+        name = "install"
+        vars-profile = "clean"
+        fw-cfg = [{ name = "opt/com.example.hoge/mode", string = "upgrade" }]
+
+        [[boot.step]]
+        expect = "Press any key"
+        [[boot.step]]
+        keys = ["ret"]
+        [[boot.step]]
+        expect = "Rebooting"
+
+        [[boot]]
+        exit-code = 0
+        save-vars-profile = "upgraded"
+
+        [[boot.step]]
+        send = "y\r"
+        "#;
+
+        let scenario: Scenario = easy::from_str(text).unwrap();
+        assert_eq!(scenario.boots.len(), 2);
+        assert_eq!(scenario.boots[0].steps.len(), 3);
+        assert_eq!(scenario.boots[0].steps[1].keys.as_deref(), Some(&["ret".to_string()][..]));
+        assert_eq!(scenario.boots[0].fw_cfg[0].string.as_deref(), Some("upgrade"));
+        assert_eq!(scenario.boots[1].exit_code, Some(0));
+        assert_eq!(scenario.boots[1].steps[0].describe(), "send `y\\r`");
+        assert!(easy::from_str::<Scenario>("[[boot]]\nreboot = true\n").is_err());
+        assert_eq!(key_combo("ctrl-alt-delete")["keys"][2]["data"], "delete");
+
+        let file = std::env::temp_dir().join(format!("cargo-uefi-test-scenario-{}.toml", std::process::id()));
+        for sleep in ["inf", "nan", "-1.0", "1e300"] {
+            std::fs::write(file.as_path(), format!("[[boot]]\n[[boot.step]]\nsleep = {}\n", sleep)).unwrap();
+            assert!(Scenario::load(file.as_path()).is_err(), "sleep = {}", sleep);
+        }
+        std::fs::write(file.as_path(), "[[boot]]\n[[boot.step]]\nsleep = 0.5\n").unwrap();
+        assert_eq!(Scenario::load(file.as_path()).unwrap().boots[0].steps[0].sleep, Some(0.5));
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn expect_waits_for_later_output() {
+        let console = Arc::new(Console::default());
+        console.output.lock().unwrap().0.extend_from_slice(b"\x1b[2JBdsDxe: loading\r\nstage 1\r\n");
+
+        let writer = console.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            writer.output.lock().unwrap().0.extend_from_slice(b"stage 2\r\n");
+            writer.updated.notify_all();
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let end = console.expect("stage 1", 0, deadline).unwrap();
+        let end = console.expect("stage 2", end, deadline).unwrap();
+        handle.join().unwrap();
+        // 一度見つけた出力は、後のステップでは対象にならない
+        assert!(console.expect("stage 1", end, Instant::now() + Duration::from_millis(10)).is_none());
+    }
+}
@@ -0,0 +1,133 @@
+use std::io;
+use std::io::{Read, Seek};
+use std::path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// ログを読み直す間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// 利用者が `--serial-tcp`、`-serial` や `-nographic` でシリアルの出力先を指定していなければ、ログに記録できる
+pub fn available(serial_tcp: bool, options: &[String]) -> bool {
+    !serial_tcp && !options.iter().any(|o| o == "-serial" || o == "-nographic")
+}
+
+/// 前回の実行のログから文字列を見つけないよう、ログを消しておき、置き場所を作る
+pub fn prepare(log: &path::Path) -> Result<(), io::Error> {
+    if let Some(dir) = log.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    match std::fs::remove_file(log) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// シリアルの出力を端末に流しつつ `log` に記録するQEMUの引数
+pub fn qemu_args(log: &path::Path) -> Vec<String> {
+    vec![
+        "-chardev".to_string(),
+        format!("stdio,id=serial-log,logfile={},logappend=off", crate::fwcfg::escape(log.display().to_string().as_str())),
+        "-serial".to_string(),
+        "chardev:serial-log".to_string(),
+    ]
+}
+
+/// QEMUが書き込んでいくシリアルのログを追いかけ、文字列を探す
+pub struct Tail {
+    log: path::PathBuf,
+    offset: u64,
+    window: Vec<u8>,
+    /// 読み取りの境界をまたいだ文字列も見つけられるよう、次の読み取りまで残す末尾の長さ
+    keep: usize,
+}
+
+impl Tail {
+    /// `longest` は探す文字列のうち最も長いものの長さ
+    pub fn new(log: &path::Path, longest: usize) -> Tail {
+        Tail { log: log.to_path_buf(), offset: 0, window: Vec::new(), keep: longest.saturating_sub(1) }
+    }
+
+    /// 前回から書き足された内容を読む
+    pub fn read(&mut self) -> Result<(), io::Error> {
+        let keep = self.window.len().min(self.keep);
+        self.window.drain(..self.window.len() - keep);
+
+        // QEMUが作成するまではログが存在しない
+        let mut file = match std::fs::File::open(self.log.as_path()) {
+            Ok(file) => file,
+            Err(_) => return Ok(()),
+        };
+        // QEMUは起動時にログを切り詰めるため、短くなっていれば先頭から読み直す
+        if file.metadata()?.len() < self.offset {
+            self.offset = 0;
+            self.window.clear();
+        }
+        file.seek(io::SeekFrom::Start(self.offset))?;
+        self.offset += file.read_to_end(&mut self.window)? as u64;
+        Ok(())
+    }
+
+    /// 最後に読んだ内容に `marker` が現れたか
+    pub fn contains(&self, marker: &[u8]) -> bool {
+        !marker.is_empty() && self.window.windows(marker.len()).any(|w| w == marker)
+    }
+}
+
+/// `log` に `marker` が現れるまで待つ。現れる前に `finished` が立てば `false` を返す
+pub fn wait_marker(log: &path::Path, marker: &[u8], finished: &AtomicBool) -> Result<bool, io::Error> {
+    let mut tail = Tail::new(log, marker.len());
+    loop {
+        tail.read()?;
+        if tail.contains(marker) {
+            return Ok(true);
+        }
+        if finished.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// マーカーを探す間隔だけ待つ
+pub fn pause() {
+    thread::sleep(POLL_INTERVAL);
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+    use crate::seriallog::{available, wait_marker};
+
+    #[test]
+    fn marker_split_across_reads_is_found() {
+        let log = std::env::temp_dir().join(format!("cargo-uefi-seriallog-{}.log", std::process::id()));
+        let mut file = std::fs::File::create(log.as_path()).unwrap();
+        file.write_all(b"BdsDxe: loading\nbegin var").unwrap();
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            file.write_all(b"iable write\n").unwrap();
+        });
+
+        assert!(wait_marker(log.as_path(), b"begin variable write", &AtomicBool::new(false)).unwrap());
+        writer.join().unwrap();
+        assert!(!wait_marker(log.as_path(), b"never printed", &AtomicBool::new(true)).unwrap());
+
+        // 読んでいる途中でログが切り詰められても、その後に書かれたマーカーを見つける
+        let truncated = log.clone();
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            std::fs::write(truncated.as_path(), b"cut here\n").unwrap();
+        });
+        assert!(wait_marker(log.as_path(), b"cut here", &AtomicBool::new(false)).unwrap());
+        writer.join().unwrap();
+        let _ = std::fs::remove_file(log);
+
+        assert!(available(false, &["-m".to_string(), "256M".to_string()]));
+        assert!(!available(true, &[]));
+        assert!(!available(false, &["-nographic".to_string()]));
+    }
+}
@@ -0,0 +1,163 @@
+use std::path;
+use clap::Args;
+use serde::Deserialize;
+use serde_json::json;
+use crate::error::{Error, ErrorKind};
+use crate::fetch::Network;
+use crate::firmware::Firmware;
+use crate::message::msg;
+use crate::staging::ExtraFile;
+use crate::target::Arch;
+
+#[derive(Args)]
+pub struct ShellToolsArgs {
+    /// 配置するツール（カンマ区切り）。省略した場合は、用意できるツールを全て配置する。
+    /// UEFI Shellは `shell`、バンドルに含まれる利用者のツールはファイル名から拡張子を除いた名前で指定する
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    pub tools: Vec<String>,
+}
+
+/// 設定ファイルの `shell-tools`。ツールをまとめたバンドルの取得元
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ShellToolsConfig {
+    /// tar.xz形式のバンドル。アーキテクチャごとのディレクトリ（`x64`、`aarch64`、`ia32`）にEFIファイルを置く。
+    /// `shell.efi` はUEFI Shellとして扱い、それ以外のEFIファイルは利用者が用意したツールとしてそのまま配置する
+    pub url: String,
+    /// バンドルのSHA-256。内容を確かめたバンドルだけを使うため、省略できない
+    pub sha256: String,
+}
+
+/// バンドル内のUEFI Shellのファイル名
+const SHELL_FILE: &str = "shell.efi";
+
+/// ツールを別に配置しなくても、UEFI Shellに組み込まれているコマンド
+const SHELL_BUILTINS: &[&str] = &["memmap", "dmpstore", "setvar"];
+
+/// バンドルをキャッシュから取得する。キャッシュになければダウンロードし、SHA-256を確かめてから展開する
+pub fn fetch_bundle(config: &ShellToolsConfig, wait_lock: bool, network: &Network) -> Result<path::PathBuf, Box<dyn std::error::Error>> {
+    let dir = crate::fetch::cache_root().join("shell-tools").join(config.sha256.to_ascii_lowercase());
+    let extracted = dir.join(".extracted");
+    if extracted.is_file() {
+        return Ok(dir);
+    }
+
+    let _lock = crate::lock::FileLock::acquire(crate::lock::lock_path_for(dir.as_path()).as_path(), wait_lock)?;
+    if extracted.is_file() {
+        return Ok(dir);
+    }
+
+    std::fs::create_dir_all(dir.as_path())?;
+    let tarball = dir.join("bundle.tar.xz");
+    if !tarball.is_file() {
+        crate::fetch::download_any(std::slice::from_ref(&config.url), tarball.as_path(), network)?;
+    }
+    if let Err(e) = crate::fetch::verify_sha256(tarball.as_path(), config.sha256.as_str()) {
+        let _ = std::fs::remove_file(tarball.as_path());
+        return Err(e);
+    }
+    crate::firmware::extract_tar_xz(tarball.as_path(), dir.as_path())?;
+    std::fs::write(extracted, b"")?;
+
+    Ok(dir)
+}
+
+/// 配置できるツールを `(名前, 配置するファイル)` で返す。UEFI Shellがバンドルになければ、`firmware` に付属するものを使う
+fn available(bundle: Option<&path::Path>, arch: Arch, firmware: Option<&Firmware>) -> Vec<(String, ExtraFile)> {
+    let arch_dir = bundle.map(|dir| dir.join(crate::firmware::prebuilt_arch_dir(arch)));
+    let shell = arch_dir.as_ref().map(|dir| dir.join(SHELL_FILE)).filter(|p| p.is_file())
+        .or_else(|| firmware.and_then(|f| f.shell.clone()));
+
+    let mut tools: Vec<_> = shell.into_iter()
+        .map(|source| ("shell".to_string(), ExtraFile { source, path: "/EFI/tools/Shell.efi".to_string() }))
+        .collect();
+    let mut bundled: Vec<_> = arch_dir.iter()
+        .flat_map(|dir| std::fs::read_dir(dir).into_iter().flatten().flatten())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && p.extension().is_some_and(|e| e.eq_ignore_ascii_case("efi")))
+        .filter(|p| p.file_name().is_some_and(|n| !n.eq_ignore_ascii_case(SHELL_FILE)))
+        .filter_map(|source| {
+            let name = source.file_stem()?.to_str()?.to_ascii_lowercase();
+            let path = format!("/EFI/tools/{}", source.file_name()?.to_str()?);
+            Some((name, ExtraFile { source, path }))
+        })
+        .collect();
+    bundled.sort_by(|(a, _), (b, _)| a.cmp(b));
+    tools.extend(bundled);
+    tools
+}
+
+/// 配置するツールを選ぶ
+pub fn select(names: &[String], bundle: Option<&path::Path>, arch: Arch, firmware: Option<&Firmware>) -> Result<Vec<ExtraFile>, Error> {
+    let tools = available(bundle, arch, firmware);
+    if names.is_empty() {
+        if tools.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidArgument, msg!(ShellToolsNone)));
+        }
+        return Ok(tools.into_iter().map(|(_, file)| file).collect());
+    }
+
+    let mut files = Vec::new();
+    for name in names {
+        let file = match tools.iter().find(|(n, _)| n == name) {
+            Some((_, file)) => file.clone(),
+            None if name == "shell" => return Err(Error::new(ErrorKind::InvalidArgument, msg!(ShellToolMissing))),
+            None if SHELL_BUILTINS.contains(&name.as_str()) => {
+                return Err(Error::new(ErrorKind::InvalidArgument, msg!(ShellToolBuiltin, name)));
+            }
+            None => {
+                let names: Vec<_> = tools.iter().map(|(n, _)| n.as_str()).collect();
+                return Err(Error::new(ErrorKind::InvalidArgument, msg!(ShellToolUnknown, name, names.join(", "))));
+            }
+        };
+        if !files.contains(&file) {
+            files.push(file);
+        }
+    }
+
+    Ok(files)
+}
+
+/// 配置したツールを報告する
+pub fn report(files: &[ExtraFile]) {
+    let paths: Vec<_> = files.iter().map(|f| f.path.replace('/', "\\")).collect();
+    crate::output::status(msg!(ShellToolsStaged, paths.join(", ")));
+    crate::output::event("shell-tools", json!({ "tools": files.iter().map(|f| json!({ "source": f.source, "path": f.path })).collect::<Vec<_>>() }));
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::firmware::Firmware;
+    use crate::shelltools::select;
+    use crate::target::Arch;
+
+    #[test]
+    fn select_tools_from_bundle_and_firmware() {
+        let bundle = std::env::temp_dir().join(format!("cargo-uefi-shell-tools-{}", std::process::id()));
+        std::fs::create_dir_all(bundle.join("x64")).unwrap();
+        std::fs::write(bundle.join("x64").join("PciDump.efi"), b"MZ").unwrap();
+        std::fs::write(bundle.join("x64").join("README"), b"").unwrap();
+        let firmware = Firmware { code: "code.fd".into(), vars: None, shell: Some("/fw/shell.efi".into()) };
+
+        // バンドルにないUEFI Shellは、ファームウェアに付属するものを使う
+        let files = select(&[], Some(bundle.as_path()), Arch::X86_64, Some(&firmware)).unwrap();
+        let staged: Vec<_> = files.iter().map(|f| (f.source.as_path(), f.path.as_str())).collect();
+        assert_eq!(staged, [
+            (path::Path::new("/fw/shell.efi"), "/EFI/tools/Shell.efi"),
+            (bundle.join("x64").join("PciDump.efi").as_path(), "/EFI/tools/PciDump.efi"),
+        ]);
+
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(select(&names(&["pcidump", "pcidump"]), Some(bundle.as_path()), Arch::X86_64, None).unwrap().len(), 1);
+        assert!(select(&names(&["shell"]), Some(bundle.as_path()), Arch::X86_64, None).is_err());
+        assert!(select(&names(&["pcidump"]), Some(bundle.as_path()), Arch::Aarch64, None).is_err());
+        // UEFI Shellの組み込みのコマンドは、配置するツールではないと伝える
+        let builtin = select(&names(&["memmap"]), None, Arch::X86_64, Some(&firmware)).unwrap_err().to_string();
+        assert!(builtin.contains("memmap") && builtin.contains("shell"), "{}", builtin);
+        assert!(select(&names(&["edit"]), None, Arch::X86_64, Some(&firmware)).is_err());
+        assert!(select(&[], None, Arch::X86_64, None).is_err());
+
+        std::fs::remove_dir_all(bundle.as_path()).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use std::path;
+use serde_json::json;
+use crate::message::msg;
+
+/// シムが結果を書くシリアルポート（COM3, I/Oポート0x3e8）のchardevのID
+const CHARDEV_ID: &str = "cargo-uefi-shim";
+
+/// シムを通して起動するとき、アプリケーションを置く場所
+pub const APP_PATH: &str = "/EFI/cargo-uefi/app.efi";
+
+/// 成果物のディレクトリに置く、シムがCOM3に書いた内容のログ
+pub const LOG_NAME: &str = "shim.log";
+
+/// シムがCOM3に書く行の接頭辞
+const PREFIX: &str = "CARGO-UEFI-SHIM ";
+
+/// shim.s を組み立てたx86_64の機械語。最後の4バイトは、続けて置くデバイスパスのノードの長さ
+const CODE: [u8; 704] = [
+    0x53, 0x56, 0x57, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57, 0x48, 0x83, 0xec, 0x60, 0x49,
+    0x89, 0xcc, 0x49, 0x89, 0xd5, 0x4c, 0x8b, 0x72, 0x60, 0x48, 0x8d, 0x35, 0x33, 0x02, 0x00, 0x00,
+    0xe8, 0xc4, 0x01, 0x00, 0x00, 0x4c, 0x89, 0xe1, 0x48, 0x8d, 0x15, 0x04, 0x02, 0x00, 0x00, 0x4c,
+    0x8d, 0x44, 0x24, 0x40, 0x41, 0xff, 0x96, 0x98, 0x00, 0x00, 0x00, 0x48, 0x85, 0xc0, 0x0f, 0x88,
+    0x72, 0x01, 0x00, 0x00, 0x48, 0x8b, 0x44, 0x24, 0x40, 0x48, 0x8b, 0x48, 0x18, 0x48, 0x8d, 0x15,
+    0xef, 0x01, 0x00, 0x00, 0x4c, 0x8d, 0x44, 0x24, 0x48, 0x41, 0xff, 0x96, 0x98, 0x00, 0x00, 0x00,
+    0x48, 0x85, 0xc0, 0x0f, 0x88, 0x4d, 0x01, 0x00, 0x00, 0x48, 0x8b, 0x74, 0x24, 0x48, 0x31, 0xdb,
+    0x80, 0x3c, 0x1e, 0x7f, 0x74, 0x0a, 0x0f, 0xb7, 0x44, 0x1e, 0x02, 0x48, 0x01, 0xc3, 0xeb, 0xf0,
+    0xb9, 0x02, 0x00, 0x00, 0x00, 0x8b, 0x15, 0x31, 0x02, 0x00, 0x00, 0x48, 0x01, 0xda, 0x4c, 0x8d,
+    0x44, 0x24, 0x50, 0x41, 0xff, 0x56, 0x40, 0x48, 0x85, 0xc0, 0x0f, 0x88, 0x16, 0x01, 0x00, 0x00,
+    0x48, 0x8b, 0x4c, 0x24, 0x50, 0x48, 0x8b, 0x54, 0x24, 0x48, 0x49, 0x89, 0xd8, 0x41, 0xff, 0x96,
+    0x60, 0x01, 0x00, 0x00, 0x48, 0x8b, 0x4c, 0x24, 0x50, 0x48, 0x01, 0xd9, 0x48, 0x8d, 0x15, 0xfd,
+    0x01, 0x00, 0x00, 0x44, 0x8b, 0x05, 0xf2, 0x01, 0x00, 0x00, 0x41, 0xff, 0x96, 0x60, 0x01, 0x00,
+    0x00, 0x31, 0xc9, 0x4c, 0x89, 0xe2, 0x4c, 0x8b, 0x44, 0x24, 0x50, 0x45, 0x31, 0xc9, 0x48, 0xc7,
+    0x44, 0x24, 0x20, 0x00, 0x00, 0x00, 0x00, 0x48, 0x8d, 0x44, 0x24, 0x58, 0x48, 0x89, 0x44, 0x24,
+    0x28, 0x41, 0xff, 0x96, 0xc8, 0x00, 0x00, 0x00, 0x48, 0x85, 0xc0, 0x0f, 0x88, 0xb5, 0x00, 0x00,
+    0x00, 0x48, 0xc7, 0x44, 0x24, 0x30, 0x00, 0x00, 0x00, 0x00, 0x48, 0xc7, 0x44, 0x24, 0x38, 0x00,
+    0x00, 0x00, 0x00, 0x48, 0x8b, 0x4c, 0x24, 0x58, 0x48, 0x8d, 0x54, 0x24, 0x30, 0x4c, 0x8d, 0x44,
+    0x24, 0x38, 0x41, 0xff, 0x96, 0xd0, 0x00, 0x00, 0x00, 0x49, 0x89, 0xc7, 0x48, 0x8d, 0x35, 0x54,
+    0x01, 0x00, 0x00, 0xe8, 0xb1, 0x00, 0x00, 0x00, 0x4c, 0x89, 0xf8, 0xe8, 0xb8, 0x00, 0x00, 0x00,
+    0xe8, 0xdf, 0x00, 0x00, 0x00, 0x48, 0x8b, 0x7c, 0x24, 0x38, 0x48, 0x85, 0xff, 0x74, 0x3f, 0x48,
+    0x8b, 0x5c, 0x24, 0x30, 0x48, 0xd1, 0xeb, 0x74, 0x35, 0x48, 0x8d, 0x35, 0x3f, 0x01, 0x00, 0x00,
+    0xe8, 0x84, 0x00, 0x00, 0x00, 0x0f, 0xb7, 0x07, 0x85, 0xc0, 0x74, 0x1d, 0x83, 0xf8, 0x20, 0x72,
+    0x05, 0x83, 0xf8, 0x7e, 0x76, 0x05, 0xb8, 0x3f, 0x00, 0x00, 0x00, 0xe8, 0x54, 0x00, 0x00, 0x00,
+    0x48, 0x83, 0xc7, 0x02, 0x48, 0xff, 0xcb, 0x75, 0xdc, 0xe8, 0x96, 0x00, 0x00, 0x00, 0x49, 0x8b,
+    0x45, 0x58, 0xb9, 0x02, 0x00, 0x00, 0x00, 0x4c, 0x89, 0xfa, 0x45, 0x31, 0xc0, 0x45, 0x31, 0xc9,
+    0xff, 0x50, 0x68, 0x4c, 0x89, 0xf8, 0x48, 0x83, 0xc4, 0x60, 0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d,
+    0x41, 0x5c, 0x5f, 0x5e, 0x5b, 0xc3, 0x49, 0x89, 0xc7, 0x48, 0x8d, 0x35, 0xab, 0x00, 0x00, 0x00,
+    0xe8, 0x24, 0x00, 0x00, 0x00, 0x4c, 0x89, 0xf8, 0xe8, 0x2b, 0x00, 0x00, 0x00, 0xe8, 0x52, 0x00,
+    0x00, 0x00, 0xeb, 0xba, 0x52, 0x50, 0xba, 0xed, 0x03, 0x00, 0x00, 0xec, 0xa8, 0x20, 0x74, 0xfb,
+    0x58, 0xba, 0xe8, 0x03, 0x00, 0x00, 0xee, 0x5a, 0xc3, 0x50, 0xac, 0x84, 0xc0, 0x74, 0x07, 0xe8,
+    0xe0, 0xff, 0xff, 0xff, 0xeb, 0xf4, 0x58, 0xc3, 0x53, 0x51, 0x48, 0x89, 0xc3, 0xb9, 0x10, 0x00,
+    0x00, 0x00, 0x48, 0xc1, 0xc3, 0x04, 0x89, 0xd8, 0x83, 0xe0, 0x0f, 0x83, 0xf8, 0x0a, 0x72, 0x05,
+    0x83, 0xc0, 0x57, 0xeb, 0x03, 0x83, 0xc0, 0x30, 0xe8, 0xb7, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x75,
+    0xe1, 0x59, 0x5b, 0xc3, 0xb0, 0x0d, 0xe8, 0xa9, 0xff, 0xff, 0xff, 0xb0, 0x0a, 0xe8, 0xa2, 0xff,
+    0xff, 0xff, 0xc3, 0xa1, 0x31, 0x1b, 0x5b, 0x62, 0x95, 0xd2, 0x11, 0x8e, 0x3f, 0x00, 0xa0, 0xc9,
+    0x69, 0x72, 0x3b, 0x91, 0x6e, 0x57, 0x09, 0x3f, 0x6d, 0xd2, 0x11, 0x8e, 0x39, 0x00, 0xa0, 0xc9,
+    0x69, 0x72, 0x3b, 0x43, 0x41, 0x52, 0x47, 0x4f, 0x2d, 0x55, 0x45, 0x46, 0x49, 0x2d, 0x53, 0x48,
+    0x49, 0x4d, 0x20, 0x73, 0x74, 0x61, 0x72, 0x74, 0x0d, 0x0a, 0x00, 0x43, 0x41, 0x52, 0x47, 0x4f,
+    0x2d, 0x55, 0x45, 0x46, 0x49, 0x2d, 0x53, 0x48, 0x49, 0x4d, 0x20, 0x6c, 0x6f, 0x61, 0x64, 0x2d,
+    0x65, 0x72, 0x72, 0x6f, 0x72, 0x3d, 0x00, 0x43, 0x41, 0x52, 0x47, 0x4f, 0x2d, 0x55, 0x45, 0x46,
+    0x49, 0x2d, 0x53, 0x48, 0x49, 0x4d, 0x20, 0x73, 0x74, 0x61, 0x74, 0x75, 0x73, 0x3d, 0x00, 0x43,
+    0x41, 0x52, 0x47, 0x4f, 0x2d, 0x55, 0x45, 0x46, 0x49, 0x2d, 0x53, 0x48, 0x49, 0x4d, 0x20, 0x65,
+    0x78, 0x69, 0x74, 0x2d, 0x64, 0x61, 0x74, 0x61, 0x3d, 0x00, 0x66, 0x90, 0x00, 0x00, 0x00, 0x00,
+];
+
+const SECTION_RVA: u32 = 0x1000;
+const FILE_ALIGNMENT: usize = 0x200;
+
+/// `app`（ESP上のパス）のアプリケーションをLoadImageとStartImageで起動し、戻り値と終了データをCOM3に書いてから電源を切るEFIアプリケーション。
+/// 終了デバイスに対応していないアプリケーションでも、返したEFI_STATUSで成否を判定できる
+pub fn image(app: &str) -> Vec<u8> {
+    // MEDIA_DEVICE_PATH / MEDIA_FILEPATH_DP のノードと、デバイスパスの終端
+    let name: Vec<u8> = app.replace('/', "\\").encode_utf16().chain([0]).flat_map(|c| c.to_le_bytes()).collect();
+    let mut nodes = vec![0x04, 0x04];
+    nodes.extend(((4 + name.len()) as u16).to_le_bytes());
+    nodes.extend(name);
+    nodes.extend([0x7f, 0xff, 0x04, 0x00]);
+
+    let mut text = CODE.to_vec();
+    let len = text.len();
+    text[len - 4..].copy_from_slice(&(nodes.len() as u32).to_le_bytes());
+    text.extend(nodes);
+    let virtual_size = text.len() as u32;
+    text.resize(text.len().next_multiple_of(FILE_ALIGNMENT), 0);
+
+    let mut data = vec![0u8; FILE_ALIGNMENT];
+    let put16 = |d: &mut Vec<u8>, o: usize, v: u16| d[o..o + 2].copy_from_slice(&v.to_le_bytes());
+    let put32 = |d: &mut Vec<u8>, o: usize, v: u32| d[o..o + 4].copy_from_slice(&v.to_le_bytes());
+    data[..2].copy_from_slice(b"MZ");
+    put32(&mut data, 0x3c, 0x40);
+    data[0x40..0x44].copy_from_slice(b"PE\0\0");
+    let coff = 0x44;
+    put16(&mut data, coff, 0x8664);
+    put16(&mut data, coff + 2, 1);
+    put16(&mut data, coff + 16, 240);
+    // 実行可能、2GB以上のアドレスを扱える。再配置の情報はないが、コードは位置に依存しない
+    put16(&mut data, coff + 18, 0x22);
+
+    let opt = coff + 20;
+    put16(&mut data, opt, 0x20b);
+    put32(&mut data, opt + 4, text.len() as u32);
+    put32(&mut data, opt + 16, SECTION_RVA);
+    put32(&mut data, opt + 20, SECTION_RVA);
+    data[opt + 24..opt + 32].copy_from_slice(&0x1_4000_0000u64.to_le_bytes());
+    put32(&mut data, opt + 32, SECTION_RVA);
+    put32(&mut data, opt + 36, FILE_ALIGNMENT as u32);
+    put32(&mut data, opt + 56, SECTION_RVA + (virtual_size).next_multiple_of(SECTION_RVA));
+    put32(&mut data, opt + 60, FILE_ALIGNMENT as u32);
+    put16(&mut data, opt + 68, 10);
+    put32(&mut data, opt + 108, 16);
+
+    let section = opt + 240;
+    data[section..section + 5].copy_from_slice(b".text");
+    put32(&mut data, section + 8, virtual_size);
+    put32(&mut data, section + 12, SECTION_RVA);
+    put32(&mut data, section + 16, text.len() as u32);
+    put32(&mut data, section + 20, FILE_ALIGNMENT as u32);
+    // コード、実行可能、読み取り可能。書き込みはしないので、メモリ保護の下でも読み込める
+    put32(&mut data, section + 36, 0x6000_0020);
+
+    data.extend(text);
+    data
+}
+
+/// シムが結果を書くシリアルポートを追加し、書いた内容を `log` に記録するQEMUの引数
+pub fn qemu_args(log: &path::Path) -> Vec<String> {
+    vec![
+        "-chardev".to_string(),
+        format!("file,id={},path={}", CHARDEV_ID, crate::fwcfg::escape(log.display().to_string().as_str())),
+        "-device".to_string(),
+        format!("isa-serial,chardev={},index=2", CHARDEV_ID),
+    ]
+}
+
+/// シムが報告した結果
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Outcome {
+    /// シムが起動した
+    pub started: bool,
+    /// アプリケーションを読み込めなかった場合のEFI_STATUS
+    pub load_error: Option<u64>,
+    /// アプリケーションが返したEFI_STATUS
+    pub status: Option<u64>,
+    /// アプリケーションがExitで渡した終了データ。表示できない文字は `?` になる
+    pub exit_data: Option<String>,
+}
+
+impl Outcome {
+    /// シムがCOM3に書いた内容を読む
+    pub fn parse(log: &str) -> Outcome {
+        let mut outcome = Outcome::default();
+        let hex = |value: &str| u64::from_str_radix(value.trim(), 16).ok();
+        for line in log.lines().filter_map(|l| l.trim_end_matches('\r').strip_prefix(PREFIX)) {
+            let (key, value) = line.split_once('=').unwrap_or((line, ""));
+            match key {
+                "start" => outcome.started = true,
+                "load-error" => outcome.load_error = hex(value),
+                "status" => outcome.status = hex(value),
+                "exit-data" => outcome.exit_data = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        outcome
+    }
+
+    /// シムのログを読む。ログがなければ、シムが起動しなかったものとする
+    pub fn read(log: &path::Path) -> Outcome {
+        let log = std::fs::read(log).unwrap_or_default();
+        Outcome::parse(String::from_utf8_lossy(&log).as_ref())
+    }
+
+    pub fn report(&self) {
+        let text = match (self.status, self.load_error) {
+            (Some(status), _) => msg!(ShimStatus, status_name(status), self.exit_data.as_deref().unwrap_or("-")),
+            (None, Some(error)) => msg!(ShimLoadFailed, status_name(error)),
+            (None, None) if self.started => msg!(ShimNoStatus),
+            (None, None) => msg!(ShimNotStarted),
+        };
+        match self.status {
+            Some(0) => crate::output::status(text),
+            _ => crate::output::warning(text),
+        }
+        crate::output::event("shim-result", json!({
+            "started": self.started,
+            "status": self.status,
+            "status-name": self.status.map(status_name),
+            "load-error": self.load_error,
+            "exit-data": self.exit_data,
+        }));
+    }
+}
+
+/// EFI_STATUSの名前（UEFI仕様 Appendix D）。名前のない値は16進数で示す
+pub fn status_name(status: u64) -> String {
+    const ERROR: u64 = 1 << 63;
+    let name = match status {
+        0 => "EFI_SUCCESS",
+        s if s & ERROR == 0 => match s {
+            1 => "EFI_WARN_UNKNOWN_GLYPH",
+            2 => "EFI_WARN_DELETE_FAILURE",
+            3 => "EFI_WARN_WRITE_FAILURE",
+            4 => "EFI_WARN_BUFFER_TOO_SMALL",
+            5 => "EFI_WARN_STALE_DATA",
+            6 => "EFI_WARN_FILE_SYSTEM",
+            7 => "EFI_WARN_RESET_REQUIRED",
+            _ => return format!("{:#x}", s),
+        },
+        s => match s & !ERROR {
+            1 => "EFI_LOAD_ERROR",
+            2 => "EFI_INVALID_PARAMETER",
+            3 => "EFI_UNSUPPORTED",
+            4 => "EFI_BAD_BUFFER_SIZE",
+            5 => "EFI_BUFFER_TOO_SMALL",
+            6 => "EFI_NOT_READY",
+            7 => "EFI_DEVICE_ERROR",
+            8 => "EFI_WRITE_PROTECTED",
+            9 => "EFI_OUT_OF_RESOURCES",
+            10 => "EFI_VOLUME_CORRUPTED",
+            11 => "EFI_VOLUME_FULL",
+            12 => "EFI_NO_MEDIA",
+            13 => "EFI_MEDIA_CHANGED",
+            14 => "EFI_NOT_FOUND",
+            15 => "EFI_ACCESS_DENIED",
+            16 => "EFI_NO_RESPONSE",
+            17 => "EFI_NO_MAPPING",
+            18 => "EFI_TIMEOUT",
+            19 => "EFI_NOT_STARTED",
+            20 => "EFI_ALREADY_STARTED",
+            21 => "EFI_ABORTED",
+            22 => "EFI_ICMP_ERROR",
+            23 => "EFI_TFTP_ERROR",
+            24 => "EFI_PROTOCOL_ERROR",
+            25 => "EFI_INCOMPATIBLE_VERSION",
+            26 => "EFI_SECURITY_VIOLATION",
+            27 => "EFI_CRC_ERROR",
+            28 => "EFI_END_OF_MEDIA",
+            31 => "EFI_END_OF_FILE",
+            32 => "EFI_INVALID_LANGUAGE",
+            33 => "EFI_COMPROMISED_DATA",
+            34 => "EFI_IP_ADDRESS_CONFLICT",
+            35 => "EFI_HTTP_ERROR",
+            _ => return format!("{:#x}", s),
+        },
+    };
+
+    name.to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use std::process::Command;
+    use sha2::{Digest, Sha256};
+    use crate::pe::PeFile;
+    use crate::shim::{image, status_name, Outcome, APP_PATH, CODE};
+
+    /// `CODE` を組み立てたときの shim.s の SHA-256（改行はLFとして計算する）。
+    /// shim.s を変更したら、組み立て直して `CODE` とこの値を更新する
+    const SOURCE_SHA256: &str = "b9b314f3931da8898ad3069dc2c501f0a1d7dff5b2798e1b8375d9c5cf54913f";
+
+    #[test]
+    fn code_matches_assembly_source() {
+        let source = path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src").join("shim.s");
+        let text = std::fs::read_to_string(source.as_path()).unwrap().replace("\r\n", "\n");
+        let digest: String = Sha256::digest(text.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(digest, SOURCE_SHA256, "shim.s has changed; reassemble it and update CODE and SOURCE_SHA256");
+
+        // x86_64のアセンブラがあれば、組み立て直した結果とも比べる
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-shim-{}", std::process::id()));
+        std::fs::create_dir_all(dir.as_path()).unwrap();
+        let (object, binary) = (dir.join("shim.o"), dir.join("shim.bin"));
+        let assembled = Command::new("as").arg("-o").arg(object.as_path()).arg(source.as_path()).output().is_ok_and(|o| o.status.success())
+            && Command::new("objcopy").args(["-O", "binary", "-j", ".text"]).arg(object.as_path()).arg(binary.as_path()).output().is_ok_and(|o| o.status.success());
+        if assembled {
+            assert_eq!(std::fs::read(binary.as_path()).unwrap(), CODE);
+        }
+        std::fs::remove_dir_all(dir.as_path()).unwrap();
+    }
+
+    #[test]
+    fn shim_image_embeds_app_path() {
+        let pe = PeFile::parse(image(APP_PATH)).unwrap();
+        assert_eq!((pe.machine_name(), pe.subsystem_name()), ("x86_64", "EFI application"));
+        assert_eq!(pe.entry_point, pe.sections[0].virtual_address);
+
+        let text = pe.section_data(".text").unwrap();
+        let nodes = &text[CODE.len()..];
+        let len = u32::from_le_bytes(text[CODE.len() - 4..CODE.len()].try_into().unwrap()) as usize;
+        let path: Vec<u16> = nodes[4..len - 6].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        assert_eq!(String::from_utf16(&path).unwrap(), "\\EFI\\cargo-uefi\\app.efi");
+        assert_eq!(&nodes[len - 4..len], [0x7f, 0xff, 0x04, 0x00]);
+    }
+
+    #[test]
+    fn parse_reported_outcome() {
+        let log = "CARGO-UEFI-SHIM start\r\nCARGO-UEFI-SHIM status=800000000000000e\r\nCARGO-UEFI-SHIM exit-data=config.toml missing\r\n";
+        let outcome = Outcome::parse(log);
+        assert_eq!(outcome.status, Some(0x8000_0000_0000_000e));
+        assert_eq!(outcome.exit_data.as_deref(), Some("config.toml missing"));
+        assert_eq!(status_name(outcome.status.unwrap()), "EFI_NOT_FOUND");
+        assert_eq!(Outcome::parse("CARGO-UEFI-SHIM start\r\nCARGO-UEFI-SHIM status=0000000000000000\r\n").status, Some(0));
+        assert_eq!(Outcome::parse(""), Outcome::default());
+    }
+}
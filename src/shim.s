@@ -0,0 +1,207 @@
+.intel_syntax noprefix
+.code64
+.text
+# efi_main(ImageHandle = rcx, SystemTable = rdx)。cargo-uefiの src/shim.rs に機械語として埋め込む
+# 組み立て方: as -o shim.o shim.s && objcopy -O binary -j .text shim.o shim.bin
+entry:
+    push rbx
+    push rsi
+    push rdi
+    push r12
+    push r13
+    push r14
+    push r15
+    sub rsp, 0x60
+    mov r12, rcx
+    mov r13, rdx
+    mov r14, [rdx + 0x60]
+    lea rsi, [rip + msg_start]
+    call puts
+    # 自身のEFI_LOADED_IMAGE_PROTOCOLから、読み込み元のデバイスを得る
+    mov rcx, r12
+    lea rdx, [rip + guid_loaded_image]
+    lea r8, [rsp + 0x40]
+    call [r14 + 0x98]
+    test rax, rax
+    js load_failed
+    mov rax, [rsp + 0x40]
+    mov rcx, [rax + 0x18]
+    lea rdx, [rip + guid_device_path]
+    lea r8, [rsp + 0x48]
+    call [r14 + 0x98]
+    test rax, rax
+    js load_failed
+    # デバイスのデバイスパスの長さ（終端のノードを除く）を数える
+    mov rsi, [rsp + 0x48]
+    xor ebx, ebx
+walk:
+    cmp byte ptr [rsi + rbx], 0x7f
+    je walked
+    movzx eax, word ptr [rsi + rbx + 2]
+    add rbx, rax
+    jmp walk
+walked:
+    mov ecx, 2
+    mov edx, [rip + file_node_len]
+    add rdx, rbx
+    lea r8, [rsp + 0x50]
+    call [r14 + 0x40]
+    test rax, rax
+    js load_failed
+    mov rcx, [rsp + 0x50]
+    mov rdx, [rsp + 0x48]
+    mov r8, rbx
+    call [r14 + 0x160]
+    mov rcx, [rsp + 0x50]
+    add rcx, rbx
+    lea rdx, [rip + file_node]
+    mov r8d, [rip + file_node_len]
+    call [r14 + 0x160]
+    # デバイスパスにアプリケーションのファイルパスのノードを繋げて、LoadImageとStartImageで起動する
+    xor ecx, ecx
+    mov rdx, r12
+    mov r8, [rsp + 0x50]
+    xor r9d, r9d
+    mov qword ptr [rsp + 0x20], 0
+    lea rax, [rsp + 0x58]
+    mov [rsp + 0x28], rax
+    call [r14 + 0xc8]
+    test rax, rax
+    js load_failed
+    mov qword ptr [rsp + 0x30], 0
+    mov qword ptr [rsp + 0x38], 0
+    mov rcx, [rsp + 0x58]
+    lea rdx, [rsp + 0x30]
+    lea r8, [rsp + 0x38]
+    call [r14 + 0xd0]
+    # 戻り値と終了データをCOM3に書き、電源を切る
+    mov r15, rax
+    lea rsi, [rip + msg_status]
+    call puts
+    mov rax, r15
+    call puthex
+    call newline
+    mov rdi, [rsp + 0x38]
+    test rdi, rdi
+    jz done
+    mov rbx, [rsp + 0x30]
+    shr rbx, 1
+    jz done
+    lea rsi, [rip + msg_exit_data]
+    call puts
+exit_data:
+    movzx eax, word ptr [rdi]
+    test eax, eax
+    jz exit_data_done
+    cmp eax, 0x20
+    jb unprintable
+    cmp eax, 0x7e
+    jbe printable
+unprintable:
+    mov eax, 0x3f
+printable:
+    call putc
+    add rdi, 2
+    dec rbx
+    jnz exit_data
+exit_data_done:
+    call newline
+done:
+    mov rax, [r13 + 0x58]
+    mov ecx, 2
+    mov rdx, r15
+    xor r8d, r8d
+    xor r9d, r9d
+    call [rax + 0x68]
+    mov rax, r15
+    add rsp, 0x60
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rdi
+    pop rsi
+    pop rbx
+    ret
+load_failed:
+    mov r15, rax
+    lea rsi, [rip + msg_load_error]
+    call puts
+    mov rax, r15
+    call puthex
+    call newline
+    jmp done
+
+# alの1文字をCOM3（I/Oポート0x3e8）に書く
+putc:
+    push rdx
+    push rax
+    mov edx, 0x3ed
+putc_wait:
+    in al, dx
+    test al, 0x20
+    jz putc_wait
+    pop rax
+    mov edx, 0x3e8
+    out dx, al
+    pop rdx
+    ret
+# rsiの指すNUL終端の文字列を書く
+puts:
+    push rax
+puts_next:
+    lodsb
+    test al, al
+    jz puts_done
+    call putc
+    jmp puts_next
+puts_done:
+    pop rax
+    ret
+# raxを16桁の16進数で書く
+puthex:
+    push rbx
+    push rcx
+    mov rbx, rax
+    mov ecx, 16
+puthex_next:
+    rol rbx, 4
+    mov eax, ebx
+    and eax, 0xf
+    cmp eax, 10
+    jb puthex_digit
+    add eax, 0x57
+    jmp puthex_put
+puthex_digit:
+    add eax, 0x30
+puthex_put:
+    call putc
+    dec ecx
+    jnz puthex_next
+    pop rcx
+    pop rbx
+    ret
+newline:
+    mov al, 0x0d
+    call putc
+    mov al, 0x0a
+    call putc
+    ret
+
+guid_loaded_image:
+    .byte 0xa1, 0x31, 0x1b, 0x5b, 0x62, 0x95, 0xd2, 0x11, 0x8e, 0x3f, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b
+guid_device_path:
+    .byte 0x91, 0x6e, 0x57, 0x09, 0x3f, 0x6d, 0xd2, 0x11, 0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b
+msg_start:
+    .asciz "CARGO-UEFI-SHIM start\r\n"
+msg_load_error:
+    .asciz "CARGO-UEFI-SHIM load-error="
+msg_status:
+    .asciz "CARGO-UEFI-SHIM status="
+msg_exit_data:
+    .asciz "CARGO-UEFI-SHIM exit-data="
+.balign 4
+# ホストがイメージを作るときに、ファイルパスのノードと終端のノードの長さを書き込み、その直後にノードを置く
+file_node_len:
+    .long 0
+file_node:
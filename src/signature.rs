@@ -0,0 +1,140 @@
+use std::path;
+use std::process::{Command, Stdio};
+use serde::Deserialize;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+
+/// 署名の形式
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureKind {
+    /// OpenPGPの分離署名。`gpg` で検証する
+    Gpg,
+    /// sigstoreの署名。`cosign verify-blob` で検証する
+    Sigstore,
+}
+
+/// ダウンロードしたファイルの署名を検証するための設定
+#[derive(Clone, Debug, Deserialize)]
+pub struct SignatureConfig {
+    pub kind: SignatureKind,
+    /// 署名の検証に使う公開鍵。相対パスはプロジェクトルートからのパスとみなす
+    pub key: path::PathBuf,
+    /// 署名ファイルの拡張子。ダウンロードしたファイルのURLにこの拡張子を付けたURLから署名を取得する
+    pub extension: Option<String>,
+}
+
+impl SignatureConfig {
+    pub fn extension(&self) -> &str {
+        match (&self.extension, self.kind) {
+            (Some(extension), _) => extension.trim_start_matches('.'),
+            (None, SignatureKind::Gpg) => "asc",
+            (None, SignatureKind::Sigstore) => "sig",
+        }
+    }
+
+    /// `url` で配布されているファイルの署名のURL
+    pub fn signature_url(&self, url: &str) -> String {
+        format!("{}.{}", url, self.extension())
+    }
+
+    /// 公開鍵の相対パスを `root` からのパスにする
+    pub fn resolve_key(&mut self, root: &path::Path) {
+        self.key = root.join(self.key.as_path());
+    }
+}
+
+/// `file` の分離署名 `signature` を、設定された公開鍵で検証する
+pub fn verify(config: &SignatureConfig, file: &path::Path, signature: &path::Path) -> Result<(), Error> {
+    if !config.key.is_file() {
+        return Err(Error::new(
+            ErrorKind::SignatureInvalid,
+            msg!(SigningKeyMissing, config.key.display())
+        ));
+    }
+
+    let verified = match config.kind {
+        SignatureKind::Gpg => verify_gpg(config.key.as_path(), file, signature)?,
+        SignatureKind::Sigstore => run_verifier(Command::new("cosign")
+            .arg("verify-blob")
+            .arg("--key").arg(config.key.as_path())
+            .arg("--signature").arg(signature)
+            .arg(file))?,
+    };
+
+    if verified {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::SignatureInvalid,
+            msg!(SignatureInvalid, file.display(), config.key.display())
+        ))
+    }
+}
+
+/// 指定した鍵だけを含む一時的なキーリングで検証する。
+/// 利用者のキーリングにある他の鍵で署名されたものを受け入れないようにする。
+fn verify_gpg(key: &path::Path, file: &path::Path, signature: &path::Path) -> Result<bool, Error> {
+    let home = signature.with_extension(format!("gnupg-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(home.as_path());
+    let _home = crate::janitor::register_path(home.as_path());
+    std::fs::create_dir_all(home.as_path())
+        .map_err(|e| Error::new(ErrorKind::SignatureInvalid, msg!(CreateDirFailed, home.display(), e)))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = std::fs::set_permissions(home.as_path(), std::fs::Permissions::from_mode(0o700));
+    }
+
+    let gpg = || {
+        let mut command = Command::new("gpg");
+        command.arg("--batch").arg("--quiet").arg("--homedir").arg(home.as_path());
+        command
+    };
+
+    let result = run_verifier(gpg().arg("--import").arg(key))
+        .and_then(|imported| match imported {
+            true => run_verifier(gpg().arg("--verify").arg(signature).arg(file)),
+            false => Err(Error::new(ErrorKind::SignatureInvalid, msg!(SigningKeyImportFailed, key.display()))),
+        });
+
+    result
+}
+
+/// 検証コマンドを実行し、成功したかを返す
+pub fn run_verifier(command: &mut Command) -> Result<bool, Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(crate::output::child_stderr())
+        .stderr(crate::output::child_stderr())
+        .status()
+        .map_err(|e| Error::new(ErrorKind::SignatureInvalid, msg!(RunFailed, program, e)))?;
+
+    Ok(status.success())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::error::ErrorKind;
+    use crate::signature::{verify, SignatureConfig, SignatureKind};
+
+    #[test]
+    fn signature_url_and_key() {
+        let mut gpg = SignatureConfig { kind: SignatureKind::Gpg, key: path::PathBuf::from("keys/ovmf.asc"), extension: None };
+        assert_eq!(gpg.signature_url("https://a/fw.tar.xz"), "https://a/fw.tar.xz.asc");
+        gpg.resolve_key(path::Path::new("/project"));
+        assert_eq!(gpg.key, path::Path::new("/project/keys/ovmf.asc"));
+
+        let sigstore = SignatureConfig { kind: SignatureKind::Sigstore, key: path::PathBuf::from("/k.pub"), extension: Some(".bundle".to_string()) };
+        assert_eq!(sigstore.signature_url("https://a/fw.tar.xz"), "https://a/fw.tar.xz.bundle");
+    }
+
+    #[test]
+    fn missing_key_is_refused() {
+        let config = SignatureConfig { kind: SignatureKind::Gpg, key: path::PathBuf::from("/nonexistent/key.asc"), extension: None };
+        let err = verify(&config, path::Path::new("/nonexistent/fw"), path::Path::new("/nonexistent/fw.asc")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SignatureInvalid);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::error::{Error, ErrorKind};
+
+/// `512M` や `64G` のような単位付きのサイズをバイト数に変換する。
+/// 単位は1024を基数とし、省略した場合はバイトとして扱う。
+pub fn parse_size(s: &str) -> Result<u64, Error> {
+    let s = s.trim();
+    let digits_end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits_end);
+
+    let invalid = || Error::new(ErrorKind::InvalidArgument, crate::message::msg!(InvalidSize, s));
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let shift = match unit.trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(invalid()),
+    };
+
+    number.checked_mul(1 << shift).ok_or_else(invalid)
+}
+
+/// バイト数を `1.5 MiB` のような読みやすい形にする
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod test {
+    use crate::size::{format_size, parse_size};
+
+    #[test]
+    fn parse_size_with_units() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("512M").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_size("64G").unwrap(), 64 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("2GiB").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("1kb").unwrap(), 1024);
+    }
+
+    #[test]
+    fn format_size_with_units() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(8 * 1024 * 1024), "8.0 MiB");
+    }
+
+    #[test]
+    fn parse_size_rejects_garbage() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("M").is_err());
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use crate::buildinfo::BuildInfo;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::target::Arch;
+
+/// ESP上へのUEFIアプリケーションの配置方法
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, ValueEnum)]
+pub enum Layout {
+    /// アプリケーションを直接 \EFI\BOOT\BOOTX64.EFI（x86_64の場合）として配置する
+    #[default]
+    Direct,
+    /// systemd-bootをローダーとして配置し、アプリケーションをエントリとして登録する
+    SystemdBoot,
+}
+
+const SYSTEMD_BOOT_SEARCH_PATHS: &[&str] = &[
+    "/usr/lib/systemd/boot/efi",
+    "/lib/systemd/boot/efi",
+];
+
+/// ESP上のファイルの内容の出どころ
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Source {
+    /// ホストのファイル
+    File(path::PathBuf),
+    /// cargo-uefiが生成する内容
+    Generated(String),
+    /// ESP上の指定したパスのアプリケーションを起動する、検証用のシム
+    Shim(String),
+}
+
+/// 配置するときに内容へ加える変更
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Transform {
+    /// ビルド情報を `.build` セクションとして埋め込む
+    StampBuildInfo(BuildInfo),
+}
+
+impl Transform {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transform::StampBuildInfo(_) => "stamp-build-info",
+        }
+    }
+
+    fn apply(&self, data: Vec<u8>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self {
+            Transform::StampBuildInfo(info) => crate::buildinfo::stamp(data, info),
+        }
+    }
+}
+
+/// ESP上の1つのファイルの配置
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Step {
+    /// ESP上の配置先（例: `/EFI/BOOT/BOOTX64.EFI`）
+    pub path: String,
+    pub source: Source,
+    pub transform: Option<Transform>,
+}
+
+impl Step {
+    fn file(path: String, source: &path::Path) -> Step {
+        Step { path, source: Source::File(source.to_path_buf()), transform: None }
+    }
+
+    fn generated(path: String, text: String) -> Step {
+        Step { path, source: Source::Generated(text), transform: None }
+    }
+
+    /// ESPのルートからの相対パス
+    fn relative(&self) -> path::PathBuf {
+        self.path.trim_start_matches('/').split('/').collect()
+    }
+
+    /// 配置するファイルの内容
+    fn content(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let data = match &self.source {
+            Source::File(source) => {
+                check_source(source)?;
+                std::fs::read(source)?
+            }
+            Source::Generated(text) => text.clone().into_bytes(),
+            Source::Shim(app) => crate::shim::image(app.as_str()),
+        };
+
+        match &self.transform {
+            Some(transform) => transform.apply(data),
+            None => Ok(data),
+        }
+    }
+}
+
+fn check_source(source: &path::Path) -> Result<(), io::Error> {
+    match source.is_file() {
+        true => Ok(()),
+        false => Err(io::Error::new(io::ErrorKind::NotFound, msg!(NotFound, source.display()))),
+    }
+}
+
+/// ESPに配置するファイルの一覧。ファイルに触れる前に全体を組み立て、`apply` でまとめて配置する。
+/// 同じ配置先のファイルは、後から加えたものが優先される
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+    /// 指定された配置方法でアプリケーションを配置する計画を立てる。`transform` はアプリケーションの内容に加える
+    pub fn new(layout: Layout, arch: Arch, app_path: &path::Path, app_name: &str, loader: Option<&path::Path>, transform: Option<Transform>) -> Result<Plan, io::Error> {
+        let boot_file = format!("/EFI/BOOT/{}", arch.boot_file_name());
+        let mut steps = match layout {
+            Layout::Direct => vec![Step::file(boot_file, app_path)],
+            Layout::SystemdBoot => {
+                let loader = match loader {
+                    Some(loader) => loader.to_path_buf(),
+                    None => find_systemd_boot(arch)?,
+                };
+
+                // bootctl installと同じく、ローダーは \EFI\systemd と \EFI\BOOT の両方に配置する
+                vec![
+                    Step::file(format!("/EFI/systemd/{}", arch.systemd_boot_name()), loader.as_path()),
+                    Step::file(boot_file, loader.as_path()),
+                    Step::file(format!("/EFI/{}/{}.efi", app_name, app_name), app_path),
+                    Step::generated(format!("/loader/entries/{}.conf", app_name), loader_entry(app_name)),
+                    Step::generated("/loader/loader.conf".to_string(), loader_conf(app_name)),
+                ]
+            }
+        };
+        if let Some(app) = steps.iter_mut().find(|s| s.source == Source::File(app_path.to_path_buf())) {
+            app.transform = transform;
+        }
+
+        Ok(Plan { steps })
+    }
+
+    /// アプリケーションを \EFI\cargo-uefi\app.efi に移し、元の場所には検証用のシムを置く
+    pub fn interpose_shim(&mut self, arch: Arch, app_path: &path::Path) -> Result<(), Error> {
+        if arch != Arch::X86_64 {
+            return Err(Error::new(ErrorKind::InvalidArgument, msg!(ShimUnsupportedArch, arch.rust_target())));
+        }
+
+        let app = self.steps.iter_mut().find(|s| s.source == Source::File(app_path.to_path_buf()))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidArgument, msg!(ShimAppNotStaged, app_path.display())))?;
+        let boot_path = std::mem::replace(&mut app.path, crate::shim::APP_PATH.to_string());
+        self.steps.push(Step { path: boot_path, source: Source::Shim(crate::shim::APP_PATH.to_string()), transform: None });
+
+        Ok(())
+    }
+
+    /// 追加のファイルを配置する
+    pub fn add_files(&mut self, files: &[ExtraFile]) -> Result<(), Error> {
+        for file in files {
+            file.esp_path()?;
+            self.steps.push(Step::file(file.path.clone(), file.source.as_path()));
+        }
+
+        Ok(())
+    }
+
+    /// UEFI Shellを \EFI\tools\Shell.efi に配置する
+    pub fn add_shell(&mut self, shell: &path::Path) {
+        self.steps.push(Step::file("/EFI/tools/Shell.efi".to_string(), shell));
+    }
+
+    /// 実際に配置される項目。同じ配置先の項目は最後のものだけが残る
+    pub fn effective(&self) -> Vec<&Step> {
+        self.steps.iter().enumerate()
+            .filter(|(idx, step)| !self.steps[idx + 1..].iter().any(|s| s.path == step.path))
+            .map(|(_, step)| step)
+            .collect()
+    }
+
+    /// アプリケーションに埋め込むビルド情報
+    pub fn build_info(&self) -> Option<&BuildInfo> {
+        self.steps.iter().find_map(|s| s.transform.as_ref().map(|Transform::StampBuildInfo(info)| info))
+    }
+
+    /// ESPのルートディレクトリに配置する
+    pub fn apply(&self, esp_root: &path::Path) -> Result<(), Box<dyn std::error::Error>> {
+        for step in self.effective() {
+            let dest = esp_root.join(step.relative());
+            std::fs::create_dir_all(dest.parent().expect("ESP path has a parent"))?;
+            match (&step.source, &step.transform) {
+                (Source::File(source), None) => {
+                    check_source(source)?;
+                    crate::copy::copy_file(source, dest.as_path())?;
+                }
+                _ => std::fs::write(dest, step.content()?)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 配置済みのESPから、配置した内容の記録を作る
+    pub fn manifest(&self, esp_root: &path::Path) -> Result<Manifest, io::Error> {
+        let files = self.effective().into_iter()
+            .map(|step| Ok((step.path.clone(), crate::fetch::sha256_file(esp_root.join(step.relative()).as_path())?)))
+            .collect::<Result<_, io::Error>>()?;
+
+        Ok(Manifest { files })
+    }
+
+    /// 各項目が `previous` の配置からどう変わるかと、配置されなくなるファイルを返す
+    pub fn diff(&self, previous: &Manifest) -> Result<Diff<'_>, Box<dyn std::error::Error>> {
+        let steps = self.effective();
+        let mut changes = Vec::new();
+        for step in steps.iter() {
+            let change = match previous.files.get(&step.path) {
+                None => Change::Added,
+                Some(hash) if *hash == sha256(&step.content()?) => Change::Unchanged,
+                Some(_) => Change::Changed,
+            };
+            changes.push((*step, change));
+        }
+        let removed = previous.files.keys()
+            .filter(|path| !steps.iter().any(|s| s.path == **path))
+            .cloned()
+            .collect();
+
+        Ok(Diff { changes, removed })
+    }
+}
+
+/// 前回の配置との差分
+pub struct Diff<'a> {
+    pub changes: Vec<(&'a Step, Change)>,
+    /// 前回は配置したが、今回は配置しないESP上のパス
+    pub removed: Vec<String>,
+}
+
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 前回の配置から変わるかどうか
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Change {
+    Added,
+    Changed,
+    Unchanged,
+}
+
+impl Change {
+    fn name(&self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Changed => "changed",
+            Change::Unchanged => "unchanged",
+        }
+    }
+
+    fn marker(&self) -> char {
+        match self {
+            Change::Added => '+',
+            Change::Changed => '~',
+            Change::Unchanged => '=',
+        }
+    }
+}
+
+/// 配置したESPの内容の記録。ESP上のパスと、そのファイルの SHA-256
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// 記録がなければ `None` を返す
+    pub fn load(path: &path::Path) -> Result<Option<Manifest>, Box<dyn std::error::Error>> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(Some(serde_json::from_str(text.as_str())?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    pub fn save(&self, path: &path::Path) -> Result<(), io::Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// 配置計画と、`previous` に記録された前回の配置から変わる内容を表示する
+pub fn explain(plan: &Plan, app_name: &str, previous: Option<&Manifest>) -> Result<(), Box<dyn std::error::Error>> {
+    let Diff { changes, removed } = plan.diff(previous.unwrap_or(&Manifest::default()))?;
+    match previous {
+        Some(_) => crate::output::status(msg!(StagingPlan, app_name)),
+        None => crate::output::status(msg!(StagingPlanNoPrevious, app_name)),
+    }
+    for (step, change) in changes.iter() {
+        let source = match &step.source {
+            Source::File(source) => source.display().to_string(),
+            Source::Generated(_) => msg!(StagingGenerated),
+            Source::Shim(app) => msg!(StagingShim, app),
+        };
+        let transform = step.transform.as_ref().map(|t| format!(" [{}]", t.name())).unwrap_or_default();
+        crate::output::status(format!("  {} {} <- {}{}", change.marker(), step.path, source, transform));
+    }
+    for path in removed.iter() {
+        crate::output::status(format!("  - {}", path));
+    }
+    let count = |change: Change| changes.iter().filter(|(_, c)| *c == change).count();
+    crate::output::status(msg!(StagingSummary, count(Change::Added), count(Change::Changed), count(Change::Unchanged), removed.len()));
+
+    let files: Vec<_> = changes.iter().map(|(step, change)| json!({
+        "path": step.path,
+        "source": match &step.source {
+            Source::File(source) => Some(source),
+            Source::Generated(_) | Source::Shim(_) => None,
+        },
+        "transform": step.transform.as_ref().map(|t| t.name()),
+        "change": change.name(),
+    })).collect();
+    crate::output::event("staging-plan", json!({
+        "app": app_name,
+        "previous": previous.is_some(),
+        "files": files,
+        "removed": removed,
+    }));
+
+    Ok(())
+}
+
+/// アプリケーションと一緒にESPへ配置するファイル
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+pub struct ExtraFile {
+    /// 配置するファイル。設定ファイル中の相対パスはプロジェクトルートを基準にする
+    pub source: path::PathBuf,
+    /// ESP上の配置先（例: `/EFI/myapp/data.bin`）
+    pub path: String,
+}
+
+impl ExtraFile {
+    /// 相対パスで指定されたファイルを `base` からのパスとみなす
+    pub fn resolve(self, base: &path::Path) -> ExtraFile {
+        ExtraFile { source: base.join(self.source), ..self }
+    }
+
+    /// ESP上の配置先を、ESPのルートからの相対パスにする
+    pub fn esp_path(&self) -> Result<path::PathBuf, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidArgument, msg!(EspPathInvalid, self.path));
+        let relative = self.path.strip_prefix('/').ok_or_else(invalid)?;
+        let components: Vec<_> = relative.split('/').collect();
+        if components.iter().any(|c| c.is_empty() || *c == "." || *c == ".." || c.contains('\\')) {
+            return Err(invalid());
+        }
+
+        Ok(components.iter().collect())
+    }
+}
+
+/// `HOST_PATH:/ESP/PATH` の形式の引数を読み取る。ホスト側のパスには `:` を含められるよう、最後の `:/` で区切る
+pub fn parse_extra_file(s: &str) -> Result<ExtraFile, Error> {
+    let idx = s.rfind(":/").filter(|idx| *idx > 0)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidArgument, msg!(ExtraFileInvalid, s)))?;
+    let file = ExtraFile { source: path::PathBuf::from(&s[..idx]), path: s[idx + 1..].to_string() };
+    file.esp_path()?;
+
+    Ok(file)
+}
+
+fn find_systemd_boot(arch: Arch) -> Result<path::PathBuf, io::Error> {
+    SYSTEMD_BOOT_SEARCH_PATHS.iter()
+        .map(|dir| path::Path::new(dir).join(arch.systemd_boot_name()))
+        .find(|path| path.is_file())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, crate::message::msg!(NotFound, arch.systemd_boot_name())))
+}
+
+fn loader_entry(app_name: &str) -> String {
+    format!("title {}\nefi /EFI/{}/{}.efi\n", app_name, app_name, app_name)
+}
+
+fn loader_conf(app_name: &str) -> String {
+    format!("default {}.conf\ntimeout 0\n", app_name)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::staging::{loader_conf, loader_entry, parse_extra_file, Change, Diff, Layout, Plan, Source};
+    use crate::target::Arch;
+
+    #[test]
+    fn systemd_boot_entry_points_at_app() {
+        let entry = loader_entry("hoge");
+        assert_eq!(entry, "title hoge\nefi /EFI/hoge/hoge.efi\n");
+    }
+
+    #[test]
+    fn systemd_boot_loader_selects_entry() {
+        let conf = loader_conf("hoge");
+        assert!(conf.lines().any(|l| l == "default hoge.conf"));
+        assert!(conf.lines().any(|l| l == "timeout 0"));
+    }
+
+    #[test]
+    fn parse_extra_file_at_last_separator() {
+        let file = parse_extra_file("C:/assets/data.bin:/EFI/myapp/data.bin").unwrap();
+        assert_eq!(file.source, path::Path::new("C:/assets/data.bin"));
+        assert_eq!(file.path, "/EFI/myapp/data.bin");
+        assert_eq!(file.esp_path().unwrap(), path::Path::new("EFI/myapp/data.bin"));
+
+        for invalid in ["data.bin", ":/EFI/data.bin", "data.bin:/EFI/../data.bin", "data.bin:/EFI//data.bin", "data.bin:/"] {
+            assert!(parse_extra_file(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn plan_diff_against_staged_tree() {
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-plan-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(dir.as_path());
+        std::fs::create_dir_all(dir.as_path()).unwrap();
+        let (app, data, esp) = (dir.join("hoge.efi"), dir.join("data.bin"), dir.join("esp"));
+        std::fs::write(app.as_path(), b"app").unwrap();
+        std::fs::write(data.as_path(), b"data").unwrap();
+
+        let mut plan = Plan::new(Layout::SystemdBoot, Arch::X86_64, app.as_path(), "hoge", Some(app.as_path()), None).unwrap();
+        plan.add_files(&[parse_extra_file(&format!("{}:/loader/loader.conf", data.display())).unwrap()]).unwrap();
+        // 追加のファイルが生成したloader.confを置き換える
+        assert_eq!(plan.effective().len(), plan.steps.len() - 1);
+        plan.apply(esp.as_path()).unwrap();
+        assert_eq!(std::fs::read(esp.join("loader").join("loader.conf")).unwrap(), b"data");
+        let manifest = plan.manifest(esp.as_path()).unwrap();
+
+        std::fs::write(data.as_path(), b"other").unwrap();
+        let direct = Plan::new(Layout::Direct, Arch::X86_64, app.as_path(), "hoge", None, None).unwrap();
+        let Diff { changes, removed } = direct.diff(&manifest).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].1, Change::Unchanged);
+        assert!(removed.contains(&"/loader/loader.conf".to_string()) && removed.len() == 4);
+        let changes = plan.diff(&manifest).unwrap().changes;
+        assert!(changes.iter().all(|(step, change)| (*change == Change::Changed) == (step.path == "/loader/loader.conf")));
+
+        // シムがアプリケーションの代わりに起動される
+        let mut shimmed = direct.clone();
+        shimmed.interpose_shim(Arch::X86_64, app.as_path()).unwrap();
+        let paths: Vec<_> = shimmed.effective().iter().map(|s| (s.path.as_str(), s.source.clone())).collect();
+        assert_eq!(paths, [
+            ("/EFI/cargo-uefi/app.efi", Source::File(app.clone())),
+            ("/EFI/BOOT/BOOTX64.EFI", Source::Shim("/EFI/cargo-uefi/app.efi".to_string())),
+        ]);
+        assert!(direct.clone().interpose_shim(Arch::Aarch64, app.as_path()).is_err());
+
+        std::fs::remove_dir_all(dir.as_path()).unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use std::io;
+use std::path;
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::backend::{Serial, VmBackend, VmConfig};
+use crate::launch::StderrCapture;
+use crate::message::msg;
+use crate::{janitor, output};
+
+/// タイムアウトでSIGTERMを送ってから、強制終了するまでに待つ時間
+const TERMINATE_GRACE: Duration = Duration::from_secs(3);
+
+/// 監視モードで、ファイルの変更によりVMを起動し直すよう求められたか
+static RESTART: AtomicBool = AtomicBool::new(false);
+
+/// チェックポイントの期限を過ぎたなどで、実行中のVMを止めるよう求められたか
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// VMを実行し終了を待つ。`timeout` を過ぎた場合はVMMを終了させて `None` を返す。
+/// VMM自身の標準エラー出力は `stderr_log` に記録し、よく知られたエラーで終了した場合はその対処を示すエラーを返す
+pub fn run_vm(
+    backend: &dyn VmBackend,
+    vm: &VmConfig,
+    timeout: Option<Duration>,
+    stderr_log: Option<&path::Path>,
+) -> Result<Option<ExitStatus>, io::Error> {
+    STOP.store(false, Ordering::SeqCst);
+    let mut process = backend.spawn(vm, Serial::Terminal)?;
+    // cargo-uefiが途中で終了してもVMMが残らないようにする
+    let registration = janitor::register_process(&process, backend.program());
+
+    let forwarder = process.stdout.take().map(output::forward_guest);
+    let capture = process.stderr.take().map(|r| StderrCapture::start(r, stderr_log));
+    let status = wait(&mut process, timeout);
+    let stopped = STOP.swap(false, Ordering::SeqCst);
+    if status.is_ok() {
+        registration.release();
+    }
+    if let Some(forwarder) = forwarder {
+        let _ = forwarder.join();
+    }
+    let stderr = capture.map(|c| c.finish()).unwrap_or_default();
+
+    if let Ok(Some(exit)) = &status {
+        crate::limits::report_exit(exit);
+        let translated = backend.explain_failure(&stderr, vm).filter(|_| !exit.success());
+        if let Some(translated) = translated {
+            let log = stderr_log.map(|log| msg!(QemuStderrSaved, log.display())).unwrap_or_default();
+            return Err(io::Error::other(msg!(QemuFailed, translated, log)));
+        }
+    }
+
+    if let (Ok(None), Some(timeout), false, false) = (&status, timeout, RESTART.load(Ordering::SeqCst), stopped) {
+        output::warning(msg!(QemuTimedOut, timeout.as_secs()));
+        output::event("qemu-timed-out", serde_json::json!({ "timeout": timeout.as_secs() }));
+    }
+    status
+}
+
+/// プロセスの終了を待つ。`timeout` を過ぎた場合や、監視モードで起動し直すよう、あるいは止めるよう求められた場合は終了させて `None` を返す
+pub fn wait(process: &mut Child, timeout: Option<Duration>) -> Result<Option<ExitStatus>, io::Error> {
+    match wait_until(process, timeout.map(|timeout| Instant::now() + timeout), true)? {
+        Some(status) => Ok(Some(status)),
+        None => {
+            terminate(process)?;
+            Ok(None)
+        }
+    }
+}
+
+fn wait_until(process: &mut Child, deadline: Option<Instant>, restartable: bool) -> Result<Option<ExitStatus>, io::Error> {
+    loop {
+        if let Some(status) = process.try_wait()? {
+            return Ok(Some(status));
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) || (restartable && (RESTART.load(Ordering::SeqCst) || STOP.load(Ordering::SeqCst))) {
+            return Ok(None);
+        }
+
+        let interval = Duration::from_millis(100);
+        thread::sleep(deadline.map_or(interval, |deadline| interval.min(deadline.saturating_duration_since(Instant::now()))));
+    }
+}
+
+/// 実行中のVMを終了させ、監視モードで起動し直せるようにする
+pub fn request_restart() {
+    RESTART.store(true, Ordering::SeqCst);
+}
+
+/// 起動し直すよう求められていたかを返し、要求を取り消す
+pub fn take_restart() -> bool {
+    RESTART.swap(false, Ordering::SeqCst)
+}
+
+/// 実行中のVMを終了させる。結果はタイムアウトと同じく扱う
+pub fn request_stop() {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+/// まずSIGTERMでディスクイメージなどを閉じる機会を与え、猶予の間に終了しなければ強制終了する
+fn terminate(process: &mut Child) -> Result<(), io::Error> {
+    if request_termination(process) && wait_until(process, Some(Instant::now() + TERMINATE_GRACE), false)?.is_some() {
+        return Ok(());
+    }
+
+    process.kill()?;
+    process.wait()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn request_termination(process: &Child) -> bool {
+    unsafe { libc::kill(process.id() as libc::pid_t, libc::SIGTERM) == 0 }
+}
+
+#[cfg(not(unix))]
+fn request_termination(_process: &Child) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use crate::supervise::wait;
+
+    #[cfg(unix)]
+    #[test]
+    fn timeout_terminates_process() {
+        let mut process = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let started = Instant::now();
+        assert_eq!(wait(&mut process, Some(Duration::from_millis(200))).unwrap(), None);
+        // SIGTERMで終了するので、猶予を待たずに戻る
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(process.try_wait().unwrap().is_some());
+
+        let mut process = std::process::Command::new("true").spawn().unwrap();
+        assert!(wait(&mut process, Some(Duration::from_secs(5))).unwrap().unwrap().success());
+    }
+}
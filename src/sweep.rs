@@ -0,0 +1,120 @@
+use std::io;
+use std::process::ExitStatus;
+use serde_json::json;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+use crate::size::{format_size, parse_size};
+
+const MIB: u64 = 1024 * 1024;
+
+/// `--memory-sweep` で試すメモリサイズの範囲
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MemorySweep {
+    pub min: u64,
+    pub max: u64,
+    pub step: u64,
+}
+
+impl MemorySweep {
+    /// 小さい順に試すメモリサイズ
+    pub fn sizes(&self) -> Vec<u64> {
+        (self.min..=self.max).step_by(self.step as usize).collect()
+    }
+}
+
+/// `64M..1G step 64M` の形式の引数を読み取る。`step` を省略した場合は下限と同じ刻みにする。
+/// QEMUの `-m` はMiB単位で指定するため、いずれもMiBの倍数でなければならない
+pub fn parse_sweep(s: &str) -> Result<MemorySweep, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidArgument, msg!(MemorySweepInvalid, s));
+
+    let (range, step) = match s.split_once("step") {
+        Some((range, step)) => (range, Some(step)),
+        None => (s, None),
+    };
+    let (min, max) = range.split_once("..").ok_or_else(invalid)?;
+    let (min, max) = (parse_size(min).map_err(|_| invalid())?, parse_size(max).map_err(|_| invalid())?);
+    let step = match step {
+        Some(step) => parse_size(step).map_err(|_| invalid())?,
+        None => min,
+    };
+
+    if [min, max, step].iter().any(|v| *v == 0 || v % MIB != 0) || min > max {
+        return Err(invalid());
+    }
+
+    Ok(MemorySweep { min, max, step })
+}
+
+/// 1つのメモリサイズでの起動の結果
+pub struct Attempt {
+    pub memory: u64,
+    pub status: Option<ExitStatus>,
+    pub exit_code: i32,
+}
+
+/// 小さいメモリサイズから順に起動し、初めて成功したサイズで止める。
+/// `run` には追加するQEMUの引数が渡され、`exit_code` で終了状態を終了コードに変換する。成功したサイズの結果が最後の要素になる
+pub fn sweep(
+    sweep: &MemorySweep,
+    exit_code: impl Fn(Option<ExitStatus>) -> i32,
+    mut run: impl FnMut(Vec<String>) -> Result<Option<ExitStatus>, io::Error>,
+) -> Result<Vec<Attempt>, io::Error> {
+    let mut attempts = Vec::new();
+    for memory in sweep.sizes() {
+        let status = run(vec!["-m".to_string(), format!("{}M", memory / MIB)])?;
+        let exit_code = exit_code(status);
+        let outcome = match status {
+            _ if exit_code == 0 => msg!(MemorySweepBooted),
+            Some(_) => msg!(MemorySweepFailed, exit_code),
+            None => msg!(RunTimedOut),
+        };
+        crate::output::status(format!("{:>10}: {}", format_size(memory), outcome));
+        attempts.push(Attempt { memory, status, exit_code });
+        if exit_code == 0 {
+            break;
+        }
+    }
+
+    Ok(attempts)
+}
+
+/// 起動できた最小のメモリサイズを報告する。どのサイズでも起動できなければ `None` を返す
+pub fn report(attempts: &[Attempt]) -> Option<u64> {
+    let minimum = attempts.last().filter(|a| a.exit_code == 0).map(|a| a.memory);
+    match minimum {
+        Some(memory) => crate::output::status(msg!(MemorySweepMinimum, format_size(memory))),
+        None => crate::output::status(msg!(MemorySweepNone)),
+    }
+
+    let results: Vec<_> = attempts.iter().map(|a| json!({
+        "memory": a.memory,
+        "qemu-exit-code": a.status.and_then(|s| s.code()),
+        "timed-out": a.status.is_none(),
+        "exit-code": a.exit_code,
+    })).collect();
+    crate::output::event("memory-sweep", json!({ "minimum": minimum, "attempts": results }));
+
+    minimum
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sweep::{parse_sweep, MemorySweep};
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn parse_sweep_range() {
+        let sweep = parse_sweep("64M..1G step 64M").unwrap();
+        assert_eq!(sweep, MemorySweep { min: 64 * MIB, max: 1024 * MIB, step: 64 * MIB });
+        assert_eq!(sweep.sizes().len(), 16);
+        assert_eq!(sweep.sizes()[1], 128 * MIB);
+
+        let sweep = parse_sweep("96M..200M").unwrap();
+        assert_eq!(sweep.sizes(), [96 * MIB, 192 * MIB]);
+
+        for invalid in ["64M", "1G..64M", "64M..1G step 0", "64M..1G step 100K", "0..1G step 64M"] {
+            assert!(parse_sweep(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
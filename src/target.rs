@@ -0,0 +1,107 @@
+use std::path;
+use clap::ValueEnum;
+use serde::Deserialize;
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+
+/// UEFIアプリケーションを実行するアーキテクチャ
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, ValueEnum, Deserialize)]
+pub enum Arch {
+    #[default]
+    #[value(name = "x86_64")]
+    #[serde(rename = "x86_64")]
+    X86_64,
+    #[value(name = "aarch64")]
+    #[serde(rename = "aarch64")]
+    Aarch64,
+    #[value(name = "i686")]
+    #[serde(rename = "i686")]
+    I686,
+}
+
+impl Arch {
+    /// cargoに渡すターゲットトリプル
+    pub fn rust_target(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64-unknown-uefi",
+            Arch::Aarch64 => "aarch64-unknown-uefi",
+            Arch::I686 => "i686-unknown-uefi",
+        }
+    }
+
+    /// PE/COFFヘッダのMachineフィールドから判定する
+    pub fn from_pe_machine(machine: u16) -> Option<Arch> {
+        match machine {
+            0x8664 => Some(Arch::X86_64),
+            0xaa64 => Some(Arch::Aarch64),
+            0x014c => Some(Arch::I686),
+            _ => None,
+        }
+    }
+
+    pub fn qemu_name(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "qemu-system-x86_64",
+            Arch::Aarch64 => "qemu-system-aarch64",
+            Arch::I686 => "qemu-system-i386",
+        }
+    }
+
+    /// マシンの種類の指定。x86ではQEMUの既定のマシンを使う
+    pub fn machine_args(&self) -> Vec<String> {
+        let args: &[&str] = match self {
+            Arch::X86_64 | Arch::I686 => &[],
+            Arch::Aarch64 => &["-machine", "virt", "-cpu", "cortex-a72"],
+        };
+
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    /// リムーバブルメディアから起動する際に、ファームウェアが読み込むファイルの名前
+    pub fn boot_file_name(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "BOOTX64.EFI",
+            Arch::Aarch64 => "BOOTAA64.EFI",
+            Arch::I686 => "BOOTIA32.EFI",
+        }
+    }
+
+    pub fn systemd_boot_name(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "systemd-bootx64.efi",
+            Arch::Aarch64 => "systemd-bootaa64.efi",
+            Arch::I686 => "systemd-bootia32.efi",
+        }
+    }
+}
+
+/// 実行するアーキテクチャを決める。指定がなければ、既存のEFIファイルのヘッダから判定する
+pub fn resolve(target: Option<Arch>, app: Option<&path::Path>) -> Result<Arch, Box<dyn std::error::Error>> {
+    if let Some(target) = target {
+        return Ok(target);
+    }
+    let app = match app {
+        Some(app) if app.is_file() => app,
+        _ => return Ok(Arch::default()),
+    };
+
+    let pe = crate::pe::PeFile::parse(std::fs::read(app)?)?;
+    match Arch::from_pe_machine(pe.machine) {
+        Some(arch) => Ok(arch),
+        None => Err(Box::new(Error::new(ErrorKind::InvalidArgument, msg!(UnsupportedArch, pe.machine_name())))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::target::Arch;
+
+    #[test]
+    fn arch_from_pe_machine() {
+        assert_eq!(Arch::from_pe_machine(0xaa64), Some(Arch::Aarch64));
+        assert_eq!(Arch::from_pe_machine(0x014c).map(|a| a.boot_file_name()), Some("BOOTIA32.EFI"));
+        assert_eq!(Arch::from_pe_machine(0x5064), None);
+        assert_eq!(Arch::Aarch64.machine_args(), ["-machine", "virt", "-cpu", "cortex-a72"]);
+        assert_eq!(Arch::default().rust_target(), "x86_64-unknown-uefi");
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::BufRead;
+use std::path;
+use serde_json::{json, Value};
+use crate::error::{Error, ErrorKind};
+use crate::message::msg;
+
+/// `-d` に指定できるQEMUのログの項目
+const ITEMS: &[&str] = &[
+    "out_asm", "in_asm", "op", "op_opt", "op_ind", "op_plugin", "int", "exec", "cpu", "fpu", "mmu", "pcall",
+    "cpu_reset", "unimp", "guest_errors", "page", "nochain", "plugin", "strace", "tid", "vpu", "invalid_mem",
+];
+
+/// QEMUに記録させるログの項目
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceItems(pub Vec<String>);
+
+/// `int,guest_errors,unimp` の形式の引数を読み取る
+pub fn parse_items(s: &str) -> Result<TraceItems, Error> {
+    let items: Vec<String> = s.split(',').map(|i| i.trim().to_string()).filter(|i| !i.is_empty()).collect();
+    if let Some(unknown) = items.iter().find(|i| !ITEMS.contains(&i.as_str())) {
+        return Err(Error::new(ErrorKind::InvalidArgument, msg!(TraceUnknownItem, unknown, ITEMS.join(", "))));
+    }
+    if items.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidArgument, msg!(TraceUnknownItem, s, ITEMS.join(", "))));
+    }
+
+    Ok(TraceItems(items))
+}
+
+impl TraceItems {
+    /// QEMUのログを `log` に書き出させる引数
+    pub fn qemu_args(&self, log: &path::Path) -> Vec<String> {
+        vec!["-d".to_string(), self.0.join(","), "-D".to_string(), log.display().to_string()]
+    }
+}
+
+/// ログから数えた、シリアルには現れない異常の件数
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct TraceSummary {
+    /// 例外と割り込みのベクタ番号ごとの件数
+    pub vectors: BTreeMap<u8, u64>,
+    pub triple_faults: u64,
+    pub unimplemented: u64,
+    /// 上記以外のメッセージ（主に guest_errors）
+    pub other: u64,
+}
+
+/// x86の例外のニーモニック
+fn exception_name(vector: u8) -> Option<&'static str> {
+    const NAMES: &[&str] = &[
+        "#DE", "#DB", "NMI", "#BP", "#OF", "#BR", "#UD", "#NM", "#DF", "", "#TS", "#NP", "#SS", "#GP", "#PF", "",
+        "#MF", "#AC", "#MC", "#XM", "#VE", "#CP",
+    ];
+    NAMES.get(vector as usize).copied().filter(|n| !n.is_empty())
+}
+
+/// 割り込みの後に続くレジスタのダンプ（`RAX=...` `CS =...` など）かどうか
+fn is_register_dump(line: &str) -> bool {
+    match line.split_once('=') {
+        Some((name, _)) => !name.is_empty() && name.len() <= 5
+            && name.trim_end().bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()),
+        None => false,
+    }
+}
+
+/// 件数に含めない、例外の詳細や `in_asm` `exec` などの項目の出力
+fn is_noise(line: &str) -> bool {
+    const PREFIXES: &[&str] = &["check_exception", "CPU Reset", "IN:", "OUT:", "OP:", "Trace ", "Linking TBs", "Chain ", "----", "0x"];
+    PREFIXES.iter().any(|p| line.starts_with(p))
+}
+
+/// `     2: v=0e e=0002 i=0 cpl=0 IP=...` の形式の行からベクタ番号を取り出す
+fn interrupt_vector(line: &str) -> Option<u8> {
+    let (count, rest) = line.trim_start().split_once(": v=")?;
+    count.parse::<u64>().ok()?;
+    u8::from_str_radix(rest.get(..2)?, 16).ok()
+}
+
+pub fn summarize<R: BufRead>(reader: R) -> Result<TraceSummary, io::Error> {
+    let mut summary = TraceSummary::default();
+    for line in reader.split(b'\n') {
+        let line = line?;
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end();
+
+        if let Some(vector) = interrupt_vector(line) {
+            *summary.vectors.entry(vector).or_default() += 1;
+        } else if line.contains("Triple fault") {
+            summary.triple_faults += 1;
+        } else if line.to_ascii_lowercase().contains("unimplemented") {
+            summary.unimplemented += 1;
+        } else if line.is_empty() || is_register_dump(line.trim_start()) || is_noise(line) {
+            continue;
+        } else if !line.starts_with(char::is_whitespace) {
+            summary.other += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+impl TraceSummary {
+    pub fn render(&self, log: &path::Path) -> String {
+        let vectors = |exceptions: bool| -> String {
+            let list: Vec<String> = self.vectors.iter()
+                .filter(|(v, _)| (**v < 32) == exceptions)
+                .map(|(v, n)| match exception_name(*v) {
+                    Some(name) => format!("{} (0x{:02x}) x{}", name, v, n),
+                    None => format!("0x{:02x} x{}", v, n),
+                })
+                .collect();
+            if list.is_empty() { "-".to_string() } else { list.join(", ") }
+        };
+
+        let mut out = msg!(TraceSummaryHeader, log.display());
+        out.push('\n');
+        out.push_str(&format!("  exceptions:             {}\n", vectors(true)));
+        out.push_str(&format!("  interrupts:             {}\n", vectors(false)));
+        out.push_str(&format!("  triple faults:          {}\n", self.triple_faults));
+        out.push_str(&format!("  unimplemented accesses: {}\n", self.unimplemented));
+        out.push_str(&format!("  other messages:         {}\n", self.other));
+        out
+    }
+
+    pub fn to_json(&self, log: &path::Path) -> Value {
+        let vectors: BTreeMap<String, u64> = self.vectors.iter().map(|(v, n)| (format!("0x{:02x}", v), *n)).collect();
+        json!({
+            "log": log,
+            "vectors": vectors,
+            "triple-faults": self.triple_faults,
+            "unimplemented": self.unimplemented,
+            "other": self.other,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::trace::{parse_items, summarize};
+
+    #[test]
+    fn parse_trace_items() {
+        assert_eq!(parse_items("int,guest_errors,unimp").unwrap().0, ["int", "guest_errors", "unimp"]);
+        assert!(parse_items("int,bogus").is_err());
+        assert!(parse_items("").is_err());
+    }
+
+    #[test]
+    fn summarize_log() {
+        let log = "\
+check_exception old: 0xffffffff new 0xe
+     0: v=0e e=0002 i=0 cpl=0 IP=0038:000000007e5a1234 pc=000000007e5a1234 SP=0030:000000007fe9d000 CR2=0000000000000000
+RAX=0000000000000000 RBX=0000000000000000 RCX=0000000000000000 RDX=0000000000000000
+CS =0038 0000000000000000 ffffffff 00af9b00 DPL=0 CS64 [-RA]
+GDT=     000000007f9dc000 00000047
+     1: v=20 e=0000 i=0 cpl=0 IP=0038:000000007e5a1300 pc=000000007e5a1300 SP=0030:000000007fe9d000 env->regs[R_EAX]=0
+     2: v=0e e=0002 i=0 cpl=0 IP=0038:000000007e5a1234 pc=000000007e5a1234 SP=0030:000000007fe9d000 CR2=0000000000000000
+Triple fault
+IN: 
+0x7e5a1234:  mov    (%rax),%rax
+pflash_write: Unimplemented flash cmd sequence (offset 0000000000000000, wcycle 0x0 cmd 0x0 value 0xf0)
+Invalid read at addr 0xFED40000, size 1, region '(null)', reason: rejected
+";
+        let summary = summarize(log.as_bytes()).unwrap();
+        assert_eq!(summary.vectors.get(&0x0e), Some(&2));
+        assert_eq!(summary.vectors.get(&0x20), Some(&1));
+        assert_eq!((summary.triple_faults, summary.unimplemented, summary.other), (1, 1, 1));
+
+        let text = summary.render(std::path::Path::new("trace.log"));
+        assert!(text.contains("#PF (0x0e) x2"));
+    }
+}
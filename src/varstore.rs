@@ -0,0 +1,114 @@
+use std::path;
+use crate::error::{Error, ErrorKind};
+use crate::firmware::Firmware;
+use crate::message::msg;
+
+/// 名前を付けて保存したUEFI変数（VARSイメージ）の置き場所
+pub fn varstore_dir(project_root: &path::Path) -> path::PathBuf {
+    project_root.join("target").join("uefi").join("varstores")
+}
+
+/// プロファイル名に対応するVARSイメージのパス。名前はディレクトリの外を指せないものに限る
+pub fn profile_path(dir: &path::Path, name: &str) -> Result<path::PathBuf, Error> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+    if !valid {
+        return Err(Error::new(ErrorKind::InvalidArgument, msg!(VarsProfileInvalidName, name)));
+    }
+
+    Ok(dir.join(format!("{}.fd", name)))
+}
+
+/// 保存されているプロファイルの名前
+pub fn profiles(dir: &path::Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir).into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "fd"))
+        .filter_map(|p| p.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .collect();
+    names.sort();
+    names
+}
+
+/// ファームウェアの既定のVARSイメージの代わりに、プロファイルのVARSイメージを使う。
+/// VARSのレイアウトはファームウェアのビルドに依存するため、少なくともサイズが一致することを確認する
+pub fn apply(firmware: &Firmware, dir: &path::Path, name: &str) -> Result<Firmware, Error> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidArgument, msg);
+
+    let vars = firmware.vars.as_deref().ok_or_else(|| invalid(msg!(VarsProfileNoVars, firmware.code.display())))?;
+    let profile = profile_path(dir, name)?;
+    if !profile.is_file() {
+        let available = profiles(dir);
+        let available = if available.is_empty() { "-".to_string() } else { available.join(", ") };
+        return Err(invalid(msg!(VarsProfileNotFound, name, dir.display(), available)));
+    }
+
+    let size = |p: &path::Path| std::fs::metadata(p).map(|m| m.len()).unwrap_or(0);
+    if size(profile.as_path()) != size(vars) {
+        return Err(invalid(msg!(VarsProfileSizeMismatch, name, size(profile.as_path()), size(vars))));
+    }
+
+    Ok(Firmware { vars: Some(profile), ..firmware.clone() })
+}
+
+/// 実行後のVARSイメージを `name` のプロファイルとして保存する。既に同じ名前があれば置き換える
+pub fn save(vars: &path::Path, dir: &path::Path, name: &str) -> Result<path::PathBuf, Error> {
+    fn io_error(p: &path::Path) -> impl FnOnce(std::io::Error) -> Error + '_ {
+        move |e| Error::new(ErrorKind::InvalidArgument, msg!(CreateDirFailed, p.display(), e))
+    }
+
+    let profile = profile_path(dir, name)?;
+    std::fs::create_dir_all(dir).map_err(io_error(dir))?;
+
+    // 保存の途中で中断されても、既存のプロファイルを壊さない
+    let tmp = dir.join(format!(".{}.fd.tmp", name));
+    let registration = crate::janitor::register_path(tmp.as_path());
+    crate::copy::copy_file(vars, tmp.as_path()).map_err(io_error(profile.as_path()))?;
+    std::fs::rename(tmp.as_path(), profile.as_path()).map_err(io_error(profile.as_path()))?;
+    registration.release();
+
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::firmware::Firmware;
+    use crate::varstore::{apply, profile_path, profiles, save};
+
+    #[test]
+    fn profile_names_stay_inside_dir() {
+        let dir = path::Path::new("/tmp/varstores");
+        assert_eq!(profile_path(dir, "secureboot-enrolled").unwrap(), dir.join("secureboot-enrolled.fd"));
+        assert!(profile_path(dir, "../factory").is_err());
+        assert!(profile_path(dir, ".hidden").is_err());
+        assert!(profile_path(dir, "").is_err());
+    }
+
+    #[test]
+    fn save_and_apply_profile() {
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-test-varstore-{}", std::process::id()));
+        let store = dir.join("varstores");
+        std::fs::create_dir_all(dir.as_path()).unwrap();
+        std::fs::write(dir.join("CODE.fd"), b"code").unwrap();
+        std::fs::write(dir.join("VARS.fd"), b"vars").unwrap();
+        std::fs::write(dir.join("run.fd"), b"boot").unwrap();
+        let firmware = Firmware { vars: Some(dir.join("VARS.fd")), ..Firmware::from_code(dir.join("CODE.fd")) };
+
+        assert!(apply(&firmware, store.as_path(), "factory").is_err());
+        save(dir.join("run.fd").as_path(), store.as_path(), "factory").unwrap();
+        assert_eq!(profiles(store.as_path()), vec!["factory".to_string()]);
+
+        let applied = apply(&firmware, store.as_path(), "factory").unwrap();
+        assert_eq!(std::fs::read(applied.vars.unwrap()).unwrap(), b"boot");
+
+        std::fs::write(dir.join("run.fd"), b"too large").unwrap();
+        save(dir.join("run.fd").as_path(), store.as_path(), "large").unwrap();
+        assert!(apply(&firmware, store.as_path(), "large").is_err());
+        assert!(apply(&Firmware::from_code(dir.join("CODE.fd")), store.as_path(), "factory").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use serde_json::{json, Value};
+use crate::freeze::Stop;
+use crate::message::msg;
+use crate::shim::{status_name, Outcome};
+
+/// EFI_STATUSのエラーを示すビット
+const EFI_ERROR: u64 = 1 << 63;
+
+/// 終了コードで区別するエラーの番号の上限。UEFIの仕様が定めるエラーは35（EFI_HTTP_ERROR）までなので、
+/// それより大きなものは全て100にまとめ、パニックなどの101以降の終了コードと重ならないようにする
+const EFI_ERROR_CODE_MAX: u64 = 36;
+
+/// ゲストの実行の結果の分類。ホストの終了コードは分類ごとに決まっている
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    /// 終了コード0
+    Success,
+    /// 検証用のシムが受け取った警告のEFI_STATUS。終了コードは32に警告の番号を足したもの
+    EfiWarning(u64),
+    /// 検証用のシムが受け取ったエラーのEFI_STATUS。読み込めなかった場合も含む。
+    /// 終了コードは64にエラーの番号を足したもの（例: EFI_NOT_FOUNDは78、EFI_SECURITY_VIOLATIONは90）で、
+    /// 番号が36以上のものは100
+    EfiError(u64),
+    /// アプリケーションのパニック。終了コードは、Rustのプロセスがパニックで終了したときと同じ101
+    Panic,
+    /// CPUの例外やトリプルフォールト。終了コード102
+    Exception(String),
+    /// `--timeout` までに終了しなかった。終了コードは、timeoutコマンドと同じ124
+    Timeout,
+    /// その他の失敗。終了コードの受け渡し方法があればゲストが書き込んだ値、なければ1
+    Failed(i32),
+}
+
+impl Verdict {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Verdict::Success => 0,
+            Verdict::EfiWarning(status) => 32 + (*status).min(31) as i32,
+            Verdict::EfiError(status) => 64 + (status & !EFI_ERROR).min(EFI_ERROR_CODE_MAX) as i32,
+            Verdict::Panic => 101,
+            Verdict::Exception(_) => 102,
+            Verdict::Timeout => 124,
+            Verdict::Failed(code) => *code,
+        }
+    }
+
+    /// JSON形式の `result`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Verdict::Success => "success",
+            Verdict::EfiWarning(_) => "efi-warning",
+            Verdict::EfiError(_) => "efi-error",
+            Verdict::Panic => "panic",
+            Verdict::Exception(_) => "exception",
+            Verdict::Timeout => "timeout",
+            Verdict::Failed(_) => "failed",
+        }
+    }
+
+    /// 人が読むための結果の説明
+    pub fn describe(&self) -> String {
+        match self {
+            Verdict::Success => msg!(VerdictSuccess),
+            Verdict::EfiWarning(status) | Verdict::EfiError(status) => msg!(VerdictEfiStatus, status_name(*status), self.exit_code()),
+            Verdict::Panic => msg!(VerdictPanic, self.exit_code()),
+            Verdict::Exception(kind) => msg!(VerdictException, kind, self.exit_code()),
+            Verdict::Timeout => msg!(VerdictTimeout, self.exit_code()),
+            Verdict::Failed(code) => msg!(VerdictFailed, code),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let status = match self {
+            Verdict::EfiWarning(status) | Verdict::EfiError(status) => Some(*status),
+            _ => None,
+        };
+        json!({
+            "class": self.name(),
+            "efi-status": status,
+            "efi-status-name": status.map(status_name),
+            "exception": match self {
+                Verdict::Exception(kind) => Some(kind.as_str()),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// 実行中に見つけた、ゲストが異常終了した兆候
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Signs {
+    panic: bool,
+    exception: Option<String>,
+    /// 改行の来ていない出力
+    pending: Vec<u8>,
+}
+
+/// 出力を監視している実行の兆候。ゲストの出力を転送するスレッドとクラッシュの監視から記録する
+static SIGNS: Mutex<Signs> = Mutex::new(Signs { panic: false, exception: None, pending: Vec::new() });
+
+/// 行が長くなりすぎないよう、改行のない出力はこの長さまでしか溜めない
+const PENDING_LIMIT: usize = 512;
+
+/// これから実行するVMのために、前の実行で見つけた兆候を消す
+pub fn reset() {
+    *SIGNS.lock().unwrap_or_else(|e| e.into_inner()) = Signs::default();
+}
+
+/// ゲストの出力から、パニックと例外の報告を探す
+pub fn observe(text: &[u8]) {
+    let mut signs = SIGNS.lock().unwrap_or_else(|e| e.into_inner());
+    signs.pending.extend_from_slice(text);
+    while let Some(pos) = signs.pending.iter().position(|b| *b == b'\n') {
+        let line: Vec<_> = signs.pending.drain(..=pos).collect();
+        signs.scan(String::from_utf8_lossy(&line).as_ref());
+    }
+    if signs.pending.len() > PENDING_LIMIT {
+        let line = std::mem::take(&mut signs.pending);
+        signs.scan(String::from_utf8_lossy(&line).as_ref());
+    }
+}
+
+/// クラッシュの監視が判定した、ゲストが停止した理由を記録する
+pub fn record_stop(stop: Stop) {
+    let mut signs = SIGNS.lock().unwrap_or_else(|e| e.into_inner());
+    match stop {
+        Stop::Panic => signs.panic = true,
+        Stop::Reset if signs.exception.is_none() => signs.exception = Some("triple fault".to_string()),
+        _ => {}
+    }
+}
+
+impl Signs {
+    fn scan(&mut self, line: &str) {
+        // Rustのパニックのメッセージ（uefiクレートのパニックハンドラも同じ形で出力する）
+        if line.contains("panicked at ") {
+            self.panic = true;
+        }
+        // EDK IIのCPU例外ハンドラの出力
+        // `!!!! X64 Exception Type - 0E(#PF - Page-Fault)  CPU Apic ID - 00000000 !!!!`
+        // `Synchronous Exception at 0x000000007F2F1A48`
+        if self.exception.is_none() {
+            let kind = line.split_once("Exception Type - ").map(|(_, rest)| rest.split("  ").next().unwrap_or(rest).trim().to_string())
+                .or_else(|| ["Synchronous Exception", "SError Exception", "IRQ Exception"].iter().find(|k| line.contains(*k)).map(|k| k.to_string()));
+            self.exception = kind;
+        }
+    }
+}
+
+/// 実行の結果を分類する。`code` は終了コードの受け渡し方法に従ったホストの終了コード、`shim` は検証用のシムの報告。
+/// パニックや例外が見つかれば、その後でタイムアウトしたりシムが結果を報告したりしても、そちらを優先する
+pub fn classify(status: Option<ExitStatus>, code: i32, shim: Option<&Outcome>) -> Verdict {
+    let signs = SIGNS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    judge(&signs, status.is_none(), code, shim)
+}
+
+fn judge(signs: &Signs, timed_out: bool, code: i32, shim: Option<&Outcome>) -> Verdict {
+    if signs.panic {
+        return Verdict::Panic;
+    }
+    if let Some(kind) = &signs.exception {
+        return Verdict::Exception(kind.clone());
+    }
+    if timed_out {
+        return Verdict::Timeout;
+    }
+
+    match shim.map(|s| s.status.or(s.load_error)) {
+        Some(Some(0)) => Verdict::Success,
+        Some(Some(status)) if status & EFI_ERROR == 0 => Verdict::EfiWarning(status),
+        Some(Some(status)) => Verdict::EfiError(status),
+        // シムが結果を報告しなかった
+        Some(None) => Verdict::Failed(1),
+        None if code == 0 => Verdict::Success,
+        None => Verdict::Failed(code),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::shim::Outcome;
+    use crate::verdict::{judge, Signs, Verdict};
+
+    #[test]
+    fn classify_guest_completion() {
+        let shim = |status| Outcome { started: true, status: Some(status), ..Default::default() };
+        let clean = Signs::default();
+        assert_eq!(judge(&clean, false, 0, Some(&shim(0))), Verdict::Success);
+        let not_found = judge(&clean, false, 0, Some(&shim(0x8000_0000_0000_000e)));
+        let violation = judge(&clean, false, 0, Some(&Outcome { load_error: Some(0x8000_0000_0000_001a), ..Default::default() }));
+        assert_eq!((not_found.exit_code(), violation.exit_code()), (78, 90));
+        // 大きなエラーの番号は、パニック（101）や例外（102）、タイムアウト（124）の終了コードにならない
+        for status in [0x8000_0000_0000_0025, 0x8000_0000_0000_003c, 0x8000_0000_ffff_ffff] {
+            assert_eq!(judge(&clean, false, 0, Some(&shim(status))).exit_code(), 100);
+        }
+        assert_eq!(judge(&clean, false, 0, Some(&shim(4))).exit_code(), 36);
+        assert_eq!(judge(&clean, false, 0, Some(&Outcome::default())), Verdict::Failed(1));
+        assert_eq!(judge(&clean, true, 1, None).exit_code(), 124);
+        assert_eq!(judge(&clean, false, 17, None), Verdict::Failed(17));
+
+        let mut signs = Signs::default();
+        signs.scan("!!!! X64 Exception Type - 0E(#PF - Page-Fault)  CPU Apic ID - 00000000 !!!!\r\n");
+        assert_eq!(judge(&signs, true, 1, None), Verdict::Exception("0E(#PF - Page-Fault)".to_string()));
+        signs.scan("[ERROR]: src/main.rs@012: panicked at src/main.rs:12:5:\n");
+        assert_eq!(judge(&signs, false, 0, Some(&shim(0))).exit_code(), 101);
+    }
+}
@@ -0,0 +1,573 @@
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path;
+use std::process::Command;
+use clap::Args;
+use serde::Deserialize;
+use serde_json::json;
+use crate::gpt::{crc32, ESP_TYPE_GUID};
+use crate::message::msg;
+use crate::pe::{PeFile, DIR_SECURITY};
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    /// 検証するディスクイメージ（GPT/MBRのディスク、FATイメージ、ISO）
+    #[arg(value_name = "IMAGE")]
+    pub disk_image: path::PathBuf,
+
+    /// ブートファイルのSecure Boot署名を検証する証明書（PEM）。設定ファイルの `secure-boot.db` より優先する
+    #[arg(long = "db-cert", value_name = "FILE")]
+    pub db_certs: Vec<path::PathBuf>,
+}
+
+/// Secure Bootの署名を検証するための設定
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SecureBootConfig {
+    /// ブートファイルの署名を検証する証明書（PEM）。相対パスはプロジェクトルートからのパスとみなす
+    #[serde(default)]
+    pub db: Vec<path::PathBuf>,
+}
+
+/// El ToritoでUEFIを表すプラットフォームID
+const PLATFORM_EFI: u8 = 0xef;
+
+const ISO_SECTOR: u64 = 2048;
+
+/// 1つの検査項目の結果
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Check {
+    pub name: String,
+    /// 問題がなければ `None`
+    pub problem: Option<String>,
+}
+
+#[derive(Default)]
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn pass(&mut self, name: impl Into<String>) {
+        self.checks.push(Check { name: name.into(), problem: None });
+    }
+
+    fn fail(&mut self, name: impl Into<String>, problem: impl Into<String>) {
+        self.checks.push(Check { name: name.into(), problem: Some(problem.into()) });
+    }
+
+    fn check(&mut self, name: impl Into<String>, ok: bool, problem: impl FnOnce() -> String) -> bool {
+        match ok {
+            true => self.pass(name),
+            false => self.fail(name, problem()),
+        }
+        ok
+    }
+}
+
+/// ディスクイメージの一部だけを読み取り専用で見せる。検証中にイメージを書き換えないよう、書き込みはエラーにする
+struct Slice<'a> {
+    file: &'a std::fs::File,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl Read for Slice<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        let mut file = self.file;
+        file.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = file.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for Slice<'_> {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, msg!(VerifyReadOnly)))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Slice<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::Current(d) => self.pos as i64 + d,
+            SeekFrom::End(d) => self.len as i64 + d,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg!(VerifySeekBeforeStart)));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+fn read_at(file: &std::fs::File, offset: u64, len: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    file.read_exact(&mut buf).ok()?;
+    Some(buf)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// イメージの構造を検証し、検査項目ごとの結果を返す
+pub fn verify(image: &path::Path, db: &[path::PathBuf], work_dir: &path::Path) -> Result<Vec<Check>, io::Error> {
+    let file = std::fs::File::open(image)?;
+    let len = file.metadata()?.len();
+    let mut report = Report::default();
+
+    let esp = if read_at(&file, 16 * ISO_SECTOR + 1, 5).as_deref() == Some(b"CD001") {
+        iso_esp(&file, &mut report)
+    } else if let Some(sector) = [512u64, 4096].into_iter().find(|s| read_at(&file, *s, 8).as_deref() == Some(b"EFI PART")) {
+        gpt_esp(&file, len, sector, &mut report)
+    } else {
+        mbr_esp(&file, len, &mut report)
+    };
+
+    if let Some((start, size)) = esp {
+        let slice = Slice { file: &file, start, len: size.min(len.saturating_sub(start)), pos: 0 };
+        verify_fat(slice, db, work_dir, &mut report);
+    }
+
+    Ok(report.checks)
+}
+
+/// 保護MBRとGPTのヘッダ、パーティションエントリを検証し、ESPの位置を返す
+fn gpt_esp(file: &std::fs::File, len: u64, sector: u64, report: &mut Report) -> Option<(u64, u64)> {
+    let mbr = read_at(file, 0, 512)?;
+    report.check(msg!(VerifyProtectiveMbr), mbr[510..512] == [0x55, 0xaa] && (0..4).any(|i| mbr[446 + i * 16 + 4] == 0xee), || {
+        msg!(VerifyNoProtectivePartition)
+    });
+
+    let primary = gpt_header(file, sector, sector, msg!(VerifyPrimaryGpt).as_str(), report)?;
+    let backup_lba = u64_at(&primary, 32);
+    // 壊れたイメージの値でも桁あふれしないよう、位置はすべて検査しながら計算する
+    let backup_offset = backup_lba.checked_mul(sector).filter(|offset| offset.checked_add(sector).is_some_and(|end| end <= len));
+    if backup_offset.is_none() {
+        report.fail(msg!(VerifyBackupGpt), msg!(VerifyBackupBeyondEnd, backup_lba));
+    } else if let Some(backup) = backup_offset.and_then(|offset| gpt_header(file, offset, sector, msg!(VerifyBackupGpt).as_str(), report)) {
+        report.check(msg!(VerifyGptHeadersAgree), backup[56..72] == primary[56..72] && u32_at(&backup, 88) == u32_at(&primary, 88), || {
+            msg!(VerifyGptHeadersDiffer)
+        });
+    }
+
+    let entries_lba = u64_at(&primary, 72);
+    let count = u32_at(&primary, 80) as usize;
+    let entry_size = u32_at(&primary, 84) as usize;
+    let table_size = match count.checked_mul(entry_size) {
+        Some(size) if entry_size >= 128 && size <= 1024 * 1024 => size,
+        _ => {
+            report.fail(msg!(VerifyGptEntries), msg!(VerifyGptTableUnsupported, count, entry_size));
+            return None;
+        }
+    };
+    let entries_offset = match entries_lba.checked_mul(sector) {
+        Some(offset) => offset,
+        None => {
+            report.fail(msg!(VerifyGptEntries), msg!(VerifyEntriesBeyondEnd, entries_lba));
+            return None;
+        }
+    };
+    let entries = read_at(file, entries_offset, table_size)?;
+    let crc = crc32(&entries);
+    report.check(msg!(VerifyGptEntriesChecksum), crc == u32_at(&primary, 88), || {
+        msg!(VerifyChecksumMismatch, format!("0x{:08x}", u32_at(&primary, 88)), format!("0x{:08x}", crc))
+    });
+
+    let esp = entries.chunks(entry_size).find(|e| e[..16] == ESP_TYPE_GUID);
+    let esp = match esp {
+        Some(esp) => esp,
+        None => {
+            report.fail(msg!(VerifyEsp), msg!(VerifyNoEspType));
+            return None;
+        }
+    };
+    let (first, last) = (u64_at(esp, 32), u64_at(esp, 40));
+    let (usable_first, usable_last) = (u64_at(&primary, 40), u64_at(&primary, 48));
+    let range = first.checked_mul(sector)
+        .zip(last.checked_sub(first).and_then(|n| n.checked_add(1)).and_then(|n| n.checked_mul(sector)));
+    let ok = report.check(msg!(VerifyEsp), range.is_some() && first >= usable_first && last <= usable_last, || {
+        msg!(VerifyEspOutOfRange, first, last, usable_first, usable_last)
+    });
+
+    range.filter(|_| ok)
+}
+
+fn gpt_header(file: &std::fs::File, offset: u64, sector: u64, name: &str, report: &mut Report) -> Option<Vec<u8>> {
+    let mut header = match read_at(file, offset, sector as usize) {
+        Some(header) if &header[..8] == b"EFI PART" => header,
+        _ => {
+            report.fail(name, msg!(VerifyNoGptSignature));
+            return None;
+        }
+    };
+
+    let size = (u32_at(&header, 12) as usize).clamp(92, sector as usize);
+    let expected = u32_at(&header, 16);
+    header[16..20].fill(0);
+    let crc = crc32(&header[..size]);
+    header[16..20].copy_from_slice(&expected.to_le_bytes());
+    report.check(msg!(VerifyHeaderChecksum, name), crc == expected, || msg!(VerifyChecksumMismatch, format!("0x{:08x}", expected), format!("0x{:08x}", crc)))
+        .then_some(header)
+}
+
+/// パーティションテーブルを持たないFATイメージか、MBRのタイプ0xEFのパーティションをESPとして扱う
+fn mbr_esp(file: &std::fs::File, len: u64, report: &mut Report) -> Option<(u64, u64)> {
+    let mbr = read_at(file, 0, 512);
+    let mbr = match mbr {
+        Some(mbr) if mbr[510..512] == [0x55, 0xaa] => mbr,
+        _ => {
+            report.fail(msg!(VerifyBootSector), msg!(VerifyNotDiskImage));
+            return None;
+        }
+    };
+
+    // FATのブートセクタはジャンプ命令で始まる
+    if matches!(mbr[0], 0xeb | 0xe9) && u16_at(&mbr, 11).is_power_of_two() {
+        report.pass(msg!(VerifyFatImage));
+        return Some((0, len));
+    }
+
+    match (0..4).map(|i| &mbr[446 + i * 16..446 + i * 16 + 16]).find(|p| p[4] == 0xef) {
+        Some(partition) => {
+            report.pass(msg!(VerifyMbrEsp));
+            Some((u32_at(partition, 8) as u64 * 512, u32_at(partition, 12) as u64 * 512))
+        }
+        None => {
+            report.fail(msg!(VerifyEsp), msg!(VerifyNoEspFound));
+            None
+        }
+    }
+}
+
+/// El Toritoのブートカタログから、UEFIのブートイメージ（FATイメージ）の位置を返す
+fn iso_esp(file: &std::fs::File, report: &mut Report) -> Option<(u64, u64)> {
+    report.pass(msg!(VerifyIsoDescriptor));
+
+    let boot_record = (17..32).map(|s| read_at(file, s * ISO_SECTOR, 0x4b))
+        .take_while(|d| d.as_ref().is_some_and(|d| d[0] != 0xff))
+        .flatten()
+        .find(|d| d[0] == 0 && d[7..30] == *b"EL TORITO SPECIFICATION");
+    let catalog_sector = match boot_record {
+        Some(record) => u32_at(&record, 0x47) as u64,
+        None => {
+            report.fail(msg!(VerifyElToritoRecord), msg!(VerifyIsoNotBootable));
+            return None;
+        }
+    };
+
+    let catalog = read_at(file, catalog_sector * ISO_SECTOR, ISO_SECTOR as usize)?;
+    let sum = catalog[..32].chunks(2).fold(0u16, |sum, w| sum.wrapping_add(u16_at(w, 0)));
+    if !report.check(msg!(VerifyElToritoCatalog), catalog[0] == 1 && catalog[30..32] == [0x55, 0xaa] && sum == 0, || {
+        msg!(VerifyInvalidValidationEntry)
+    }) {
+        return None;
+    }
+
+    // 既定のエントリか、プラットフォームIDがUEFIのセクションに含まれるエントリを探す
+    let mut entry = (catalog[1] == PLATFORM_EFI).then(|| catalog[32..64].to_vec());
+    let mut pos = 64;
+    while entry.is_none() && pos + 32 <= catalog.len() && matches!(catalog[pos], 0x90 | 0x91) {
+        let platform = catalog[pos + 1];
+        let count = u16_at(&catalog, pos + 2) as usize;
+        if platform == PLATFORM_EFI && count > 0 {
+            entry = catalog.get(pos + 32..pos + 64).map(|e| e.to_vec());
+        }
+        pos += 32 * (count + 1);
+    }
+
+    let entry = match entry {
+        Some(entry) if entry[0] == 0x88 => entry,
+        _ => {
+            report.fail(msg!(VerifyElToritoEntry), msg!(VerifyNoEfiEntry));
+            return None;
+        }
+    };
+    report.pass(msg!(VerifyElToritoEntry));
+
+    // セクタ数は512バイト単位だが、大きなイメージでは0や1になっていることが多いため、FATのブートセクタから大きさを求める
+    let start = u32_at(&entry, 8) as u64 * ISO_SECTOR;
+    let boot = read_at(file, start, 512)?;
+    let total = match u16_at(&boot, 19) {
+        0 => u32_at(&boot, 32) as u64,
+        n => n as u64,
+    };
+    let size = (total * u16_at(&boot, 11) as u64).max(u16_at(&entry, 6) as u64 * 512);
+    Some((start, size))
+}
+
+fn verify_fat(slice: Slice, db: &[path::PathBuf], work_dir: &path::Path, report: &mut Report) {
+    let fs = match fatfs::FileSystem::new(slice, fatfs::FsOptions::new()) {
+        Ok(fs) => fs,
+        Err(e) => {
+            report.fail(msg!(VerifyEspFat), e.to_string());
+            return;
+        }
+    };
+    match fs.read_status_flags() {
+        Ok(flags) if flags.dirty() || flags.io_error() => report.fail(msg!(VerifyEspFat), msg!(VerifyFatDirty)),
+        Ok(_) => match fs.stats() {
+            Ok(_) => report.pass(msg!(VerifyEspFat)),
+            Err(e) => report.fail(msg!(VerifyEspFat), msg!(VerifyFatTableUnreadable, e)),
+        },
+        Err(e) => report.fail(msg!(VerifyEspFat), e.to_string()),
+    }
+
+    let mut files = Vec::new();
+    if let Err(e) = collect_files(&fs.root_dir(), "", &mut files) {
+        report.fail(msg!(VerifyEspFiles), e.to_string());
+        return;
+    }
+    report.pass(msg!(VerifyEspFilesReadable, files.len()));
+
+    let boot_files: Vec<&(String, Vec<u8>)> = files.iter()
+        .filter(|(p, _)| p.to_ascii_uppercase().starts_with("/EFI/BOOT/BOOT") && p.to_ascii_uppercase().ends_with(".EFI"))
+        .collect();
+    report.check(msg!(VerifyDefaultBootFile), !boot_files.is_empty(), || msg!(VerifyNoDefaultBootFile));
+
+    for (path, data) in files.iter().filter(|(p, _)| p.to_ascii_uppercase().ends_with(".EFI")) {
+        let pe = match PeFile::parse(data.clone()) {
+            Ok(pe) => pe,
+            Err(e) => {
+                report.fail(msg!(VerifyPeImage, path), e.to_string());
+                continue;
+            }
+        };
+        let arch = boot_arch(pe.machine);
+        let upper = path.to_ascii_uppercase();
+        let ok = report.check(msg!(VerifyPeImage, path), matches!(pe.subsystem, 10..=12), || {
+            msg!(VerifyNotEfiSubsystem, pe.subsystem_name())
+        });
+        if ok && upper.starts_with("/EFI/BOOT/BOOT") && arch.is_some_and(|a| upper != format!("/EFI/BOOT/BOOT{}.EFI", a)) {
+            report.fail(msg!(VerifyPeImage, path), msg!(VerifyBootFileArchMismatch, pe.machine_name()));
+        }
+
+        if !db.is_empty() {
+            verify_signature(path, data, &pe, db, work_dir, report);
+        }
+    }
+}
+
+/// `\EFI\BOOT\BOOT<arch>.EFI` の `<arch>`
+fn boot_arch(machine: u16) -> Option<&'static str> {
+    match machine {
+        0x8664 => Some("X64"),
+        0x014c => Some("IA32"),
+        0xaa64 => Some("AA64"),
+        0x01c2 => Some("ARM"),
+        0x5064 => Some("RISCV64"),
+        _ => None,
+    }
+}
+
+fn collect_files<T: fatfs::ReadWriteSeek>(dir: &fatfs::Dir<T>, prefix: &str, files: &mut Vec<(String, Vec<u8>)>) -> Result<(), io::Error> {
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        let path = format!("{}/{}", prefix, name);
+        if entry.is_dir() {
+            collect_files(&entry.to_dir(), path.as_str(), files)?;
+        } else {
+            // クラスタチェーンが途中で切れていれば、読み取った長さがファイルサイズに届かない
+            let mut data = Vec::new();
+            entry.to_file().read_to_end(&mut data)?;
+            if data.len() as u64 != entry.len() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg!(VerifyFileTruncated, path, data.len(), entry.len())));
+            }
+            files.push((path, data));
+        }
+    }
+
+    Ok(())
+}
+
+/// `sbverify` で、いずれかの証明書による署名であることを確認する
+fn verify_signature(path: &str, data: &[u8], pe: &PeFile, db: &[path::PathBuf], work_dir: &path::Path, report: &mut Report) {
+    let name = msg!(VerifySignature, path);
+    if pe.directory(DIR_SECURITY).is_none() {
+        report.fail(name, msg!(VerifyNotSigned));
+        return;
+    }
+
+    let extracted = work_dir.join(format!("verify-{}.efi", std::process::id()));
+    let _extracted = crate::janitor::register_path(extracted.as_path());
+    if let Err(e) = std::fs::write(extracted.as_path(), data) {
+        report.fail(name, e.to_string());
+        return;
+    }
+
+    for cert in db {
+        match crate::signature::run_verifier(Command::new("sbverify").arg("--cert").arg(cert).arg(extracted.as_path())) {
+            Ok(true) => {
+                report.pass(msg!(VerifySignedBy, name, cert.display()));
+                return;
+            }
+            Ok(false) => continue,
+            Err(e) => {
+                report.fail(name, e.to_string());
+                return;
+            }
+        }
+    }
+    report.fail(name, msg!(VerifyNoMatchingCert));
+}
+
+pub fn render(image: &path::Path, checks: &[Check]) -> String {
+    let mut out = format!("{}\n", image.display());
+    for check in checks {
+        match &check.problem {
+            None => out.push_str(&format!("  ok    {}\n", check.name)),
+            Some(problem) => out.push_str(&format!("  FAIL  {}: {}\n", check.name, problem)),
+        }
+    }
+    out
+}
+
+/// 検証結果を出力し、全ての検査に通ったかを返す
+pub fn report(image: &path::Path, checks: &[Check]) -> bool {
+    let failed = checks.iter().filter(|c| c.problem.is_some()).count();
+    if crate::output::json() {
+        let checks: Vec<_> = checks.iter().map(|c| json!({ "name": c.name, "ok": c.problem.is_none(), "problem": c.problem })).collect();
+        crate::output::event("verify-image", json!({ "image": image, "checks": checks, "failed": failed }));
+    } else {
+        print!("{}", render(image, checks));
+        match failed {
+            0 => crate::output::status(msg!(VerifyImagePassed, checks.len())),
+            n => crate::output::status(msg!(VerifyImageFailed, n, checks.len())),
+        }
+    }
+
+    failed == 0
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path;
+    use crate::gpt::{crc32, ESP_TYPE_GUID};
+    use crate::message::msg;
+    use crate::verify::verify;
+
+    /// `\EFI\BOOT\BOOTX64.EFI` を含むFATイメージを `offset` の位置に書き込む
+    fn write_esp(image: &mut std::fs::File, offset: u64, size: u64) {
+        let mut fat = vec![0u8; size as usize];
+        {
+            let mut cursor = std::io::Cursor::new(&mut fat[..]);
+            fatfs::format_volume(&mut cursor, fatfs::FormatVolumeOptions::new()).unwrap();
+            let fs = fatfs::FileSystem::new(&mut cursor, fatfs::FsOptions::new()).unwrap();
+            let boot = fs.root_dir().create_dir("EFI").unwrap().create_dir("BOOT").unwrap();
+            boot.create_file("BOOTX64.EFI").unwrap().write_all(&crate::pe::test::sample_image()).unwrap();
+        }
+        image.seek(SeekFrom::Start(offset)).unwrap();
+        image.write_all(&fat).unwrap();
+    }
+
+    fn failures(image: &path::Path) -> Vec<String> {
+        verify(image, &[], std::env::temp_dir().as_path()).unwrap().into_iter()
+            .filter_map(|c| c.problem.map(|p| format!("{}: {}", c.name, p)))
+            .collect()
+    }
+
+    #[test]
+    fn verify_gpt_disk() {
+        let path = std::env::temp_dir().join(format!("cargo-uefi-test-verify-gpt-{}.img", std::process::id()));
+        let mut image = std::fs::File::create(path.as_path()).unwrap();
+        let sectors = 8192u64;
+        image.set_len(sectors * 512).unwrap();
+        write_esp(&mut image, 2048 * 512, 4096 * 512);
+
+        let mut mbr = vec![0u8; 512];
+        mbr[446 + 4] = 0xee;
+        mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        let mut entries = vec![0u8; 128 * 128];
+        entries[..16].copy_from_slice(&ESP_TYPE_GUID);
+        entries[32..40].copy_from_slice(&2048u64.to_le_bytes());
+        entries[40..48].copy_from_slice(&6143u64.to_le_bytes());
+
+        let header = |lba: u64, backup: u64, entries_lba: u64| {
+            let mut h = vec![0u8; 512];
+            h[..8].copy_from_slice(b"EFI PART");
+            h[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+            h[12..16].copy_from_slice(&92u32.to_le_bytes());
+            h[24..32].copy_from_slice(&lba.to_le_bytes());
+            h[32..40].copy_from_slice(&backup.to_le_bytes());
+            h[40..48].copy_from_slice(&34u64.to_le_bytes());
+            h[48..56].copy_from_slice(&(sectors - 34).to_le_bytes());
+            h[56..72].copy_from_slice(&[7; 16]);
+            h[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+            h[80..84].copy_from_slice(&128u32.to_le_bytes());
+            h[84..88].copy_from_slice(&128u32.to_le_bytes());
+            h[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+            let crc = crc32(&h[..92]);
+            h[16..20].copy_from_slice(&crc.to_le_bytes());
+            h
+        };
+        let write = |image: &mut std::fs::File, lba: u64, data: &[u8]| {
+            image.seek(SeekFrom::Start(lba * 512)).unwrap();
+            image.write_all(data).unwrap();
+        };
+        write(&mut image, 0, &mbr);
+        write(&mut image, 1, &header(1, sectors - 1, 2));
+        write(&mut image, 2, &entries);
+        write(&mut image, sectors - 33, &entries);
+        write(&mut image, sectors - 1, &header(sectors - 1, 1, sectors - 33));
+
+        assert_eq!(failures(path.as_path()), Vec::<String>::new());
+
+        // パーティションエントリを壊すとチェックサムの検査に失敗する
+        write(&mut image, 3, &[1]);
+        let failed = failures(path.as_path());
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0].starts_with(msg!(VerifyGptEntriesChecksum).as_str()));
+
+        // 位置が桁あふれするヘッダでも、失敗として報告する
+        write(&mut image, 1, &header(1, u64::MAX / 2, u64::MAX / 2));
+        let failed = failures(path.as_path());
+        assert!(failed.iter().any(|f| f.starts_with(msg!(VerifyBackupGpt).as_str())));
+        assert!(failed.iter().any(|f| f.starts_with(msg!(VerifyGptEntries).as_str())));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn verify_fat_image_without_boot_file() {
+        let path = std::env::temp_dir().join(format!("cargo-uefi-test-verify-fat-{}.img", std::process::id()));
+        let mut image = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path.as_path()).unwrap();
+        image.set_len(4 * 1024 * 1024).unwrap();
+        {
+            fatfs::format_volume(&mut image, fatfs::FormatVolumeOptions::new()).unwrap();
+            image.seek(SeekFrom::Start(0)).unwrap();
+            let fs = fatfs::FileSystem::new(&mut image, fatfs::FsOptions::new()).unwrap();
+            fs.root_dir().create_dir("EFI").unwrap().create_file("hoge.efi").unwrap().write_all(b"efi\n").unwrap();
+        }
+
+        let failed = failures(path.as_path());
+        assert!(failed.iter().any(|f| f.starts_with(msg!(VerifyDefaultBootFile).as_str())));
+        assert!(failed.iter().any(|f| f.starts_with(msg!(VerifyPeImage, "/EFI/hoge.efi").as_str())));
+        std::fs::remove_file(path).unwrap();
+    }
+}
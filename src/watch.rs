@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::path;
+use std::time::{Duration, SystemTime};
+use crate::staging::{Plan, Source};
+
+/// ファイルの更新時刻を確かめる間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 監視しないディレクトリ
+const IGNORED_DIRS: &[&str] = &["target", ".git"];
+
+/// パッケージのディレクトリのうち、ビルドに使うもの
+const PACKAGE_INPUTS: &[&str] = &["src", "Cargo.toml", "build.rs"];
+
+/// 監視モードで見つけた変更。どのファイルが再読み込みのきっかけになったかを持つ
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Change {
+    /// ビルドに使うファイルが変わった。cargoでビルドし直す
+    Sources(path::PathBuf),
+    /// ESPに配置するデータだけが変わった。cargoを呼ばずに配置し直す
+    Assets(path::PathBuf),
+}
+
+impl Change {
+    pub fn path(&self) -> &path::Path {
+        match self {
+            Change::Sources(path) | Change::Assets(path) => path.as_path(),
+        }
+    }
+}
+
+/// ファイルの更新時刻を定期的に比べて変更を見つける
+pub struct Watcher {
+    /// ビルドに使うファイルとディレクトリ。ビルドしない場合は空
+    sources: Vec<path::PathBuf>,
+    /// `sources` の中でも監視しない場所。VMが書き込むディスクイメージや一時ファイル
+    excluded: Vec<path::PathBuf>,
+    assets: Vec<path::PathBuf>,
+    snapshot: BTreeMap<path::PathBuf, Option<SystemTime>>,
+}
+
+/// 配置計画のうち、ビルドせずに配置し直せるホストのファイル。`app` はcargoがビルドするアプリケーション
+pub fn assets(plan: &Plan, app: Option<&path::Path>) -> Vec<path::PathBuf> {
+    let mut assets: Vec<_> = plan.effective().into_iter()
+        .filter_map(|step| match &step.source {
+            Source::File(source) if Some(source.as_path()) != app => Some(source.clone()),
+            _ => None,
+        })
+        .collect();
+    assets.sort();
+    assets.dedup();
+    assets
+}
+
+/// `packages` のディレクトリそれぞれで、ビルドに使う `src`、`Cargo.toml`、`build.rs`
+pub fn package_inputs(packages: &[path::PathBuf]) -> Vec<path::PathBuf> {
+    packages.iter().flat_map(|dir| PACKAGE_INPUTS.iter().map(|input| dir.join(input))).collect()
+}
+
+impl Watcher {
+    /// `sources` のファイルとディレクトリ以下のファイル（`excluded` と target などを除く）と `assets` を監視する
+    pub fn new(sources: Vec<path::PathBuf>, excluded: Vec<path::PathBuf>, assets: Vec<path::PathBuf>) -> Watcher {
+        let mut watcher = Watcher { sources, excluded, assets, snapshot: BTreeMap::new() };
+        watcher.snapshot = watcher.scan();
+        watcher
+    }
+
+    /// 配置し直した後の配置計画に合わせて、監視するデータを変える
+    pub fn set_assets(&mut self, assets: Vec<path::PathBuf>) {
+        self.assets = assets;
+        self.snapshot = self.scan();
+    }
+
+    fn scan(&self) -> BTreeMap<path::PathBuf, Option<SystemTime>> {
+        let mut files = BTreeMap::new();
+        for source in self.sources.iter() {
+            match source.is_dir() {
+                true => collect_sources(source.as_path(), &self.excluded, &mut files),
+                // まだ作られていない `build.rs` なども、作られた時点で変更として見つける
+                false => {
+                    if !self.excluded.iter().any(|e| source.starts_with(e)) {
+                        files.insert(source.clone(), modified(source.as_path()));
+                    }
+                }
+            }
+        }
+        for asset in self.assets.iter() {
+            // 削除されたファイルも変更として扱う
+            files.insert(asset.clone(), modified(asset.as_path()));
+        }
+        files
+    }
+
+    /// 前回から変わったファイルがあれば、その変更を返す
+    pub fn poll(&mut self) -> Option<Change> {
+        let current = self.scan();
+        let mut changed: Vec<_> = current.iter().filter(|(path, time)| self.snapshot.get(*path) != Some(*time)).map(|(path, _)| path.clone()).collect();
+        changed.extend(self.snapshot.keys().filter(|path| !current.contains_key(*path)).cloned());
+        if changed.is_empty() {
+            return None;
+        }
+
+        // エディタが複数のファイルを続けて書き込み終えるのを待ってから、まとめて判断する
+        std::thread::sleep(POLL_INTERVAL);
+        let settled = self.scan();
+        changed.extend(settled.iter().filter(|(path, time)| current.get(*path) != Some(*time)).map(|(path, _)| path.clone()));
+        self.snapshot = settled;
+        Some(classify(&changed, &self.assets))
+    }
+
+    /// 変更が見つかるまで待つ
+    pub fn wait(&mut self) -> Change {
+        loop {
+            if let Some(change) = self.poll() {
+                return change;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// `running` が偽を返すか、変更が見つかるまで待つ
+    pub fn wait_while(&mut self, running: impl Fn() -> bool) -> Option<Change> {
+        while running() {
+            if let Some(change) = self.poll() {
+                return Some(change);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        None
+    }
+}
+
+/// 変わったファイルの中に配置するデータ以外のものがあればビルドし直す
+fn classify(changed: &[path::PathBuf], assets: &[path::PathBuf]) -> Change {
+    match changed.iter().find(|path| !assets.contains(path)) {
+        Some(source) => Change::Sources(source.clone()),
+        None => Change::Assets(changed[0].clone()),
+    }
+}
+
+fn modified(path: &path::Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn collect_sources(dir: &path::Path, excluded: &[path::PathBuf], files: &mut BTreeMap<path::PathBuf, Option<SystemTime>>) {
+    for entry in std::fs::read_dir(dir).into_iter().flatten().filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let name = entry.file_name();
+        if excluded.iter().any(|e| path.starts_with(e)) {
+            continue;
+        }
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => {
+                if !IGNORED_DIRS.iter().any(|d| name == *d) {
+                    collect_sources(path.as_path(), excluded, files);
+                }
+            }
+            Ok(_) => {
+                let time = modified(path.as_path());
+                files.insert(path, time);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path;
+    use crate::watch::{classify, package_inputs, Change, Watcher};
+
+    #[test]
+    fn asset_only_changes_skip_the_build() {
+        let assets = vec![path::PathBuf::from("/data/config.txt"), "/kernels/vmlinuz".into()];
+        assert_eq!(classify(&["/kernels/vmlinuz".into()], &assets), Change::Assets("/kernels/vmlinuz".into()));
+        assert_eq!(classify(&["/data/config.txt".into(), "/proj/src/main.rs".into()], &assets), Change::Sources("/proj/src/main.rs".into()));
+
+        let dir = std::env::temp_dir().join(format!("cargo-uefi-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(dir.as_path());
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join("target")).unwrap();
+        std::fs::create_dir_all(dir.join("src").join("images")).unwrap();
+        std::fs::write(dir.join("src").join("main.rs"), b"fn main() {}").unwrap();
+        std::fs::write(dir.join("Cargo.toml"), b"[package]").unwrap();
+        let asset = dir.join("startup.nsh");
+        std::fs::write(asset.as_path(), b"echo 1").unwrap();
+
+        let mut watcher = Watcher::new(package_inputs(std::slice::from_ref(&dir)), vec![dir.join("src").join("images")], vec![asset.clone()]);
+        assert_eq!(watcher.poll(), None);
+        // ビルドの出力や、パッケージの入力でないファイル、除いたディスクイメージは監視しない
+        std::fs::write(dir.join("target").join("app.efi"), b"MZ").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"").unwrap();
+        std::fs::write(dir.join("src").join("images").join("disk.img"), b"").unwrap();
+        assert_eq!(watcher.poll(), None);
+        std::fs::write(dir.join("build.rs"), b"fn main() {}").unwrap();
+        assert_eq!(watcher.poll(), Some(Change::Sources(dir.join("build.rs"))));
+        std::fs::remove_file(asset.as_path()).unwrap();
+        assert_eq!(watcher.poll(), Some(Change::Assets(asset)));
+        std::fs::write(dir.join("src").join("lib.rs"), b"").unwrap();
+        assert_eq!(watcher.poll(), Some(Change::Sources(dir.join("src").join("lib.rs"))));
+
+        std::fs::remove_dir_all(dir.as_path()).unwrap();
+    }
+}